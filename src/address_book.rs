@@ -0,0 +1,281 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+
+/// data directory 内で AddressBook を保存するファイルの名前
+pub const ADDRESS_BOOK_FILE_NAME: &str = "peers.json";
+/// 保持する address の最大数
+pub const MAX_ADDRESSES: usize = 1000;
+/// Addr で一度に送る address の最大数
+pub const MAX_ADDR_PER_MESSAGE: usize = 100;
+/// 連続してこの回数接続に失敗した address は削除する
+const MAX_FAILURES: u32 = 5;
+
+/// address を知った経路
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AddressSource {
+    /// 起動時に指定された node
+    Bootstrap,
+    /// 接続してきた node
+    Inbound,
+    /// 他の node から教えてもらった
+    Gossip { from: SocketAddr },
+}
+
+/// Core ノードの address ごとの情報
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerInfo {
+    source: AddressSource,
+    /// 最後に handshake できた時刻. 一度も接続できていない場合は None.
+    last_seen: Option<DateTime<Utc>>,
+    /// 最後に接続できてから連続して失敗した回数
+    failures: u32,
+}
+
+impl PeerInfo {
+    pub fn get_source(&self) -> AddressSource {
+        self.source
+    }
+
+    pub fn get_last_seen(&self) -> Option<DateTime<Utc>> {
+        self.last_seen
+    }
+
+    pub fn get_failures(&self) -> u32 {
+        self.failures
+    }
+}
+
+/// 知っている Core ノードの address の一覧.
+/// 接続先の選択や Addr での共有に使い、path を指定した場合は json でファイルに保存する。
+#[derive(Debug, Default)]
+pub struct AddressBook {
+    peers: HashMap<SocketAddr, PeerInfo>,
+    path: Option<PathBuf>,
+}
+
+impl AddressBook {
+    /// メモリ上にのみ保持する AddressBook を作る。
+    pub fn new() -> AddressBook {
+        AddressBook {
+            peers: HashMap::new(),
+            path: None,
+        }
+    }
+
+    /// path から読み込む。ファイルが無い場合は空の AddressBook を作る。
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<AddressBook> {
+        let path = path.as_ref().to_path_buf();
+        let peers = if path.exists() {
+            let data = fs::read(&path)
+                .with_context(|| format!("Failed to read address book: {:?}", path))?;
+            let peers: Vec<(SocketAddr, PeerInfo)> = serde_json::from_slice(&data)
+                .with_context(|| format!("Invalid address book: {:?}", path))?;
+            info!("Load {} addresses from {:?}", peers.len(), path);
+            peers.into_iter().collect()
+        } else {
+            HashMap::new()
+        };
+        Ok(AddressBook {
+            peers,
+            path: Some(path),
+        })
+    }
+
+    /// ファイルに保存する。書き込み途中で終了しても壊れないように一時ファイルから置き換える。
+    pub fn save(&self) -> Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut peers: Vec<(&SocketAddr, &PeerInfo)> = self.peers.iter().collect();
+        peers.sort_by_key(|(addr, _)| **addr);
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, serde_json::to_vec_pretty(&peers)?)?;
+        fs::rename(&tmp_path, path)
+            .with_context(|| format!("Failed to save address book: {:?}", path))?;
+        Ok(())
+    }
+
+    /// 知らない address であれば追加する。追加した場合は true を返す。
+    /// 一杯の場合は最も価値の低い address を追い出す。
+    pub fn add(&mut self, addr: SocketAddr, source: AddressSource) -> bool {
+        if self.peers.contains_key(&addr) {
+            return false;
+        }
+        if self.peers.len() >= MAX_ADDRESSES {
+            if let Some(worst) = self.rank().pop() {
+                self.peers.remove(&worst);
+            }
+        }
+        self.peers.insert(
+            addr,
+            PeerInfo {
+                source,
+                last_seen: None,
+                failures: 0,
+            },
+        );
+        true
+    }
+
+    /// handshake できた address を記録する。
+    pub fn mark_seen(&mut self, addr: &SocketAddr) {
+        if let Some(info) = self.peers.get_mut(addr) {
+            info.last_seen = Some(Utc::now());
+            info.failures = 0;
+        }
+    }
+
+    /// 接続に失敗した address を記録する。失敗が続いた address は削除する。
+    pub fn mark_failed(&mut self, addr: &SocketAddr) {
+        let failures = match self.peers.get_mut(addr) {
+            Some(info) => {
+                info.failures += 1;
+                info.failures
+            }
+            None => return,
+        };
+        if failures >= MAX_FAILURES {
+            warn!("Forget {} after {} failures", addr, failures);
+            self.peers.remove(addr);
+        }
+    }
+
+    pub fn remove(&mut self, addr: &SocketAddr) -> bool {
+        self.peers.remove(addr).is_some()
+    }
+
+    /// ip の address をすべて削除し、削除した数を返す。
+    pub fn remove_ip(&mut self, ip: &IpAddr) -> usize {
+        let len = self.peers.len();
+        self.peers.retain(|addr, _| addr.ip() != *ip);
+        len - self.peers.len()
+    }
+
+    pub fn get(&self, addr: &SocketAddr) -> Option<&PeerInfo> {
+        self.peers.get(addr)
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    /// 失敗が少なく、最近接続できた順に並べる。
+    fn rank(&self) -> Vec<SocketAddr> {
+        let mut addrs: Vec<(&SocketAddr, &PeerInfo)> = self.peers.iter().collect();
+        addrs.sort_by(|(a_addr, a), (b_addr, b)| {
+            a.failures
+                .cmp(&b.failures)
+                .then(b.last_seen.cmp(&a.last_seen))
+                .then(a_addr.cmp(b_addr))
+        });
+        addrs.into_iter().map(|(addr, _)| *addr).collect()
+    }
+
+    /// 接続を試みる address を良い順に最大 count 個選ぶ。exclude に含まれる address は選ばない。
+    pub fn select_peers(&self, count: usize, exclude: &[SocketAddr]) -> Vec<SocketAddr> {
+        self.rank()
+            .into_iter()
+            .filter(|addr| !exclude.contains(addr))
+            .take(count)
+            .collect()
+    }
+
+    /// Addr で他の node に教える address. 接続できたことのある address だけを返す。
+    pub fn get_addresses_to_share(&self) -> Vec<SocketAddr> {
+        self.rank()
+            .into_iter()
+            .filter(|addr| self.peers[addr].last_seen.is_some())
+            .take(MAX_ADDR_PER_MESSAGE)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from_str(&format!("127.0.0.1:{}", port)).unwrap()
+    }
+
+    #[test]
+    fn test_select_peers() {
+        let mut book = AddressBook::new();
+        let from = addr(50000);
+        assert!(book.add(addr(50001), AddressSource::Bootstrap));
+        assert!(book.add(addr(50002), AddressSource::Gossip { from }));
+        assert!(book.add(addr(50003), AddressSource::Gossip { from }));
+        assert!(!book.add(addr(50003), AddressSource::Inbound));
+        assert_eq!(
+            book.get(&addr(50003)).unwrap().get_source(),
+            AddressSource::Gossip { from }
+        );
+
+        book.mark_seen(&addr(50003));
+        book.mark_failed(&addr(50001));
+        assert_eq!(
+            book.select_peers(10, &[]),
+            vec![addr(50003), addr(50002), addr(50001)]
+        );
+        assert_eq!(book.select_peers(1, &[addr(50003)]), vec![addr(50002)]);
+        // 一度も接続できていない address は共有しない
+        assert_eq!(book.get_addresses_to_share(), vec![addr(50003)]);
+
+        // 接続できれば失敗の回数はリセットされる
+        book.mark_seen(&addr(50001));
+        assert_eq!(book.get(&addr(50001)).unwrap().get_failures(), 0);
+        for _ in 0..MAX_FAILURES {
+            book.mark_failed(&addr(50002));
+        }
+        assert!(book.get(&addr(50002)).is_none());
+        assert_eq!(book.len(), 2);
+    }
+
+    #[test]
+    fn test_evict_when_full() {
+        let mut book = AddressBook::new();
+        for port in 0..MAX_ADDRESSES as u16 {
+            book.add(addr(port), AddressSource::Inbound);
+        }
+        book.mark_failed(&addr(7));
+        assert!(book.add(addr(60000), AddressSource::Inbound));
+        assert_eq!(book.len(), MAX_ADDRESSES);
+        assert!(book.get(&addr(7)).is_none());
+    }
+
+    #[test]
+    fn test_save_and_open() {
+        let dir = std::env::temp_dir().join(format!("address_book_test_{}", rand::random::<u64>()));
+        let path = dir.join("peers.json");
+
+        let mut book = AddressBook::open(&path).unwrap();
+        assert!(book.is_empty());
+        book.add(addr(50001), AddressSource::Bootstrap);
+        book.add(addr(50002), AddressSource::Gossip { from: addr(50001) });
+        book.mark_seen(&addr(50002));
+        book.mark_failed(&addr(50001));
+        book.save().unwrap();
+
+        let loaded = AddressBook::open(&path).unwrap();
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded.get(&addr(50001)), book.get(&addr(50001)));
+        assert_eq!(loaded.get(&addr(50002)), book.get(&addr(50002)));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,5 +1,15 @@
 pub mod block;
+pub mod block_tree;
+pub mod difficulty;
+pub mod encoding;
+pub mod header_chain;
 pub mod manager;
+pub mod merkle;
+pub mod miner;
+pub mod store;
+pub mod subsidy;
 pub mod transaction;
 pub mod transaction_pool;
+pub mod tx_index;
 pub mod utxo;
+pub mod utxo_set;
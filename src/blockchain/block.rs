@@ -1,27 +1,47 @@
+use crate::blockchain::difficulty::{self, Target};
+use crate::blockchain::encoding::{self, Decode, Encode, Reader};
+use crate::blockchain::merkle::{self, MerkleProof};
+use crate::blockchain::miner::Miner;
+use crate::blockchain::subsidy;
 use crate::blockchain::transaction::{
-    Address, CoinbaseTransaction, NormalTransaction, Transaction, Transactions,
+    Address, ChainedResolver, CoinbaseTransaction, NormalTransaction, OutputResolver, Transaction,
+    TransactionError, TransactionId, Transactions,
 };
-use crate::blockchain::transaction_pool::COINBASE_INCENTIVE;
 use crate::util;
 use anyhow::{bail, Result};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
 
 pub type BlockHash = String;
 
+/// 現在の block header の version
+pub const BLOCK_VERSION: u32 = 1;
+/// block の大きさ (byte) の上限
+pub const MAX_BLOCK_SIZE: usize = 1_000_000;
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub struct BlockWithoutProof {
     timestamp: DateTime<Utc>,
     transaction: Transactions,
     prev_block_hash: BlockHash,
+    // この block の hash が満たすべき target (compact 形式)
+    bits: u32,
 }
 
 impl BlockWithoutProof {
-    pub fn new(transaction: Transactions, prev_block_hash: BlockHash) -> BlockWithoutProof {
+    pub fn new(
+        transaction: Transactions,
+        prev_block_hash: BlockHash,
+        bits: u32,
+    ) -> BlockWithoutProof {
         BlockWithoutProof {
             timestamp: Utc::now(),
             transaction,
             prev_block_hash,
+            bits,
         }
     }
 
@@ -29,147 +49,567 @@ impl BlockWithoutProof {
         self.prev_block_hash.clone()
     }
 
-    pub fn mine(self, difficulty: usize) -> Result<Block> {
-        let mut nonce = 0;
-        let target = "0".repeat(difficulty);
-        loop {
-            let hash = self.calculate_hash(nonce)?;
-            if hash.ends_with(&target) {
-                let block = Block::new(self, nonce);
-                return Ok(block);
-            }
-            nonce += 1;
+    /// header の nonce を変えながら target を満たす hash を 1 thread で探す。
+    pub fn mine(self) -> Result<Block> {
+        let (block, _) = Miner::new(1).mine(self, Arc::new(AtomicBool::new(false)))?;
+        Ok(block.expect("mining without cancellation always finishes"))
+    }
+
+    /// mining した後の block の大きさ (byte). nonce の値によらず一定になる。
+    pub fn get_size(&self) -> usize {
+        let (header, transaction) = self.clone().into_parts();
+        Block::new(header, transaction).get_size()
+    }
+
+    /// nonce が 0 の header と body に分ける。
+    pub(crate) fn into_parts(self) -> (BlockHeader, Transactions) {
+        let header = BlockHeader::new(
+            self.prev_block_hash,
+            calculate_merkle_root(&self.transaction),
+            self.timestamp,
+            self.bits,
+            0,
+        );
+        (header, self.transaction)
+    }
+}
+
+/// block header. この hash が block の hash であり、proof of work の対象になる。
+/// transaction は Merkle root を通してのみ含まれるので、body を受け取る前に
+/// chain の繋がりと proof of work を確認できる。
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct BlockHeader {
+    version: u32,
+    prev_block_hash: BlockHash,
+    merkle_root: String,
+    timestamp: DateTime<Utc>,
+    // hash が満たすべき target (compact 形式)
+    bits: u32,
+    nonce: u64,
+}
+
+impl BlockHeader {
+    pub fn new(
+        prev_block_hash: BlockHash,
+        merkle_root: String,
+        timestamp: DateTime<Utc>,
+        bits: u32,
+        nonce: u64,
+    ) -> BlockHeader {
+        BlockHeader {
+            version: BLOCK_VERSION,
+            prev_block_hash,
+            merkle_root,
+            timestamp,
+            bits,
+            nonce,
         }
     }
 
-    fn calculate_hash(&self, nonce: usize) -> Result<BlockHash> {
-        let data = serde_json::to_string(self)?;
-        Ok(util::sha256(data.as_bytes(), &nonce.to_be_bytes()))
+    pub fn get_version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn get_prev_block_hash(&self) -> BlockHash {
+        self.prev_block_hash.clone()
+    }
+
+    pub fn get_merkle_root(&self) -> String {
+        self.merkle_root.clone()
+    }
+
+    pub fn get_timestamp(&self) -> DateTime<Utc> {
+        self.timestamp
+    }
+
+    pub fn get_bits(&self) -> u32 {
+        self.bits
+    }
+
+    pub fn get_nonce(&self) -> u64 {
+        self.nonce
+    }
+
+    /// header の binary 表現 (encoding::to_bytes) の hash を block の hash とする。
+    pub fn get_hash(&self) -> BlockHash {
+        util::sha256(&encoding::to_bytes(self), &[])
+    }
+
+    pub(crate) fn set_nonce(&mut self, nonce: u64) {
+        self.nonce = nonce;
     }
+
+    /// nonce を除いた部分の encoding. nonce は最後に encode されるので、
+    /// これに nonce の encoding を続けたものの hash が get_hash と一致する。
+    pub(crate) fn to_bytes_without_nonce(&self) -> Vec<u8> {
+        let mut buf = vec![encoding::ENCODING_VERSION];
+        self.encode_without_nonce(&mut buf);
+        buf
+    }
+
+    fn encode_without_nonce(&self, buf: &mut Vec<u8>) {
+        self.version.encode(buf);
+        self.prev_block_hash.encode(buf);
+        self.merkle_root.encode(buf);
+        self.timestamp.encode(buf);
+        self.bits.encode(buf);
+    }
+
+    /// hash が header 自身の target を満たしているか確認する。
+    pub fn check_proof_of_work(&self) -> Result<()> {
+        if !Target::from_compact(self.bits).is_met_by(&self.get_hash()) {
+            bail!("invalid target");
+        }
+        Ok(())
+    }
+}
+
+/// transactions の id から Merkle root を計算する。
+fn calculate_merkle_root(transactions: &Transactions) -> String {
+    let txids = transactions
+        .get_transactions()
+        .iter()
+        .map(|tx| tx.get_id())
+        .collect::<Vec<_>>();
+    merkle::merkle_root(&txids)
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub struct Block {
-    #[serde(flatten)]
-    inner: BlockWithoutProof,
-    nonce: usize,
+    header: BlockHeader,
+    transaction: Transactions,
 }
 
 impl Block {
-    pub fn new(inner: BlockWithoutProof, nonce: usize) -> Block {
-        Block { inner, nonce }
+    pub fn new(header: BlockHeader, transaction: Transactions) -> Block {
+        Block {
+            header,
+            transaction,
+        }
     }
 
     pub fn get_transaction_at(&self, idx: usize) -> Option<Transaction> {
-        self.inner.transaction.get_transaction_at(idx)
+        self.transaction.get_transaction_at(idx)
     }
 
     pub fn get_transactions(&self) -> Vec<Transaction> {
-        self.inner.transaction.get_transactions()
+        self.transaction.get_transactions()
     }
 
     pub fn get_coinbase_transaction(&self) -> CoinbaseTransaction {
-        self.inner.transaction.get_coinbase_transaction()
+        self.transaction.get_coinbase_transaction()
     }
 
     pub fn get_normal_transactions(&self) -> Vec<NormalTransaction> {
-        self.inner.transaction.get_normal_transactions()
+        self.transaction.get_normal_transactions()
     }
 
     pub fn get_prev_block_hash(&self) -> BlockHash {
-        self.inner.prev_block_hash.clone()
+        self.header.get_prev_block_hash()
+    }
+
+    pub fn get_timestamp(&self) -> DateTime<Utc> {
+        self.header.get_timestamp()
+    }
+
+    pub fn get_bits(&self) -> u32 {
+        self.header.get_bits()
+    }
+
+    pub fn get_header(&self) -> &BlockHeader {
+        &self.header
     }
 
-    // 常に生成される json のキー順が一致するかどうか確認が必要。
-    // -> serde_json は どうやら struct で定義した順に出力する。
-    // https://users.rust-lang.org/t/order-of-fields-in-serde-json-to-string/48928
     pub fn calculate_hash(&self) -> Result<BlockHash> {
-        self.inner.calculate_hash(self.nonce)
+        Ok(self.header.get_hash())
     }
 
-    pub fn is_valid(&self, difficulty: usize) -> Result<()> {
-        // check target
-        let hash = self.calculate_hash()?;
-        let target = "0".repeat(difficulty);
-        if !hash.ends_with(&target) {
-            bail!("invalid target");
+    /// block の hash が block 自身の target を満たしているか確認する。
+    pub fn check_proof_of_work(&self) -> Result<()> {
+        self.header.check_proof_of_work()
+    }
+
+    /// block 内の transaction について、header の Merkle root に含まれていることの証明を作る。
+    pub fn get_merkle_proof(&self, txid: &TransactionId) -> Option<MerkleProof> {
+        let txids = self
+            .get_transactions()
+            .iter()
+            .map(|tx| tx.get_id())
+            .collect::<Vec<_>>();
+        let index = txids.iter().position(|id| id == txid)?;
+        merkle::merkle_proof(&txids, index)
+    }
+
+    /// header の Merkle root が body の transaction と一致しているか確認する。
+    /// 同じ transaction を重複させても同じ Merkle root になり得るので、重複も不正とする。
+    pub fn check_merkle_root(&self) -> Result<()> {
+        let mut txids = HashSet::new();
+        if !self
+            .get_transactions()
+            .iter()
+            .all(|tx| txids.insert(tx.get_id()))
+        {
+            bail!("block contains duplicate transactions");
+        }
+        if self.header.get_merkle_root() != calculate_merkle_root(&self.transaction) {
+            bail!("merkle root doesn't match transactions");
+        }
+        Ok(())
+    }
+
+    /// expected_bits はこの block の高さ height で要求される target. coinbase は height の発行額と fee の合計を受け取る。
+    /// ancestors は親から遡った block の (timestamp, bits) で、timestamp の下限を求めるために使う。
+    /// resolver は normal transaction の input が参照する output を引くために使う。
+    pub fn is_valid(
+        &self,
+        expected_bits: u32,
+        height: usize,
+        ancestors: &[(DateTime<Utc>, u32)],
+        resolver: &dyn OutputResolver,
+    ) -> Result<()> {
+        if self.get_bits() != expected_bits {
+            bail!(
+                "unexpected target: {:#010x} (expected {:#010x})",
+                self.get_bits(),
+                expected_bits
+            );
+        }
+        difficulty::check_timestamp(self.get_timestamp(), ancestors, Utc::now())?;
+        self.check_proof_of_work()?;
+        self.check_merkle_root()?;
+        if self.get_size() > MAX_BLOCK_SIZE {
+            bail!("block is too large: {} bytes", self.get_size());
         }
 
         // check incentive
+        // block 内の前の transaction が作った output も使える
         let coinbase_tx = self.get_coinbase_transaction();
         let normal_txs = self.get_normal_transactions();
-        let mut total_fee = 0;
+        let mut created: HashMap<TransactionId, Transaction> = HashMap::new();
+        let mut total_fee: u64 = 0;
         for tx in normal_txs {
-            total_fee += tx.get_input_value() - tx.get_output_value();
+            let fee = tx.get_fee(&ChainedResolver::new(vec![resolver, &created]))?;
+            total_fee = total_fee
+                .checked_add(fee)
+                .ok_or(TransactionError::ValueOverflow)?;
+            created.insert(tx.get_id(), Transaction::Normal(tx));
         }
-        if coinbase_tx.get_value() != total_fee + COINBASE_INCENTIVE {
-            bail!("invalid coinbase value");
+        let subsidy = subsidy::get_block_subsidy(height);
+        if Some(coinbase_tx.get_value()) != total_fee.checked_add(subsidy) {
+            bail!(
+                "invalid coinbase value: {} (subsidy {} at height {} and fee {})",
+                coinbase_tx.get_value(),
+                subsidy,
+                height,
+                total_fee
+            );
         }
 
         Ok(())
     }
 
+    /// block の大きさ (byte)
+    pub fn get_size(&self) -> usize {
+        let mut buf = vec![];
+        self.encode(&mut buf);
+        buf.len()
+    }
+
     pub fn miner(&self) -> Address {
         let tx = self.get_transaction_at(0).unwrap();
         tx.get_output(0).unwrap().get_recipient()
     }
 }
 
+impl Encode for BlockWithoutProof {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.timestamp.encode(buf);
+        self.transaction.encode(buf);
+        self.prev_block_hash.encode(buf);
+        self.bits.encode(buf);
+    }
+}
+
+impl Decode for BlockWithoutProof {
+    fn decode(reader: &mut Reader) -> Result<BlockWithoutProof> {
+        Ok(BlockWithoutProof {
+            timestamp: Decode::decode(reader)?,
+            transaction: Decode::decode(reader)?,
+            prev_block_hash: Decode::decode(reader)?,
+            bits: Decode::decode(reader)?,
+        })
+    }
+}
+
+impl Encode for BlockHeader {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.encode_without_nonce(buf);
+        self.nonce.encode(buf);
+    }
+}
+
+impl Decode for BlockHeader {
+    fn decode(reader: &mut Reader) -> Result<BlockHeader> {
+        Ok(BlockHeader {
+            version: Decode::decode(reader)?,
+            prev_block_hash: Decode::decode(reader)?,
+            merkle_root: Decode::decode(reader)?,
+            timestamp: Decode::decode(reader)?,
+            bits: Decode::decode(reader)?,
+            nonce: Decode::decode(reader)?,
+        })
+    }
+}
+
+impl Encode for Block {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.header.encode(buf);
+        self.transaction.encode(buf);
+    }
+}
+
+impl Decode for Block {
+    fn decode(reader: &mut Reader) -> Result<Block> {
+        Ok(Block::new(Decode::decode(reader)?, Decode::decode(reader)?))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::blockchain::difficulty::{
+        INITIAL_BITS, MAX_FUTURE_BLOCK_TIME_SECS, MEDIAN_TIME_SPAN, POW_LIMIT_BITS,
+    };
+    use crate::blockchain::subsidy::{get_block_subsidy, HALVING_INTERVAL};
     use crate::blockchain::transaction::{
         CoinbaseTransaction, TransactionInput, TransactionOutput,
     };
-    use crate::blockchain::transaction_pool::COINBASE_INCENTIVE;
     use chrono::Duration;
+    use std::collections::HashMap;
     use std::str::FromStr;
 
     fn generate_sample(
         incentive: Option<u64>,
-    ) -> (CoinbaseTransaction, NormalTransaction, Transactions) {
+    ) -> (
+        HashMap<TransactionId, Transaction>,
+        CoinbaseTransaction,
+        NormalTransaction,
+        Transactions,
+    ) {
         let now: DateTime<Utc> = DateTime::from_str("2022-03-09T12:00:00Z").unwrap();
         let sec = Duration::seconds(1);
 
-        let incentive = incentive.unwrap_or(COINBASE_INCENTIVE + 1);
+        let incentive = incentive.unwrap_or(get_block_subsidy(1) + 1);
 
-        let tx1 = CoinbaseTransaction::new("alice".to_string(), incentive, now + sec * 0);
+        let tx0 = Transaction::Coinbase(CoinbaseTransaction::new(
+            "alice".to_string(),
+            10,
+            now + sec * 1,
+        ));
+        let tx1 = CoinbaseTransaction::new("alice".to_string(), incentive, now);
         let tx2 = NormalTransaction::new(
-            vec![TransactionInput::new(
-                Transaction::Coinbase(CoinbaseTransaction::new(
-                    "alice".to_string(),
-                    10,
-                    now + sec * 1,
-                )),
-                0,
-            )],
+            vec![TransactionInput::new(tx0.get_id(), 0)],
             vec![TransactionOutput::new("bob".to_string(), 9)],
             Utc::now(),
         );
         let txs = Transactions::new(tx1.clone(), vec![tx2.clone()]);
-        (tx1, tx2, txs)
+        let index = vec![(tx0.get_id(), tx0)].into_iter().collect();
+        (index, tx1, tx2, txs)
     }
 
     #[tokio::test]
     async fn test_block_mine() {
-        let (_, _, txs) = generate_sample(None);
-        let block_without_proof =
-            BlockWithoutProof::new(txs, util::sha256("foo".as_bytes(), "123".as_bytes()));
+        let (index, _, _, txs) = generate_sample(None);
+        let block_without_proof = BlockWithoutProof::new(
+            txs,
+            util::sha256("foo".as_bytes(), "123".as_bytes()),
+            INITIAL_BITS,
+        );
 
-        let difficulty = 2;
-        let block = block_without_proof.mine(difficulty).unwrap();
-        assert!(block.calculate_hash().unwrap().ends_with("00"));
-        assert!(block.is_valid(difficulty).is_ok())
+        let block = block_without_proof.mine().unwrap();
+        assert!(block.calculate_hash().unwrap().starts_with("000"));
+        assert!(block.is_valid(INITIAL_BITS, 1, &[], &index).is_ok());
+        // target differs from the one required at its height
+        assert!(block.is_valid(POW_LIMIT_BITS, 1, &[], &index).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_is_valid_returns_false_with_forged_timestamp() {
+        let (index, _, _, txs) = generate_sample(None);
+        let prev_block_hash = util::sha256("foo".as_bytes(), "123".as_bytes());
+        let mine_at = |timestamp: DateTime<Utc>| {
+            BlockWithoutProof {
+                timestamp,
+                transaction: txs.clone(),
+                prev_block_hash: prev_block_hash.clone(),
+                bits: INITIAL_BITS,
+            }
+            .mine()
+            .unwrap()
+        };
+        let now = Utc::now();
+        let minute = Duration::minutes(1);
+        let ancestors = (1..=MEDIAN_TIME_SPAN as i32)
+            .map(|i| (now - minute * i, INITIAL_BITS))
+            .collect::<Vec<_>>();
+        let median = now - minute * (MEDIAN_TIME_SPAN as i32 / 2 + 1);
+
+        let block = mine_at(median + Duration::seconds(1));
+        assert!(block.is_valid(INITIAL_BITS, 1, &ancestors, &index).is_ok());
+        // not after the median time of the past blocks
+        let block = mine_at(median);
+        assert!(block.is_valid(INITIAL_BITS, 1, &ancestors, &index).is_err());
+        // too far in the future
+        let block = mine_at(now + Duration::seconds(MAX_FUTURE_BLOCK_TIME_SECS) + minute);
+        assert!(block.is_valid(INITIAL_BITS, 1, &ancestors, &index).is_err());
+        assert!(block.is_valid(INITIAL_BITS, 1, &[], &index).is_err());
     }
 
     #[tokio::test]
     async fn test_is_valid_returns_false_with_excessive_incentive() {
-        let (_, _, txs) = generate_sample(Some(12));
-        let block_without_proof =
-            BlockWithoutProof::new(txs, util::sha256("foo".as_bytes(), "123".as_bytes()));
+        let (index, _, _, txs) = generate_sample(Some(12));
+        let block_without_proof = BlockWithoutProof::new(
+            txs,
+            util::sha256("foo".as_bytes(), "123".as_bytes()),
+            INITIAL_BITS,
+        );
+
+        let block = block_without_proof.mine().unwrap();
+        assert!(block.is_valid(INITIAL_BITS, 1, &[], &index).is_err())
+    }
+
+    #[tokio::test]
+    async fn test_is_valid_requires_subsidy_at_height() {
+        let height = HALVING_INTERVAL + 1;
+        // the sample transaction pays 1 as fee
+        let (index, _, _, txs) = generate_sample(Some(get_block_subsidy(height) + 1));
+        let block = BlockWithoutProof::new(
+            txs,
+            util::sha256("foo".as_bytes(), "123".as_bytes()),
+            INITIAL_BITS,
+        )
+        .mine()
+        .unwrap();
+        assert!(block.is_valid(INITIAL_BITS, height, &[], &index).is_ok());
+        assert!(block.is_valid(INITIAL_BITS, 1, &[], &index).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_is_valid_returns_false_with_unknown_input() {
+        let (_, _, _, txs) = generate_sample(None);
+        let block_without_proof = BlockWithoutProof::new(
+            txs,
+            util::sha256("foo".as_bytes(), "123".as_bytes()),
+            INITIAL_BITS,
+        );
+
+        let block = block_without_proof.mine().unwrap();
+        assert!(block
+            .is_valid(INITIAL_BITS, 1, &[], &HashMap::new())
+            .is_err())
+    }
+
+    #[tokio::test]
+    async fn test_is_valid_returns_false_with_outputs_exceeding_inputs() {
+        let (index, _, tx2, _) = generate_sample(None);
+        let overspending = NormalTransaction::new(
+            tx2.get_inputs(),
+            vec![TransactionOutput::new("bob".to_string(), 11)],
+            Utc::now(),
+        );
+        let txs = Transactions::new(
+            CoinbaseTransaction::new("alice".to_string(), get_block_subsidy(1), Utc::now()),
+            vec![overspending],
+        );
+        let block_without_proof = BlockWithoutProof::new(
+            txs,
+            util::sha256("foo".as_bytes(), "123".as_bytes()),
+            INITIAL_BITS,
+        );
+
+        let block = block_without_proof.mine().unwrap();
+        let err = block.is_valid(INITIAL_BITS, 1, &[], &index).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<TransactionError>(),
+            Some(&TransactionError::OutputsExceedInputs {
+                input: 10,
+                output: 11
+            })
+        );
+    }
 
-        let difficulty = 2;
-        let block = block_without_proof.mine(difficulty).unwrap();
-        assert!(block.is_valid(difficulty).is_err())
+    #[tokio::test]
+    async fn test_is_valid_returns_false_with_tampered_body() {
+        let (index, tx1, tx2, txs) = generate_sample(None);
+        let block = BlockWithoutProof::new(
+            txs,
+            util::sha256("foo".as_bytes(), "123".as_bytes()),
+            INITIAL_BITS,
+        )
+        .mine()
+        .unwrap();
+        assert!(block.check_merkle_root().is_ok());
+
+        // the header stays the same, so the block hash doesn't change
+        let tampered = Block::new(
+            block.get_header().clone(),
+            Transactions::new(tx1.clone(), vec![]),
+        );
+        assert_eq!(
+            tampered.calculate_hash().unwrap(),
+            block.calculate_hash().unwrap()
+        );
+        assert!(tampered.check_merkle_root().is_err());
+        assert!(tampered.is_valid(INITIAL_BITS, 1, &[], &index).is_err());
+
+        // a proof from the genuine block doesn't match the tampered one
+        let proof = block.get_merkle_proof(&tx2.get_id()).unwrap();
+        assert!(proof.verify(&tx2.get_id(), &block.get_header().get_merkle_root()));
+        assert_eq!(tampered.get_merkle_proof(&tx2.get_id()), None);
+
+        // the same transaction must not appear twice
+        let duplicated = Block::new(
+            block.get_header().clone(),
+            Transactions::new(tx1, vec![tx2.clone(), tx2]),
+        );
+        assert!(duplicated.check_merkle_root().is_err());
+    }
+
+    #[test]
+    fn test_encode_block() {
+        let now: DateTime<Utc> = DateTime::from_str("2022-03-09T12:00:00Z").unwrap();
+        let header = BlockHeader::new("00ff".to_string(), "ab".to_string(), now, POW_LIMIT_BITS, 1);
+
+        // golden vectors: changing these breaks every block hash
+        assert_eq!(
+            util::bytes_to_hex(&encoding::to_bytes(&header)),
+            [
+                "02",
+                "00000001",
+                "0000000430306666",
+                "000000026162",
+                "00000000622896c000000000",
+                "207fffff",
+                "0000000000000001",
+            ]
+            .concat()
+        );
+        assert_eq!(
+            header.get_hash(),
+            "5cfbfa4d780a427065b164aba4b92b9a6839ab017e18a6d8b33527da044b3aff"
+        );
+
+        // round trip
+        let (_, _, _, txs) = generate_sample(None);
+        let block_without_proof = BlockWithoutProof::new(txs, "00ff".to_string(), POW_LIMIT_BITS);
+        let bytes = encoding::to_bytes(&block_without_proof);
+        assert_eq!(
+            encoding::from_bytes::<BlockWithoutProof>(&bytes).unwrap(),
+            block_without_proof
+        );
+        let block = block_without_proof.mine().unwrap();
+        let bytes = encoding::to_bytes(&block);
+        assert_eq!(encoding::from_bytes::<Block>(&bytes).unwrap(), block);
+        assert!(encoding::from_bytes::<Block>(&bytes[..bytes.len() - 1]).is_err());
     }
 }
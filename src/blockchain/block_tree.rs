@@ -0,0 +1,145 @@
+use crate::blockchain::block::{Block, BlockHash};
+use anyhow::{bail, Result};
+use std::collections::HashMap;
+
+/// tree 内の block とその位置
+#[derive(Clone, Debug)]
+struct BlockEntry {
+    block: Block,
+    height: usize,
+    // genesis からこの block までの proof of work の合計
+    chain_work: u128,
+    // main chain に繋ごうとして検証に失敗した
+    invalid: bool,
+}
+
+/// genesis を根として、受け取った block を競合する枝も含めて保持する。
+/// 根 (genesis) 自体は block を持たず、高さと work は 0 とする。
+pub struct BlockTree {
+    root: BlockHash,
+    entries: HashMap<BlockHash, BlockEntry>,
+}
+
+impl BlockTree {
+    pub fn new(root: BlockHash) -> BlockTree {
+        BlockTree {
+            root,
+            entries: HashMap::new(),
+        }
+    }
+
+    pub fn contains(&self, hash: &BlockHash) -> bool {
+        hash == &self.root || self.entries.contains_key(hash)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get_block(&self, hash: &BlockHash) -> Option<&Block> {
+        self.entries.get(hash).map(|entry| &entry.block)
+    }
+
+    pub fn get_height(&self, hash: &BlockHash) -> Option<usize> {
+        if hash == &self.root {
+            return Some(0);
+        }
+        self.entries.get(hash).map(|entry| entry.height)
+    }
+
+    pub fn get_chain_work(&self, hash: &BlockHash) -> Option<u128> {
+        if hash == &self.root {
+            return Some(0);
+        }
+        self.entries.get(hash).map(|entry| entry.chain_work)
+    }
+
+    pub fn is_invalid(&self, hash: &BlockHash) -> bool {
+        self.entries
+            .get(hash)
+            .map(|entry| entry.invalid)
+            .unwrap_or(false)
+    }
+
+    /// block を親 block の子として追加する。
+    /// 親が tree に無い場合や invalid な場合はエラーになる。
+    pub fn insert(&mut self, hash: BlockHash, block: Block, work: u128) -> Result<()> {
+        let prev_hash = block.get_prev_block_hash();
+        if self.is_invalid(&prev_hash) {
+            bail!("Parent block {} is invalid", prev_hash);
+        }
+        let (height, chain_work) =
+            match (self.get_height(&prev_hash), self.get_chain_work(&prev_hash)) {
+                (Some(height), Some(chain_work)) => (height + 1, chain_work + work),
+                _ => bail!("Parent block {} is unknown", prev_hash),
+            };
+        self.entries.entry(hash).or_insert(BlockEntry {
+            block,
+            height,
+            chain_work,
+            invalid: false,
+        });
+        Ok(())
+    }
+
+    /// 検証に失敗した block に印を付け、それを含む枝が選ばれないようにする。
+    pub fn mark_invalid(&mut self, hash: &BlockHash) {
+        if let Some(entry) = self.entries.get_mut(hash) {
+            entry.invalid = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::block::BlockWithoutProof;
+    use crate::blockchain::difficulty::POW_LIMIT_BITS;
+    use crate::blockchain::transaction::{CoinbaseTransaction, Transactions};
+    use chrono::Utc;
+
+    fn generate_block(prev_block_hash: BlockHash) -> (BlockHash, Block) {
+        let coinbase = CoinbaseTransaction::new("miner".to_string(), 10, Utc::now());
+        let block = BlockWithoutProof::new(
+            Transactions::new(coinbase, vec![]),
+            prev_block_hash,
+            POW_LIMIT_BITS,
+        )
+        .mine()
+        .unwrap();
+        (block.calculate_hash().unwrap(), block)
+    }
+
+    #[test]
+    fn test_insert_and_mark_invalid() {
+        let root = "genesis".to_string();
+        let mut tree = BlockTree::new(root.clone());
+        assert!(tree.contains(&root));
+        assert_eq!(tree.get_chain_work(&root), Some(0));
+
+        let (hash1, block1) = generate_block(root.clone());
+        tree.insert(hash1.clone(), block1.clone(), 16).unwrap();
+        let (hash2, block2) = generate_block(hash1.clone());
+        tree.insert(hash2.clone(), block2, 256).unwrap();
+
+        assert_eq!(tree.len(), 2);
+        assert_eq!(tree.get_block(&hash1), Some(&block1));
+        assert_eq!(tree.get_height(&hash2), Some(2));
+        assert_eq!(tree.get_chain_work(&hash2), Some(16 + 256));
+
+        // parent is unknown
+        let (_, orphan) = generate_block("unknown".to_string());
+        assert!(tree.insert("orphan".to_string(), orphan, 16).is_err());
+
+        // parent is invalid
+        tree.mark_invalid(&hash2);
+        assert!(tree.is_invalid(&hash2));
+        let (hash3, block3) = generate_block(hash2);
+        assert!(tree.insert(hash3.clone(), block3, 16).is_err());
+        assert!(!tree.contains(&hash3));
+    }
+}
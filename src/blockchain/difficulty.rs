@@ -0,0 +1,273 @@
+use crate::util;
+use anyhow::{bail, Result};
+use chrono::{DateTime, Duration, Utc};
+
+/// target を計算し直す間隔 (block 数)
+pub const RETARGET_INTERVAL: usize = 10;
+/// 目標とする block の生成間隔 (秒)
+pub const TARGET_BLOCK_INTERVAL_SECS: i64 = 60;
+/// timestamp の下限とする中央値を取る、親から遡った block の数
+pub const MEDIAN_TIME_SPAN: usize = 11;
+/// 受け入れる timestamp が現在時刻より先であってよい幅 (秒)
+pub const MAX_FUTURE_BLOCK_TIME_SECS: i64 = 2 * 60 * 60;
+/// block の検証に使う、親から遡った block の数
+pub const ANCESTORS_LEN: usize = if MEDIAN_TIME_SPAN > RETARGET_INTERVAL {
+    MEDIAN_TIME_SPAN
+} else {
+    RETARGET_INTERVAL
+};
+/// 最初の block に要求される target. hash の先頭 12 bit が 0 であることにほぼ等しい。
+pub const INITIAL_BITS: u32 = 0x1f0f_ffff;
+/// 最も易しい target. 計算し直してもこれより易しくはならない。
+pub const POW_LIMIT_BITS: u32 = 0x207f_ffff;
+
+/// 256 bit の target. block の hash を big endian の整数とみなし、これ以下であれば proof of work を満たす。
+/// block には bitcoin と同じ compact 形式 (上位 1 byte が byte 数、下位 3 byte が仮数) で持たせる。
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Target([u8; 32]);
+
+impl Target {
+    pub fn from_compact(bits: u32) -> Target {
+        let size = (bits >> 24) as usize;
+        if size > 32 {
+            return Target([0xff; 32]);
+        }
+        let mut bytes = [0u8; 32];
+        let mantissa = (bits & 0x007f_ffff).to_be_bytes();
+        for (i, byte) in mantissa[1..].iter().enumerate() {
+            // 仮数の i 番目の byte は 256^(size - 1 - i) の位になる
+            if i < size {
+                bytes[32 - size + i] = *byte;
+            }
+        }
+        Target(bytes)
+    }
+
+    pub fn to_compact(&self) -> u32 {
+        let mut size = 32 - self.0.iter().take_while(|b| **b == 0).count();
+        let start = 32 - size;
+        let mut mantissa = (0..3).fold(0u32, |acc, i| {
+            (acc << 8) | *self.0.get(start + i).unwrap_or(&0) as u32
+        });
+        // 最上位 bit は符号として扱われるので使わない
+        if mantissa & 0x0080_0000 != 0 {
+            mantissa >>= 8;
+            size += 1;
+        }
+        ((size as u32) << 24) | mantissa
+    }
+
+    /// 16 進の hash がこの target 以下か
+    pub fn is_met_by(&self, hash: &str) -> bool {
+        hash.len() == 64
+            && hash
+                .bytes()
+                .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+            && hash <= util::bytes_to_hex(&self.0).as_str()
+    }
+
+    /// この target を満たす hash を見つけるのに必要な試行回数のおおよその期待値
+    pub fn get_work(&self) -> u128 {
+        let bits = self.to_compact();
+        let size = bits >> 24;
+        let mantissa = (bits & 0x007f_ffff) as u128;
+        if mantissa == 0 || size < 3 {
+            return u128::MAX;
+        }
+        // 2^256 / (mantissa * 256^(size - 3))
+        let shift = 256 - 8 * (size - 3);
+        if shift >= 128 {
+            u128::MAX / mantissa
+        } else {
+            (1u128 << shift) / mantissa
+        }
+    }
+
+    /// 実際にかかった時間と目標の時間の比で target を調整する。
+    /// 一度に変わる幅は 4 倍までとし、POW_LIMIT_BITS より易しくはしない。
+    pub fn retarget(&self, actual_secs: i64, expected_secs: i64) -> Target {
+        let expected_secs = expected_secs.max(1);
+        let actual_secs = actual_secs.max(expected_secs / 4).min(expected_secs * 4);
+
+        // 掛け算で溢れないように上位に 8 byte 余分に取る
+        let mut bytes = [0u8; 40];
+        bytes[8..].copy_from_slice(&self.0);
+        let mut carry: u128 = 0;
+        for byte in bytes.iter_mut().rev() {
+            let v = *byte as u128 * actual_secs as u128 + carry;
+            *byte = (v & 0xff) as u8;
+            carry = v >> 8;
+        }
+        let mut rem: u128 = 0;
+        for byte in bytes.iter_mut() {
+            rem = (rem << 8) | *byte as u128;
+            *byte = (rem / expected_secs as u128) as u8;
+            rem %= expected_secs as u128;
+        }
+
+        let limit = Target::from_compact(POW_LIMIT_BITS);
+        if bytes[..8].iter().any(|b| *b != 0) {
+            return limit;
+        }
+        let mut target = [0u8; 32];
+        target.copy_from_slice(&bytes[8..]);
+        Target(target).min(limit)
+    }
+}
+
+/// 親 block から遡った block の (timestamp, bits) を元に、height の block に要求される bits を計算する。
+/// ancestors は親から順に並べたもので、先頭の最大 RETARGET_INTERVAL 個を使う。
+pub fn next_bits(height: usize, ancestors: &[(DateTime<Utc>, u32)], initial_bits: u32) -> u32 {
+    let ancestors = &ancestors[..ancestors.len().min(RETARGET_INTERVAL)];
+    let (last_timestamp, last_bits) = match ancestors.first() {
+        Some(ancestor) => *ancestor,
+        None => return initial_bits,
+    };
+    // 計算し直すのは高さが RETARGET_INTERVAL の倍数の block のみ
+    let position_in_interval = height % RETARGET_INTERVAL;
+    if position_in_interval != 0 || ancestors.len() < 2 {
+        return last_bits;
+    }
+
+    let (first_timestamp, _) = ancestors[ancestors.len() - 1];
+    let actual_secs = (last_timestamp - first_timestamp).num_seconds();
+    let expected_secs = TARGET_BLOCK_INTERVAL_SECS * (ancestors.len() as i64 - 1);
+    Target::from_compact(last_bits)
+        .retarget(actual_secs, expected_secs)
+        .to_compact()
+}
+
+/// 親から遡った最大 MEDIAN_TIME_SPAN 個の block の timestamp の中央値. genesis の子では None.
+pub fn median_time_past(ancestors: &[(DateTime<Utc>, u32)]) -> Option<DateTime<Utc>> {
+    let mut timestamps = ancestors
+        .iter()
+        .take(MEDIAN_TIME_SPAN)
+        .map(|(timestamp, _)| *timestamp)
+        .collect::<Vec<_>>();
+    timestamps.sort();
+    timestamps.get(timestamps.len() / 2).cloned()
+}
+
+/// timestamp が親から遡った block の timestamp の中央値より後で、
+/// now から MAX_FUTURE_BLOCK_TIME_SECS 以内であることを確認する。
+pub fn check_timestamp(
+    timestamp: DateTime<Utc>,
+    ancestors: &[(DateTime<Utc>, u32)],
+    now: DateTime<Utc>,
+) -> Result<()> {
+    if let Some(median) = median_time_past(ancestors) {
+        if timestamp <= median {
+            bail!(
+                "timestamp {} is not after the median time of past blocks {}",
+                timestamp,
+                median
+            );
+        }
+    }
+    if timestamp > now + Duration::seconds(MAX_FUTURE_BLOCK_TIME_SECS) {
+        bail!("timestamp {} is too far in the future", timestamp);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compact_round_trip() {
+        for bits in [INITIAL_BITS, POW_LIMIT_BITS, 0x1d00_ffff, 0x0312_3456] {
+            assert_eq!(Target::from_compact(bits).to_compact(), bits);
+        }
+        assert_eq!(
+            util::bytes_to_hex(&Target::from_compact(0x0412_3456).0[28..]),
+            "12345600"
+        );
+        assert_eq!(
+            util::bytes_to_hex(&Target::from_compact(0x0212_3456).0[29..]),
+            "001234"
+        );
+        // mantissa with the highest bit set is shifted
+        let target = Target::from_compact(0x0300_8000);
+        assert_eq!(target.to_compact(), 0x0300_8000);
+    }
+
+    #[test]
+    fn test_is_met_by() {
+        let target = Target::from_compact(INITIAL_BITS);
+        assert!(target.is_met_by(&format!("000fffff{}", "0".repeat(56))));
+        assert!(!target.is_met_by(&format!("000fffff{}1", "0".repeat(55))));
+        assert!(!target.is_met_by(&format!("0010{}", "0".repeat(60))));
+        assert!(!target.is_met_by("000"));
+        assert!(!target.is_met_by(&format!("000F{}", "0".repeat(60))));
+    }
+
+    #[test]
+    fn test_get_work() {
+        assert_eq!(Target::from_compact(POW_LIMIT_BITS).get_work(), 2);
+        assert_eq!(Target::from_compact(INITIAL_BITS).get_work(), 4096);
+        assert!(
+            Target::from_compact(0x1d00_ffff).get_work()
+                > Target::from_compact(INITIAL_BITS).get_work()
+        );
+    }
+
+    #[test]
+    fn test_retarget() {
+        let target = Target::from_compact(INITIAL_BITS);
+        assert_eq!(target.retarget(600, 600), target);
+
+        // blocks came twice as fast, so the target is halved
+        assert_eq!(target.retarget(300, 600).to_compact(), 0x1f07_ffff);
+        // adjustment is limited to 4 times
+        assert_eq!(target.retarget(1, 600), target.retarget(150, 600));
+        // never easier than the pow limit
+        let limit = Target::from_compact(POW_LIMIT_BITS);
+        assert_eq!(limit.retarget(1200, 600), limit);
+        assert_eq!(Target([0xff; 32]).retarget(1200, 600), limit);
+    }
+
+    #[test]
+    fn test_next_bits() {
+        let now = Utc::now();
+        let interval = Duration::seconds(TARGET_BLOCK_INTERVAL_SECS / 2);
+        let ancestors = (0..RETARGET_INTERVAL)
+            .map(|i| (now - interval * i as i32, INITIAL_BITS))
+            .collect::<Vec<_>>();
+
+        assert_eq!(next_bits(1, &[], INITIAL_BITS), INITIAL_BITS);
+        assert_eq!(
+            next_bits(RETARGET_INTERVAL + 1, &ancestors, POW_LIMIT_BITS),
+            INITIAL_BITS
+        );
+        assert_eq!(
+            next_bits(RETARGET_INTERVAL, &ancestors, POW_LIMIT_BITS),
+            0x1f07_ffff
+        );
+    }
+
+    #[test]
+    fn test_check_timestamp() {
+        let now = Utc::now();
+        let interval = Duration::seconds(TARGET_BLOCK_INTERVAL_SECS);
+        // 新しい順に並ぶが、timestamp は前後していてもよい
+        let mut ancestors = (0..ANCESTORS_LEN)
+            .map(|i| (now - interval * (i as i32 + 1), INITIAL_BITS))
+            .collect::<Vec<_>>();
+        ancestors.swap(0, 1);
+        let median = now - interval * (MEDIAN_TIME_SPAN as i32 / 2 + 1);
+        assert_eq!(median_time_past(&ancestors), Some(median));
+        assert_eq!(median_time_past(&[]), None);
+
+        assert!(check_timestamp(now, &ancestors, now).is_ok());
+        assert!(check_timestamp(median + Duration::seconds(1), &ancestors, now).is_ok());
+        assert!(check_timestamp(median, &ancestors, now).is_err());
+        assert!(check_timestamp(median - Duration::seconds(1), &ancestors, now).is_err());
+
+        let limit = now + Duration::seconds(MAX_FUTURE_BLOCK_TIME_SECS);
+        assert!(check_timestamp(limit, &ancestors, now).is_ok());
+        assert!(check_timestamp(limit + Duration::seconds(1), &ancestors, now).is_err());
+        assert!(check_timestamp(limit + Duration::seconds(1), &[], now).is_err());
+        assert!(check_timestamp(now - interval * 100, &[], now).is_ok());
+    }
+}
@@ -0,0 +1,293 @@
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, TimeZone, Utc};
+
+/// encoding の形式の version. to_bytes の先頭 1 byte に置く。
+pub const ENCODING_VERSION: u8 = 2;
+
+/// hash や署名の対象となる決定的な binary 表現.
+///
+/// - 整数は big endian の固定長 (usize は u64 として扱う)
+/// - bool は 1 byte の 0 か 1
+/// - 文字列と byte 列は 4 byte (u32) の長さを前に付ける
+/// - Vec は 4 byte (u32) の要素数の後に各要素を並べる
+/// - 時刻は 8 byte (i64) の unix 秒と 4 byte (u32) の nano 秒
+/// - struct は field を定義順に並べ、enum は 1 byte の tag の後に中身を置く
+pub trait Encode {
+    fn encode(&self, buf: &mut Vec<u8>);
+}
+
+pub trait Decode: Sized {
+    fn decode(reader: &mut Reader) -> Result<Self>;
+}
+
+/// version を先頭に付けて value を encode する。
+pub fn to_bytes<T: Encode>(value: &T) -> Vec<u8> {
+    let mut buf = vec![ENCODING_VERSION];
+    value.encode(&mut buf);
+    buf
+}
+
+/// to_bytes で encode された byte 列を decode する。余分な byte が残っている場合はエラーになる。
+pub fn from_bytes<T: Decode>(bytes: &[u8]) -> Result<T> {
+    let mut reader = Reader::new(bytes);
+    let version = reader.read_u8()?;
+    if version != ENCODING_VERSION {
+        bail!("Unsupported encoding version: {}", version);
+    }
+    let value = T::decode(&mut reader)?;
+    if !reader.is_empty() {
+        bail!("{} trailing bytes after value", reader.remaining());
+    }
+    Ok(value)
+}
+
+/// byte 列を先頭から読み進める。
+pub struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Reader<'a> {
+        Reader { buf }
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    pub fn read_bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.buf.len() < len {
+            bail!(
+                "Unexpected end of data: {} bytes required, {} bytes left",
+                len,
+                self.buf.len()
+            );
+        }
+        let (head, tail) = self.buf.split_at(len);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    fn read_array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let mut array = [0u8; N];
+        array.copy_from_slice(self.read_bytes(N)?);
+        Ok(array)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.read_array()?))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64> {
+        Ok(u64::from_be_bytes(self.read_array()?))
+    }
+
+    pub fn read_i64(&mut self) -> Result<i64> {
+        Ok(i64::from_be_bytes(self.read_array()?))
+    }
+}
+
+impl Encode for u8 {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.push(*self);
+    }
+}
+
+impl Decode for u8 {
+    fn decode(reader: &mut Reader) -> Result<u8> {
+        reader.read_u8()
+    }
+}
+
+impl Encode for bool {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.push(*self as u8);
+    }
+}
+
+impl Decode for bool {
+    fn decode(reader: &mut Reader) -> Result<bool> {
+        match reader.read_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            value => bail!("Invalid bool: {}", value),
+        }
+    }
+}
+
+impl Encode for u32 {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend(self.to_be_bytes());
+    }
+}
+
+impl Decode for u32 {
+    fn decode(reader: &mut Reader) -> Result<u32> {
+        reader.read_u32()
+    }
+}
+
+impl Encode for u64 {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend(self.to_be_bytes());
+    }
+}
+
+impl Decode for u64 {
+    fn decode(reader: &mut Reader) -> Result<u64> {
+        reader.read_u64()
+    }
+}
+
+impl Encode for usize {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        (*self as u64).encode(buf);
+    }
+}
+
+impl Decode for usize {
+    fn decode(reader: &mut Reader) -> Result<usize> {
+        let value = reader.read_u64()?;
+        if value > usize::MAX as u64 {
+            bail!("{} doesn't fit in usize", value);
+        }
+        Ok(value as usize)
+    }
+}
+
+/// 長さの prefix を書く。u32 に収まらない長さは扱わない。
+fn encode_len(len: usize, buf: &mut Vec<u8>) {
+    assert!(len <= u32::MAX as usize, "length {} exceeds u32", len);
+    (len as u32).encode(buf);
+}
+
+impl Encode for String {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        encode_len(self.len(), buf);
+        buf.extend(self.as_bytes());
+    }
+}
+
+impl Decode for String {
+    fn decode(reader: &mut Reader) -> Result<String> {
+        let len = reader.read_u32()? as usize;
+        let bytes = reader.read_bytes(len)?;
+        String::from_utf8(bytes.to_vec()).context("String is not valid UTF-8")
+    }
+}
+
+impl<T: Encode> Encode for Vec<T> {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        encode_len(self.len(), buf);
+        for item in self.iter() {
+            item.encode(buf);
+        }
+    }
+}
+
+impl<T: Decode> Decode for Vec<T> {
+    fn decode(reader: &mut Reader) -> Result<Vec<T>> {
+        let len = reader.read_u32()? as usize;
+        // 要素は少なくとも 1 byte なので、残りの byte 数より多くは確保しない
+        let mut items = Vec::with_capacity(len.min(reader.remaining()));
+        for _ in 0..len {
+            items.push(T::decode(reader)?);
+        }
+        Ok(items)
+    }
+}
+
+impl Encode for DateTime<Utc> {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend(self.timestamp().to_be_bytes());
+        self.timestamp_subsec_nanos().encode(buf);
+    }
+}
+
+impl Decode for DateTime<Utc> {
+    fn decode(reader: &mut Reader) -> Result<DateTime<Utc>> {
+        let secs = reader.read_i64()?;
+        let nanos = reader.read_u32()?;
+        match Utc.timestamp_opt(secs, nanos) {
+            chrono::LocalResult::Single(timestamp) => Ok(timestamp),
+            _ => bail!("Invalid timestamp: {}.{:09}", secs, nanos),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_encode_primitives() {
+        let timestamp: DateTime<Utc> = DateTime::from_str("2022-03-09T12:00:00.5Z").unwrap();
+        let mut buf = vec![];
+        1u8.encode(&mut buf);
+        true.encode(&mut buf);
+        2u32.encode(&mut buf);
+        3usize.encode(&mut buf);
+        "ab".to_string().encode(&mut buf);
+        vec![4u64].encode(&mut buf);
+        timestamp.encode(&mut buf);
+        assert_eq!(
+            util::bytes_to_hex(&buf),
+            [
+                "01",
+                "01",
+                "00000002",
+                "0000000000000003",
+                "000000026162",
+                "000000010000000000000004",
+                "00000000622896c01dcd6500",
+            ]
+            .concat()
+        );
+
+        let mut reader = Reader::new(&buf);
+        assert_eq!(u8::decode(&mut reader).unwrap(), 1);
+        assert!(bool::decode(&mut reader).unwrap());
+        assert_eq!(u32::decode(&mut reader).unwrap(), 2);
+        assert_eq!(usize::decode(&mut reader).unwrap(), 3);
+        assert_eq!(String::decode(&mut reader).unwrap(), "ab");
+        assert_eq!(Vec::<u64>::decode(&mut reader).unwrap(), vec![4]);
+        assert_eq!(DateTime::<Utc>::decode(&mut reader).unwrap(), timestamp);
+        assert!(reader.is_empty());
+    }
+
+    #[test]
+    fn test_from_bytes_returns_err() {
+        let bytes = to_bytes(&"ab".to_string());
+        assert_eq!(from_bytes::<String>(&bytes).unwrap(), "ab");
+
+        // unknown version
+        let mut unknown = bytes.clone();
+        unknown[0] = ENCODING_VERSION + 1;
+        assert!(from_bytes::<String>(&unknown).is_err());
+
+        // truncated or trailing bytes
+        assert!(from_bytes::<String>(&bytes[..bytes.len() - 1]).is_err());
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert!(from_bytes::<String>(&trailing).is_err());
+
+        // a huge length doesn't allocate beyond the data
+        assert!(from_bytes::<Vec<u64>>(&[ENCODING_VERSION, 0xff, 0xff, 0xff, 0xff]).is_err());
+
+        // invalid bool
+        assert!(from_bytes::<bool>(&[ENCODING_VERSION, 2]).is_err());
+
+        // invalid UTF-8
+        assert!(from_bytes::<String>(&[ENCODING_VERSION, 0, 0, 0, 1, 0xff]).is_err());
+    }
+}
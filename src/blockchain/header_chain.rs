@@ -0,0 +1,307 @@
+use crate::blockchain::block::{BlockHash, BlockHeader};
+use crate::blockchain::difficulty::{self, ANCESTORS_LEN};
+use crate::blockchain::manager::{self, block_work};
+use crate::blockchain::merkle::MerkleProof;
+use crate::blockchain::transaction::TransactionId;
+use anyhow::{bail, Context, Result};
+use chrono::Utc;
+use std::collections::HashMap;
+
+/// block の body を持たず、header だけで main chain を追う SPV 用の chain.
+/// transaction が main chain 上の block に含まれていることを MerkleProof で確認するために使う。
+pub struct HeaderChain {
+    // main chain の header (genesis の次の block から順に並ぶ)
+    headers: Vec<BlockHeader>,
+    hashes: Vec<BlockHash>,
+    // block hash から main chain 上の高さへの索引
+    heights: HashMap<BlockHash, usize>,
+    initial_bits: u32,
+}
+
+impl HeaderChain {
+    pub fn new(initial_bits: u32) -> HeaderChain {
+        HeaderChain {
+            headers: vec![],
+            hashes: vec![],
+            heights: HashMap::new(),
+            initial_bits,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.headers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.headers.is_empty()
+    }
+
+    pub fn get_last_block_hash(&self) -> BlockHash {
+        match self.hashes.last() {
+            Some(hash) => hash.clone(),
+            None => manager::genesis_block_hash(),
+        }
+    }
+
+    pub fn get_block_locator(&self) -> Vec<BlockHash> {
+        manager::block_locator(&self.hashes)
+    }
+
+    /// main chain 上の block の高さを返す。genesis は 0 とする。
+    pub fn get_height(&self, hash: &BlockHash) -> Option<usize> {
+        if hash == &manager::genesis_block_hash() {
+            return Some(0);
+        }
+        self.heights.get(hash).cloned()
+    }
+
+    /// 受け取った header の列を検証し、main chain より work が多くなる場合は main chain を置き換える。
+    /// header の列は連続していて、先頭の親は main chain 上になければならない。
+    /// main chain に新しく加わった header の数を返す。
+    pub fn add_headers(&mut self, headers: &[BlockHeader]) -> Result<usize> {
+        let prev_hash = match headers.first() {
+            Some(header) => header.get_prev_block_hash(),
+            None => return Ok(0),
+        };
+        let fork_point = match self.get_height(&prev_hash) {
+            Some(height) => height,
+            None => bail!("Parent of headers {} is unknown", prev_hash),
+        };
+
+        let mut ancestors = self.headers[..fork_point]
+            .iter()
+            .rev()
+            .take(ANCESTORS_LEN)
+            .map(|header| (header.get_timestamp(), header.get_bits()))
+            .collect::<Vec<_>>();
+        let now = Utc::now();
+        let mut prev_hash = prev_hash;
+        let mut hashes = vec![];
+        let mut work: u128 = 0;
+        for (i, header) in headers.iter().enumerate() {
+            if header.get_prev_block_hash() != prev_hash {
+                bail!("Headers are not connected at {}", prev_hash);
+            }
+            let height = fork_point + i + 1;
+            let expected_bits = difficulty::next_bits(height, &ancestors, self.initial_bits);
+            if header.get_bits() != expected_bits {
+                bail!(
+                    "Header has unexpected target {:#010x} at height {} (expected {:#010x})",
+                    header.get_bits(),
+                    height,
+                    expected_bits
+                );
+            }
+            difficulty::check_timestamp(header.get_timestamp(), &ancestors, now)
+                .with_context(|| format!("Header has invalid timestamp at height {}", height))?;
+            header.check_proof_of_work()?;
+            work = work.saturating_add(block_work(header.get_bits()));
+            ancestors.insert(0, (header.get_timestamp(), header.get_bits()));
+            ancestors.truncate(ANCESTORS_LEN);
+            prev_hash = header.get_hash();
+            hashes.push(prev_hash.clone());
+        }
+
+        let current_work = self.headers[fork_point..]
+            .iter()
+            .fold(0u128, |acc, header| {
+                acc.saturating_add(block_work(header.get_bits()))
+            });
+        // 同じ work の場合は先に受け取った方を優先する
+        if work <= current_work {
+            return Ok(0);
+        }
+
+        let added = hashes
+            .iter()
+            .filter(|hash| !self.heights.contains_key(*hash))
+            .count();
+        for hash in self.hashes.drain(fork_point..) {
+            self.heights.remove(&hash);
+        }
+        self.headers.truncate(fork_point);
+        for (header, hash) in headers.iter().zip(hashes) {
+            self.heights.insert(hash.clone(), self.hashes.len() + 1);
+            self.hashes.push(hash);
+            self.headers.push(header.clone());
+        }
+        Ok(added)
+    }
+
+    /// txid の transaction が main chain 上の block_hash の block に含まれていることを proof で確認し、
+    /// その block の承認数 (block 自身を含めてその上に積まれた block の数) を返す。
+    pub fn verify_merkle_proof(
+        &self,
+        block_hash: &BlockHash,
+        txid: &TransactionId,
+        proof: &MerkleProof,
+    ) -> Result<usize> {
+        let height = match self.heights.get(block_hash) {
+            Some(height) => *height,
+            None => bail!("Block {} is not in the main chain", block_hash),
+        };
+        let header = &self.headers[height - 1];
+        if !proof.verify(txid, &header.get_merkle_root()) {
+            bail!(
+                "Transaction {} is not included in block {}",
+                txid,
+                block_hash
+            );
+        }
+        Ok(self.get_confirmations(block_hash).unwrap())
+    }
+
+    /// main chain 上の block の承認数を返す。main chain 上に無い場合は None.
+    pub fn get_confirmations(&self, block_hash: &BlockHash) -> Option<usize> {
+        self.heights
+            .get(block_hash)
+            .map(|height| self.headers.len() - height + 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::block::{Block, BlockWithoutProof};
+    use crate::blockchain::difficulty::{
+        MAX_FUTURE_BLOCK_TIME_SECS, MEDIAN_TIME_SPAN, POW_LIMIT_BITS,
+    };
+    use crate::blockchain::manager::BlockchainManager;
+    use crate::blockchain::transaction::{CoinbaseTransaction, Transactions};
+    use chrono::{DateTime, Duration, Utc};
+
+    fn generate_block(recipient: &str, prev_block_hash: BlockHash, bits: u32) -> Block {
+        let coinbase = CoinbaseTransaction::new(recipient.to_string(), 10, Utc::now());
+        BlockWithoutProof::new(Transactions::new(coinbase, vec![]), prev_block_hash, bits)
+            .mine()
+            .unwrap()
+    }
+
+    fn generate_chain(manager: &mut BlockchainManager, recipient: &str, len: usize) {
+        for _ in 0..len {
+            let block = generate_block(
+                recipient,
+                manager.get_last_block_hash(),
+                manager.get_next_bits(),
+            );
+            manager.add_new_block(block).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_add_headers_and_verify_merkle_proof() {
+        let mut manager = BlockchainManager::new(POW_LIMIT_BITS);
+        generate_chain(&mut manager, "recipient1", 3);
+        let mut chain = HeaderChain::new(POW_LIMIT_BITS);
+
+        let headers = manager.get_headers_after(&chain.get_block_locator(), 10);
+        assert_eq!(chain.add_headers(&headers[..2]).unwrap(), 2);
+        assert_eq!(chain.add_headers(&headers).unwrap(), 1);
+        assert_eq!(chain.add_headers(&headers).unwrap(), 0);
+        assert_eq!(chain.get_last_block_hash(), manager.get_last_block_hash());
+
+        let block = manager.get_chain()[1].clone();
+        let txid = block.get_coinbase_transaction().get_id();
+        let (block_hash, proof) = manager.get_merkle_proof(&txid).unwrap();
+        assert_eq!(block_hash, block.calculate_hash().unwrap());
+        assert_eq!(
+            chain
+                .verify_merkle_proof(&block_hash, &txid, &proof)
+                .unwrap(),
+            2
+        );
+
+        // proof for another transaction
+        let other = manager.get_chain()[0].get_coinbase_transaction().get_id();
+        assert!(chain
+            .verify_merkle_proof(&block_hash, &other, &proof)
+            .is_err());
+        // block which is unknown to the header chain
+        assert!(chain
+            .verify_merkle_proof(&"unknown".to_string(), &txid, &proof)
+            .is_err());
+        assert_eq!(manager.get_merkle_proof(&"unknown".to_string()), None);
+    }
+
+    #[test]
+    fn test_add_headers_switches_to_branch_with_more_work() {
+        let mut manager = BlockchainManager::new(POW_LIMIT_BITS);
+        generate_chain(&mut manager, "recipient1", 2);
+        let mut chain = HeaderChain::new(POW_LIMIT_BITS);
+        let headers = manager.get_headers_after(&chain.get_block_locator(), 10);
+        chain.add_headers(&headers).unwrap();
+
+        // a branch from the first block with the same work is ignored
+        let fork = headers[0].get_hash();
+        let block2b = generate_block("recipient2", fork.clone(), POW_LIMIT_BITS);
+        let block3b = generate_block(
+            "recipient2",
+            block2b.calculate_hash().unwrap(),
+            POW_LIMIT_BITS,
+        );
+        let branch = vec![block2b.get_header().clone(), block3b.get_header().clone()];
+        assert_eq!(chain.add_headers(&branch[..1]).unwrap(), 0);
+        assert_eq!(chain.get_last_block_hash(), headers[1].get_hash());
+
+        // a longer branch replaces the main chain
+        assert_eq!(chain.add_headers(&branch).unwrap(), 2);
+        assert_eq!(chain.len(), 3);
+        assert_eq!(
+            chain.get_last_block_hash(),
+            block3b.calculate_hash().unwrap()
+        );
+        assert_eq!(chain.get_confirmations(&headers[1].get_hash()), None);
+        assert_eq!(chain.get_confirmations(&fork), Some(3));
+
+        // headers with an unexpected target are rejected
+        let invalid = generate_block("recipient3", chain.get_last_block_hash(), 0x2000_ffff);
+        assert!(chain.add_headers(&[invalid.get_header().clone()]).is_err());
+        assert_eq!(chain.len(), 3);
+    }
+
+    #[test]
+    fn test_add_headers_rejects_forged_timestamp() {
+        let mut manager = BlockchainManager::new(POW_LIMIT_BITS);
+        generate_chain(&mut manager, "recipient1", MEDIAN_TIME_SPAN);
+        let mut chain = HeaderChain::new(POW_LIMIT_BITS);
+        let headers = manager.get_headers_after(&chain.get_block_locator(), MEDIAN_TIME_SPAN);
+        chain.add_headers(&headers).unwrap();
+
+        let last_block_hash = chain.get_last_block_hash();
+        // 次の高さでは target を計算し直さない
+        let bits = headers[MEDIAN_TIME_SPAN - 1].get_bits();
+        let mut timestamps = headers
+            .iter()
+            .map(|header| header.get_timestamp())
+            .collect::<Vec<_>>();
+        timestamps.sort();
+        let median = timestamps[MEDIAN_TIME_SPAN / 2];
+        let forge = |timestamp: DateTime<Utc>| {
+            (0..)
+                .map(|nonce| {
+                    BlockHeader::new(
+                        last_block_hash.clone(),
+                        headers[0].get_merkle_root(),
+                        timestamp,
+                        bits,
+                        nonce,
+                    )
+                })
+                .find(|header| header.check_proof_of_work().is_ok())
+                .unwrap()
+        };
+
+        let future =
+            Utc::now() + Duration::seconds(MAX_FUTURE_BLOCK_TIME_SECS) + Duration::minutes(1);
+        for timestamp in [median, median - Duration::seconds(1), future] {
+            assert!(chain.add_headers(&[forge(timestamp)]).is_err());
+        }
+        assert_eq!(chain.len(), MEDIAN_TIME_SPAN);
+        assert_eq!(
+            chain
+                .add_headers(&[forge(median + Duration::seconds(1))])
+                .unwrap(),
+            1
+        );
+    }
+}
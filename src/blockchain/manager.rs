@@ -1,40 +1,120 @@
-use crate::blockchain::block::{Block, BlockHash};
-use crate::blockchain::transaction::{NormalTransaction, Transaction};
+use crate::blockchain::block::{Block, BlockHash, BlockHeader};
+use crate::blockchain::block_tree::BlockTree;
+use crate::blockchain::difficulty::{self, Target, ANCESTORS_LEN};
+use crate::blockchain::merkle::MerkleProof;
+use crate::blockchain::store::{self, BlockStore, MemoryBlockStore};
+use crate::blockchain::subsidy;
+use crate::blockchain::transaction::{
+    Address, ChainedResolver, NormalTransaction, OutPoint, OutputResolver, Transaction,
+    TransactionError, TransactionId, TransactionOutput,
+};
 use crate::blockchain::transaction_pool::TransactionPool;
+use crate::blockchain::tx_index::TransactionIndex;
+use crate::blockchain::utxo_set::{BlockUndo, UTXOSet};
 use crate::util;
-use anyhow::{anyhow, bail, Result};
-use log::warn;
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, Utc};
+use log::{info, warn};
 use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+
+/// add_new_block で block を受け取った結果
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ChainUpdate {
+    /// すでに受け取っている block
+    AlreadyKnown,
+    /// 親 block を知らないので tree に繋げられない
+    MissingParent,
+    /// main chain より work の少ない枝に追加した
+    SideChain,
+    /// main chain の先頭が変わった。main chain から外れた block に含まれていた transaction を持つ
+    NewTip {
+        orphan_transactions: Vec<NormalTransaction>,
+    },
+}
 
 pub struct BlockchainManager {
-    chain: Vec<Block>,
-    difficulty: usize,
+    // 受け取った block 全体 (main chain 以外の枝も含む)
+    tree: BlockTree,
+    // main chain の block の hash (genesis の次の block から順に並ぶ)
+    chain: Vec<BlockHash>,
+    // genesis の次の block に要求される target (compact 形式)
+    initial_bits: u32,
+    store: Box<dyn BlockStore>,
+    // main chain の UTXO 全体
+    utxo_set: UTXOSet,
+    // chain の各 block に対応する UTXOSet の巻き戻し情報
+    undos: Vec<BlockUndo>,
+    // main chain の transaction の位置と address ごとの transaction の索引
+    tx_index: TransactionIndex,
 }
 
 impl BlockchainManager {
-    pub fn new(difficulty: usize) -> BlockchainManager {
+    pub fn new(initial_bits: u32) -> BlockchainManager {
+        Self::with_empty_chain(initial_bits, Box::new(MemoryBlockStore::new()))
+    }
+
+    fn with_empty_chain(initial_bits: u32, store: Box<dyn BlockStore>) -> BlockchainManager {
         BlockchainManager {
+            tree: BlockTree::new(genesis_block_hash()),
             chain: vec![],
-            difficulty,
+            initial_bits,
+            store,
+            utxo_set: UTXOSet::new(),
+            undos: vec![],
+            tx_index: TransactionIndex::new(),
         }
     }
 
+    /// store に保存された main chain を読み込み、検証し直した上で BlockchainManager を作る。
+    /// 検証に失敗した block 以降は捨てる。
+    pub fn with_store(initial_bits: u32, store: Box<dyn BlockStore>) -> Result<BlockchainManager> {
+        let mut manager = Self::with_empty_chain(initial_bits, store);
+
+        let stored_chain =
+            store::load_chain(manager.store.as_ref(), &manager.get_genesis_block_hash())?;
+        let stored_len = stored_chain.len();
+        for block in stored_chain {
+            if let Err(err) = manager.accept_block(block) {
+                warn!(
+                    "Stored block is invalid, discard it and the following blocks: {:?}",
+                    err
+                );
+                let last_block_hash = manager.get_last_block_hash();
+                manager.store.set_tip(&last_block_hash)?;
+                break;
+            }
+        }
+        info!(
+            "Loaded {} of {} stored blocks",
+            manager.chain.len(),
+            stored_len
+        );
+
+        Ok(manager)
+    }
+
     pub fn get_last_block_hash(&self) -> BlockHash {
-        if let Some(block) = self.chain.last() {
-            block.calculate_hash().unwrap()
-        } else {
-            self.get_genesis_block_hash()
+        match self.chain.last() {
+            Some(hash) => hash.clone(),
+            None => self.get_genesis_block_hash(),
         }
     }
 
+    /// main chain の高さ. genesis だけの場合は 0.
+    pub fn get_height(&self) -> usize {
+        self.chain.len()
+    }
+
     pub fn get_genesis_block_hash(&self) -> BlockHash {
-        let mut hasher = Sha256::new();
-        hasher.update(r#"{"message":"this_is_simple_bitcoin_genesis_block"}"#);
-        util::bytes_to_hex(&hasher.finalize().to_vec())
+        genesis_block_hash()
     }
 
     pub fn get_chain(&self) -> Vec<Block> {
-        self.chain.clone()
+        self.chain
+            .iter()
+            .map(|hash| self.get_block(hash).clone())
+            .collect()
     }
 
     pub fn get_transactions(&self) -> Vec<Transaction> {
@@ -45,12 +125,189 @@ impl BlockchainManager {
         res
     }
 
-    pub fn get_difficulty(&self) -> usize {
-        self.difficulty
+    /// main chain の次の block に要求される target を返す。
+    pub fn get_next_bits(&self) -> u32 {
+        self.get_expected_bits(&self.get_last_block_hash())
+    }
+
+    /// prev_hash の子となる block に要求される target を返す。
+    fn get_expected_bits(&self, prev_hash: &BlockHash) -> u32 {
+        let height = self.tree.get_height(prev_hash).unwrap_or(0) + 1;
+        difficulty::next_bits(height, &self.get_ancestors(prev_hash), self.initial_bits)
+    }
+
+    /// hash から遡った最大 ANCESTORS_LEN 個の block の (timestamp, bits) を新しい順に返す。
+    fn get_ancestors(&self, hash: &BlockHash) -> Vec<(DateTime<Utc>, u32)> {
+        let mut ancestors = vec![];
+        let mut hash = hash.clone();
+        while ancestors.len() < ANCESTORS_LEN {
+            match self.tree.get_block(&hash) {
+                Some(block) => {
+                    ancestors.push((block.get_timestamp(), block.get_bits()));
+                    hash = block.get_prev_block_hash();
+                }
+                None => break,
+            }
+        }
+        ancestors
+    }
+
+    /// 受け取った block を tree に追加し、main chain より work の多い枝ができたらそれを main chain とする。
+    /// 新しい枝の block が不正だった場合は main chain を元に戻してエラーを返す。
+    pub fn add_new_block(&mut self, block: Block) -> Result<ChainUpdate> {
+        let update = self.accept_block(block.clone())?;
+        match update {
+            ChainUpdate::SideChain => {
+                self.store.put_block(&block)?;
+            }
+            ChainUpdate::NewTip { .. } => {
+                self.store.put_block(&block)?;
+                self.store.set_tip(&self.get_last_block_hash())?;
+            }
+            ChainUpdate::AlreadyKnown | ChainUpdate::MissingParent => {}
+        }
+        Ok(update)
+    }
+
+    /// add_new_block のうち store に書き込まない部分
+    fn accept_block(&mut self, block: Block) -> Result<ChainUpdate> {
+        let hash = block.calculate_hash()?;
+        if self.tree.contains(&hash) {
+            return Ok(ChainUpdate::AlreadyKnown);
+        }
+        if !self.tree.contains(&block.get_prev_block_hash()) {
+            return Ok(ChainUpdate::MissingParent);
+        }
+        self.check_headers(std::slice::from_ref(block.get_header()))?;
+        // 不正な body のために正しい header が invalid と記録されないよう、tree に入れる前に確認する
+        block.check_merkle_root()?;
+        let work = block_work(block.get_bits());
+        self.tree.insert(hash.clone(), block, work)?;
+
+        // 同じ work の場合は先に受け取った方を優先する
+        if self.tree.get_chain_work(&hash) <= self.tree.get_chain_work(&self.get_last_block_hash())
+        {
+            return Ok(ChainUpdate::SideChain);
+        }
+        let orphan_transactions = self.reorganize(&hash)?;
+        Ok(ChainUpdate::NewTip {
+            orphan_transactions,
+        })
     }
 
-    pub fn add_new_block(&mut self, block: Block) {
-        self.chain.push(block);
+    /// main chain を new_tip までの枝に切り替える。
+    /// UTXOSet を分岐点まで巻き戻してから新しい枝の block を検証しつつ適用し、
+    /// main chain から外れた block に含まれていた transaction を返す。
+    fn reorganize(&mut self, new_tip: &BlockHash) -> Result<Vec<NormalTransaction>> {
+        // 新しい枝を main chain との分岐点まで遡る
+        let mut branch = vec![];
+        let mut hash = new_tip.clone();
+        while !self.is_in_main_chain(&hash) {
+            if self.tree.is_invalid(&hash) {
+                bail!("Branch contains invalid block {}", hash);
+            }
+            let prev_hash = self.get_block(&hash).get_prev_block_hash();
+            branch.push(hash);
+            hash = prev_hash;
+        }
+        branch.reverse();
+        let fork_point = self.tree.get_height(&hash).unwrap();
+
+        let mut disconnected = vec![];
+        while self.chain.len() > fork_point {
+            disconnected.push(self.pop_block().unwrap());
+        }
+        disconnected.reverse();
+
+        for hash in branch.iter() {
+            let block = self.get_block(hash).clone();
+            if let Err(err) = self
+                .is_valid_block(&block)
+                .and_then(|_| self.push_block(hash.clone()))
+            {
+                self.tree.mark_invalid(hash);
+                self.restore_blocks(fork_point, disconnected);
+                return Err(err);
+            }
+        }
+        if !disconnected.is_empty() {
+            info!(
+                "Reorganized main chain: {} blocks disconnected, {} blocks connected",
+                disconnected.len(),
+                branch.len()
+            );
+        }
+
+        let connected_ids = branch
+            .iter()
+            .flat_map(|hash| self.get_block(hash).get_normal_transactions())
+            .map(|tx| tx.get_id())
+            .collect::<HashSet<TransactionId>>();
+        let orphan_transactions = disconnected
+            .iter()
+            .flat_map(|hash| self.get_block(hash).get_normal_transactions())
+            .filter(|tx| !connected_ids.contains(&tx.get_id()))
+            .collect();
+        Ok(orphan_transactions)
+    }
+
+    fn is_in_main_chain(&self, hash: &BlockHash) -> bool {
+        match self.tree.get_height(hash) {
+            Some(0) => true,
+            Some(height) => self.chain.get(height - 1) == Some(hash),
+            None => false,
+        }
+    }
+
+    fn get_block(&self, hash: &BlockHash) -> &Block {
+        self.tree
+            .get_block(hash)
+            .expect("block in main chain must be in block tree")
+    }
+
+    /// tree 内の block を chain の末尾に追加し、UTXOSet に反映する。
+    fn push_block(&mut self, hash: BlockHash) -> Result<()> {
+        let block = self
+            .tree
+            .get_block(&hash)
+            .ok_or_else(|| anyhow!("Block {} is not in block tree", hash))?;
+        let undo = self.utxo_set.apply_block(block)?;
+        self.tx_index.add_block(&hash, block, &undo);
+        self.undos.push(undo);
+        self.chain.push(hash);
+        Ok(())
+    }
+
+    /// chain の末尾の block を取り除き、UTXOSet と索引への反映を取り消す。
+    fn pop_block(&mut self) -> Option<BlockHash> {
+        let hash = self.chain.pop()?;
+        let undo = self.undos.pop().unwrap();
+        let block = self
+            .tree
+            .get_block(&hash)
+            .expect("block in main chain must be in block tree");
+        self.tx_index.remove_block(&hash, block, &undo);
+        self.utxo_set.rollback_block(undo);
+        Some(hash)
+    }
+
+    /// main chain の高さ height の block までに発行された金額の合計を返す。
+    /// height が main chain の先頭より先の場合は None.
+    pub fn get_supply(&self, height: usize) -> Option<u64> {
+        if height > self.get_height() {
+            return None;
+        }
+        Some(subsidy::get_supply(height))
+    }
+
+    /// address が main chain 上で持っている残高を返す。
+    pub fn get_balance(&self, address: &Address) -> u64 {
+        self.utxo_set.get_balance(address)
+    }
+
+    /// address 宛ての UTXO を返す。
+    pub fn get_utxos_for(&self, address: &Address) -> Vec<(OutPoint, TransactionOutput)> {
+        self.utxo_set.get_utxos_for(address)
     }
 
     pub fn is_valid_block(&self, block: &Block) -> Result<()> {
@@ -64,360 +321,1257 @@ impl BlockchainManager {
             return Err(err);
         }
 
-        // check difficulty etc.
-        block.is_valid(self.difficulty)?;
-
+        // check target etc.
+        block.is_valid(
+            self.get_next_bits(),
+            self.get_height() + 1,
+            &self.get_ancestors(&last_block_hash),
+            self,
+        )?;
+
+        // block 内の前の transaction が作った output も使える
+        let mut spent = HashSet::new();
+        let mut created: HashMap<TransactionId, Transaction> = HashMap::new();
         for tx in block.get_normal_transactions() {
-            self.is_valid_transaction(&tx)?;
+            self.check_transaction(&tx, &ChainedResolver::new(vec![&self.utxo_set, &created]))?;
+            for input in tx.get_inputs() {
+                if !spent.insert(input.get_outpoint().clone()) {
+                    bail!(
+                        "{:?} is spent more than once in block",
+                        input.get_outpoint()
+                    );
+                }
+            }
+            created.insert(tx.get_id(), Transaction::Normal(tx));
         }
 
         Ok(())
     }
 
-    /// TransactionPool から自身の blockchain にすでに取り込んだ transaction を除く。
+    /// TransactionPool から自身の blockchain にすでに取り込んだ transaction や、
+    /// main chain の切り替えによって有効でなくなった transaction, 有効期限が過ぎた transaction を除く。
     /// 主に他 Core ノードから新 block を受け取った場合に必要な処理。
     pub fn remove_useless_transactions(&self, pool: &mut TransactionPool) {
-        for block in self.chain.iter() {
-            for transaction_in_block in block.get_normal_transactions() {
-                for transaction_in_pool in pool.get_transactions() {
-                    if transaction_in_block == transaction_in_pool {
-                        pool.remove_transaction(&transaction_in_pool);
-                    }
-                }
+        // 取り込まれた transaction は、その output を使う子の transaction を残して除く
+        for transaction in pool.get_transactions() {
+            let txid = transaction.get_id();
+            let is_confirmed = (0..transaction.get_outputs().len())
+                .any(|idx| self.utxo_set.contains(&OutPoint::new(txid.clone(), idx)));
+            if is_confirmed {
+                pool.remove_transaction_by_id(&txid);
             }
         }
+        // 現在の main chain に対して有効でなくなった transaction は子孫ごと除く
+        for transaction in pool.get_transactions() {
+            let txid = transaction.get_id();
+            if pool.get_transaction(&txid).is_none() {
+                continue;
+            }
+            if let Err(err) = self.is_valid_transaction_in_pool(&transaction, pool) {
+                let removed = pool.remove_transaction_with_descendants(&txid);
+                info!(
+                    "Remove {} invalid transactions from pool: {}",
+                    removed.len(),
+                    err
+                );
+            }
+        }
+        pool.remove_expired_transactions(Utc::now());
     }
 
-    /// 他 Core ノードから受け取った blockchain と比較して必要ならそれを main chain とする。
-    /// その場合に除かれることになる block 内の未反映 transactions を返す。
-    pub fn resolve_conflicts(&mut self, other_chain: Vec<Block>) -> Vec<NormalTransaction> {
-        if self.chain.len() >= other_chain.len() {
-            warn!(
-                "Received full chain is shorter than me, ignore it: {:?}",
-                other_chain
-            );
-            return vec![];
+    /// TransactionPool::load_saved で読み込んだ transaction を、pool に入った時刻を引き継いで pool に戻す。
+    /// 現在の main chain に対して有効でないものや、有効期限が過ぎたものは捨てる。
+    pub fn restore_saved_transactions(
+        &self,
+        pool: &mut TransactionPool,
+        saved: Vec<(NormalTransaction, DateTime<Utc>)>,
+    ) {
+        let count = saved.len();
+        for (transaction, added_at) in saved {
+            if let Err(err) = self.is_valid_transaction_in_pool(&transaction, pool) {
+                info!("Drop saved transaction: {}", err);
+                continue;
+            }
+            if let Err(err) = pool.add_transaction_at(transaction, self, added_at) {
+                info!("Drop saved transaction: {}", err);
+            }
         }
+        pool.remove_expired_transactions(Utc::now());
+        info!(
+            "Restore {} of {} saved transactions to pool",
+            pool.len(),
+            count
+        );
+    }
 
-        if !is_valid_chain(self.get_genesis_block_hash(), &other_chain).unwrap() {
-            warn!(
-                "Received full chain is invalid, ignore it: {:?}",
-                other_chain
-            );
-            return vec![];
+    /// main chain から外れた block に含まれていた transaction を TransactionPool に戻す。
+    /// 新しい main chain に対して有効でないものや、pool に入らないものは捨てる。
+    pub fn restore_orphan_transactions(
+        &self,
+        pool: &mut TransactionPool,
+        orphan_transactions: Vec<NormalTransaction>,
+    ) {
+        for transaction in orphan_transactions {
+            if let Err(err) = self.is_valid_transaction_in_pool(&transaction, pool) {
+                info!("Drop orphan transaction: {}", err);
+                continue;
+            }
+            if let Err(err) = pool.add_new_transaction(transaction, self) {
+                info!("Drop orphan transaction: {}", err);
+            }
         }
+    }
 
-        let orphan_blocks = self
-            .chain
+    /// main chain を遡った block hash の列を返す。
+    pub fn get_block_locator(&self) -> Vec<BlockHash> {
+        block_locator(&self.chain)
+    }
+
+    /// locator の中で最初に main chain 上にある block より後の header を最大 max 個返す。
+    pub fn get_headers_after(&self, locator: &[BlockHash], max: usize) -> Vec<BlockHeader> {
+        let start = locator
             .iter()
-            .cloned()
-            .filter(|t| !other_chain.contains(t))
-            .collect::<Vec<_>>();
+            .find(|hash| self.is_in_main_chain(hash))
+            .and_then(|hash| self.tree.get_height(hash))
+            .unwrap_or(0);
+        self.chain[start..]
+            .iter()
+            .take(max)
+            .map(|hash| self.get_block(hash).get_header().clone())
+            .collect()
+    }
 
-        self.chain = other_chain;
+    /// main chain 上で txid の transaction を含む block の hash と、その Merkle root に含まれていることの証明を返す。
+    pub fn get_merkle_proof(&self, txid: &TransactionId) -> Option<(BlockHash, MerkleProof)> {
+        self.tx_index.get_merkle_proof(txid)
+    }
 
-        let main_transactions = self
-            .chain
+    /// main chain 上で address 宛ての output を持つ transaction と、その output を使う transaction を
+    /// 含まれる block の hash と MerkleProof とともに古い順に返す。
+    /// Edge ノードはこれを header chain に対して検証して自分の UTXO を求める。
+    pub fn get_transactions_for(
+        &self,
+        address: &Address,
+    ) -> Vec<(Transaction, BlockHash, MerkleProof)> {
+        self.tx_index
+            .get_transaction_ids_for(address)
             .iter()
-            .flat_map(|b| b.get_normal_transactions())
-            .collect::<Vec<_>>();
+            .filter_map(|txid| {
+                let (hash, idx) = self.tx_index.get_position(txid)?;
+                let tx = self.get_block(&hash).get_transaction_at(idx)?;
+                let (_, proof) = self.tx_index.get_merkle_proof(txid)?;
+                Some((tx, hash, proof))
+            })
+            .collect()
+    }
 
-        let orphan_transactions = orphan_blocks
-            .into_iter()
-            .flat_map(|b| b.get_normal_transactions())
-            .filter(|t| !main_transactions.contains(t))
-            .collect();
+    /// tree 内 (side chain を含む) に block があるか
+    pub fn has_block(&self, hash: &BlockHash) -> bool {
+        self.tree.contains(hash)
+    }
 
-        orphan_transactions
+    /// tree 内にある block を返す。知らない hash は無視する。
+    pub fn get_blocks(&self, hashes: &[BlockHash]) -> Vec<Block> {
+        hashes
+            .iter()
+            .filter_map(|hash| self.tree.get_block(hash).cloned())
+            .collect()
     }
 
-    pub fn is_valid_transaction(&self, tx: &NormalTransaction) -> Result<()> {
-        // block 内に組み込まれた transaction か
-        // TODO?: ただこれ pool は考慮しないので chain に埋め込まれてからでないと作成された UTXO を利用できない。
-        // それは間違っていないんだけど使い勝手としてどうなんだろうか
-        fn does_exist_in_chain(target: &NormalTransaction, chain: &[Block]) -> Result<()> {
-            for input in target.get_inputs() {
-                let tx_opt = chain
-                    .iter()
-                    .flat_map(|block| block.get_transactions())
-                    .find(|tx| tx == input.get_transaction());
+    /// 他ノードから受け取った header の列を body を取得する前に検証し、取得すべき block の hash を返す。
+    /// header の列は連続していて、先頭の親は tree 内の block でなければならない。
+    /// その枝の work が main chain を上回らない場合は何も取得しない。
+    pub fn process_headers(&self, headers: &[BlockHeader]) -> Result<Vec<BlockHash>> {
+        if headers.is_empty() {
+            return Ok(vec![]);
+        }
+        let chain_work = self.check_headers(headers)?;
+        if Some(chain_work) <= self.tree.get_chain_work(&self.get_last_block_hash()) {
+            return Ok(vec![]);
+        }
+        Ok(headers
+            .iter()
+            .map(|header| header.get_hash())
+            .filter(|hash| !self.tree.contains(hash))
+            .collect())
+    }
 
-                if tx_opt.is_none() {
-                    bail!("Invalid input is included in transaction (not exist in chain)");
-                }
+    /// 連続する header の列が tree 内の block に繋がり、各高さで要求される target を満たしているか確認する。
+    /// 最後の header までの work の合計を返す。
+    fn check_headers(&self, headers: &[BlockHeader]) -> Result<u128> {
+        let mut prev_hash = match headers.first() {
+            Some(header) => header.get_prev_block_hash(),
+            None => bail!("No headers to check"),
+        };
+        if self.tree.is_invalid(&prev_hash) {
+            bail!("Parent of headers {} is invalid", prev_hash);
+        }
+        let (mut height, mut chain_work) = match (
+            self.tree.get_height(&prev_hash),
+            self.tree.get_chain_work(&prev_hash),
+        ) {
+            (Some(height), Some(chain_work)) => (height, chain_work),
+            _ => bail!("Parent of headers {} is unknown", prev_hash),
+        };
+        let mut ancestors = self.get_ancestors(&prev_hash);
+        let now = Utc::now();
+
+        for header in headers {
+            if header.get_prev_block_hash() != prev_hash {
+                bail!(
+                    "Headers are not connected: {} is not the parent of {}",
+                    prev_hash,
+                    header.get_hash()
+                );
+            }
+            height += 1;
+            let expected_bits = difficulty::next_bits(height, &ancestors, self.initial_bits);
+            if header.get_bits() != expected_bits {
+                bail!(
+                    "Header {} has unexpected target {:#010x} at height {} (expected {:#010x})",
+                    header.get_hash(),
+                    header.get_bits(),
+                    height,
+                    expected_bits
+                );
             }
-            Ok(())
+            difficulty::check_timestamp(header.get_timestamp(), &ancestors, now)
+                .with_context(|| format!("Header {} has invalid timestamp", header.get_hash()))?;
+            header.check_proof_of_work()?;
+            let hash = header.get_hash();
+            if self.tree.is_invalid(&hash) {
+                bail!("Header {} is known to be invalid", hash);
+            }
+            chain_work = chain_work.saturating_add(block_work(header.get_bits()));
+            ancestors.insert(0, (header.get_timestamp(), header.get_bits()));
+            ancestors.truncate(ANCESTORS_LEN);
+            prev_hash = hash;
         }
+        Ok(chain_work)
+    }
+
+    /// 渡された chain が tree 内の block に繋がる連続した block の列で、
+    /// 各 block がその高さで要求される target を満たしているか確認する。
+    fn is_valid_chain(&self, chain: &[Block]) -> Result<()> {
+        let headers = chain
+            .iter()
+            .map(|block| block.get_header().clone())
+            .collect::<Vec<_>>();
+        self.check_headers(&headers).map(|_| ())
+    }
 
-        // まだ使われていない transaction か
-        fn is_utxo(target: &NormalTransaction, chain: &[Block]) -> Result<()> {
-            for target_input in target.get_inputs() {
-                let input_opt = chain
-                    .iter()
-                    .flat_map(|block| block.get_transactions())
-                    .flat_map(|tx| tx.get_inputs())
-                    .find(|input| input == &target_input);
+    /// 他ノードから受け取った連続する block を順に add_new_block に渡し、
+    /// main chain より work の多い枝になればそれを main chain とする。
+    /// その場合に除かれることになる block 内の未反映 transactions を返す。
+    pub fn resolve_conflicts(&mut self, other_chain: Vec<Block>) -> Result<Vec<NormalTransaction>> {
+        if other_chain.is_empty() {
+            return Ok(vec![]);
+        }
+        if let Err(err) = self.is_valid_chain(&other_chain) {
+            warn!("Received blocks are invalid, ignore them: {:?}", err);
+            return Ok(vec![]);
+        }
 
-                if input_opt.is_some() {
-                    bail!("Invalid input is included in transaction (already used)");
+        let mut orphan_transactions = vec![];
+        for block in other_chain {
+            match self.add_new_block(block) {
+                Ok(ChainUpdate::NewTip {
+                    orphan_transactions: txs,
+                }) => orphan_transactions.extend(txs),
+                Ok(_) => {}
+                Err(err) => {
+                    warn!(
+                        "Received blocks contain invalid block, ignore the rest: {:?}",
+                        err
+                    );
+                    break;
                 }
             }
-            Ok(())
         }
 
-        does_exist_in_chain(tx, &self.get_chain())?;
-        is_utxo(tx, &self.get_chain())?;
+        // 途中で main chain に戻ったものは除く
+        let main_ids = self
+            .get_transactions()
+            .iter()
+            .map(|tx| tx.get_id())
+            .collect::<HashSet<_>>();
+        let mut seen = HashSet::new();
+        orphan_transactions.retain(|tx| {
+            let id = tx.get_id();
+            !main_ids.contains(&id) && seen.insert(id)
+        });
+        Ok(orphan_transactions)
+    }
+
+    /// chain を fork_point まで巻き戻した上で blocks を積み直す。
+    fn restore_blocks(&mut self, fork_point: usize, blocks: Vec<BlockHash>) {
+        while self.chain.len() > fork_point {
+            self.pop_block();
+        }
+        for hash in blocks {
+            self.push_block(hash)
+                .expect("blocks which were in main chain must be applicable");
+        }
+    }
+
+    /// transaction が main chain の UTXO に対して有効か確認し、違反している規則を返す。
+    pub fn is_valid_transaction(
+        &self,
+        tx: &NormalTransaction,
+    ) -> std::result::Result<(), TransactionError> {
+        self.check_transaction(tx, &self.utxo_set)
+    }
+
+    /// main chain の UTXO に加えて、pool 内の transaction が作る output も使えるものとして確認する。
+    /// pool 内の transaction と input が重なるかどうかは TransactionPool が確認する。
+    pub fn is_valid_transaction_in_pool(
+        &self,
+        tx: &NormalTransaction,
+        pool: &TransactionPool,
+    ) -> std::result::Result<(), TransactionError> {
+        self.check_transaction(tx, &ChainedResolver::new(vec![&self.utxo_set, pool]))
+    }
+
+    // resolver が引ける output を使う transaction として有効か確認する。
+    fn check_transaction(
+        &self,
+        tx: &NormalTransaction,
+        resolver: &dyn OutputResolver,
+    ) -> std::result::Result<(), TransactionError> {
+        if tx.get_inputs().is_empty() {
+            return Err(TransactionError::NoInput);
+        }
+        let mut outpoints = HashSet::new();
+        for input in tx.get_inputs() {
+            let outpoint = input.get_outpoint().clone();
+            if resolver.resolve_output(&outpoint).is_none() {
+                return Err(TransactionError::UnknownInput(outpoint));
+            }
+            if !outpoints.insert(outpoint.clone()) {
+                return Err(TransactionError::DuplicateInput(outpoint));
+            }
+        }
+        tx.get_fee(resolver)?;
+        tx.verify_signatures(resolver)
+            .map_err(|err| TransactionError::InvalidSignature(format!("{:#}", err)))?;
         Ok(())
     }
 }
 
-/// 渡された chain が valid か確認する
-fn is_valid_chain(first_hash: BlockHash, chain: &[Block]) -> Result<bool> {
-    let mut prev_block_hash = first_hash;
-    for block in chain.iter() {
-        if prev_block_hash != block.get_prev_block_hash() {
-            return Ok(false);
+impl OutputResolver for BlockchainManager {
+    fn resolve_output(&self, outpoint: &OutPoint) -> Option<TransactionOutput> {
+        self.utxo_set.resolve_output(outpoint)
+    }
+}
+
+pub fn genesis_block_hash() -> BlockHash {
+    let mut hasher = Sha256::new();
+    hasher.update(r#"{"message":"this_is_simple_bitcoin_genesis_block"}"#);
+    util::bytes_to_hex(&hasher.finalize())
+}
+
+/// genesis の次の block から並ぶ chain を遡った block hash の列を返す。
+/// 先頭 (最新) から 10 個の後は間隔を倍々に広げていき、最後は genesis block の hash になる。
+pub fn block_locator(chain: &[BlockHash]) -> Vec<BlockHash> {
+    let mut locator = vec![];
+    let mut step = 1;
+    let mut height = chain.len();
+    while height > 0 {
+        locator.push(chain[height - 1].clone());
+        if locator.len() >= 10 {
+            step *= 2;
         }
-        prev_block_hash = block.calculate_hash()?;
+        height = height.saturating_sub(step);
     }
-    Ok(true)
+    locator.push(genesis_block_hash());
+    locator
+}
+
+/// target を満たす hash を見つけるのに必要な試行回数の期待値
+pub fn block_work(bits: u32) -> u128 {
+    Target::from_compact(bits).get_work()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::blockchain::block::BlockWithoutProof;
+    use crate::blockchain::difficulty::{
+        INITIAL_BITS, MAX_FUTURE_BLOCK_TIME_SECS, MEDIAN_TIME_SPAN, POW_LIMIT_BITS,
+        RETARGET_INTERVAL,
+    };
     use crate::blockchain::transaction::{
         CoinbaseTransaction, Transaction, TransactionInput, TransactionOutput, Transactions,
     };
     use crate::blockchain::utxo::UTXOManager;
     use crate::key_manager::KeyManager;
-    use chrono::{Duration, Utc};
     use rand::rngs::OsRng;
 
     fn generate_block(
+        recipient: Address,
         transactions: Vec<NormalTransaction>,
         prev_block_hash: BlockHash,
-        difficulty: usize,
+        bits: u32,
     ) -> Block {
-        let coinbase = CoinbaseTransaction::new("recipient1".to_string(), 10, Utc::now());
-        BlockWithoutProof::new(Transactions::new(coinbase, transactions), prev_block_hash)
-            .mine(difficulty)
-            .unwrap()
+        let coinbase = CoinbaseTransaction::new(recipient, 10, Utc::now());
+        BlockWithoutProof::new(
+            Transactions::new(coinbase, transactions),
+            prev_block_hash,
+            bits,
+        )
+        .mine()
+        .unwrap()
+    }
+
+    /// block の coinbase が作った output をすべて使う transaction を作り、km で署名する。
+    fn spend_coinbase(block: &Block, km: &mut KeyManager) -> NormalTransaction {
+        let coinbase = block.get_coinbase_transaction();
+        let mut tx = NormalTransaction::new(
+            vec![TransactionInput::new(coinbase.get_id(), 0)],
+            vec![TransactionOutput::new(
+                "recipient2".to_string(),
+                coinbase.get_value(),
+            )],
+            Utc::now(),
+        );
+        tx.sign(km).unwrap();
+        tx
     }
 
     #[test]
     fn test_remove_useless_transactions() {
         // setup
+        let mut km = KeyManager::new(OsRng).unwrap();
         let mut pool = TransactionPool::new();
-        let mut manager = BlockchainManager::new(1);
-
-        let base = Transaction::Coinbase(CoinbaseTransaction::new(
-            "alice".to_string(),
-            10,
-            Utc::now(),
-        ));
+        let mut manager = BlockchainManager::new(POW_LIMIT_BITS);
 
-        let trans1 = NormalTransaction::new(
-            vec![TransactionInput::new(base, 0)],
-            vec![TransactionOutput::new("recipient1".to_string(), 1)],
-            Utc::now(),
+        let block1 = generate_block(
+            km.get_address(),
+            vec![],
+            manager.get_last_block_hash(),
+            manager.get_next_bits(),
         );
-        let trans2 = NormalTransaction::new(
+        manager.add_new_block(block1.clone()).unwrap();
+
+        let mut trans1 = NormalTransaction::new(
             vec![TransactionInput::new(
-                Transaction::Normal(trans1.clone()),
+                block1.get_coinbase_transaction().get_id(),
                 0,
             )],
+            vec![TransactionOutput::new(km.get_address(), 10)],
+            Utc::now(),
+        );
+        trans1.sign(&mut km).unwrap();
+        let mut trans2 = NormalTransaction::new(
+            vec![TransactionInput::new(trans1.get_id(), 0)],
             vec![TransactionOutput::new("recipient1".to_string(), 1)],
             Utc::now(),
         );
+        trans2.sign(&mut km).unwrap();
+        // 署名の無い transaction は次の block で除かれる
+        let unsigned = NormalTransaction::new(
+            vec![TransactionInput::new(trans1.get_id(), 0)],
+            vec![TransactionOutput::new("recipient1".to_string(), 2)],
+            Utc::now(),
+        );
+        pool.add_new_transaction(trans1.clone(), &manager).unwrap();
+        pool.add_new_transaction(trans2.clone(), &manager).unwrap();
+        let mut other_pool = TransactionPool::new();
+        other_pool
+            .add_new_transaction(trans1.clone(), &manager)
+            .unwrap();
+        other_pool
+            .add_new_transaction(unsigned.clone(), &manager)
+            .unwrap();
 
-        let block = generate_block(
+        let block2 = generate_block(
+            km.get_address(),
             vec![trans1.clone()],
             manager.get_last_block_hash(),
-            manager.get_difficulty(),
+            manager.get_next_bits(),
         );
-        manager.add_new_block(block);
-
-        pool.add_new_transaction(trans1.clone());
-        pool.add_new_transaction(trans2.clone());
+        manager.add_new_block(block2).unwrap();
 
         // exercise
         manager.remove_useless_transactions(&mut pool);
+        manager.remove_useless_transactions(&mut other_pool);
 
         // verify
         assert_eq!(pool.get_transactions(), vec![trans2]);
+        assert!(other_pool.is_empty());
     }
 
     #[test]
-    fn test_resolve_conflicts_longer_than_mine() {
+    fn test_restore_saved_transactions() {
         // setup
-        let mut manager = BlockchainManager::new(1);
+        let mut km = KeyManager::new(OsRng).unwrap();
+        let mut pool = TransactionPool::new();
+        let mut manager = BlockchainManager::new(POW_LIMIT_BITS);
+        let mut blocks = vec![];
+        for _ in 0..3 {
+            let block = generate_block(
+                km.get_address(),
+                vec![],
+                manager.get_last_block_hash(),
+                manager.get_next_bits(),
+            );
+            manager.add_new_block(block.clone()).unwrap();
+            blocks.push(block);
+        }
+        let confirmed = spend_coinbase(&blocks[0], &mut km);
+        let fresh = spend_coinbase(&blocks[1], &mut km);
+        let expired = spend_coinbase(&blocks[2], &mut km);
+        let block = generate_block(
+            km.get_address(),
+            vec![confirmed.clone()],
+            manager.get_last_block_hash(),
+            manager.get_next_bits(),
+        );
+        manager.add_new_block(block).unwrap();
 
-        let base = Transaction::Coinbase(CoinbaseTransaction::new(
-            "alice".to_string(),
-            10,
-            Utc::now(),
-        ));
+        let now = Utc::now();
+        let saved = vec![
+            (confirmed, now),
+            (fresh.clone(), now - chrono::Duration::hours(1)),
+            (expired, now - chrono::Duration::days(15)),
+        ];
 
-        let trans1 = NormalTransaction::new(
-            vec![TransactionInput::new(base, 0)],
-            vec![TransactionOutput::new("recipient1".to_string(), 1)],
-            Utc::now(),
+        // exercise
+        manager.restore_saved_transactions(&mut pool, saved);
+
+        // verify
+        assert_eq!(pool.get_transactions(), vec![fresh.clone()]);
+        assert_eq!(
+            pool.get_added_at(&fresh.get_id()),
+            Some(now - chrono::Duration::hours(1))
         );
+    }
 
-        let trans2 = NormalTransaction::new(
-            vec![TransactionInput::new(
-                Transaction::Normal(trans1.clone()),
-                0,
-            )],
-            vec![TransactionOutput::new("recipient1".to_string(), 1)],
-            Utc::now(),
+    #[test]
+    fn test_chained_transactions() {
+        // setup
+        let mut km = KeyManager::new(OsRng).unwrap();
+        let mut pool = TransactionPool::new();
+        let mut manager = BlockchainManager::new(POW_LIMIT_BITS);
+
+        let block1 = generate_block(
+            km.get_address(),
+            vec![],
+            manager.get_last_block_hash(),
+            manager.get_next_bits(),
+        );
+        manager.add_new_block(block1.clone()).unwrap();
+
+        // txid の最初の output を手数料無しで recipient に送る
+        let mut forward = |txid: TransactionId, recipient: Address| {
+            let mut tx = NormalTransaction::new(
+                vec![TransactionInput::new(txid, 0)],
+                vec![TransactionOutput::new(recipient, 10)],
+                Utc::now(),
+            );
+            tx.sign(&mut km).unwrap();
+            tx
+        };
+        let addr = block1.miner();
+        let parent = forward(block1.get_coinbase_transaction().get_id(), addr.clone());
+        let child = forward(parent.get_id(), addr.clone());
+        let grandchild = forward(child.get_id(), addr.clone());
+        let conflict = forward(child.get_id(), "recipient1".to_string());
+
+        // exercise and verify: outputs of pool transactions can be spent
+        assert_eq!(
+            manager.is_valid_transaction_in_pool(&child, &pool),
+            Err(TransactionError::UnknownInput(OutPoint::new(
+                parent.get_id(),
+                0
+            )))
+        );
+        pool.add_new_transaction(parent.clone(), &manager).unwrap();
+        manager.is_valid_transaction_in_pool(&child, &pool).unwrap();
+        assert!(manager.is_valid_transaction(&child).is_err());
+        pool.add_new_transaction(child.clone(), &manager).unwrap();
+        pool.add_new_transaction(grandchild.clone(), &manager)
+            .unwrap();
+        assert_eq!(
+            pool.get_ancestors(&grandchild.get_id()),
+            vec![parent.get_id(), child.get_id()].into_iter().collect()
         );
 
-        let trans3 = NormalTransaction::new(
-            vec![TransactionInput::new(
-                Transaction::Normal(trans2.clone()),
-                0,
-            )],
-            vec![TransactionOutput::new("recipient1".to_string(), 1)],
-            Utc::now(),
+        // exercise and verify: a block can contain a parent and its child
+        let block2 = generate_block(
+            km.get_address(),
+            vec![parent.clone(), child.clone()],
+            manager.get_last_block_hash(),
+            manager.get_next_bits(),
+        );
+        manager.add_new_block(block2).unwrap();
+        manager.remove_useless_transactions(&mut pool);
+        assert_eq!(pool.get_transactions(), vec![grandchild.clone()]);
+        assert!(pool.get_ancestors(&grandchild.get_id()).is_empty());
+
+        // exercise and verify: a transaction is removed when a block spends its input
+        let block3 = generate_block(
+            km.get_address(),
+            vec![conflict],
+            manager.get_last_block_hash(),
+            manager.get_next_bits(),
         );
+        manager.add_new_block(block3).unwrap();
+        manager.remove_useless_transactions(&mut pool);
+        assert!(pool.is_empty());
+    }
+
+    #[test]
+    fn test_resolve_conflicts_longer_than_mine() {
+        // setup
+        let mut km = KeyManager::new(OsRng).unwrap();
+        let mut manager = BlockchainManager::new(POW_LIMIT_BITS);
 
-        // manager contains only block1 and block2
+        // manager contains block1, block2 and block3
         let block1 = generate_block(
+            km.get_address(),
+            vec![],
+            manager.get_last_block_hash(),
+            manager.get_next_bits(),
+        );
+        manager.add_new_block(block1.clone()).unwrap();
+
+        let block2 = generate_block(
+            km.get_address(),
+            vec![],
+            manager.get_last_block_hash(),
+            manager.get_next_bits(),
+        );
+        manager.add_new_block(block2.clone()).unwrap();
+
+        let trans1 = spend_coinbase(&block1, &mut km);
+        let trans2 = spend_coinbase(&block2, &mut km);
+        let block3 = generate_block(
+            km.get_address(),
+            vec![trans1.clone(), trans2.clone()],
+            manager.get_last_block_hash(),
+            manager.get_next_bits(),
+        );
+        manager.add_new_block(block3.clone()).unwrap();
+
+        let block4 = generate_block(
+            km.get_address(),
             vec![trans1.clone()],
+            block2.calculate_hash().unwrap(),
+            manager.get_next_bits(),
+        );
+
+        let block5 = generate_block(
+            km.get_address(),
+            vec![],
+            block4.calculate_hash().unwrap(),
+            manager.get_next_bits(),
+        );
+
+        // exercise
+        let other_chain = vec![block1.clone(), block2.clone(), block4, block5];
+        let res = manager.resolve_conflicts(other_chain.clone()).unwrap();
+
+        // verify
+        assert_eq!(res, vec![trans2.clone()]);
+        assert_eq!(manager.get_chain(), other_chain);
+        assert!(manager.is_valid_transaction(&trans2).is_ok());
+        assert!(manager.is_valid_transaction(&trans1).is_err());
+        assert_eq!(
+            manager.store.get_tip().unwrap(),
+            Some(manager.get_last_block_hash())
+        );
+    }
+
+    #[test]
+    fn test_resolve_conflicts_with_invalid_chain() {
+        // setup
+        let mut km = KeyManager::new(OsRng).unwrap();
+        let mut manager = BlockchainManager::new(POW_LIMIT_BITS);
+
+        let block1 = generate_block(
+            km.get_address(),
+            vec![],
             manager.get_last_block_hash(),
-            manager.get_difficulty(),
+            manager.get_next_bits(),
         );
-        manager.add_new_block(block1.clone());
+        manager.add_new_block(block1.clone()).unwrap();
 
+        let trans1 = spend_coinbase(&block1, &mut km);
         let block2 = generate_block(
-            vec![trans2.clone(), trans3.clone()],
+            km.get_address(),
+            vec![trans1.clone()],
             manager.get_last_block_hash(),
-            manager.get_difficulty(),
+            manager.get_next_bits(),
         );
-        manager.add_new_block(block2.clone());
+        manager.add_new_block(block2.clone()).unwrap();
 
+        // block4 spends the output which is already spent in block3
         let block3 = generate_block(
-            vec![trans3.clone()],
+            km.get_address(),
+            vec![trans1.clone()],
             block1.calculate_hash().unwrap(),
-            manager.get_difficulty(),
+            manager.get_next_bits(),
         );
-
         let block4 = generate_block(
-            vec![trans3.clone()],
+            km.get_address(),
+            vec![trans1.clone()],
             block3.calculate_hash().unwrap(),
-            manager.get_difficulty(),
+            manager.get_next_bits(),
         );
 
         // exercise
-        let other_chain = vec![block1, block3, block4];
-        let res = manager.resolve_conflicts(other_chain.clone());
+        let other_chain = vec![block1.clone(), block3, block4];
+        let res = manager.resolve_conflicts(other_chain).unwrap();
 
         // verify
-        assert_eq!(res, vec![trans2]);
-        assert_eq!(manager.get_chain(), other_chain);
+        assert_eq!(res.len(), 0);
+        assert_eq!(manager.get_chain(), vec![block1, block2.clone()]);
+        assert_eq!(manager.get_balance(&"recipient2".to_string()), 10);
+        assert_eq!(
+            manager.store.get_tip().unwrap(),
+            Some(block2.calculate_hash().unwrap())
+        );
     }
 
     #[test]
-    fn test_resolve_conflicts_shorter_than_mine() {
+    fn test_add_new_block_switches_to_branch_with_more_work() {
         // setup
-        let mut manager = BlockchainManager::new(1);
+        let mut km = KeyManager::new(OsRng).unwrap();
+        let mut pool = TransactionPool::new();
+        let mut manager = BlockchainManager::new(POW_LIMIT_BITS);
 
-        let base = Transaction::Coinbase(CoinbaseTransaction::new(
-            "alice".to_string(),
-            10,
-            Utc::now(),
+        let block1 = generate_block(
+            km.get_address(),
+            vec![],
+            manager.get_last_block_hash(),
+            manager.get_next_bits(),
+        );
+        manager.add_new_block(block1.clone()).unwrap();
+        let trans1 = spend_coinbase(&block1, &mut km);
+        let block2a = generate_block(
+            km.get_address(),
+            vec![trans1.clone()],
+            manager.get_last_block_hash(),
+            manager.get_next_bits(),
+        );
+        manager.add_new_block(block2a.clone()).unwrap();
+
+        // exercise and verify: a competing block with the same work is kept as a side chain
+        let block2b = generate_block(
+            km.get_address(),
+            vec![],
+            block1.calculate_hash().unwrap(),
+            manager.get_next_bits(),
+        );
+        assert_eq!(
+            manager.add_new_block(block2b.clone()).unwrap(),
+            ChainUpdate::SideChain
+        );
+        assert_eq!(
+            manager.add_new_block(block2b.clone()).unwrap(),
+            ChainUpdate::AlreadyKnown
+        );
+        assert_eq!(manager.get_chain(), vec![block1.clone(), block2a.clone()]);
+
+        // exercise and verify: the side chain gets more work than the main chain
+        let block3b = generate_block(
+            km.get_address(),
+            vec![],
+            block2b.calculate_hash().unwrap(),
+            manager.get_next_bits(),
+        );
+        let update = manager.add_new_block(block3b.clone()).unwrap();
+        assert_eq!(
+            update,
+            ChainUpdate::NewTip {
+                orphan_transactions: vec![trans1.clone()]
+            }
+        );
+        assert_eq!(
+            manager.get_chain(),
+            vec![block1.clone(), block2b.clone(), block3b.clone()]
+        );
+        assert_eq!(manager.get_balance(&"recipient2".to_string()), 0);
+        assert_eq!(
+            manager.store.get_tip().unwrap(),
+            Some(block3b.calculate_hash().unwrap())
+        );
+        // main chain から外れた block の transaction は索引からも除かれる
+        assert!(manager.get_merkle_proof(&trans1.get_id()).is_none());
+        assert!(manager
+            .get_transactions_for(&"recipient2".to_string())
+            .is_empty());
+        assert_eq!(manager.get_transactions_for(&km.get_address()).len(), 3);
+
+        if let ChainUpdate::NewTip {
+            orphan_transactions,
+        } = update
+        {
+            manager.restore_orphan_transactions(&mut pool, orphan_transactions);
+        }
+        assert_eq!(pool.get_transactions(), vec![trans1]);
+
+        // exercise and verify: a block whose parent is unknown
+        let orphan = generate_block(
+            km.get_address(),
+            vec![],
+            "unknown".to_string(),
+            manager.get_next_bits(),
+        );
+        assert_eq!(
+            manager.add_new_block(orphan).unwrap(),
+            ChainUpdate::MissingParent
+        );
+    }
+
+    #[test]
+    fn test_get_block_locator() {
+        let mut manager = BlockchainManager::new(POW_LIMIT_BITS);
+        let mut hashes = vec![];
+        for _ in 0..15 {
+            let block = generate_block(
+                "recipient1".to_string(),
+                vec![],
+                manager.get_last_block_hash(),
+                manager.get_next_bits(),
+            );
+            hashes.push(block.calculate_hash().unwrap());
+            manager.add_new_block(block).unwrap();
+        }
+
+        let mut expected = hashes[5..].iter().rev().cloned().collect::<Vec<_>>();
+        expected.push(hashes[3].clone());
+        expected.push(manager.get_genesis_block_hash());
+        assert_eq!(manager.get_block_locator(), expected);
+    }
+
+    #[test]
+    fn test_headers_first_sync() {
+        // setup: remote has block1, block2 and block3 while local has only block1
+        let mut remote = BlockchainManager::new(POW_LIMIT_BITS);
+        let mut local = BlockchainManager::new(POW_LIMIT_BITS);
+        for i in 0..3 {
+            let block = generate_block(
+                "recipient1".to_string(),
+                vec![],
+                remote.get_last_block_hash(),
+                remote.get_next_bits(),
+            );
+            remote.add_new_block(block.clone()).unwrap();
+            if i == 0 {
+                local.add_new_block(block).unwrap();
+            }
+        }
+        let remote_chain = remote.get_chain();
+
+        // exercise
+        let headers = remote.get_headers_after(&local.get_block_locator(), 10);
+        let hashes = local.process_headers(&headers).unwrap();
+        let blocks = remote.get_blocks(&hashes);
+        local.resolve_conflicts(blocks).unwrap();
+
+        // verify
+        assert_eq!(headers.len(), 2);
+        assert_eq!(hashes, vec![headers[0].get_hash(), headers[1].get_hash()]);
+        assert_eq!(local.get_chain(), remote_chain);
+
+        // nothing to fetch once synchronized
+        let headers = remote.get_headers_after(&local.get_block_locator(), 10);
+        assert!(headers.is_empty());
+        let headers = remote.get_headers_after(&[remote.get_genesis_block_hash()], 10);
+        assert_eq!(headers.len(), 3);
+        assert!(local.process_headers(&headers).unwrap().is_empty());
+        assert_eq!(remote.get_headers_after(&[], 1).len(), 1);
+    }
+
+    #[test]
+    fn test_process_headers_returns_err() {
+        let manager = BlockchainManager::new(POW_LIMIT_BITS);
+        let genesis_hash = manager.get_genesis_block_hash();
+        let header = |prev_block_hash: BlockHash, bits: u32| {
+            generate_block("recipient1".to_string(), vec![], prev_block_hash, bits)
+                .get_header()
+                .clone()
+        };
+
+        let valid = header(genesis_hash.clone(), POW_LIMIT_BITS);
+        assert_eq!(
+            manager
+                .process_headers(std::slice::from_ref(&valid))
+                .unwrap(),
+            vec![valid.get_hash()]
+        );
+
+        // parent is unknown
+        let headers = vec![header("unknown".to_string(), POW_LIMIT_BITS)];
+        assert!(manager.process_headers(&headers).is_err());
+
+        // proof of work is insufficient
+        let weak = (0..)
+            .map(|nonce| {
+                BlockHeader::new(
+                    genesis_hash.clone(),
+                    valid.get_merkle_root(),
+                    Utc::now(),
+                    POW_LIMIT_BITS,
+                    nonce,
+                )
+            })
+            .find(|header| header.check_proof_of_work().is_err())
+            .unwrap();
+        assert!(manager.process_headers(&[weak]).is_err());
+
+        // target is different from the required one
+        let headers = vec![header(genesis_hash.clone(), INITIAL_BITS)];
+        assert!(manager.process_headers(&headers).is_err());
+
+        // headers are not connected
+        let headers = vec![valid, header(genesis_hash, POW_LIMIT_BITS)];
+        assert!(manager.process_headers(&headers).is_err());
+    }
+
+    #[test]
+    fn test_rejects_forged_timestamp() {
+        let mut manager = BlockchainManager::new(POW_LIMIT_BITS);
+        for _ in 0..MEDIAN_TIME_SPAN {
+            let block = generate_block(
+                "recipient1".to_string(),
+                vec![],
+                manager.get_last_block_hash(),
+                manager.get_next_bits(),
+            );
+            manager.add_new_block(block).unwrap();
+        }
+        let last_block_hash = manager.get_last_block_hash();
+        let median =
+            difficulty::median_time_past(&manager.get_ancestors(&last_block_hash)).unwrap();
+
+        // 同じ body で timestamp だけを変えた block を mining する
+        let bits = manager.get_next_bits();
+        let template = generate_block(
+            "recipient2".to_string(),
+            vec![],
+            last_block_hash.clone(),
+            bits,
+        );
+        let forge = |timestamp: DateTime<Utc>| {
+            let header = (0..)
+                .map(|nonce| {
+                    BlockHeader::new(
+                        last_block_hash.clone(),
+                        template.get_header().get_merkle_root(),
+                        timestamp,
+                        bits,
+                        nonce,
+                    )
+                })
+                .find(|header| header.check_proof_of_work().is_ok())
+                .unwrap();
+            Block::new(
+                header,
+                Transactions::new(template.get_coinbase_transaction(), vec![]),
+            )
+        };
+
+        let future = Utc::now()
+            + chrono::Duration::seconds(MAX_FUTURE_BLOCK_TIME_SECS)
+            + chrono::Duration::minutes(1);
+        for timestamp in [median, median - chrono::Duration::seconds(1), future] {
+            let forged = forge(timestamp);
+            assert!(manager
+                .process_headers(std::slice::from_ref(forged.get_header()))
+                .is_err());
+            assert!(manager.is_valid_block(&forged).is_err());
+            assert!(manager.add_new_block(forged).is_err());
+        }
+        assert_eq!(manager.get_last_block_hash(), last_block_hash);
+
+        let block = forge(median + chrono::Duration::seconds(1));
+        assert!(manager.is_valid_block(&block).is_ok());
+        assert!(matches!(
+            manager.add_new_block(block),
+            Ok(ChainUpdate::NewTip { .. })
         ));
+    }
 
-        let trans1 = NormalTransaction::new(
-            vec![TransactionInput::new(base, 0)],
-            vec![TransactionOutput::new("recipient1".to_string(), 1)],
-            Utc::now(),
+    #[test]
+    fn test_add_new_block_rejects_tampered_body() {
+        let mut manager = BlockchainManager::new(POW_LIMIT_BITS);
+        let block = generate_block(
+            "recipient1".to_string(),
+            vec![],
+            manager.get_last_block_hash(),
+            manager.get_next_bits(),
+        );
+        let other = generate_block(
+            "recipient2".to_string(),
+            vec![],
+            manager.get_last_block_hash(),
+            manager.get_next_bits(),
         );
 
-        let trans2 = NormalTransaction::new(
-            vec![TransactionInput::new(
-                Transaction::Normal(trans1.clone()),
-                0,
-            )],
-            vec![TransactionOutput::new("recipient1".to_string(), 1)],
-            Utc::now(),
+        // the header of block with the body of other
+        let tampered = Block::new(
+            block.get_header().clone(),
+            Transactions::new(other.get_coinbase_transaction(), vec![]),
         );
+        assert!(manager.add_new_block(tampered).is_err());
 
-        let trans3 = NormalTransaction::new(
-            vec![TransactionInput::new(
-                Transaction::Normal(trans2.clone()),
-                0,
-            )],
-            vec![TransactionOutput::new("recipient1".to_string(), 1)],
-            Utc::now(),
+        // the genuine block is still accepted
+        assert!(matches!(
+            manager.add_new_block(block.clone()).unwrap(),
+            ChainUpdate::NewTip { .. }
+        ));
+        assert_eq!(manager.get_chain(), vec![block]);
+    }
+
+    #[test]
+    fn test_add_new_block_requires_retargeted_bits() {
+        let mut manager = BlockchainManager::new(POW_LIMIT_BITS);
+        for _ in 1..RETARGET_INTERVAL {
+            assert_eq!(manager.get_next_bits(), POW_LIMIT_BITS);
+            let block = generate_block(
+                "recipient1".to_string(),
+                vec![],
+                manager.get_last_block_hash(),
+                manager.get_next_bits(),
+            );
+            manager.add_new_block(block).unwrap();
+        }
+
+        // blocks were mined much faster than the target interval
+        assert_eq!(manager.get_next_bits(), 0x201f_ffff);
+
+        let block = generate_block(
+            "recipient1".to_string(),
+            vec![],
+            manager.get_last_block_hash(),
+            POW_LIMIT_BITS,
+        );
+        assert!(manager.add_new_block(block).is_err());
+        assert_eq!(manager.get_chain().len(), RETARGET_INTERVAL - 1);
+
+        let block = generate_block(
+            "recipient1".to_string(),
+            vec![],
+            manager.get_last_block_hash(),
+            manager.get_next_bits(),
         );
+        manager.add_new_block(block).unwrap();
+        assert_eq!(manager.get_chain().len(), RETARGET_INTERVAL);
+        assert_eq!(manager.get_next_bits(), 0x201f_ffff);
+    }
+
+    #[test]
+    fn test_resolve_conflicts_shorter_than_mine() {
+        // setup
+        let mut km = KeyManager::new(OsRng).unwrap();
+        let mut manager = BlockchainManager::new(POW_LIMIT_BITS);
 
         let block1 = generate_block(
-            vec![trans1.clone()],
+            km.get_address(),
+            vec![],
             manager.get_last_block_hash(),
-            manager.get_difficulty(),
+            manager.get_next_bits(),
         );
-        manager.add_new_block(block1.clone());
+        manager.add_new_block(block1.clone()).unwrap();
 
+        let trans1 = spend_coinbase(&block1, &mut km);
         let block2 = generate_block(
-            vec![trans2.clone(), trans3.clone()],
+            km.get_address(),
+            vec![trans1],
             manager.get_last_block_hash(),
-            manager.get_difficulty(),
+            manager.get_next_bits(),
         );
-        manager.add_new_block(block2.clone());
+        manager.add_new_block(block2.clone()).unwrap();
 
         // exercise
         let other_chain = vec![block1.clone()];
-        let res = manager.resolve_conflicts(other_chain);
+        let res = manager.resolve_conflicts(other_chain).unwrap();
 
         // verify
         assert_eq!(res.len(), 0);
         assert_eq!(manager.get_chain(), vec![block1, block2]);
     }
 
+    #[test]
+    fn test_get_balance() {
+        let mut km = KeyManager::new(OsRng).unwrap();
+        let mut manager = BlockchainManager::new(POW_LIMIT_BITS);
+
+        let block1 = generate_block(
+            km.get_address(),
+            vec![],
+            manager.get_last_block_hash(),
+            manager.get_next_bits(),
+        );
+        manager.add_new_block(block1.clone()).unwrap();
+        assert_eq!(manager.get_balance(&km.get_address()), 10);
+
+        let block2 = generate_block(
+            km.get_address(),
+            vec![spend_coinbase(&block1, &mut km)],
+            manager.get_last_block_hash(),
+            manager.get_next_bits(),
+        );
+        manager.add_new_block(block2).unwrap();
+        assert_eq!(manager.get_balance(&km.get_address()), 10);
+        assert_eq!(manager.get_balance(&"recipient2".to_string()), 10);
+        assert_eq!(manager.get_utxos_for(&"recipient2".to_string()).len(), 1);
+
+        // fee は新たな発行に含まない
+        assert_eq!(manager.get_supply(0), Some(0));
+        assert_eq!(manager.get_supply(1), Some(10));
+        assert_eq!(manager.get_supply(2), Some(20));
+        assert_eq!(manager.get_supply(3), None);
+    }
+
+    #[test]
+    fn test_get_transactions_for() {
+        let mut km = KeyManager::new(OsRng).unwrap();
+        let mut manager = BlockchainManager::new(POW_LIMIT_BITS);
+
+        let block1 = generate_block(
+            km.get_address(),
+            vec![],
+            manager.get_last_block_hash(),
+            manager.get_next_bits(),
+        );
+        manager.add_new_block(block1.clone()).unwrap();
+        let spend = spend_coinbase(&block1, &mut km);
+        let block2 = generate_block(
+            "recipient1".to_string(),
+            vec![spend.clone()],
+            manager.get_last_block_hash(),
+            manager.get_next_bits(),
+        );
+        manager.add_new_block(block2.clone()).unwrap();
+
+        // 自分宛ての coinbase と、それを使う transaction だけを返す
+        let txs = manager.get_transactions_for(&km.get_address());
+        assert_eq!(txs.len(), 2);
+        assert_eq!(
+            txs[0].0.get_id(),
+            block1.get_coinbase_transaction().get_id()
+        );
+        assert_eq!(txs[1].0.get_id(), spend.get_id());
+        for (tx, block_hash, proof) in txs.iter() {
+            let header = manager.get_blocks(std::slice::from_ref(block_hash))[0]
+                .get_header()
+                .clone();
+            assert!(proof.verify(&tx.get_id(), &header.get_merkle_root()));
+        }
+
+        assert_eq!(
+            manager
+                .get_transactions_for(&"recipient2".to_string())
+                .len(),
+            1
+        );
+        assert!(manager
+            .get_transactions_for(&"unknown".to_string())
+            .is_empty());
+    }
+
+    #[test]
+    fn test_with_store_reloads_valid_blocks() {
+        // setup
+        let mut manager = BlockchainManager::new(POW_LIMIT_BITS);
+        let block1 = generate_block(
+            "recipient1".to_string(),
+            vec![],
+            manager.get_last_block_hash(),
+            manager.get_next_bits(),
+        );
+        manager.add_new_block(block1.clone()).unwrap();
+        let block2 = generate_block(
+            "recipient1".to_string(),
+            vec![],
+            manager.get_last_block_hash(),
+            manager.get_next_bits(),
+        );
+        manager.add_new_block(block2.clone()).unwrap();
+
+        // block3 contains a transaction whose input doesn't exist in the chain
+        let base = Transaction::Coinbase(CoinbaseTransaction::new(
+            "alice".to_string(),
+            10,
+            Utc::now(),
+        ));
+        let trans1 = NormalTransaction::new(
+            vec![TransactionInput::new(base.get_id(), 0)],
+            vec![TransactionOutput::new("recipient1".to_string(), 1)],
+            Utc::now(),
+        );
+        let block3 = generate_block(
+            "recipient1".to_string(),
+            vec![trans1],
+            manager.get_last_block_hash(),
+            manager.get_next_bits(),
+        );
+
+        let mut store = MemoryBlockStore::new();
+        for block in [&block1, &block2, &block3] {
+            store.put_block(block).unwrap();
+        }
+        store.set_tip(&block3.calculate_hash().unwrap()).unwrap();
+
+        // exercise
+        let manager = BlockchainManager::with_store(POW_LIMIT_BITS, Box::new(store)).unwrap();
+
+        // verify
+        assert_eq!(manager.get_chain(), vec![block1, block2.clone()]);
+        assert_eq!(
+            manager.store.get_tip().unwrap(),
+            Some(block2.calculate_hash().unwrap())
+        );
+    }
+
     #[test]
     fn test_is_valid_transaction_returns_ok() {
         // setup
         let rng = OsRng;
-        let km1 = KeyManager::new(rng.clone()).unwrap();
-        let km2 = KeyManager::new(rng.clone()).unwrap();
+        let mut km1 = KeyManager::new(rng).unwrap();
+        let mut km2 = KeyManager::new(rng).unwrap();
         let mut um1 = UTXOManager::new(km1.get_address());
-        let mut bm = BlockchainManager::new(1);
+        let mut bm = BlockchainManager::new(POW_LIMIT_BITS);
 
         // block1
-        let tx1 = CoinbaseTransaction::new(km1.get_address(), 20, Utc::now());
+        let tx1 = CoinbaseTransaction::new(km1.get_address(), 10, Utc::now());
         let block1 = BlockWithoutProof::new(
             Transactions::new(tx1.clone(), vec![]),
             bm.get_last_block_hash(),
+            bm.get_next_bits(),
         )
-        .mine(bm.get_difficulty())
+        .mine()
         .unwrap();
-        bm.add_new_block(block1.clone());
+        bm.add_new_block(block1.clone()).unwrap();
         um1.refresh_utxos(&bm.get_transactions());
 
         // block2
-        let tx2 = CoinbaseTransaction::new(km1.get_address(), 10, Utc::now());
-        let tx3 = um1.create_transaction_for(km2.get_address(), 5, 1).unwrap();
+        let tx2 = CoinbaseTransaction::new(km1.get_address(), 11, Utc::now());
+        let mut tx3 = um1.create_transaction_for(km2.get_address(), 5, 1).unwrap();
+        tx3.sign(&mut km1).unwrap();
         let block2 = BlockWithoutProof::new(
             Transactions::new(tx2.clone(), vec![tx3.clone()]),
             bm.get_last_block_hash(),
+            bm.get_next_bits(),
         )
-        .mine(bm.get_difficulty())
+        .mine()
         .unwrap();
-        bm.add_new_block(block2.clone());
+        bm.add_new_block(block2.clone()).unwrap();
         um1.refresh_utxos(&bm.get_transactions());
 
-        assert!(is_valid_chain(bm.get_genesis_block_hash(), &bm.get_chain()).unwrap());
+        assert!(bm.is_valid_chain(&bm.get_chain()).is_ok());
 
         // exercise and verify
-        let new_tx = NormalTransaction::new(
-            vec![TransactionInput::new(Transaction::Normal(tx3.clone()), 0)],
+        let mut new_tx = NormalTransaction::new(
+            vec![TransactionInput::new(tx3.get_id(), 0)],
             vec![TransactionOutput::new(km1.get_address(), 4)],
             Utc::now(),
         );
+        new_tx.sign(&mut km2).unwrap();
         assert!(bm.is_valid_transaction(&new_tx).is_ok());
     }
 
@@ -425,54 +1579,96 @@ mod tests {
     fn test_is_valid_transaction_returns_err() {
         // setup
         let rng = OsRng;
-        let km1 = KeyManager::new(rng.clone()).unwrap();
-        let km2 = KeyManager::new(rng.clone()).unwrap();
-        let km3 = KeyManager::new(rng.clone()).unwrap();
+        let mut km1 = KeyManager::new(rng).unwrap();
+        let km2 = KeyManager::new(rng).unwrap();
+        let mut km3 = KeyManager::new(rng).unwrap();
         let mut um1 = UTXOManager::new(km1.get_address());
-        let mut bm = BlockchainManager::new(1);
+        let mut bm = BlockchainManager::new(POW_LIMIT_BITS);
 
         // block1
-        let tx1 = CoinbaseTransaction::new(km1.get_address(), 20, Utc::now());
+        let tx1 = CoinbaseTransaction::new(km1.get_address(), 10, Utc::now());
         let block1 = BlockWithoutProof::new(
             Transactions::new(tx1.clone(), vec![]),
             bm.get_last_block_hash(),
+            bm.get_next_bits(),
         )
-        .mine(bm.get_difficulty())
+        .mine()
         .unwrap();
-        bm.add_new_block(block1.clone());
+        bm.add_new_block(block1.clone()).unwrap();
         um1.refresh_utxos(&bm.get_transactions());
 
         // block2
-        let tx2 = CoinbaseTransaction::new(km1.get_address(), 10, Utc::now());
-        let tx3 = um1.create_transaction_for(km2.get_address(), 5, 1).unwrap();
+        let tx2 = CoinbaseTransaction::new(km1.get_address(), 11, Utc::now());
+        let mut tx3 = um1.create_transaction_for(km2.get_address(), 5, 1).unwrap();
+        tx3.sign(&mut km1).unwrap();
         let block2 = BlockWithoutProof::new(
             Transactions::new(tx2.clone(), vec![tx3]),
             bm.get_last_block_hash(),
+            bm.get_next_bits(),
         )
-        .mine(bm.get_difficulty())
+        .mine()
         .unwrap();
-        bm.add_new_block(block2.clone());
+        bm.add_new_block(block2.clone()).unwrap();
         um1.refresh_utxos(&bm.get_transactions());
 
-        assert!(is_valid_chain(bm.get_genesis_block_hash(), &bm.get_chain()).unwrap());
+        assert!(bm.is_valid_chain(&bm.get_chain()).is_ok());
 
         // exercise and verify with unknown transaction
         let new_tx = NormalTransaction::new(
             vec![TransactionInput::new(
-                Transaction::Coinbase(CoinbaseTransaction::new(km3.get_address(), 10, Utc::now())),
+                CoinbaseTransaction::new(km3.get_address(), 10, Utc::now()).get_id(),
                 0,
             )],
             vec![TransactionOutput::new(km1.get_address(), 4)],
             Utc::now(),
         );
-        assert!(bm.is_valid_transaction(&new_tx).is_err());
+        assert!(matches!(
+            bm.is_valid_transaction(&new_tx),
+            Err(TransactionError::UnknownInput(_))
+        ));
 
         // exercise and verify with used transaction
         let new_tx = NormalTransaction::new(
-            vec![TransactionInput::new(Transaction::Coinbase(tx1.clone()), 0)],
+            vec![TransactionInput::new(tx1.get_id(), 0)],
             vec![TransactionOutput::new(km1.get_address(), 4)],
             Utc::now(),
         );
+        assert_eq!(
+            bm.is_valid_transaction(&new_tx),
+            Err(TransactionError::UnknownInput(OutPoint::new(
+                tx1.get_id(),
+                0
+            )))
+        );
+
+        // exercise and verify without signature or with signature by non-owner
+        let mut new_tx = NormalTransaction::new(
+            vec![TransactionInput::new(tx2.get_id(), 0)],
+            vec![TransactionOutput::new(km3.get_address(), 11)],
+            Utc::now(),
+        );
         assert!(bm.is_valid_transaction(&new_tx).is_err());
+        new_tx.sign(&mut km3).unwrap();
+        assert!(matches!(
+            bm.is_valid_transaction(&new_tx),
+            Err(TransactionError::InvalidSignature(_))
+        ));
+        new_tx.sign(&mut km1).unwrap();
+        assert!(bm.is_valid_transaction(&new_tx).is_ok());
+
+        // exercise and verify with outputs exceeding inputs
+        let mut new_tx = NormalTransaction::new(
+            vec![TransactionInput::new(tx2.get_id(), 0)],
+            vec![TransactionOutput::new(km3.get_address(), 12)],
+            Utc::now(),
+        );
+        new_tx.sign(&mut km1).unwrap();
+        assert_eq!(
+            bm.is_valid_transaction(&new_tx),
+            Err(TransactionError::OutputsExceedInputs {
+                input: 11,
+                output: 12
+            })
+        );
     }
 }
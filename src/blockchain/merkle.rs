@@ -0,0 +1,133 @@
+use crate::blockchain::transaction::TransactionId;
+use crate::util;
+use serde::{Deserialize, Serialize};
+
+/// 2 つの node を連結した hash を親 node とする
+fn hash_pair(left: &str, right: &str) -> String {
+    util::sha256(left.as_bytes(), right.as_bytes())
+}
+
+/// transaction id を葉とする Merkle tree の根を計算する。
+/// 各段で node の数が奇数の場合は bitcoin と同様に最後の node を複製する。
+/// transaction が無い場合は 0 を並べた hash とする。
+pub fn merkle_root(txids: &[TransactionId]) -> String {
+    if txids.is_empty() {
+        return "0".repeat(64);
+    }
+    let mut level = txids.to_vec();
+    while level.len() > 1 {
+        level = level
+            .chunks(2)
+            .map(|pair| hash_pair(&pair[0], pair.get(1).unwrap_or(&pair[0])))
+            .collect();
+    }
+    level.remove(0)
+}
+
+/// transaction が Merkle tree に含まれていることの証明.
+/// 葉から根に向かって順に、各段で兄弟となる node を持つ。
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct MerkleProof {
+    // 葉の位置 (block 内での transaction の位置)
+    index: usize,
+    branch: Vec<String>,
+}
+
+impl MerkleProof {
+    pub fn new(index: usize, branch: Vec<String>) -> MerkleProof {
+        MerkleProof { index, branch }
+    }
+
+    pub fn get_index(&self) -> usize {
+        self.index
+    }
+
+    /// txid を葉として branch を辿り、Merkle root を計算する。
+    pub fn compute_root(&self, txid: &TransactionId) -> String {
+        let mut node = txid.clone();
+        let mut index = self.index;
+        for sibling in self.branch.iter() {
+            node = if index % 2 == 1 {
+                hash_pair(sibling, &node)
+            } else {
+                hash_pair(&node, sibling)
+            };
+            index /= 2;
+        }
+        node
+    }
+
+    pub fn verify(&self, txid: &TransactionId, merkle_root: &str) -> bool {
+        self.compute_root(txid) == merkle_root
+    }
+}
+
+/// txids の index 番目の transaction についての MerkleProof を作る。
+pub fn merkle_proof(txids: &[TransactionId], index: usize) -> Option<MerkleProof> {
+    if index >= txids.len() {
+        return None;
+    }
+    let mut branch = vec![];
+    let mut level = txids.to_vec();
+    let mut pos = index;
+    while level.len() > 1 {
+        let sibling = if pos % 2 == 1 {
+            &level[pos - 1]
+        } else {
+            level.get(pos + 1).unwrap_or(&level[pos])
+        };
+        branch.push(sibling.clone());
+        level = level
+            .chunks(2)
+            .map(|pair| hash_pair(&pair[0], pair.get(1).unwrap_or(&pair[0])))
+            .collect();
+        pos /= 2;
+    }
+    Some(MerkleProof::new(index, branch))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merkle_root() {
+        let ids = ["a", "b", "c"]
+            .iter()
+            .map(|id| id.to_string())
+            .collect::<Vec<_>>();
+
+        assert_eq!(merkle_root(&[]), "0".repeat(64));
+        assert_eq!(merkle_root(&ids[..1]), "a");
+        assert_eq!(merkle_root(&ids[..2]), hash_pair("a", "b"));
+        assert_eq!(
+            merkle_root(&ids),
+            hash_pair(&hash_pair("a", "b"), &hash_pair("c", "c"))
+        );
+
+        // order of transactions matters
+        let swapped = vec![ids[1].clone(), ids[0].clone()];
+        assert_ne!(merkle_root(&swapped), merkle_root(&ids[..2]));
+    }
+
+    #[test]
+    fn test_merkle_proof() {
+        for len in 1..8 {
+            let ids = (0..len).map(|i| format!("tx{}", i)).collect::<Vec<_>>();
+            let root = merkle_root(&ids);
+            for (i, id) in ids.iter().enumerate() {
+                let proof = merkle_proof(&ids, i).unwrap();
+                assert_eq!(proof.get_index(), i);
+                assert!(proof.verify(id, &root));
+                assert!(!proof.verify(&"unknown".to_string(), &root));
+            }
+            assert_eq!(merkle_proof(&ids, len), None);
+        }
+
+        // a proof for another position doesn't prove the transaction
+        let ids = vec!["a".to_string(), "b".to_string()];
+        let proof = merkle_proof(&ids, 0).unwrap();
+        let wrong_position = MerkleProof::new(1, proof.branch.clone());
+        assert!(!wrong_position.verify(&ids[0], &merkle_root(&ids)));
+    }
+}
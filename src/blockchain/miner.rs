@@ -0,0 +1,197 @@
+use crate::blockchain::block::{Block, BlockWithoutProof};
+use crate::blockchain::difficulty::Target;
+use crate::blockchain::encoding::Encode;
+use crate::util;
+use anyhow::{anyhow, bail, Result};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// 停止の要求を確認する間隔 (nonce の試行回数). 2 の累乗にする。
+const STOP_CHECK_INTERVAL: u64 = 1 << 12;
+
+/// 1 回の mining の統計
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MiningStats {
+    hashes: u64,
+    elapsed: Duration,
+}
+
+impl MiningStats {
+    /// 全 thread で計算した hash の数
+    pub fn get_hashes(&self) -> u64 {
+        self.hashes
+    }
+
+    pub fn get_elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    /// 1 秒あたりの hash の数
+    pub fn get_hashrate(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs == 0.0 {
+            return 0.0;
+        }
+        self.hashes as f64 / secs
+    }
+}
+
+/// nonce の範囲を分割して複数の thread で block を探す。
+/// いずれかの thread が見つけるか、中断を要求されると全ての thread を止める。
+#[derive(Clone, Copy, Debug)]
+pub struct Miner {
+    threads: usize,
+}
+
+impl Miner {
+    /// threads 個の thread で mining する。0 の場合は 1 とみなす。
+    pub fn new(threads: usize) -> Miner {
+        Miner {
+            threads: threads.max(1),
+        }
+    }
+
+    pub fn get_threads(&self) -> usize {
+        self.threads
+    }
+
+    /// target を満たす block を探す。cancelled が true になった場合は中断して None を返す。
+    pub fn mine(
+        &self,
+        block: BlockWithoutProof,
+        cancelled: Arc<AtomicBool>,
+    ) -> Result<(Option<Block>, MiningStats)> {
+        let started_at = Instant::now();
+        let (mut header, transactions) = block.into_parts();
+        let target = Target::from_compact(header.get_bits());
+        // nonce 以外は試行ごとに変わらないので一度だけ encode する
+        let prefix = Arc::new(header.to_bytes_without_nonce());
+
+        let found = Arc::new(AtomicBool::new(false));
+        let hashes = Arc::new(AtomicU64::new(0));
+        let chunk = u64::MAX / self.threads as u64;
+        let workers = (0..self.threads as u64)
+            .map(|i| {
+                let start = i * chunk;
+                let end = if i + 1 == self.threads as u64 {
+                    u64::MAX
+                } else {
+                    start + chunk
+                };
+                let prefix = Arc::clone(&prefix);
+                let found = Arc::clone(&found);
+                let cancelled = Arc::clone(&cancelled);
+                let hashes = Arc::clone(&hashes);
+                thread::spawn(move || {
+                    search(&prefix, target, start, end, &found, &cancelled, &hashes)
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut nonce = None;
+        for worker in workers {
+            let result = worker
+                .join()
+                .map_err(|_| anyhow!("mining thread panicked"))?;
+            nonce = nonce.or(result);
+        }
+        let stats = MiningStats {
+            hashes: hashes.load(Ordering::Relaxed),
+            elapsed: started_at.elapsed(),
+        };
+
+        match nonce {
+            Some(nonce) => {
+                header.set_nonce(nonce);
+                Ok((Some(Block::new(header, transactions)), stats))
+            }
+            None if cancelled.load(Ordering::Relaxed) => Ok((None, stats)),
+            None => bail!("nonce is exhausted"),
+        }
+    }
+}
+
+// [start, end] の nonce から target を満たすものを探す。
+// 他の thread が見つけた場合や中断を要求された場合は None を返す。
+fn search(
+    prefix: &[u8],
+    target: Target,
+    start: u64,
+    end: u64,
+    found: &AtomicBool,
+    cancelled: &AtomicBool,
+    hashes: &AtomicU64,
+) -> Option<u64> {
+    let mut nonce = start;
+    let mut count = 0;
+    let mut buf = vec![];
+    let result = loop {
+        if count & (STOP_CHECK_INTERVAL - 1) == 0
+            && (found.load(Ordering::Relaxed) || cancelled.load(Ordering::Relaxed))
+        {
+            break None;
+        }
+        buf.clear();
+        nonce.encode(&mut buf);
+        count += 1;
+        if target.is_met_by(&util::sha256(prefix, &buf)) {
+            found.store(true, Ordering::Relaxed);
+            break Some(nonce);
+        }
+        if nonce == end {
+            break None;
+        }
+        nonce += 1;
+    };
+    hashes.fetch_add(count, Ordering::Relaxed);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::difficulty::{INITIAL_BITS, POW_LIMIT_BITS};
+    use crate::blockchain::transaction::{CoinbaseTransaction, Transactions};
+    use chrono::Utc;
+
+    fn sample_block(bits: u32) -> BlockWithoutProof {
+        let transactions = Transactions::new(
+            CoinbaseTransaction::new("alice".to_string(), 10, Utc::now()),
+            vec![],
+        );
+        BlockWithoutProof::new(transactions, util::sha256(b"foo", b"123"), bits)
+    }
+
+    #[test]
+    fn test_mine_with_threads() {
+        for bits in [POW_LIMIT_BITS, INITIAL_BITS] {
+            let miner = Miner::new(4);
+            let (block, stats) = miner
+                .mine(sample_block(bits), Arc::new(AtomicBool::new(false)))
+                .unwrap();
+            let block = block.unwrap();
+            assert!(block.get_header().check_proof_of_work().is_ok());
+            assert_eq!(block.get_header().get_bits(), bits);
+            assert!(stats.get_hashes() >= 1);
+        }
+        assert_eq!(Miner::new(0).get_threads(), 1);
+    }
+
+    #[test]
+    fn test_mine_cancelled() {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let handle = {
+            let cancelled = Arc::clone(&cancelled);
+            // target is too hard to find while the test runs
+            thread::spawn(move || Miner::new(2).mine(sample_block(0x0300_0001), cancelled))
+        };
+        thread::sleep(Duration::from_millis(50));
+        cancelled.store(true, Ordering::Relaxed);
+        let (block, stats) = handle.join().unwrap().unwrap();
+        assert!(block.is_none());
+        assert!(stats.get_hashes() > 0);
+        assert!(stats.get_hashrate() > 0.0);
+    }
+}
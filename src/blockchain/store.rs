@@ -0,0 +1,428 @@
+use crate::blockchain::block::{Block, BlockHash};
+use anyhow::{anyhow, bail, Context, Result};
+use log::warn;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+const BLOCK_FILE_NAME: &str = "blocks.dat";
+const INDEX_FILE_NAME: &str = "index.dat";
+const TIP_FILE_NAME: &str = "tip";
+
+/// BlockchainManager が block を永続化するための storage backend.
+/// 一度保存された block は消さずに残し、main chain は先頭 block の hash (tip) で表現する。
+pub trait BlockStore: Send {
+    /// block を保存する。すでに保存済みの場合は何もしない。
+    fn put_block(&mut self, block: &Block) -> Result<()>;
+
+    fn get_block(&self, hash: &BlockHash) -> Result<Option<Block>>;
+
+    /// main chain の先頭 block の hash を記録する。
+    fn set_tip(&mut self, hash: &BlockHash) -> Result<()>;
+
+    fn get_tip(&self) -> Result<Option<BlockHash>>;
+}
+
+/// tip から genesis まで遡って main chain を復元する。
+/// 返される chain は genesis 側から順に並ぶ。
+pub fn load_chain(store: &dyn BlockStore, genesis_hash: &BlockHash) -> Result<Vec<Block>> {
+    let mut chain = vec![];
+    let mut hash = match store.get_tip()? {
+        Some(hash) => hash,
+        None => return Ok(chain),
+    };
+    while &hash != genesis_hash {
+        let block = store
+            .get_block(&hash)?
+            .ok_or_else(|| anyhow!("Block {} is not found in store", hash))?;
+        hash = block.get_prev_block_hash();
+        chain.push(block);
+    }
+    chain.reverse();
+    Ok(chain)
+}
+
+/// メモリ上にのみ block を保持する BlockStore.
+#[derive(Default)]
+pub struct MemoryBlockStore {
+    blocks: HashMap<BlockHash, Block>,
+    tip: Option<BlockHash>,
+}
+
+impl MemoryBlockStore {
+    pub fn new() -> MemoryBlockStore {
+        MemoryBlockStore {
+            blocks: HashMap::new(),
+            tip: None,
+        }
+    }
+}
+
+impl BlockStore for MemoryBlockStore {
+    fn put_block(&mut self, block: &Block) -> Result<()> {
+        let hash = block.calculate_hash()?;
+        self.blocks.entry(hash).or_insert_with(|| block.clone());
+        Ok(())
+    }
+
+    fn get_block(&self, hash: &BlockHash) -> Result<Option<Block>> {
+        Ok(self.blocks.get(hash).cloned())
+    }
+
+    fn set_tip(&mut self, hash: &BlockHash) -> Result<()> {
+        self.tip = Some(hash.clone());
+        Ok(())
+    }
+
+    fn get_tip(&self) -> Result<Option<BlockHash>> {
+        Ok(self.tip.clone())
+    }
+}
+
+/// block file 内のレコードの位置
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct BlockLocation {
+    offset: u64,
+    length: u64,
+}
+
+/// ディレクトリ配下のファイルに block を保存する BlockStore.
+///
+/// - `blocks.dat`: 4 byte (big endian) の長さと block の json からなるレコードを追記していく
+/// - `index.dat`: `<hash> <offset> <length>` の行を追記していく
+/// - `tip`: main chain の先頭 block の hash
+pub struct FileBlockStore {
+    dir: PathBuf,
+    block_file: File,
+    index_file: File,
+    index: HashMap<BlockHash, BlockLocation>,
+}
+
+impl FileBlockStore {
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<FileBlockStore> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create data directory: {:?}", dir))?;
+
+        let block_file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(dir.join(BLOCK_FILE_NAME))?;
+        let index_file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(dir.join(INDEX_FILE_NAME))?;
+
+        let mut store = FileBlockStore {
+            dir,
+            block_file,
+            index_file,
+            index: HashMap::new(),
+        };
+        store.load_index()?;
+        store.recover_index()?;
+        Ok(store)
+    }
+
+    fn load_index(&mut self) -> Result<()> {
+        let reader = BufReader::new(&self.index_file);
+        for line in reader.lines() {
+            let line = line?;
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            if let [hash, offset, length] = fields[..] {
+                let location = BlockLocation {
+                    offset: offset.parse()?,
+                    length: length.parse()?,
+                };
+                self.index.insert(hash.to_string(), location);
+            } else {
+                bail!("Invalid line in index file: {}", line);
+            }
+        }
+        Ok(())
+    }
+
+    /// index への書き込み前に終了した場合に備えて、index に無いレコードを block file から拾い直す。
+    /// 途中までしか書き込まれていないレコードは切り捨てる。
+    fn recover_index(&mut self) -> Result<()> {
+        let file_len = self.block_file.metadata()?.len();
+        let mut offset = self
+            .index
+            .values()
+            .map(|loc| loc.offset + 4 + loc.length)
+            .max()
+            .unwrap_or(0);
+
+        let mut reader = File::open(self.dir.join(BLOCK_FILE_NAME))?;
+        while offset < file_len {
+            match Self::read_record(&mut reader, offset) {
+                Ok((block, length)) => {
+                    let hash = block.calculate_hash()?;
+                    warn!("Recover index of block {} at {}", hash, offset);
+                    self.append_index(&hash, BlockLocation { offset, length })?;
+                    offset += 4 + length;
+                }
+                Err(err) => {
+                    warn!(
+                        "Truncate broken record in block file at {}: {:?}",
+                        offset, err
+                    );
+                    self.block_file.set_len(offset)?;
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// offset の位置にあるレコードを読み、block とその長さを返す。
+    /// 壊れた長さで大きな領域を確保しないよう、ファイルの残りより長いレコードはエラーとする。
+    fn read_record(reader: &mut File, offset: u64) -> Result<(Block, u64)> {
+        let file_len = reader.metadata()?.len();
+        reader.seek(SeekFrom::Start(offset))?;
+        let mut len_buf = [0u8; 4];
+        reader.read_exact(&mut len_buf)?;
+        let len = u32::from_be_bytes(len_buf) as u64;
+        let remaining = file_len.saturating_sub(offset + 4);
+        if len > remaining {
+            bail!(
+                "Record at {} is longer than the rest of block file: {} > {}",
+                offset,
+                len,
+                remaining
+            );
+        }
+        let mut buf = vec![0u8; len as usize];
+        reader.read_exact(&mut buf)?;
+        Ok((serde_json::from_slice(&buf)?, buf.len() as u64))
+    }
+
+    fn append_index(&mut self, hash: &BlockHash, location: BlockLocation) -> Result<()> {
+        writeln!(
+            self.index_file,
+            "{} {} {}",
+            hash, location.offset, location.length
+        )?;
+        self.index_file.sync_data()?;
+        self.index.insert(hash.clone(), location);
+        Ok(())
+    }
+}
+
+impl BlockStore for FileBlockStore {
+    fn put_block(&mut self, block: &Block) -> Result<()> {
+        let hash = block.calculate_hash()?;
+        if self.index.contains_key(&hash) {
+            return Ok(());
+        }
+
+        let data = serde_json::to_vec(block)?;
+        let offset = self.block_file.seek(SeekFrom::End(0))?;
+        let mut record = (data.len() as u32).to_be_bytes().to_vec();
+        record.extend(data.iter());
+        self.block_file.write_all(&record)?;
+        self.block_file.sync_data()?;
+
+        let location = BlockLocation {
+            offset,
+            length: data.len() as u64,
+        };
+        self.append_index(&hash, location)
+    }
+
+    fn get_block(&self, hash: &BlockHash) -> Result<Option<Block>> {
+        match self.index.get(hash) {
+            Some(location) => {
+                let mut reader = File::open(self.dir.join(BLOCK_FILE_NAME))?;
+                let (block, _) = Self::read_record(&mut reader, location.offset)?;
+                Ok(Some(block))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn set_tip(&mut self, hash: &BlockHash) -> Result<()> {
+        // 書き込み途中で壊れないように一時ファイルを rename する。
+        // block file と同様に sync し、保存された block より先の tip が残らないようにする
+        let tmp_path = self.dir.join(format!("{}.tmp", TIP_FILE_NAME));
+        let mut tmp_file = File::create(&tmp_path)?;
+        tmp_file.write_all(hash.as_bytes())?;
+        tmp_file.sync_all()?;
+        fs::rename(&tmp_path, self.dir.join(TIP_FILE_NAME))?;
+        sync_dir(&self.dir)
+    }
+
+    fn get_tip(&self) -> Result<Option<BlockHash>> {
+        match fs::read_to_string(self.dir.join(TIP_FILE_NAME)) {
+            Ok(hash) => Ok(Some(hash.trim().to_string())),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+/// rename した entry が失われないように directory を sync する。
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+/// directory を開いて sync できない環境では何もしない。
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::block::BlockWithoutProof;
+    use crate::blockchain::difficulty::POW_LIMIT_BITS;
+    use crate::blockchain::transaction::{CoinbaseTransaction, Transactions};
+    use crate::util;
+    use chrono::Utc;
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "simple-bitcoin-store-test-{}",
+            util::bytes_to_hex(&rand::random::<[u8; 8]>())
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn generate_chain(genesis_hash: BlockHash, len: usize) -> Vec<Block> {
+        let mut chain: Vec<Block> = vec![];
+        for i in 0..len {
+            let prev_block_hash = chain
+                .last()
+                .map(|b| b.calculate_hash().unwrap())
+                .unwrap_or_else(|| genesis_hash.clone());
+            let coinbase = CoinbaseTransaction::new(format!("miner{}", i), 10, Utc::now());
+            let block = BlockWithoutProof::new(
+                Transactions::new(coinbase, vec![]),
+                prev_block_hash,
+                POW_LIMIT_BITS,
+            )
+            .mine()
+            .unwrap();
+            chain.push(block);
+        }
+        chain
+    }
+
+    #[test]
+    fn test_load_chain_from_tip() {
+        let genesis_hash = "genesis".to_string();
+        let chain = generate_chain(genesis_hash.clone(), 3);
+
+        let mut store = MemoryBlockStore::new();
+        assert_eq!(load_chain(&store, &genesis_hash).unwrap(), vec![]);
+
+        for block in chain.iter() {
+            store.put_block(block).unwrap();
+        }
+        store.set_tip(&chain[1].calculate_hash().unwrap()).unwrap();
+
+        assert_eq!(
+            load_chain(&store, &genesis_hash).unwrap(),
+            chain[0..2].to_vec()
+        );
+    }
+
+    #[test]
+    fn test_file_block_store_reopen() {
+        let dir = temp_dir();
+        let genesis_hash = "genesis".to_string();
+        let chain = generate_chain(genesis_hash.clone(), 3);
+
+        {
+            let mut store = FileBlockStore::open(&dir).unwrap();
+            for block in chain.iter() {
+                store.put_block(block).unwrap();
+                store.put_block(block).unwrap();
+                store.set_tip(&block.calculate_hash().unwrap()).unwrap();
+            }
+        }
+
+        let store = FileBlockStore::open(&dir).unwrap();
+        assert_eq!(store.index.len(), 3);
+        assert_eq!(load_chain(&store, &genesis_hash).unwrap(), chain);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_file_block_store_recovers_index() {
+        let dir = temp_dir();
+        let genesis_hash = "genesis".to_string();
+        let chain = generate_chain(genesis_hash.clone(), 2);
+
+        {
+            let mut store = FileBlockStore::open(&dir).unwrap();
+            for block in chain.iter() {
+                store.put_block(block).unwrap();
+            }
+            store.set_tip(&chain[1].calculate_hash().unwrap()).unwrap();
+        }
+
+        // lose the last index entry and leave a partially written record
+        let index = fs::read_to_string(dir.join(INDEX_FILE_NAME)).unwrap();
+        let first_line = index.lines().next().unwrap();
+        fs::write(dir.join(INDEX_FILE_NAME), format!("{}\n", first_line)).unwrap();
+        let block_file_len = fs::metadata(dir.join(BLOCK_FILE_NAME)).unwrap().len();
+        let mut block_file = OpenOptions::new()
+            .append(true)
+            .open(dir.join(BLOCK_FILE_NAME))
+            .unwrap();
+        block_file.write_all(&[0, 0, 1, 0, b'{']).unwrap();
+
+        let store = FileBlockStore::open(&dir).unwrap();
+        assert_eq!(load_chain(&store, &genesis_hash).unwrap(), chain);
+        assert_eq!(
+            fs::metadata(dir.join(BLOCK_FILE_NAME)).unwrap().len(),
+            block_file_len
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_file_block_store_truncates_record_with_broken_length() {
+        let dir = temp_dir();
+        let genesis_hash = "genesis".to_string();
+        let chain = generate_chain(genesis_hash.clone(), 1);
+
+        {
+            let mut store = FileBlockStore::open(&dir).unwrap();
+            store.put_block(&chain[0]).unwrap();
+            store.set_tip(&chain[0].calculate_hash().unwrap()).unwrap();
+        }
+
+        // a record whose length is far beyond the end of file
+        let block_file_len = fs::metadata(dir.join(BLOCK_FILE_NAME)).unwrap().len();
+        let mut block_file = OpenOptions::new()
+            .append(true)
+            .open(dir.join(BLOCK_FILE_NAME))
+            .unwrap();
+        block_file
+            .write_all(&[0xff, 0xff, 0xff, 0xff, b'{'])
+            .unwrap();
+        let mut reader = File::open(dir.join(BLOCK_FILE_NAME)).unwrap();
+        assert!(FileBlockStore::read_record(&mut reader, block_file_len).is_err());
+
+        let store = FileBlockStore::open(&dir).unwrap();
+        assert_eq!(load_chain(&store, &genesis_hash).unwrap(), chain);
+        assert_eq!(
+            fs::metadata(dir.join(BLOCK_FILE_NAME)).unwrap().len(),
+            block_file_len
+        );
+        assert!(!dir.join(format!("{}.tmp", TIP_FILE_NAME)).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,78 @@
+use std::cmp;
+
+/// 高さ 1 の block の coinbase が新たに発行できる金額
+pub const INITIAL_SUBSIDY: u64 = 10;
+/// 発行額を半分にする間隔 (block 数)
+pub const HALVING_INTERVAL: usize = 210;
+/// 発行される金額の合計の上限. 発行額が 0 になるまで半減を繰り返した場合の合計に等しい。
+pub const MAX_SUPPLY: u64 = max_supply();
+
+const fn max_supply() -> u64 {
+    let mut supply = 0;
+    let mut subsidy = INITIAL_SUBSIDY;
+    while subsidy > 0 {
+        supply += subsidy * HALVING_INTERVAL as u64;
+        subsidy >>= 1;
+    }
+    supply
+}
+
+/// 高さ height の block の coinbase が新たに発行できる金額. fee は含まない。
+/// HALVING_INTERVAL 個の block ごとに半分 (端数切り捨て) になり、いずれ 0 になる。
+/// genesis (高さ 0) は coinbase を持たないので 0.
+pub fn get_block_subsidy(height: usize) -> u64 {
+    if height == 0 {
+        return 0;
+    }
+    let halvings = (height - 1) / HALVING_INTERVAL;
+    if halvings >= 64 {
+        return 0;
+    }
+    INITIAL_SUBSIDY >> halvings
+}
+
+/// 高さ height の block までに発行された金額の合計
+pub fn get_supply(height: usize) -> u64 {
+    let mut supply: u64 = 0;
+    let mut start = 1;
+    while start <= height {
+        let subsidy = get_block_subsidy(start);
+        if subsidy == 0 {
+            break;
+        }
+        // start から始まる半減期のうち height までの block 数
+        let end = cmp::min(start + HALVING_INTERVAL - 1, height);
+        supply += subsidy * (end - start + 1) as u64;
+        start += HALVING_INTERVAL;
+    }
+    supply
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_block_subsidy() {
+        assert_eq!(get_block_subsidy(0), 0);
+        assert_eq!(get_block_subsidy(1), INITIAL_SUBSIDY);
+        assert_eq!(get_block_subsidy(HALVING_INTERVAL), INITIAL_SUBSIDY);
+        assert_eq!(get_block_subsidy(HALVING_INTERVAL + 1), INITIAL_SUBSIDY / 2);
+        assert_eq!(get_block_subsidy(HALVING_INTERVAL * 2 + 1), 2);
+        assert_eq!(get_block_subsidy(HALVING_INTERVAL * 3 + 1), 1);
+        assert_eq!(get_block_subsidy(HALVING_INTERVAL * 4 + 1), 0);
+        assert_eq!(get_block_subsidy(usize::MAX), 0);
+    }
+
+    #[test]
+    fn test_get_supply() {
+        assert_eq!(get_supply(0), 0);
+        assert_eq!(get_supply(1), INITIAL_SUBSIDY);
+        assert_eq!(get_supply(HALVING_INTERVAL + 2), 2_110);
+        let expected: u64 = (1..=HALVING_INTERVAL * 5).map(get_block_subsidy).sum();
+        assert_eq!(get_supply(HALVING_INTERVAL * 5), expected);
+        assert_eq!(get_supply(HALVING_INTERVAL * 4), MAX_SUPPLY);
+        assert_eq!(get_supply(usize::MAX), MAX_SUPPLY);
+        assert_eq!(MAX_SUPPLY, 3_780);
+    }
+}
@@ -1,42 +1,157 @@
+use crate::blockchain::encoding::{self, Decode, Encode, Reader};
+use crate::key_manager::KeyManager;
 use crate::util;
+use anyhow::{anyhow, bail, Context, Result};
 use chrono::{DateTime, Utc};
 use rsa::pkcs1::FromRsaPublicKey;
 use rsa::RsaPublicKey;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
 
 pub type Address = String;
 pub type TransactionSignature = String;
+pub type TransactionId = String;
+
+/// address は公開鍵 (PKCS#1 DER) の hex 表現. 公開鍵として解釈できない場合はエラー.
+pub fn address_to_public_key(address: &Address) -> Result<RsaPublicKey> {
+    let der = util::hex_to_bytes(address.clone())?;
+    Ok(RsaPublicKey::from_pkcs1_der(&der)?)
+}
+
+/// ある transaction の output を指す。
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct OutPoint {
+    txid: TransactionId,
+    index: usize,
+}
+
+impl OutPoint {
+    pub fn new(txid: TransactionId, index: usize) -> OutPoint {
+        OutPoint { txid, index }
+    }
+
+    pub fn get_txid(&self) -> &TransactionId {
+        &self.txid
+    }
+
+    pub fn get_index(&self) -> usize {
+        self.index
+    }
+}
+
+/// transaction が満たすべき規則への違反
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TransactionError {
+    /// input が一つもない
+    NoInput,
+    /// 参照先の output が存在しないか、すでに使われている
+    UnknownInput(OutPoint),
+    /// 同じ output を複数の input で使っている
+    DuplicateInput(OutPoint),
+    /// 金額が 0 の output がある
+    ZeroValueOutput(usize),
+    /// input または output の合計が u64 に収まらない
+    ValueOverflow,
+    /// output の合計が input の合計を超えている
+    OutputsExceedInputs { input: u64, output: u64 },
+    /// input の署名が正しくない
+    InvalidSignature(String),
+}
+
+impl fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransactionError::NoInput => write!(f, "Transaction has no input"),
+            TransactionError::UnknownInput(outpoint) => write!(
+                f,
+                "Invalid input is included in transaction (not exist in chain or already used): {:?}",
+                outpoint
+            ),
+            TransactionError::DuplicateInput(outpoint) => write!(
+                f,
+                "Invalid input is included in transaction (used more than once): {:?}",
+                outpoint
+            ),
+            TransactionError::ZeroValueOutput(idx) => {
+                write!(f, "Value of output {} is zero", idx)
+            }
+            TransactionError::ValueOverflow => write!(f, "Total value of transaction overflows"),
+            TransactionError::OutputsExceedInputs { input, output } => write!(
+                f,
+                "Total output value {} exceeds total input value {}",
+                output, input
+            ),
+            TransactionError::InvalidSignature(reason) => {
+                write!(f, "Invalid signature: {}", reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TransactionError {}
+
+/// OutPoint が指す TransactionOutput を引くためのもの
+pub trait OutputResolver {
+    fn resolve_output(&self, outpoint: &OutPoint) -> Option<TransactionOutput>;
+}
+
+impl OutputResolver for HashMap<TransactionId, Transaction> {
+    fn resolve_output(&self, outpoint: &OutPoint) -> Option<TransactionOutput> {
+        self.get(&outpoint.txid)
+            .and_then(|tx| tx.get_output(outpoint.index))
+    }
+}
+
+/// 複数の resolver を順に試し、最初に見つかった output を返す。
+/// chain の UTXO と未承認の transaction が作る output を合わせて引くために使う。
+pub struct ChainedResolver<'a> {
+    resolvers: Vec<&'a dyn OutputResolver>,
+}
+
+impl<'a> ChainedResolver<'a> {
+    pub fn new(resolvers: Vec<&'a dyn OutputResolver>) -> ChainedResolver<'a> {
+        ChainedResolver { resolvers }
+    }
+}
+
+impl OutputResolver for ChainedResolver<'_> {
+    fn resolve_output(&self, outpoint: &OutPoint) -> Option<TransactionOutput> {
+        self.resolvers
+            .iter()
+            .find_map(|resolver| resolver.resolve_output(outpoint))
+    }
+}
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub struct TransactionInput {
-    transaction: Transaction,
-    index: usize,
+    previous_output: OutPoint,
+    // 参照先の output の所有者による transaction への署名
+    #[serde(default)]
+    signature: TransactionSignature,
 }
 
 impl TransactionInput {
-    pub fn new(transaction: Transaction, index: usize) -> TransactionInput {
-        TransactionInput { transaction, index }
+    pub fn new(txid: TransactionId, index: usize) -> TransactionInput {
+        TransactionInput {
+            previous_output: OutPoint::new(txid, index),
+            signature: TransactionSignature::new(),
+        }
     }
 
-    pub fn get_transaction(&self) -> &Transaction {
-        &self.transaction
+    pub fn get_outpoint(&self) -> &OutPoint {
+        &self.previous_output
     }
 
-    pub fn get_recipient(&self) -> Address {
-        self.transaction
-            .get_outputs()
-            .get(self.index)
-            .unwrap()
-            .recipient
-            .clone()
+    pub fn get_signature(&self) -> &TransactionSignature {
+        &self.signature
     }
 
-    pub fn get_value(&self) -> u64 {
-        self.transaction
-            .get_outputs()
-            .get(self.index)
-            .unwrap()
-            .value
+    /// 参照先の output を返す。
+    pub fn resolve(&self, resolver: &dyn OutputResolver) -> Result<TransactionOutput> {
+        resolver
+            .resolve_output(&self.previous_output)
+            .ok_or_else(|| anyhow!("Unknown output is referred: {:?}", self.previous_output))
     }
 }
 
@@ -102,6 +217,16 @@ impl Transaction {
             Transaction::Normal(tx) => tx.outputs.clone(),
         }
     }
+
+    /// transaction の binary 表現 (encoding::to_bytes) の hash を transaction ID とする。
+    /// 署名によって ID が変わらないように、input の署名は含めない。
+    pub fn get_id(&self) -> TransactionId {
+        let data = match self {
+            Transaction::Coinbase(_) => encoding::to_bytes(self),
+            Transaction::Normal(tx) => tx.get_signing_data(),
+        };
+        util::bytes_to_hex(&util::calc_hash(&data))
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
@@ -123,6 +248,10 @@ impl CoinbaseTransaction {
     pub fn get_value(&self) -> u64 {
         self.value
     }
+
+    pub fn get_id(&self) -> TransactionId {
+        Transaction::Coinbase(self.clone()).get_id()
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
@@ -130,6 +259,9 @@ pub struct NormalTransaction {
     inputs: Vec<TransactionInput>,
     outputs: Vec<TransactionOutput>,
     timestamp: DateTime<Utc>,
+    // 未承認の間、fee を上げた transaction で置き換えることを認めるか
+    #[serde(default)]
+    replaceable: bool,
 }
 
 impl NormalTransaction {
@@ -142,24 +274,115 @@ impl NormalTransaction {
             inputs,
             outputs,
             timestamp,
+            replaceable: false,
         }
     }
 
-    pub fn get_input_value(&self) -> u64 {
-        self.inputs
-            .iter()
-            .fold(0, |acc, input| acc + input.get_value())
+    /// 未承認の間、同じ input を使い fee を上げた transaction による置き換えを認める。署名の前に呼び出す。
+    pub fn set_replaceable(&mut self, replaceable: bool) {
+        self.replaceable = replaceable;
     }
 
-    pub fn get_output_value(&self) -> u64 {
-        self.outputs
-            .iter()
-            .fold(0, |acc, output| acc + output.get_value())
+    pub fn is_replaceable(&self) -> bool {
+        self.replaceable
     }
 
-    pub fn get_input_pubkey(&self) -> RsaPublicKey {
-        let recipient = self.inputs.first().unwrap().get_recipient();
-        RsaPublicKey::from_pkcs1_der(&util::hex_to_bytes(recipient)).unwrap()
+    pub fn get_id(&self) -> TransactionId {
+        Transaction::Normal(self.clone()).get_id()
+    }
+
+    pub fn get_input_value(
+        &self,
+        resolver: &dyn OutputResolver,
+    ) -> std::result::Result<u64, TransactionError> {
+        let mut value: u64 = 0;
+        for input in self.inputs.iter() {
+            let output = resolver
+                .resolve_output(input.get_outpoint())
+                .ok_or_else(|| TransactionError::UnknownInput(input.get_outpoint().clone()))?;
+            value = value
+                .checked_add(output.get_value())
+                .ok_or(TransactionError::ValueOverflow)?;
+        }
+        Ok(value)
+    }
+
+    pub fn get_output_value(&self) -> std::result::Result<u64, TransactionError> {
+        let mut value: u64 = 0;
+        for (idx, output) in self.outputs.iter().enumerate() {
+            if output.get_value() == 0 {
+                return Err(TransactionError::ZeroValueOutput(idx));
+            }
+            value = value
+                .checked_add(output.get_value())
+                .ok_or(TransactionError::ValueOverflow)?;
+        }
+        Ok(value)
+    }
+
+    /// input と output の合計の差を fee として返す。
+    /// output の合計が input の合計を超える場合はエラーになる。
+    pub fn get_fee(
+        &self,
+        resolver: &dyn OutputResolver,
+    ) -> std::result::Result<u64, TransactionError> {
+        let input = self.get_input_value(resolver)?;
+        let output = self.get_output_value()?;
+        input
+            .checked_sub(output)
+            .ok_or(TransactionError::OutputsExceedInputs { input, output })
+    }
+
+    /// block 内で占める大きさ (byte)
+    pub fn get_size(&self) -> usize {
+        let mut buf = vec![];
+        self.encode(&mut buf);
+        buf.len()
+    }
+
+    /// 署名の対象となるデータ。input の署名を除いた transaction の binary 表現を使う。
+    pub fn get_signing_data(&self) -> Vec<u8> {
+        let mut tx = self.clone();
+        for input in tx.inputs.iter_mut() {
+            input.signature = TransactionSignature::new();
+        }
+        encoding::to_bytes(&Transaction::Normal(tx))
+    }
+
+    /// すべての input に key_manager の鍵で署名する。すべての input の所有者が同じ場合に使う。
+    pub fn sign(&mut self, key_manager: &mut KeyManager) -> Result<()> {
+        let data = self.get_signing_data();
+        let signature = util::bytes_to_hex(&key_manager.sign(&data)?);
+        for input in self.inputs.iter_mut() {
+            input.signature = signature.clone();
+        }
+        Ok(())
+    }
+
+    /// idx 番目の input に key_manager の鍵で署名する。
+    /// 所有者の異なる output を使う場合は、それぞれの所有者の鍵で各 input に署名する。
+    pub fn sign_input(&mut self, idx: usize, key_manager: &mut KeyManager) -> Result<()> {
+        if idx >= self.inputs.len() {
+            bail!("Input {} doesn't exist", idx);
+        }
+        let data = self.get_signing_data();
+        self.inputs[idx].signature = util::bytes_to_hex(&key_manager.sign(&data)?);
+        Ok(())
+    }
+
+    /// 各 input の署名が参照先の output の所有者によるものか確認する。
+    pub fn verify_signatures(&self, resolver: &dyn OutputResolver) -> Result<()> {
+        let data = self.get_signing_data();
+        for (idx, input) in self.inputs.iter().enumerate() {
+            let recipient = input.resolve(resolver)?.get_recipient();
+            let public_key = address_to_public_key(&recipient)
+                .with_context(|| format!("Owner of input {} is not a valid public key", idx))?;
+            let signature = util::hex_to_bytes(input.signature.clone())
+                .with_context(|| format!("Signature of input {} is malformed", idx))?;
+            util::verify_signature(&signature, &public_key, &data)
+                .with_context(|| format!("Signature of input {} is invalid", idx))?;
+        }
+        Ok(())
     }
 
     pub fn get_input(&self, idx: usize) -> Option<TransactionInput> {
@@ -203,9 +426,8 @@ impl Transactions {
             Some(Transaction::Coinbase(self.coinbase.clone()))
         } else {
             self.transactions
-                .iter()
+                .get(idx - 1)
                 .cloned()
-                .nth(idx - 1)
                 .map(Transaction::Normal)
         }
     }
@@ -226,6 +448,134 @@ impl Transactions {
     }
 }
 
+impl Encode for OutPoint {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.txid.encode(buf);
+        self.index.encode(buf);
+    }
+}
+
+impl Decode for OutPoint {
+    fn decode(reader: &mut Reader) -> Result<OutPoint> {
+        Ok(OutPoint::new(
+            Decode::decode(reader)?,
+            Decode::decode(reader)?,
+        ))
+    }
+}
+
+impl Encode for TransactionInput {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.previous_output.encode(buf);
+        self.signature.encode(buf);
+    }
+}
+
+impl Decode for TransactionInput {
+    fn decode(reader: &mut Reader) -> Result<TransactionInput> {
+        Ok(TransactionInput {
+            previous_output: Decode::decode(reader)?,
+            signature: Decode::decode(reader)?,
+        })
+    }
+}
+
+impl Encode for TransactionOutput {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.recipient.encode(buf);
+        self.value.encode(buf);
+    }
+}
+
+impl Decode for TransactionOutput {
+    fn decode(reader: &mut Reader) -> Result<TransactionOutput> {
+        Ok(TransactionOutput::new(
+            Decode::decode(reader)?,
+            Decode::decode(reader)?,
+        ))
+    }
+}
+
+impl Encode for CoinbaseTransaction {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.recipient.encode(buf);
+        self.value.encode(buf);
+        self.timestamp.encode(buf);
+    }
+}
+
+impl Decode for CoinbaseTransaction {
+    fn decode(reader: &mut Reader) -> Result<CoinbaseTransaction> {
+        Ok(CoinbaseTransaction::new(
+            Decode::decode(reader)?,
+            Decode::decode(reader)?,
+            Decode::decode(reader)?,
+        ))
+    }
+}
+
+impl Encode for NormalTransaction {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.inputs.encode(buf);
+        self.outputs.encode(buf);
+        self.timestamp.encode(buf);
+        self.replaceable.encode(buf);
+    }
+}
+
+impl Decode for NormalTransaction {
+    fn decode(reader: &mut Reader) -> Result<NormalTransaction> {
+        Ok(NormalTransaction {
+            inputs: Decode::decode(reader)?,
+            outputs: Decode::decode(reader)?,
+            timestamp: Decode::decode(reader)?,
+            replaceable: Decode::decode(reader)?,
+        })
+    }
+}
+
+/// tag は json での tx_type と同じく coinbase を 0, normal を 1 とする。
+impl Encode for Transaction {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            Transaction::Coinbase(tx) => {
+                0u8.encode(buf);
+                tx.encode(buf);
+            }
+            Transaction::Normal(tx) => {
+                1u8.encode(buf);
+                tx.encode(buf);
+            }
+        }
+    }
+}
+
+impl Decode for Transaction {
+    fn decode(reader: &mut Reader) -> Result<Transaction> {
+        match reader.read_u8()? {
+            0 => Ok(Transaction::Coinbase(Decode::decode(reader)?)),
+            1 => Ok(Transaction::Normal(Decode::decode(reader)?)),
+            tag => bail!("Unknown transaction type: {}", tag),
+        }
+    }
+}
+
+impl Encode for Transactions {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.coinbase.encode(buf);
+        self.transactions.encode(buf);
+    }
+}
+
+impl Decode for Transactions {
+    fn decode(reader: &mut Reader) -> Result<Transactions> {
+        Ok(Transactions::new(
+            Decode::decode(reader)?,
+            Decode::decode(reader)?,
+        ))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -247,13 +597,10 @@ mod tests {
                 {
                   "inputs": [
                     {
-                      "transaction": {
-                        "tx_type": "0",
-                        "recipient": "alice",
-                        "value": 10,
-                        "timestamp": "2022-03-09T12:00:00Z"
-                      },
-                      "index": 0
+                      "previous_output": {
+                        "txid": "0a1b2c",
+                        "index": 0
+                      }
                     }
                   ],
                   "outputs": [
@@ -274,10 +621,7 @@ mod tests {
         let expected = Transactions::new(
             CoinbaseTransaction::new("alice".to_string(), 10, now),
             vec![NormalTransaction::new(
-                vec![TransactionInput::new(
-                    Transaction::Coinbase(CoinbaseTransaction::new("alice".to_string(), 10, now)),
-                    0,
-                )],
+                vec![TransactionInput::new("0a1b2c".to_string(), 0)],
                 vec![TransactionOutput::new("bob".to_string(), 10)],
                 now,
             )],
@@ -294,7 +638,7 @@ mod tests {
             CoinbaseTransaction::new("alice".to_string(), 10, now),
             vec![NormalTransaction::new(
                 vec![TransactionInput::new(
-                    Transaction::Coinbase(CoinbaseTransaction::new("alice".to_string(), 10, now)),
+                    CoinbaseTransaction::new("alice".to_string(), 10, now).get_id(),
                     0,
                 )],
                 vec![TransactionOutput::new("bob".to_string(), 10)],
@@ -309,18 +653,82 @@ mod tests {
         assert_eq!(actual, txs);
     }
 
+    #[test]
+    fn test_encode_transactions() {
+        let now: DateTime<Utc> = DateTime::from_str("2022-03-09T12:00:00Z").unwrap();
+        let coinbase = CoinbaseTransaction::new("alice".to_string(), 10, now);
+        let tx = NormalTransaction::new(
+            vec![TransactionInput::new("0a1b2c".to_string(), 0)],
+            vec![TransactionOutput::new("bob".to_string(), 10)],
+            now,
+        );
+
+        // golden vectors: changing these breaks every transaction ID and signature
+        assert_eq!(
+            util::bytes_to_hex(&encoding::to_bytes(&Transaction::Coinbase(
+                coinbase.clone()
+            ))),
+            [
+                "02",
+                "00",
+                "00000005616c696365",
+                "000000000000000a",
+                "00000000622896c000000000",
+            ]
+            .concat()
+        );
+        assert_eq!(
+            util::bytes_to_hex(&tx.get_signing_data()),
+            [
+                "02",
+                "01",
+                "00000001",
+                "00000006306131623263",
+                "0000000000000000",
+                "00000000",
+                "00000001",
+                "00000003626f62",
+                "000000000000000a",
+                "00000000622896c000000000",
+                "00",
+            ]
+            .concat()
+        );
+        assert_eq!(
+            coinbase.get_id(),
+            "b75119628513d3aad4be24cc619405f2054884a896a381bd7424bd323ee8a7d9"
+        );
+        assert_eq!(
+            tx.get_id(),
+            "e4e856ba6238aac819af81045a0ee5dea301bae24bb457790c5545b1bc5cb31a"
+        );
+
+        // round trip
+        let mut signed = tx.clone();
+        signed.inputs[0].signature = "0123".to_string();
+        let mut replaceable = tx.clone();
+        replaceable.set_replaceable(true);
+        assert_ne!(replaceable.get_id(), tx.get_id());
+        let txs = Transactions::new(coinbase, vec![tx, signed, replaceable]);
+        let bytes = encoding::to_bytes(&txs);
+        assert_eq!(encoding::from_bytes::<Transactions>(&bytes).unwrap(), txs);
+        for tx in txs.get_transactions() {
+            let bytes = encoding::to_bytes(&tx);
+            assert_eq!(encoding::from_bytes::<Transaction>(&bytes).unwrap(), tx);
+        }
+
+        // unknown transaction type
+        assert!(encoding::from_bytes::<Transaction>(&[encoding::ENCODING_VERSION, 2]).is_err());
+    }
+
     fn generate_sample() -> (CoinbaseTransaction, NormalTransaction, Transactions) {
         let now: DateTime<Utc> = DateTime::from_str("2022-03-09T12:00:00Z").unwrap();
         let sec = Duration::seconds(1);
 
-        let tx1 = CoinbaseTransaction::new("alice".to_string(), 10, now + sec * 0);
+        let tx1 = CoinbaseTransaction::new("alice".to_string(), 10, now);
         let tx2 = NormalTransaction::new(
             vec![TransactionInput::new(
-                Transaction::Coinbase(CoinbaseTransaction::new(
-                    "alice".to_string(),
-                    10,
-                    now + sec * 1,
-                )),
+                CoinbaseTransaction::new("alice".to_string(), 10, now + sec * 1).get_id(),
                 0,
             )],
             vec![TransactionOutput::new("bob".to_string(), 10)],
@@ -353,9 +761,156 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_resolve_input() {
+        let (tx1, _, _) = generate_sample();
+        let tx1 = Transaction::Coinbase(tx1);
+        let index: HashMap<TransactionId, Transaction> =
+            vec![(tx1.get_id(), tx1.clone())].into_iter().collect();
+
+        let input = TransactionInput::new(tx1.get_id(), 0);
+        assert_eq!(
+            input.resolve(&index).unwrap(),
+            TransactionOutput::new("alice".to_string(), 10)
+        );
+
+        let input = TransactionInput::new(tx1.get_id(), 1);
+        assert!(input.resolve(&index).is_err());
+
+        let input = TransactionInput::new("unknown".to_string(), 0);
+        assert!(input.resolve(&index).is_err());
+    }
+
+    #[test]
+    fn test_get_id() {
+        let (tx1, tx2, _) = generate_sample();
+        assert_eq!(tx1.get_id(), Transaction::Coinbase(tx1.clone()).get_id());
+        assert_eq!(tx2.get_id(), Transaction::Normal(tx2.clone()).get_id());
+        assert_ne!(tx1.get_id(), tx2.get_id());
+        assert_eq!(tx1.get_id().len(), 64);
+    }
+
+    #[test]
+    fn test_sign_and_verify_signatures() {
+        let mut km = KeyManager::new(rand::rngs::OsRng).unwrap();
+        let base =
+            Transaction::Coinbase(CoinbaseTransaction::new(km.get_address(), 10, Utc::now()));
+        let index: HashMap<TransactionId, Transaction> =
+            vec![(base.get_id(), base.clone())].into_iter().collect();
+
+        let mut tx = NormalTransaction::new(
+            vec![TransactionInput::new(base.get_id(), 0)],
+            vec![TransactionOutput::new("bob".to_string(), 10)],
+            Utc::now(),
+        );
+        let unsigned_id = tx.get_id();
+        assert!(tx.verify_signatures(&index).is_err());
+
+        tx.sign(&mut km).unwrap();
+        assert!(tx.verify_signatures(&index).is_ok());
+        // signatures don't change transaction ID
+        assert_eq!(tx.get_id(), unsigned_id);
+
+        // tampering with outputs invalidates signatures
+        tx.outputs[0] = TransactionOutput::new("mallory".to_string(), 10);
+        assert!(tx.verify_signatures(&index).is_err());
+
+        // inputs owned by two different keys are signed one by one
+        let mut km2 = KeyManager::new(rand::rngs::OsRng).unwrap();
+        let base2 =
+            Transaction::Coinbase(CoinbaseTransaction::new(km2.get_address(), 5, Utc::now()));
+        let index: HashMap<TransactionId, Transaction> = vec![
+            (base.get_id(), base.clone()),
+            (base2.get_id(), base2.clone()),
+        ]
+        .into_iter()
+        .collect();
+        let mut tx = NormalTransaction::new(
+            vec![
+                TransactionInput::new(base.get_id(), 0),
+                TransactionInput::new(base2.get_id(), 0),
+            ],
+            vec![TransactionOutput::new("bob".to_string(), 15)],
+            Utc::now(),
+        );
+        tx.sign(&mut km).unwrap();
+        assert!(tx.verify_signatures(&index).is_err());
+        tx.sign_input(1, &mut km2).unwrap();
+        assert!(tx.verify_signatures(&index).is_ok());
+        tx.sign_input(0, &mut km2).unwrap();
+        assert!(tx.verify_signatures(&index).is_err());
+        tx.sign_input(0, &mut km).unwrap();
+        assert!(tx.verify_signatures(&index).is_ok());
+        assert!(tx.sign_input(2, &mut km).is_err());
+    }
+
+    #[test]
+    fn test_address_to_public_key() {
+        let km = KeyManager::new(rand::rngs::OsRng).unwrap();
+        assert!(address_to_public_key(&km.get_address()).is_ok());
+        assert!(address_to_public_key(&"alice".to_string()).is_err());
+        assert!(address_to_public_key(&"00ff".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_get_fee() {
+        let base = Transaction::Coinbase(CoinbaseTransaction::new(
+            "alice".to_string(),
+            u64::MAX,
+            Utc::now(),
+        ));
+        let index: HashMap<TransactionId, Transaction> =
+            vec![(base.get_id(), base.clone())].into_iter().collect();
+        let inputs = vec![TransactionInput::new(base.get_id(), 0)];
+        let new_tx = |outputs: Vec<u64>| {
+            NormalTransaction::new(
+                inputs.clone(),
+                outputs
+                    .into_iter()
+                    .map(|value| TransactionOutput::new("bob".to_string(), value))
+                    .collect(),
+                Utc::now(),
+            )
+        };
+
+        assert_eq!(new_tx(vec![u64::MAX - 3]).get_fee(&index), Ok(3));
+        assert_eq!(
+            new_tx(vec![1, 0]).get_fee(&index),
+            Err(TransactionError::ZeroValueOutput(1))
+        );
+        assert_eq!(
+            new_tx(vec![u64::MAX, 1]).get_fee(&index),
+            Err(TransactionError::ValueOverflow)
+        );
+        assert_eq!(
+            new_tx(vec![1]).get_fee(&HashMap::new()),
+            Err(TransactionError::UnknownInput(OutPoint::new(
+                base.get_id(),
+                0
+            )))
+        );
+
+        let small =
+            Transaction::Coinbase(CoinbaseTransaction::new("alice".to_string(), 5, Utc::now()));
+        let index: HashMap<TransactionId, Transaction> =
+            vec![(small.get_id(), small.clone())].into_iter().collect();
+        let overspending = NormalTransaction::new(
+            vec![TransactionInput::new(small.get_id(), 0)],
+            vec![TransactionOutput::new("bob".to_string(), 6)],
+            Utc::now(),
+        );
+        assert_eq!(
+            overspending.get_fee(&index),
+            Err(TransactionError::OutputsExceedInputs {
+                input: 5,
+                output: 6
+            })
+        );
+    }
+
     #[test]
     fn test_get_normal_transactions() {
-        let (tx1, tx2, txs) = generate_sample();
+        let (_, tx2, txs) = generate_sample();
         assert_eq!(txs.get_normal_transactions(), vec![tx2]);
     }
 }
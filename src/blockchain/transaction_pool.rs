@@ -1,84 +1,623 @@
-use crate::blockchain::block::BlockWithoutProof;
+use crate::blockchain::block::{BlockWithoutProof, MAX_BLOCK_SIZE};
+use crate::blockchain::encoding::{self, Decode, Encode, Reader};
 use crate::blockchain::manager::BlockchainManager;
+use crate::blockchain::miner::Miner;
+use crate::blockchain::subsidy;
 use crate::blockchain::transaction::{
-    CoinbaseTransaction, NormalTransaction, TransactionInput, Transactions,
+    ChainedResolver, CoinbaseTransaction, NormalTransaction, OutPoint, OutputResolver,
+    TransactionError, TransactionId, TransactionInput, TransactionOutput, Transactions,
 };
 use crate::connection_manager_core::{ConnectionManagerCore, ConnectionManagerInner};
+use crate::inventory::InventoryItem;
 use crate::key_manager::KeyManager;
 use crate::message::{ApplicationPayload, Message, Payload};
-use chrono::Utc;
-use log::{debug, info};
-use std::ops::RangeBounds;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use log::{debug, error, info};
+use std::cmp;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
-// FIXME: mining 報酬のベタ書き
-pub const COINBASE_INCENTIVE: u64 = 10;
+/// 他の node から新しい先頭の block を受け取ったことを mining 中の処理に知らせる。
+/// 知らせを受けた mining は中断し、新しい先頭の上で block を作り直す。
+#[derive(Clone, Debug, Default)]
+pub struct MiningCanceller {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl MiningCanceller {
+    pub fn new() -> MiningCanceller {
+        MiningCanceller {
+            cancelled: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// 進行中の mining を中断させる。
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    fn reset(&self) {
+        self.cancelled.store(false, Ordering::Relaxed);
+    }
+}
 
+/// pool 全体の transaction の大きさ (byte) の上限の既定値
+pub const DEFAULT_MAX_POOL_SIZE: usize = 5_000_000;
+/// pool に置く transaction の数の上限の既定値
+pub const DEFAULT_MAX_POOL_TRANSACTIONS: usize = 10_000;
+/// pool 内の祖先 (未承認の親、親の親...) の数の上限
+pub const MAX_ANCESTORS: usize = 25;
+/// pool に入ってから block に取り込まれないまま、この期間が過ぎた transaction は除く
+pub const DEFAULT_POOL_EXPIRY: Duration = Duration::from_secs(14 * 24 * 60 * 60);
+/// data directory 内で pool の transaction を保存するファイルの名前
+pub const MEMPOOL_FILE_NAME: &str = "mempool.dat";
+/// 1 つの transaction で置き換えられる pool 内の transaction (子孫を含む) の数の上限
+pub const MAX_REPLACED: usize = 100;
+
+/// 1 byte あたりの fee. 比較は分数のまま行う。
+#[derive(Clone, Copy, Debug)]
+pub struct FeeRate {
+    fee: u64,
+    size: usize,
+}
+
+impl FeeRate {
+    pub fn new(fee: u64, size: usize) -> FeeRate {
+        FeeRate {
+            fee,
+            size: size.max(1),
+        }
+    }
+
+    pub fn get_fee(&self) -> u64 {
+        self.fee
+    }
+
+    pub fn get_size(&self) -> usize {
+        self.size
+    }
+}
+
+impl Ord for FeeRate {
+    fn cmp(&self, other: &FeeRate) -> cmp::Ordering {
+        (self.fee as u128 * other.size as u128).cmp(&(other.fee as u128 * self.size as u128))
+    }
+}
+
+impl PartialOrd for FeeRate {
+    fn partial_cmp(&self, other: &FeeRate) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for FeeRate {
+    fn eq(&self, other: &FeeRate) -> bool {
+        self.cmp(other) == cmp::Ordering::Equal
+    }
+}
+
+impl Eq for FeeRate {}
+
+impl fmt::Display for FeeRate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:.3} per byte", self.fee as f64 / self.size as f64)
+    }
+}
+
+/// transaction を pool に追加できない理由
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PoolError {
+    /// すでに pool にある
+    AlreadyInPool,
+    /// input が参照する output をすでに pool 内の置き換えできない transaction が使用している
+    Conflict(OutPoint),
+    /// 置き換えられる transaction の fee の合計を上回らない
+    InsufficientReplacementFee(u64),
+    /// 置き換えられる transaction の fee rate を上回らない
+    ReplacementFeeRateTooLow(FeeRate),
+    /// 置き換えられる transaction が多すぎる
+    TooManyReplacements(usize),
+    /// pool が一杯で、pool 内のどの transaction よりも fee rate が低い
+    FeeRateTooLow(FeeRate),
+    /// pool 内の祖先が多すぎる
+    TooManyAncestors(usize),
+    /// fee を計算できない
+    InvalidTransaction(TransactionError),
+}
+
+impl fmt::Display for PoolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PoolError::AlreadyInPool => write!(f, "Transaction is already in transaction pool"),
+            PoolError::Conflict(outpoint) => write!(
+                f,
+                "{:?} is already spent by transaction in transaction pool",
+                outpoint
+            ),
+            PoolError::InsufficientReplacementFee(fee) => write!(
+                f,
+                "Replacement must pay more fee than replaced transactions: {}",
+                fee
+            ),
+            PoolError::ReplacementFeeRateTooLow(fee_rate) => write!(
+                f,
+                "Replacement must pay higher fee rate than replaced transaction: {}",
+                fee_rate
+            ),
+            PoolError::TooManyReplacements(count) => write!(
+                f,
+                "Replacement would remove too many transactions: {}",
+                count
+            ),
+            PoolError::FeeRateTooLow(fee_rate) => write!(
+                f,
+                "Transaction pool is full and fee rate is too low: {}",
+                fee_rate
+            ),
+            PoolError::TooManyAncestors(count) => write!(
+                f,
+                "Transaction has too many unconfirmed ancestors: {}",
+                count
+            ),
+            PoolError::InvalidTransaction(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for PoolError {}
+
+// pool 内の transaction と、追加時に計算した fee rate
+struct PoolEntry {
+    transaction: NormalTransaction,
+    fee_rate: FeeRate,
+    // pool に入った時刻
+    added_at: DateTime<Utc>,
+    // この transaction が output を使っている、pool 内の transaction
+    parents: HashSet<TransactionId>,
+    // この transaction の output を使っている、pool 内の transaction
+    children: HashSet<TransactionId>,
+}
+
+/// 未承認の transaction の pool.
+/// transaction id で引けるようにし、block に取り込む transaction は fee rate の高い順に選ぶ。
+/// 上限を超えた場合は fee rate の低い transaction から追い出す。
+/// pool 内の transaction が作る output を使う transaction も受け入れ、親子関係を追跡する。
 pub struct TransactionPool {
-    transactions: Vec<NormalTransaction>,
+    entries: HashMap<TransactionId, PoolEntry>,
+    // fee rate の低い順
+    by_fee_rate: BTreeSet<(FeeRate, TransactionId)>,
+    // pool 内の transaction が使用している output と、使用している transaction
+    spent_outpoints: HashMap<OutPoint, TransactionId>,
+    total_size: usize,
+    max_size: usize,
+    max_transactions: usize,
+    expiry: Duration,
+}
+
+impl Default for TransactionPool {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl TransactionPool {
     pub fn new() -> TransactionPool {
+        TransactionPool::with_limits(DEFAULT_MAX_POOL_SIZE, DEFAULT_MAX_POOL_TRANSACTIONS)
+    }
+
+    /// transaction の大きさの合計が max_size, 数が max_transactions を超えないようにする。
+    pub fn with_limits(max_size: usize, max_transactions: usize) -> TransactionPool {
         TransactionPool {
-            transactions: vec![],
+            entries: HashMap::new(),
+            by_fee_rate: BTreeSet::new(),
+            spent_outpoints: HashMap::new(),
+            total_size: 0,
+            max_size,
+            max_transactions,
+            expiry: DEFAULT_POOL_EXPIRY,
         }
     }
 
-    pub fn add_new_transaction(&mut self, transaction: NormalTransaction) {
-        if !self.has_transaction(&transaction) {
-            self.transactions.push(transaction);
+    /// pool に入ってから expiry が過ぎた transaction を remove_expired_transactions で除くようにする。
+    pub fn set_expiry(&mut self, expiry: Duration) {
+        self.expiry = expiry;
+    }
+
+    /// transaction を追加する。fee は resolver と pool 内の transaction で input を解決して計算する。
+    /// 上限を超える場合は fee rate の低い transaction を子孫ごと追い出し、追い出した transaction の id を返す。
+    pub fn add_new_transaction(
+        &mut self,
+        transaction: NormalTransaction,
+        resolver: &dyn OutputResolver,
+    ) -> std::result::Result<Vec<TransactionId>, PoolError> {
+        self.add_transaction_at(transaction, resolver, Utc::now())
+    }
+
+    /// add_new_transaction と同じだが、pool に入った時刻を added_at とする。
+    /// 保存した pool を読み込む場合に、有効期限を引き継ぐために使う。
+    pub fn add_transaction_at(
+        &mut self,
+        transaction: NormalTransaction,
+        resolver: &dyn OutputResolver,
+        added_at: DateTime<Utc>,
+    ) -> std::result::Result<Vec<TransactionId>, PoolError> {
+        let txid = transaction.get_id();
+        if self.entries.contains_key(&txid) {
+            return Err(PoolError::AlreadyInPool);
         }
+        // 同じ output を使っている pool 内の transaction. すべて replaceable なら置き換えを試みる
+        let mut conflicts = HashSet::new();
+        for input in transaction.get_inputs() {
+            if let Some(conflict) = self.spent_outpoints.get(input.get_outpoint()) {
+                if !self.entries[conflict].transaction.is_replaceable() {
+                    return Err(PoolError::Conflict(input.get_outpoint().clone()));
+                }
+                conflicts.insert(conflict.clone());
+            }
+        }
+        let fee = transaction
+            .get_fee(&ChainedResolver::new(vec![resolver, &*self]))
+            .map_err(PoolError::InvalidTransaction)?;
+        let fee_rate = FeeRate::new(fee, transaction.get_size());
+        let replaced = self.check_replacement(&transaction, &conflicts, &fee_rate)?;
+
+        let parents: HashSet<TransactionId> = transaction
+            .get_inputs()
+            .iter()
+            .map(|input| input.get_outpoint().get_txid().clone())
+            .filter(|parent| self.entries.contains_key(parent))
+            .collect();
+        let mut ancestors = parents.clone();
+        for parent in parents.iter() {
+            ancestors.extend(self.get_ancestors(parent));
+        }
+        if ancestors.len() > MAX_ANCESTORS {
+            return Err(PoolError::TooManyAncestors(ancestors.len()));
+        }
+
+        // fee rate の低いものから、入る余地ができるまで子孫ごと追い出す。
+        // 追加する transaction の祖先は追い出さない
+        // 置き換えられる transaction はすでに取り除いたものとして数える
+        let mut evicted = replaced.clone();
+        let mut size = self.total_size + fee_rate.get_size();
+        let mut count = self.entries.len() + 1;
+        for replaced_txid in replaced.iter() {
+            size -= self.entries[replaced_txid].fee_rate.get_size();
+            count -= 1;
+        }
+        for (lowest, lowest_txid) in self.by_fee_rate.iter() {
+            if size <= self.max_size && count <= self.max_transactions {
+                break;
+            }
+            if evicted.contains(lowest_txid) || ancestors.contains(lowest_txid) {
+                continue;
+            }
+            if *lowest >= fee_rate {
+                return Err(PoolError::FeeRateTooLow(fee_rate));
+            }
+            let mut package = self.get_descendants(lowest_txid);
+            package.insert(lowest_txid.clone());
+            for evicted_txid in package {
+                if evicted.insert(evicted_txid.clone()) {
+                    size -= self.entries[&evicted_txid].fee_rate.get_size();
+                    count -= 1;
+                }
+            }
+        }
+        if size > self.max_size || count > self.max_transactions {
+            return Err(PoolError::FeeRateTooLow(fee_rate));
+        }
+        let evicted: Vec<TransactionId> = evicted.into_iter().collect();
+        for evicted_txid in evicted.iter() {
+            if replaced.contains(evicted_txid) {
+                info!("Replace transaction {} with {}", evicted_txid, txid);
+            } else {
+                info!("Evict transaction {} from transaction pool", evicted_txid);
+            }
+            self.remove_transaction_by_id(evicted_txid);
+        }
+
+        for input in transaction.get_inputs() {
+            self.spent_outpoints
+                .insert(input.get_outpoint().clone(), txid.clone());
+        }
+        for parent in parents.iter() {
+            if let Some(entry) = self.entries.get_mut(parent) {
+                entry.children.insert(txid.clone());
+            }
+        }
+        // block から外れて戻ってきた transaction の場合、その output をすでに pool 内の transaction が使っている
+        let mut children = HashSet::new();
+        for idx in 0..transaction.get_outputs().len() {
+            let outpoint = OutPoint::new(txid.clone(), idx);
+            if let Some(child) = self.spent_outpoints.get(&outpoint).cloned() {
+                if let Some(entry) = self.entries.get_mut(&child) {
+                    entry.parents.insert(txid.clone());
+                }
+                children.insert(child);
+            }
+        }
+        self.total_size += fee_rate.get_size();
+        self.by_fee_rate.insert((fee_rate, txid.clone()));
+        self.entries.insert(
+            txid,
+            PoolEntry {
+                transaction,
+                fee_rate,
+                added_at,
+                parents,
+                children,
+            },
+        );
+        Ok(evicted)
+    }
+
+    // conflicts を transaction で置き換えられるか確認し、置き換えられる transaction (conflicts とその子孫) を返す。
+    // 置き換えられる transaction の fee の合計と、conflicts それぞれの fee rate を上回る必要がある
+    fn check_replacement(
+        &self,
+        transaction: &NormalTransaction,
+        conflicts: &HashSet<TransactionId>,
+        fee_rate: &FeeRate,
+    ) -> std::result::Result<HashSet<TransactionId>, PoolError> {
+        let mut replaced = HashSet::new();
+        for conflict in conflicts.iter() {
+            replaced.extend(self.get_descendants(conflict));
+            replaced.insert(conflict.clone());
+        }
+        if replaced.len() > MAX_REPLACED {
+            return Err(PoolError::TooManyReplacements(replaced.len()));
+        }
+        // 置き換えられる transaction の output を使うことはできない
+        for input in transaction.get_inputs() {
+            if replaced.contains(input.get_outpoint().get_txid()) {
+                return Err(PoolError::Conflict(input.get_outpoint().clone()));
+            }
+        }
+        let replaced_fee: u64 = replaced
+            .iter()
+            .map(|txid| self.entries[txid].fee_rate.get_fee())
+            .sum();
+        if !replaced.is_empty() && fee_rate.get_fee() <= replaced_fee {
+            return Err(PoolError::InsufficientReplacementFee(replaced_fee));
+        }
+        for conflict in conflicts.iter() {
+            let conflict_fee_rate = self.entries[conflict].fee_rate;
+            if *fee_rate <= conflict_fee_rate {
+                return Err(PoolError::ReplacementFeeRateTooLow(conflict_fee_rate));
+            }
+        }
+        Ok(replaced)
     }
 
     pub fn has_transaction(&self, transaction: &NormalTransaction) -> bool {
-        self.transactions.contains(transaction)
+        self.entries.contains_key(&transaction.get_id())
+    }
+
+    pub fn get_transaction(&self, txid: &TransactionId) -> Option<NormalTransaction> {
+        self.entries
+            .get(txid)
+            .map(|entry| entry.transaction.clone())
+    }
+
+    pub fn get_fee_rate(&self, txid: &TransactionId) -> Option<FeeRate> {
+        self.entries.get(txid).map(|entry| entry.fee_rate)
+    }
+
+    /// pool に入った時刻
+    pub fn get_added_at(&self, txid: &TransactionId) -> Option<DateTime<Utc>> {
+        self.entries.get(txid).map(|entry| entry.added_at)
+    }
+
+    /// txid の transaction が output を使っている pool 内の transaction を、親の親も含めて返す。
+    pub fn get_ancestors(&self, txid: &TransactionId) -> HashSet<TransactionId> {
+        self.collect_related(txid, |entry| &entry.parents)
+    }
+
+    /// txid の transaction の output を使っている pool 内の transaction を、子の子も含めて返す。
+    pub fn get_descendants(&self, txid: &TransactionId) -> HashSet<TransactionId> {
+        self.collect_related(txid, |entry| &entry.children)
+    }
+
+    fn collect_related<F>(&self, txid: &TransactionId, next: F) -> HashSet<TransactionId>
+    where
+        F: Fn(&PoolEntry) -> &HashSet<TransactionId>,
+    {
+        let mut related = HashSet::new();
+        let mut queue = vec![txid.clone()];
+        while let Some(current) = queue.pop() {
+            if let Some(entry) = self.entries.get(&current) {
+                for other in next(entry) {
+                    if related.insert(other.clone()) {
+                        queue.push(other.clone());
+                    }
+                }
+            }
+        }
+        related
     }
 
     pub fn clear_transactions(&mut self) {
-        self.transactions.clear();
+        self.entries.clear();
+        self.by_fee_rate.clear();
+        self.spent_outpoints.clear();
+        self.total_size = 0;
     }
 
+    /// pool 内の全ての transaction を fee rate の高い順に返す。
     pub fn get_transactions(&self) -> Vec<NormalTransaction> {
-        self.transactions.clone()
+        self.by_fee_rate
+            .iter()
+            .rev()
+            .map(|(_, txid)| self.entries[txid].transaction.clone())
+            .collect()
     }
 
-    pub fn take_transactions(&mut self) -> Vec<NormalTransaction> {
-        self.transactions.drain(0..).collect()
+    pub fn len(&self) -> usize {
+        self.entries.len()
     }
 
-    pub fn remove_transactions<R: RangeBounds<usize>>(&mut self, range: R) {
-        self.transactions.drain(range);
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// pool 内の transaction の大きさの合計
+    pub fn get_total_size(&self) -> usize {
+        self.total_size
+    }
+
+    /// 大きさの合計が max_size 以下になるように fee rate の高い順に transaction を選び、fee の合計と共に返す。
+    /// 選んだ transaction の祖先はその前に並べ、祖先ごと入りきらない場合は飛ばしてより小さいものを詰める。
+    pub fn select_transactions(&self, max_size: usize) -> (Vec<NormalTransaction>, u64) {
+        let mut selected = vec![];
+        let mut selected_ids = HashSet::new();
+        let mut size = 0;
+        let mut total_fee: u64 = 0;
+        for (_, txid) in self.by_fee_rate.iter().rev() {
+            if selected_ids.contains(txid) {
+                continue;
+            }
+            let mut package: Vec<TransactionId> = self
+                .get_ancestors(txid)
+                .into_iter()
+                .filter(|ancestor| !selected_ids.contains(ancestor))
+                .collect();
+            package.push(txid.clone());
+            let package_size: usize = package
+                .iter()
+                .map(|id| self.entries[id].fee_rate.get_size())
+                .sum();
+            if size + package_size > max_size {
+                continue;
+            }
+            // 親が子より先に来るように、祖先の少ない順に並べる
+            package.sort_by_cached_key(|id| (self.get_ancestors(id).len(), id.clone()));
+            for id in package {
+                let entry = &self.entries[&id];
+                size += entry.fee_rate.get_size();
+                total_fee = total_fee.saturating_add(entry.fee_rate.get_fee());
+                selected.push(entry.transaction.clone());
+                selected_ids.insert(id);
+            }
+        }
+        (selected, total_fee)
     }
 
     pub fn remove_transaction(&mut self, transaction: &NormalTransaction) {
-        if let Some((index, _)) = self
-            .transactions
-            .iter()
-            .enumerate()
-            .find(|(_, t)| *t == transaction)
-        {
-            self.transactions.remove(index);
+        self.remove_transaction_by_id(&transaction.get_id());
+    }
+
+    /// txid の transaction だけを除く。block に取り込まれた場合に使い、子の transaction は残す。
+    pub fn remove_transaction_by_id(&mut self, txid: &TransactionId) -> Option<NormalTransaction> {
+        let entry = self.entries.remove(txid)?;
+        self.by_fee_rate.remove(&(entry.fee_rate, txid.clone()));
+        for input in entry.transaction.get_inputs() {
+            self.spent_outpoints.remove(input.get_outpoint());
+        }
+        for parent in entry.parents.iter() {
+            if let Some(parent) = self.entries.get_mut(parent) {
+                parent.children.remove(txid);
+            }
         }
+        for child in entry.children.iter() {
+            if let Some(child) = self.entries.get_mut(child) {
+                child.parents.remove(txid);
+            }
+        }
+        self.total_size -= entry.fee_rate.get_size();
+        Some(entry.transaction)
     }
 
-    pub fn has_transaction_input(&self, target_input: &TransactionInput) -> bool {
-        for tx in self.transactions.iter() {
-            for input in tx.get_inputs() {
-                if &input == target_input {
-                    return true;
-                }
+    /// txid の transaction を子孫ごと除く。input が使えなくなった場合に使う。
+    pub fn remove_transaction_with_descendants(
+        &mut self,
+        txid: &TransactionId,
+    ) -> Vec<NormalTransaction> {
+        let mut removed = vec![];
+        let descendants = self.get_descendants(txid);
+        for id in std::iter::once(txid).chain(descendants.iter()) {
+            if let Some(transaction) = self.remove_transaction_by_id(id) {
+                removed.push(transaction);
             }
         }
-        false
+        removed
     }
 
-    pub fn calc_total_fee(&self) -> u64 {
-        self.transactions.iter().fold(0, |acc, tx| {
-            let fee = tx.get_input_value() - tx.get_output_value();
-            acc + fee
-        })
+    /// pool に入ってから有効期限が過ぎた transaction を子孫ごと除き、除いた transaction を返す。
+    pub fn remove_expired_transactions(&mut self, now: DateTime<Utc>) -> Vec<NormalTransaction> {
+        let expiry = chrono::Duration::from_std(self.expiry)
+            .unwrap_or_else(|_| chrono::Duration::max_value());
+        let expired: Vec<TransactionId> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| now.signed_duration_since(entry.added_at) >= expiry)
+            .map(|(txid, _)| txid.clone())
+            .collect();
+        let mut removed = vec![];
+        for txid in expired.iter() {
+            removed.extend(self.remove_transaction_with_descendants(txid));
+        }
+        if !removed.is_empty() {
+            info!("Remove {} expired transactions from pool", removed.len());
+        }
+        removed
+    }
+
+    /// pool 内の transaction を、pool に入った時刻と共に path に保存する。
+    /// 読み込む際に親から追加できるように、祖先の少ない順に並べる。
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let mut saved: Vec<SavedTransaction> = self
+            .entries
+            .values()
+            .map(|entry| SavedTransaction {
+                transaction: entry.transaction.clone(),
+                added_at: entry.added_at,
+            })
+            .collect();
+        saved.sort_by_cached_key(|saved| {
+            let txid = saved.transaction.get_id();
+            (self.get_ancestors(&txid).len(), saved.added_at, txid)
+        });
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // 書き込み途中で終了しても壊れないように一時ファイルから置き換える
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, encoding::to_bytes(&saved))?;
+        fs::rename(&tmp_path, path)
+            .with_context(|| format!("Failed to save transaction pool: {:?}", path))?;
+        Ok(())
+    }
+
+    /// save で保存した transaction と pool に入った時刻を読み込む。ファイルが無い場合は空になる。
+    /// 現在の chain に対して有効かどうかは確認しない。
+    pub fn load_saved<P: AsRef<Path>>(path: P) -> Result<Vec<(NormalTransaction, DateTime<Utc>)>> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(vec![]);
+        }
+        let data = fs::read(path)
+            .with_context(|| format!("Failed to read transaction pool: {:?}", path))?;
+        let saved: Vec<SavedTransaction> = encoding::from_bytes(&data)
+            .with_context(|| format!("Invalid transaction pool: {:?}", path))?;
+        info!("Load {} transactions from {:?}", saved.len(), path);
+        Ok(saved
+            .into_iter()
+            .map(|saved| (saved.transaction, saved.added_at))
+            .collect())
+    }
+
+    /// input が参照する output をすでに pool 内の transaction が使用しているか
+    pub fn has_transaction_input(&self, target_input: &TransactionInput) -> bool {
+        self.spent_outpoints
+            .contains_key(target_input.get_outpoint())
     }
 
     pub async fn generate_block_periodically(
@@ -86,36 +625,81 @@ impl TransactionPool {
         blockchain_manager: Arc<Mutex<BlockchainManager>>,
         connection_manager: Arc<Mutex<ConnectionManagerInner>>,
         key_manager: Arc<Mutex<KeyManager>>,
+        miner: Miner,
+        canceller: MiningCanceller,
         interval: Duration,
     ) {
+        // 中断した場合は待たずに作り直す
+        let mut retry_now = false;
         loop {
-            tokio::time::sleep(interval).await;
-            debug!("generate_block_periodically was called");
-
-            let pool_txs: Vec<NormalTransaction>;
-            let num_pool_txs: usize;
-            let total_fee: u64;
-            {
-                let pool = pool.lock().unwrap();
-                pool_txs = pool.get_transactions();
-                num_pool_txs = pool_txs.len();
-                total_fee = pool.calc_total_fee();
+            if !retry_now {
+                tokio::time::sleep(interval).await;
             }
+            retry_now = false;
+            debug!("generate_block_periodically was called");
+            // これ以降に届いた先頭の block だけが mining を中断させる
+            canceller.reset();
 
-            let difficulty = blockchain_manager.lock().unwrap().get_difficulty();
             let addr = key_manager.lock().unwrap().get_address();
+            let timestamp = Utc::now();
 
-            let prev_block_hash = blockchain_manager.lock().unwrap().get_last_block_hash();
-            let block = tokio::task::spawn_blocking(move || {
+            let (prev_block_hash, bits, height) = {
+                let manager = blockchain_manager.lock().unwrap();
+                (
+                    manager.get_last_block_hash(),
+                    manager.get_next_bits(),
+                    manager.get_height() + 1,
+                )
+            };
+            // coinbase の金額によらず大きさは変わらないので、transaction の無い block から空きを計算する
+            let empty_block_size = BlockWithoutProof::new(
+                Transactions::new(CoinbaseTransaction::new(addr.clone(), 0, timestamp), vec![]),
+                prev_block_hash.clone(),
+                bits,
+            )
+            .get_size();
+            let (pool_txs, total_fee) = pool
+                .lock()
+                .unwrap()
+                .select_transactions(MAX_BLOCK_SIZE.saturating_sub(empty_block_size));
+
+            let incentive = match subsidy::get_block_subsidy(height).checked_add(total_fee) {
+                Some(incentive) => incentive,
+                None => {
+                    error!("Coinbase value overflows. Skip generating a block.");
+                    continue;
+                }
+            };
+
+            let cancelled = Arc::clone(&canceller.cancelled);
+            let (block, stats) = tokio::task::spawn_blocking(move || {
                 let transactions = Transactions::new(
-                    CoinbaseTransaction::new(addr, COINBASE_INCENTIVE + total_fee, Utc::now()),
+                    CoinbaseTransaction::new(addr, incentive, timestamp),
                     pool_txs,
                 );
-                BlockWithoutProof::new(transactions, prev_block_hash.clone()).mine(difficulty)
+                miner.mine(
+                    BlockWithoutProof::new(transactions, prev_block_hash, bits),
+                    cancelled,
+                )
             })
             .await
             .unwrap()
             .unwrap();
+            info!(
+                "Mined {} hashes in {:.1}s with {} threads ({:.0} H/s)",
+                stats.get_hashes(),
+                stats.get_elapsed().as_secs_f64(),
+                miner.get_threads(),
+                stats.get_hashrate()
+            );
+            let block = match block {
+                Some(block) => block,
+                None => {
+                    info!("New tip arrived while mining. Rebuild the block on it.");
+                    retry_now = true;
+                    continue;
+                }
+            };
 
             let is_block_added;
             {
@@ -127,17 +711,22 @@ impl TransactionPool {
                 if is_block_added {
                     info!("generated block, but it was old. Ignore it.");
                 } else {
-                    manager.add_new_block(block.clone());
+                    if let Err(err) = manager.add_new_block(block.clone()) {
+                        error!("Failed to add generated block: {:?}", err);
+                        continue;
+                    }
                     debug!("generated block: {:?}", block);
                     debug!("Current blockchain is: {:?}", manager.get_chain());
-                    pool.remove_transactions(0..num_pool_txs);
+                    manager.remove_useless_transactions(&mut pool);
                 }
             }
 
             // notify a new block
             if !is_block_added {
                 let payload = Payload::Application {
-                    payload: ApplicationPayload::NewBlock { block },
+                    payload: ApplicationPayload::Inv {
+                        items: vec![InventoryItem::Block(block.get_header().get_hash())],
+                    },
                 };
                 let port = connection_manager.lock().unwrap().get_my_addr().port();
                 ConnectionManagerCore::send_msg_to_core_nodes(
@@ -149,3 +738,408 @@ impl TransactionPool {
         }
     }
 }
+
+// ファイルに保存する pool 内の transaction
+struct SavedTransaction {
+    transaction: NormalTransaction,
+    added_at: DateTime<Utc>,
+}
+
+impl Encode for SavedTransaction {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.transaction.encode(buf);
+        self.added_at.encode(buf);
+    }
+}
+
+impl Decode for SavedTransaction {
+    fn decode(reader: &mut Reader) -> Result<SavedTransaction> {
+        Ok(SavedTransaction {
+            transaction: Decode::decode(reader)?,
+            added_at: Decode::decode(reader)?,
+        })
+    }
+}
+
+/// pool 内の transaction が作る output を引く。pool 内で使用済みかどうかは問わない。
+impl OutputResolver for TransactionPool {
+    fn resolve_output(&self, outpoint: &OutPoint) -> Option<TransactionOutput> {
+        self.entries
+            .get(outpoint.get_txid())
+            .and_then(|entry| entry.transaction.get_output(outpoint.get_index()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::transaction::Transaction;
+
+    // value の coinbase を作り、そこから fee を払う transaction を返す
+    fn spend(
+        resolver: &mut HashMap<TransactionId, Transaction>,
+        value: u64,
+        fee: u64,
+    ) -> NormalTransaction {
+        let coinbase = CoinbaseTransaction::new("alice".to_string(), value, Utc::now());
+        let tx = NormalTransaction::new(
+            vec![TransactionInput::new(coinbase.get_id(), 0)],
+            vec![TransactionOutput::new("bob".to_string(), value - fee)],
+            Utc::now(),
+        );
+        resolver.insert(coinbase.get_id(), Transaction::Coinbase(coinbase));
+        tx
+    }
+
+    #[test]
+    fn test_select_transactions_by_fee_rate() {
+        let mut resolver = HashMap::new();
+        let mut pool = TransactionPool::new();
+        let low = spend(&mut resolver, 10, 1);
+        let high = spend(&mut resolver, 10, 5);
+        let middle = spend(&mut resolver, 10, 3);
+        for tx in [low.clone(), high.clone(), middle.clone()] {
+            pool.add_new_transaction(tx, &resolver).unwrap();
+        }
+
+        assert_eq!(
+            pool.add_new_transaction(high.clone(), &resolver),
+            Err(PoolError::AlreadyInPool)
+        );
+        let conflict = NormalTransaction::new(
+            high.get_inputs(),
+            vec![TransactionOutput::new("carol".to_string(), 1)],
+            Utc::now(),
+        );
+        assert_eq!(
+            pool.add_new_transaction(conflict, &resolver),
+            Err(PoolError::Conflict(
+                high.get_inputs()[0].get_outpoint().clone()
+            ))
+        );
+        let unknown = spend(&mut HashMap::new(), 10, 1);
+        assert!(matches!(
+            pool.add_new_transaction(unknown, &resolver),
+            Err(PoolError::InvalidTransaction(
+                TransactionError::UnknownInput(_)
+            ))
+        ));
+
+        assert_eq!(
+            pool.get_transactions(),
+            vec![high.clone(), middle.clone(), low.clone()]
+        );
+        assert_eq!(pool.get_fee_rate(&high.get_id()).unwrap().get_fee(), 5);
+        let size = high.get_size();
+        assert_eq!(pool.get_total_size(), size * 3);
+        assert_eq!(
+            pool.select_transactions(size * 2 + 1),
+            (vec![high.clone(), middle.clone()], 8)
+        );
+        assert_eq!(pool.select_transactions(size - 1), (vec![], 0));
+
+        pool.remove_transaction(&middle);
+        assert_eq!(pool.len(), 2);
+        assert_eq!(pool.get_total_size(), size * 2);
+        assert!(!pool.has_transaction_input(&middle.get_inputs()[0]));
+        assert_eq!(pool.select_transactions(size * 2), (vec![high, low], 6));
+    }
+
+    #[test]
+    fn test_evict_lowest_fee_rate() {
+        let mut resolver = HashMap::new();
+        let mut pool = TransactionPool::with_limits(DEFAULT_MAX_POOL_SIZE, 2);
+        let low = spend(&mut resolver, 10, 1);
+        let high = spend(&mut resolver, 10, 5);
+        let middle = spend(&mut resolver, 10, 3);
+        pool.add_new_transaction(low.clone(), &resolver).unwrap();
+        pool.add_new_transaction(high.clone(), &resolver).unwrap();
+
+        assert_eq!(
+            pool.add_new_transaction(middle.clone(), &resolver),
+            Ok(vec![low.get_id()])
+        );
+        assert!(!pool.has_transaction(&low));
+        assert!(!pool.has_transaction_input(&low.get_inputs()[0]));
+
+        // 一杯の pool で最も低い fee rate 以下の transaction は入らない
+        let lower = spend(&mut resolver, 10, 3);
+        assert!(matches!(
+            pool.add_new_transaction(lower, &resolver),
+            Err(PoolError::FeeRateTooLow(_))
+        ));
+        assert_eq!(pool.get_transactions(), vec![high.clone(), middle]);
+
+        // 大きさの上限でも同じように追い出す
+        let size = high.get_size();
+        let mut pool = TransactionPool::with_limits(size * 2, DEFAULT_MAX_POOL_TRANSACTIONS);
+        pool.add_new_transaction(low.clone(), &resolver).unwrap();
+        pool.add_new_transaction(high.clone(), &resolver).unwrap();
+        let highest = spend(&mut resolver, 10, 9);
+        assert_eq!(
+            pool.add_new_transaction(highest.clone(), &resolver),
+            Ok(vec![low.get_id()])
+        );
+        assert_eq!(pool.get_transactions(), vec![highest, high]);
+    }
+
+    // parent の最初の output から fee を払う transaction
+    fn spend_unconfirmed(parent: &NormalTransaction, fee: u64) -> NormalTransaction {
+        let value = parent.get_output(0).unwrap().get_value();
+        NormalTransaction::new(
+            vec![TransactionInput::new(parent.get_id(), 0)],
+            vec![TransactionOutput::new("bob".to_string(), value - fee)],
+            Utc::now(),
+        )
+    }
+
+    #[test]
+    fn test_chained_transactions() {
+        let mut resolver = HashMap::new();
+        let mut pool = TransactionPool::new();
+        let parent = spend(&mut resolver, 10, 1);
+        let child = spend_unconfirmed(&parent, 5);
+        let other = spend(&mut resolver, 10, 3);
+
+        // 親が pool に無ければ input を解決できない
+        assert!(matches!(
+            pool.add_new_transaction(child.clone(), &resolver),
+            Err(PoolError::InvalidTransaction(
+                TransactionError::UnknownInput(_)
+            ))
+        ));
+        pool.add_new_transaction(parent.clone(), &resolver).unwrap();
+        pool.add_new_transaction(child.clone(), &resolver).unwrap();
+        pool.add_new_transaction(other.clone(), &resolver).unwrap();
+        assert_eq!(pool.get_fee_rate(&child.get_id()).unwrap().get_fee(), 5);
+        assert_eq!(
+            pool.get_descendants(&parent.get_id()),
+            vec![child.get_id()].into_iter().collect()
+        );
+
+        // 子を選ぶ場合は親を先に並べる
+        let size = parent.get_size();
+        assert_eq!(
+            pool.select_transactions(size * 3),
+            (vec![parent.clone(), child.clone(), other.clone()], 9)
+        );
+        // 親子で入りきらない場合は他を詰める
+        assert_eq!(pool.select_transactions(size), (vec![other.clone()], 3));
+
+        // 親が使えなくなった場合は子孫ごと除く
+        assert_eq!(
+            pool.remove_transaction_with_descendants(&parent.get_id()),
+            vec![parent.clone(), child.clone()]
+        );
+        assert_eq!(pool.get_transactions(), vec![other.clone()]);
+        assert!(!pool.has_transaction_input(&child.get_inputs()[0]));
+
+        // 親が block に取り込まれた場合は子を残す
+        pool.add_new_transaction(parent.clone(), &resolver).unwrap();
+        pool.add_new_transaction(child.clone(), &resolver).unwrap();
+        pool.remove_transaction_by_id(&parent.get_id());
+        assert_eq!(pool.get_transactions(), vec![child.clone(), other]);
+        assert!(pool.get_ancestors(&child.get_id()).is_empty());
+        assert!(pool.get_descendants(&parent.get_id()).is_empty());
+    }
+
+    #[test]
+    fn test_chain_limit_and_eviction_with_descendants() {
+        let mut resolver = HashMap::new();
+        let mut pool = TransactionPool::with_limits(DEFAULT_MAX_POOL_SIZE, 3);
+        let mut chain = vec![spend(&mut resolver, 1000, 1)];
+        pool.add_new_transaction(chain[0].clone(), &resolver)
+            .unwrap();
+        for _ in 0..MAX_ANCESTORS {
+            let tx = spend_unconfirmed(chain.last().unwrap(), 1);
+            chain.push(tx);
+        }
+        let mut pool_large = TransactionPool::new();
+        for tx in chain.iter() {
+            pool_large
+                .add_new_transaction(tx.clone(), &resolver)
+                .unwrap();
+        }
+        let too_long = spend_unconfirmed(chain.last().unwrap(), 1);
+        assert_eq!(
+            pool_large.add_new_transaction(too_long, &resolver),
+            Err(PoolError::TooManyAncestors(MAX_ANCESTORS + 1))
+        );
+
+        // 追い出す transaction の子孫も除き、追加する transaction の祖先は残す
+        let low = chain[0].clone();
+        let child = spend_unconfirmed(&low, 8);
+        pool.add_new_transaction(child.clone(), &resolver).unwrap();
+        let middle = spend(&mut resolver, 100, 3);
+        pool.add_new_transaction(middle.clone(), &resolver).unwrap();
+        let high = spend(&mut resolver, 10, 5);
+        let mut evicted = pool.add_new_transaction(high.clone(), &resolver).unwrap();
+        evicted.sort();
+        let mut expected = vec![low.get_id(), child.get_id()];
+        expected.sort();
+        assert_eq!(evicted, expected);
+        assert_eq!(pool.get_transactions(), vec![high.clone(), middle.clone()]);
+
+        let grandchild = spend_unconfirmed(&middle, 1);
+        pool.add_new_transaction(grandchild.clone(), &resolver)
+            .unwrap();
+        let great_grandchild = spend_unconfirmed(&grandchild, 6);
+        assert_eq!(
+            pool.add_new_transaction(great_grandchild.clone(), &resolver),
+            Ok(vec![high.get_id()])
+        );
+        assert_eq!(
+            pool.get_transactions(),
+            vec![great_grandchild, middle, grandchild]
+        );
+
+        let lowest = spend(&mut resolver, 10, 1);
+        assert!(matches!(
+            pool.add_new_transaction(lowest, &resolver),
+            Err(PoolError::FeeRateTooLow(_))
+        ));
+    }
+
+    // original と同じ value の input を使い、fee を払って outputs 個の output に分ける transaction
+    fn replace(
+        original: &NormalTransaction,
+        value: u64,
+        fee: u64,
+        outputs: u64,
+    ) -> NormalTransaction {
+        let mut outs: Vec<TransactionOutput> = (1..outputs)
+            .map(|_| TransactionOutput::new("bob".to_string(), 1))
+            .collect();
+        outs.push(TransactionOutput::new(
+            "bob".to_string(),
+            value - fee - (outputs - 1),
+        ));
+        NormalTransaction::new(original.get_inputs(), outs, Utc::now())
+    }
+
+    #[test]
+    fn test_replace_by_fee() {
+        let mut resolver = HashMap::new();
+        let mut pool = TransactionPool::new();
+        let mut original = spend(&mut resolver, 100, 2);
+        original.set_replaceable(true);
+        let child = spend_unconfirmed(&original, 1);
+        pool.add_new_transaction(original.clone(), &resolver)
+            .unwrap();
+        pool.add_new_transaction(child.clone(), &resolver).unwrap();
+
+        // 子孫を含めた fee の合計を上回る必要がある
+        assert_eq!(
+            pool.add_new_transaction(replace(&original, 100, 3, 1), &resolver),
+            Err(PoolError::InsufficientReplacementFee(3))
+        );
+        // fee が多くても fee rate が低ければ置き換えない
+        assert!(matches!(
+            pool.add_new_transaction(replace(&original, 100, 4, 20), &resolver),
+            Err(PoolError::ReplacementFeeRateTooLow(_))
+        ));
+        // 置き換えられる transaction の output は使えない
+        let spends_child = NormalTransaction::new(
+            vec![
+                original.get_inputs()[0].clone(),
+                TransactionInput::new(child.get_id(), 0),
+            ],
+            vec![TransactionOutput::new("bob".to_string(), 100)],
+            Utc::now(),
+        );
+        assert_eq!(
+            pool.add_new_transaction(spends_child, &resolver),
+            Err(PoolError::Conflict(OutPoint::new(child.get_id(), 0)))
+        );
+        assert_eq!(pool.len(), 2);
+
+        let replacement = replace(&original, 100, 4, 1);
+        let mut replaced = pool
+            .add_new_transaction(replacement.clone(), &resolver)
+            .unwrap();
+        replaced.sort();
+        let mut expected = vec![original.get_id(), child.get_id()];
+        expected.sort();
+        assert_eq!(replaced, expected);
+        assert_eq!(pool.get_transactions(), vec![replacement.clone()]);
+        assert_eq!(pool.get_total_size(), replacement.get_size());
+
+        // replaceable でない transaction は置き換えない
+        assert_eq!(
+            pool.add_new_transaction(replace(&original, 100, 10, 1), &resolver),
+            Err(PoolError::Conflict(
+                original.get_inputs()[0].get_outpoint().clone()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_remove_expired_transactions() {
+        let mut resolver = HashMap::new();
+        let mut pool = TransactionPool::new();
+        pool.set_expiry(Duration::from_secs(60 * 60));
+        let now = Utc::now();
+        let old = spend(&mut resolver, 10, 1);
+        let child = spend_unconfirmed(&old, 1);
+        let young = spend(&mut resolver, 10, 1);
+        pool.add_transaction_at(old.clone(), &resolver, now - chrono::Duration::hours(2))
+            .unwrap();
+        pool.add_new_transaction(child.clone(), &resolver).unwrap();
+        pool.add_transaction_at(
+            young.clone(),
+            &resolver,
+            now - chrono::Duration::minutes(30),
+        )
+        .unwrap();
+
+        assert_eq!(pool.remove_expired_transactions(now).len(), 2);
+        assert_eq!(pool.get_transactions(), vec![young.clone()]);
+        assert!(!pool.has_transaction_input(&child.get_inputs()[0]));
+        assert_eq!(
+            pool.remove_expired_transactions(now + chrono::Duration::minutes(30)),
+            vec![young]
+        );
+        assert!(pool.is_empty());
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let dir =
+            std::env::temp_dir().join(format!("transaction_pool_test_{}", rand::random::<u64>()));
+        let path = dir.join(MEMPOOL_FILE_NAME);
+        assert!(TransactionPool::load_saved(&path).unwrap().is_empty());
+
+        let mut resolver = HashMap::new();
+        let mut pool = TransactionPool::new();
+        let parent = spend(&mut resolver, 10, 1);
+        let child = spend_unconfirmed(&parent, 5);
+        let mut replaceable = spend(&mut resolver, 10, 3);
+        replaceable.set_replaceable(true);
+        let added_at = Utc::now() - chrono::Duration::hours(1);
+        pool.add_transaction_at(parent.clone(), &resolver, added_at)
+            .unwrap();
+        pool.add_new_transaction(child.clone(), &resolver).unwrap();
+        pool.add_new_transaction(replaceable.clone(), &resolver)
+            .unwrap();
+        pool.save(&path).unwrap();
+
+        // 親は子より先に並ぶ
+        let saved = TransactionPool::load_saved(&path).unwrap();
+        assert_eq!(saved.len(), 3);
+        let position = |tx: &NormalTransaction| saved.iter().position(|(saved, _)| saved == tx);
+        assert!(position(&parent).unwrap() < position(&child).unwrap());
+        assert!(position(&replaceable).is_some());
+
+        let mut loaded = TransactionPool::new();
+        for (tx, added_at) in saved {
+            loaded.add_transaction_at(tx, &resolver, added_at).unwrap();
+        }
+        assert_eq!(loaded.get_transactions(), pool.get_transactions());
+        assert_eq!(loaded.get_added_at(&parent.get_id()), Some(added_at));
+        assert_eq!(loaded.get_ancestors(&child.get_id()).len(), 1);
+
+        fs::write(&path, b"broken").unwrap();
+        assert!(TransactionPool::load_saved(&path).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
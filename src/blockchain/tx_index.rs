@@ -0,0 +1,179 @@
+use crate::blockchain::block::{Block, BlockHash};
+use crate::blockchain::merkle::{self, MerkleProof};
+use crate::blockchain::transaction::{Address, TransactionId};
+use crate::blockchain::utxo_set::BlockUndo;
+use std::collections::{HashMap, HashSet};
+
+/// main chain 上の transaction の位置と、address ごとの関係する transaction の索引。
+/// MerkleProof の要求のたびに chain 全体を走査しないために使う。
+/// block を main chain に追加、除去するたびに更新する。
+#[derive(Default)]
+pub struct TransactionIndex {
+    // txid -> (含まれる block の hash, block 内の位置)
+    positions: HashMap<TransactionId, (BlockHash, usize)>,
+    // main chain の block の txid の列. MerkleProof を作るのに使う
+    block_txids: HashMap<BlockHash, Vec<TransactionId>>,
+    // address -> その address 宛ての output を作る、または使う transaction (古い順)
+    addresses: HashMap<Address, Vec<TransactionId>>,
+}
+
+impl TransactionIndex {
+    pub fn new() -> TransactionIndex {
+        TransactionIndex::default()
+    }
+
+    /// main chain の末尾に追加した block を索引に加える。undo はその block を適用した際のもの。
+    pub fn add_block(&mut self, hash: &BlockHash, block: &Block, undo: &BlockUndo) {
+        let txids = block
+            .get_transactions()
+            .iter()
+            .map(|tx| tx.get_id())
+            .collect::<Vec<_>>();
+        for (idx, txid) in txids.iter().enumerate() {
+            self.positions.insert(txid.clone(), (hash.clone(), idx));
+        }
+        for (address, txid) in related_addresses(block, &txids, undo) {
+            self.addresses.entry(address).or_default().push(txid);
+        }
+        self.block_txids.insert(hash.clone(), txids);
+    }
+
+    /// main chain の末尾から除いた block を索引から除く。undo はその block を適用した際のもの。
+    pub fn remove_block(&mut self, hash: &BlockHash, block: &Block, undo: &BlockUndo) {
+        let txids = match self.block_txids.remove(hash) {
+            Some(txids) => txids,
+            None => return,
+        };
+        // 末尾の block なので、各 address の列の末尾にこの block の transaction が並んでいる
+        for (address, _) in related_addresses(block, &txids, undo).into_iter().rev() {
+            if let Some(related) = self.addresses.get_mut(&address) {
+                related.pop();
+                if related.is_empty() {
+                    self.addresses.remove(&address);
+                }
+            }
+        }
+        for txid in txids.iter() {
+            self.positions.remove(txid);
+        }
+    }
+
+    /// txid の transaction を含む block の hash と block 内の位置
+    pub fn get_position(&self, txid: &TransactionId) -> Option<(BlockHash, usize)> {
+        self.positions.get(txid).cloned()
+    }
+
+    /// txid の transaction を含む block の hash と、その Merkle root に含まれていることの証明を返す。
+    pub fn get_merkle_proof(&self, txid: &TransactionId) -> Option<(BlockHash, MerkleProof)> {
+        let (hash, idx) = self.positions.get(txid)?;
+        let proof = merkle::merkle_proof(self.block_txids.get(hash)?, *idx)?;
+        Some((hash.clone(), proof))
+    }
+
+    /// address 宛ての output を作る、または使う transaction の id を古い順に返す。
+    pub fn get_transaction_ids_for(&self, address: &Address) -> Vec<TransactionId> {
+        self.addresses.get(address).cloned().unwrap_or_default()
+    }
+}
+
+// block 内の各 transaction と、その transaction が output を作る、または使う address の組を順に返す。
+// 同じ transaction と address の組は一度だけ返す。
+fn related_addresses(
+    block: &Block,
+    txids: &[TransactionId],
+    undo: &BlockUndo,
+) -> Vec<(Address, TransactionId)> {
+    let mut spent = undo.get_spent().iter();
+    let mut res = vec![];
+    for (tx, txid) in block.get_transactions().iter().zip(txids) {
+        let mut addresses = HashSet::new();
+        let inputs = tx.get_inputs();
+        let spent_recipients = spent
+            .by_ref()
+            .take(inputs.len())
+            .map(|(_, output)| output.get_recipient());
+        let recipients = tx.get_outputs().into_iter().map(|o| o.get_recipient());
+        for address in spent_recipients.chain(recipients) {
+            if addresses.insert(address.clone()) {
+                res.push((address, txid.clone()));
+            }
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::block::BlockWithoutProof;
+    use crate::blockchain::difficulty::POW_LIMIT_BITS;
+    use crate::blockchain::transaction::{
+        CoinbaseTransaction, NormalTransaction, TransactionInput, TransactionOutput, Transactions,
+    };
+    use crate::blockchain::utxo_set::UTXOSet;
+    use chrono::Utc;
+
+    fn generate_block(
+        coinbase: CoinbaseTransaction,
+        transactions: Vec<NormalTransaction>,
+    ) -> (BlockHash, Block) {
+        let block = BlockWithoutProof::new(
+            Transactions::new(coinbase, transactions),
+            "prev".to_string(),
+            POW_LIMIT_BITS,
+        )
+        .mine()
+        .unwrap();
+        (block.get_header().get_hash(), block)
+    }
+
+    #[test]
+    fn test_add_and_remove_block() {
+        let mut utxo_set = UTXOSet::new();
+        let mut index = TransactionIndex::new();
+        let alice = "alice".to_string();
+        let bob = "bob".to_string();
+
+        let coinbase1 = CoinbaseTransaction::new(alice.clone(), 10, Utc::now());
+        let (hash1, block1) = generate_block(coinbase1.clone(), vec![]);
+        let undo1 = utxo_set.apply_block(&block1).unwrap();
+        index.add_block(&hash1, &block1, &undo1);
+
+        // alice の output を使い、bob と alice に送る
+        let tx = NormalTransaction::new(
+            vec![TransactionInput::new(coinbase1.get_id(), 0)],
+            vec![
+                TransactionOutput::new(bob.clone(), 3),
+                TransactionOutput::new(alice.clone(), 7),
+            ],
+            Utc::now(),
+        );
+        let coinbase2 = CoinbaseTransaction::new(bob.clone(), 10, Utc::now());
+        let (hash2, block2) = generate_block(coinbase2.clone(), vec![tx.clone()]);
+        let undo2 = utxo_set.apply_block(&block2).unwrap();
+        index.add_block(&hash2, &block2, &undo2);
+
+        assert_eq!(index.get_position(&tx.get_id()), Some((hash2.clone(), 1)));
+        assert_eq!(
+            index.get_transaction_ids_for(&alice),
+            vec![coinbase1.get_id(), tx.get_id()]
+        );
+        assert_eq!(
+            index.get_transaction_ids_for(&bob),
+            vec![coinbase2.get_id(), tx.get_id()]
+        );
+        let (hash, proof) = index.get_merkle_proof(&tx.get_id()).unwrap();
+        assert_eq!(hash, hash2);
+        assert!(proof.verify(&tx.get_id(), &block2.get_header().get_merkle_root()));
+
+        index.remove_block(&hash2, &block2, &undo2);
+        assert_eq!(index.get_position(&tx.get_id()), None);
+        assert!(index.get_merkle_proof(&tx.get_id()).is_none());
+        assert_eq!(
+            index.get_transaction_ids_for(&alice),
+            vec![coinbase1.get_id()]
+        );
+        assert!(index.get_transaction_ids_for(&bob).is_empty());
+        assert_eq!(index.get_position(&coinbase1.get_id()), Some((hash1, 0)));
+    }
+}
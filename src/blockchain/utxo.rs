@@ -1,21 +1,26 @@
 use crate::blockchain::transaction::{
-    Address, NormalTransaction, Transaction, TransactionInput, TransactionOutput,
+    Address, NormalTransaction, OutPoint, Transaction, TransactionId, TransactionInput,
+    TransactionOutput,
 };
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Result};
 use chrono::Utc;
+use std::collections::{HashMap, HashSet};
 
 pub struct UTXOManager {
     my_address: Address,
-    transactions: Vec<(Transaction, usize)>,
+    utxos: Vec<(OutPoint, TransactionOutput)>,
     balance: u64,
+    // 送信した replaceable な transaction と、その fee. 承認されるまで fee を上げられる
+    replaceable: HashMap<TransactionId, (NormalTransaction, u64)>,
 }
 
 impl UTXOManager {
     pub fn new(my_address: Address) -> UTXOManager {
         UTXOManager {
             my_address,
-            transactions: vec![],
+            utxos: vec![],
             balance: 0,
+            replaceable: HashMap::new(),
         }
     }
 
@@ -25,49 +30,45 @@ impl UTXOManager {
 
     /// 与えられた transaction 群から UTXO を再計算する。
     pub fn refresh_utxos(&mut self, txs: &[Transaction]) {
-        let txs = self.extract_utxos(txs);
-        self.transactions.clear();
-        for tx in txs.into_iter() {
-            self.put_utxo(tx);
+        // 承認された transaction はもう置き換えられない
+        for tx in txs.iter() {
+            self.replaceable.remove(&tx.get_id());
+        }
+        let utxos = self.extract_utxos(txs);
+        self.utxos.clear();
+        for (outpoint, output) in utxos.into_iter() {
+            self.utxos.push((outpoint, output));
         }
+        self.compute_my_balance();
     }
 
-    /// 与えられた Transaction 群の中から UTXO としてまだ利用可能なもののみを抽出する
-    fn extract_utxos(&self, txs: &[Transaction]) -> Vec<Transaction> {
-        let mut outputs = vec![];
-        let mut inputs = vec![];
+    /// 与えられた Transaction 群の中から自分宛ての output で、まだ利用可能なもののみを抽出する
+    fn extract_utxos(&self, txs: &[Transaction]) -> Vec<(OutPoint, TransactionOutput)> {
+        let spent = txs
+            .iter()
+            .flat_map(|tx| tx.get_inputs())
+            .map(|input| input.get_outpoint().clone())
+            .collect::<HashSet<_>>();
 
+        let mut utxos = vec![];
         for tx in txs.iter() {
-            for tx_out in tx.get_outputs() {
-                let recipient = tx_out.get_recipient();
-                if recipient == self.my_address {
-                    outputs.push(tx.clone());
-                }
-            }
-            for tx_in in tx.get_inputs() {
-                let recipient = tx_in.get_recipient();
-                if recipient == self.my_address {
-                    inputs.push(tx.clone());
+            let txid = tx.get_id();
+            for (idx, output) in tx.get_outputs().into_iter().enumerate() {
+                let outpoint = OutPoint::new(txid.clone(), idx);
+                if output.get_recipient() == self.my_address && !spent.contains(&outpoint) {
+                    utxos.push((outpoint, output));
                 }
             }
         }
-
-        outputs
-            .into_iter()
-            .filter(|output| {
-                !inputs
-                    .iter()
-                    .flat_map(|tx| tx.get_inputs())
-                    .any(|i| output == i.get_transaction())
-            })
-            .collect::<Vec<_>>()
+        utxos
     }
 
-    /// 与えられた transaction を UTXO として保存する。
+    /// 与えられた transaction の自分宛ての output を UTXO として保存する。
     fn put_utxo(&mut self, tx: Transaction) {
-        for (idx, output) in tx.get_outputs().iter().enumerate() {
+        let txid = tx.get_id();
+        for (idx, output) in tx.get_outputs().into_iter().enumerate() {
             if output.get_recipient() == self.my_address {
-                self.transactions.push((tx.clone(), idx));
+                self.utxos.push((OutPoint::new(txid.clone(), idx), output));
             }
         }
         self.compute_my_balance();
@@ -76,8 +77,8 @@ impl UTXOManager {
     fn compute_my_balance(&mut self) {
         let mut balance = 0;
 
-        for (tx, idx) in self.transactions.iter() {
-            balance += tx.get_output(*idx).unwrap().get_value();
+        for (_, output) in self.utxos.iter() {
+            balance += output.get_value();
         }
 
         self.balance = balance;
@@ -89,34 +90,105 @@ impl UTXOManager {
         value: u64,
         fee: u64,
     ) -> Result<NormalTransaction> {
-        if self.balance < value + fee {
+        self.build_transaction(recipient, value, fee, false)
+    }
+
+    /// 後から bump_fee で fee を上げられる transaction を作る。
+    pub fn create_replaceable_transaction_for(
+        &mut self,
+        recipient: Address,
+        value: u64,
+        fee: u64,
+    ) -> Result<NormalTransaction> {
+        let tx = self.build_transaction(recipient, value, fee, true)?;
+        self.replaceable.insert(tx.get_id(), (tx.clone(), fee));
+        Ok(tx)
+    }
+
+    /// 送信した replaceable な transaction を、同じ input で fee を上げた transaction に置き換える。
+    /// 増えた分の fee はお釣りから払う。
+    pub fn bump_fee(&mut self, txid: &TransactionId, fee: u64) -> Result<NormalTransaction> {
+        let (original, original_fee) = self
+            .replaceable
+            .get(txid)
+            .cloned()
+            .ok_or_else(|| anyhow!("transaction {} is not replaceable", txid))?;
+        if fee <= original_fee {
+            bail!("fee must be greater than {}", original_fee);
+        }
+        let increase = fee - original_fee;
+
+        // 最初の output が送金先で、2 つ目があればお釣り
+        let mut outputs = original.get_outputs();
+        let change = match outputs.get(1) {
+            Some(change) if change.get_value() >= increase => change.get_value(),
+            _ => bail!("doesn't have enough change to bump fee"),
+        };
+        outputs.pop();
+        if change > increase {
+            outputs.push(TransactionOutput::new(
+                self.my_address.clone(),
+                change - increase,
+            ));
+        }
+        let mut tx = NormalTransaction::new(original.get_inputs(), outputs, Utc::now());
+        tx.set_replaceable(true);
+
+        // 置き換えたお釣りを使えないようにする
+        self.utxos
+            .retain(|(outpoint, _)| outpoint.get_txid() != txid);
+        self.put_utxo(Transaction::Normal(tx.clone()));
+        self.replaceable.remove(txid);
+        self.replaceable.insert(tx.get_id(), (tx.clone(), fee));
+        Ok(tx)
+    }
+
+    fn build_transaction(
+        &mut self,
+        recipient: Address,
+        value: u64,
+        fee: u64,
+        replaceable: bool,
+    ) -> Result<NormalTransaction> {
+        if value == 0 {
+            bail!("value must be greater than zero");
+        }
+        let total = match value.checked_add(fee) {
+            Some(total) => total,
+            None => bail!("value and fee are too large"),
+        };
+        if self.balance < total {
             bail!("doesn't have enough coins");
         }
 
         let mut sum = 0;
-        let input_txs = self.transactions.iter().fold(vec![], |mut acc, (tx, idx)| {
-            if sum < value + fee {
-                sum += tx.get_output(*idx).unwrap().get_value();
-                acc.push(TransactionInput::new(tx.clone(), *idx));
-                acc
-            } else {
-                acc
-            }
-        });
+        let input_txs = self
+            .utxos
+            .iter()
+            .fold(vec![], |mut acc, (outpoint, output)| {
+                if sum < total {
+                    sum += output.get_value();
+                    acc.push(TransactionInput::new(
+                        outpoint.get_txid().clone(),
+                        outpoint.get_index(),
+                    ));
+                    acc
+                } else {
+                    acc
+                }
+            });
 
         let mut output_txs = vec![TransactionOutput::new(recipient, value)];
-        if sum > value + fee {
-            output_txs.push(TransactionOutput::new(
-                self.my_address.clone(),
-                sum - (value + fee),
-            ));
+        if sum > total {
+            output_txs.push(TransactionOutput::new(self.my_address.clone(), sum - total));
         }
 
         let input_len = input_txs.len();
-        let res = NormalTransaction::new(input_txs, output_txs, Utc::now());
+        let mut res = NormalTransaction::new(input_txs, output_txs, Utc::now());
+        res.set_replaceable(replaceable);
 
-        // drain used transactions
-        self.transactions = self.transactions.drain(input_len..).collect();
+        // drain used outputs
+        self.utxos = self.utxos.drain(input_len..).collect();
         self.put_utxo(Transaction::Normal(res.clone()));
 
         Ok(res)
@@ -132,6 +204,7 @@ mod tests {
     use crate::key_manager::KeyManager;
     use chrono::{Duration, Utc};
     use rand::rngs::OsRng;
+    use std::collections::HashMap;
 
     #[test]
     fn test_refresh_utxos() {
@@ -145,11 +218,7 @@ mod tests {
         let now = Utc::now();
         let sec = Duration::seconds(1);
 
-        let tx1 = Transaction::Coinbase(CoinbaseTransaction::new(
-            my_km.get_address(),
-            2,
-            now + sec * 0,
-        ));
+        let tx1 = Transaction::Coinbase(CoinbaseTransaction::new(my_km.get_address(), 2, now));
         let tx2 = Transaction::Coinbase(CoinbaseTransaction::new(
             my_km.get_address(),
             3,
@@ -162,7 +231,7 @@ mod tests {
         ));
 
         let tx4 = Transaction::Normal(NormalTransaction::new(
-            vec![TransactionInput::new(tx1.clone(), 0)],
+            vec![TransactionInput::new(tx1.get_id(), 0)],
             vec![
                 TransactionOutput::new(km1.get_address(), 1),
                 TransactionOutput::new(my_km.get_address(), 1),
@@ -188,11 +257,7 @@ mod tests {
         let now = Utc::now();
         let sec = Duration::seconds(1);
 
-        let tx1 = Transaction::Coinbase(CoinbaseTransaction::new(
-            my_km.get_address(),
-            2,
-            now + sec * 0,
-        ));
+        let tx1 = Transaction::Coinbase(CoinbaseTransaction::new(my_km.get_address(), 2, now));
         let tx2 = Transaction::Coinbase(CoinbaseTransaction::new(
             my_km.get_address(),
             3,
@@ -206,21 +271,83 @@ mod tests {
 
         let txs = vec![tx1, tx2, tx3];
         my_um.refresh_utxos(&txs);
+        let index = txs
+            .iter()
+            .map(|tx| (tx.get_id(), tx.clone()))
+            .collect::<HashMap<_, _>>();
 
         let tx = my_um
             .create_transaction_for(km1.get_address(), 1, 1)
             .unwrap();
 
-        assert_eq!(tx.get_input_value(), 2);
-        assert_eq!(tx.get_output_value(), 1);
+        assert_eq!(tx.get_input_value(&index).unwrap(), 2);
+        assert_eq!(tx.get_output_value().unwrap(), 1);
         assert_eq!(my_um.get_balance(), 7);
+        let mut index = index;
+        index.insert(tx.get_id(), Transaction::Normal(tx.clone()));
 
         let tx = my_um
             .create_transaction_for(km1.get_address(), 3, 1)
             .unwrap();
 
-        assert_eq!(tx.get_input_value(), 7);
-        assert_eq!(tx.get_output_value(), 6);
+        assert_eq!(tx.get_input_value(&index).unwrap(), 7);
+        assert_eq!(tx.get_output_value().unwrap(), 6);
         assert_eq!(my_um.get_balance(), 3);
     }
+
+    #[test]
+    fn test_bump_fee() {
+        let rng = OsRng;
+
+        let my_km = KeyManager::new(rng).unwrap();
+        let mut my_um = UTXOManager::new(my_km.get_address());
+
+        let km1 = KeyManager::new(rng).unwrap();
+
+        let coinbase = Transaction::Coinbase(CoinbaseTransaction::new(
+            my_km.get_address(),
+            10,
+            Utc::now(),
+        ));
+        let txs = vec![coinbase.clone()];
+        my_um.refresh_utxos(&txs);
+        let index = vec![(coinbase.get_id(), coinbase.clone())]
+            .into_iter()
+            .collect::<HashMap<_, _>>();
+
+        let fixed = my_um
+            .create_transaction_for(km1.get_address(), 1, 1)
+            .unwrap();
+        assert!(!fixed.is_replaceable());
+        assert!(my_um.bump_fee(&fixed.get_id(), 2).is_err());
+        my_um.refresh_utxos(&txs);
+
+        let tx = my_um
+            .create_replaceable_transaction_for(km1.get_address(), 3, 1)
+            .unwrap();
+        assert!(tx.is_replaceable());
+        assert_eq!(my_um.get_balance(), 6);
+        assert!(my_um.bump_fee(&tx.get_id(), 1).is_err());
+
+        let bumped = my_um.bump_fee(&tx.get_id(), 4).unwrap();
+        assert!(bumped.is_replaceable());
+        assert_eq!(bumped.get_inputs(), tx.get_inputs());
+        assert_eq!(bumped.get_fee(&index).unwrap(), 4);
+        assert_eq!(my_um.get_balance(), 3);
+        assert!(my_um.bump_fee(&tx.get_id(), 5).is_err());
+
+        // お釣りを使い切る場合はお釣りの output を無くす
+        let bumped = my_um.bump_fee(&bumped.get_id(), 7).unwrap();
+        assert_eq!(bumped.get_outputs().len(), 1);
+        assert_eq!(my_um.get_balance(), 0);
+        assert!(my_um.bump_fee(&bumped.get_id(), 8).is_err());
+
+        // 承認されたものは置き換えない
+        my_um.refresh_utxos(&txs);
+        let tx = my_um
+            .create_replaceable_transaction_for(km1.get_address(), 3, 1)
+            .unwrap();
+        my_um.refresh_utxos(&[coinbase, Transaction::Normal(tx.clone())]);
+        assert!(my_um.bump_fee(&tx.get_id(), 2).is_err());
+    }
 }
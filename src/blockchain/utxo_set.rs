@@ -0,0 +1,205 @@
+use crate::blockchain::block::Block;
+use crate::blockchain::transaction::{Address, OutPoint, OutputResolver, TransactionOutput};
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+
+/// main chain 上でまだ使われていない output 全体の集合。
+/// block を適用するたびに更新し、巻き戻しに必要な情報を BlockUndo として返す。
+#[derive(Default)]
+pub struct UTXOSet {
+    utxos: HashMap<OutPoint, TransactionOutput>,
+    balances: HashMap<Address, u64>,
+}
+
+/// block の適用を取り消すための情報
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BlockUndo {
+    // block 内の transaction が使用した output
+    spent: Vec<(OutPoint, TransactionOutput)>,
+    // block 内の transaction が作成した output
+    created: Vec<OutPoint>,
+}
+
+impl BlockUndo {
+    /// block 内の transaction が使用した output を input の順に返す。
+    pub fn get_spent(&self) -> &[(OutPoint, TransactionOutput)] {
+        &self.spent
+    }
+}
+
+impl UTXOSet {
+    pub fn new() -> UTXOSet {
+        UTXOSet {
+            utxos: HashMap::new(),
+            balances: HashMap::new(),
+        }
+    }
+
+    pub fn get(&self, outpoint: &OutPoint) -> Option<&TransactionOutput> {
+        self.utxos.get(outpoint)
+    }
+
+    pub fn contains(&self, outpoint: &OutPoint) -> bool {
+        self.utxos.contains_key(outpoint)
+    }
+
+    pub fn len(&self) -> usize {
+        self.utxos.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.utxos.is_empty()
+    }
+
+    pub fn get_balance(&self, address: &Address) -> u64 {
+        self.balances.get(address).cloned().unwrap_or(0)
+    }
+
+    pub fn get_utxos_for(&self, address: &Address) -> Vec<(OutPoint, TransactionOutput)> {
+        self.utxos
+            .iter()
+            .filter(|(_, output)| &output.get_recipient() == address)
+            .map(|(outpoint, output)| (outpoint.clone(), output.clone()))
+            .collect()
+    }
+
+    /// block 内の transaction を先頭から順に適用する。
+    /// 存在しない output を使おうとした場合や、同じ output を作ろうとした場合は
+    /// それまでの変更を戻してエラーを返す。
+    pub fn apply_block(&mut self, block: &Block) -> Result<BlockUndo> {
+        let mut undo = BlockUndo::default();
+        for tx in block.get_transactions() {
+            for input in tx.get_inputs() {
+                let outpoint = input.get_outpoint().clone();
+                match self.remove(&outpoint) {
+                    Some(output) => undo.spent.push((outpoint, output)),
+                    None => {
+                        self.rollback_block(undo);
+                        return Err(anyhow!(
+                            "Output {:?} doesn't exist or is already spent",
+                            outpoint
+                        ));
+                    }
+                }
+            }
+
+            let txid = tx.get_id();
+            for (idx, output) in tx.get_outputs().into_iter().enumerate() {
+                let outpoint = OutPoint::new(txid.clone(), idx);
+                if self.contains(&outpoint) {
+                    self.rollback_block(undo);
+                    return Err(anyhow!("Output {:?} already exists", outpoint));
+                }
+                self.insert(outpoint.clone(), output);
+                undo.created.push(outpoint);
+            }
+        }
+        Ok(undo)
+    }
+
+    /// apply_block で適用した block を取り消す。
+    pub fn rollback_block(&mut self, undo: BlockUndo) {
+        for outpoint in undo.created.iter().rev() {
+            self.remove(outpoint);
+        }
+        for (outpoint, output) in undo.spent.into_iter().rev() {
+            self.insert(outpoint, output);
+        }
+    }
+
+    fn insert(&mut self, outpoint: OutPoint, output: TransactionOutput) {
+        *self.balances.entry(output.get_recipient()).or_insert(0) += output.get_value();
+        self.utxos.insert(outpoint, output);
+    }
+
+    fn remove(&mut self, outpoint: &OutPoint) -> Option<TransactionOutput> {
+        let output = self.utxos.remove(outpoint)?;
+        let recipient = output.get_recipient();
+        if let Some(balance) = self.balances.get_mut(&recipient) {
+            *balance -= output.get_value();
+            if *balance == 0 {
+                self.balances.remove(&recipient);
+            }
+        }
+        Some(output)
+    }
+}
+
+impl OutputResolver for UTXOSet {
+    fn resolve_output(&self, outpoint: &OutPoint) -> Option<TransactionOutput> {
+        self.get(outpoint).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::block::BlockWithoutProof;
+    use crate::blockchain::difficulty::POW_LIMIT_BITS;
+    use crate::blockchain::transaction::{
+        CoinbaseTransaction, NormalTransaction, TransactionInput, Transactions,
+    };
+    use chrono::Utc;
+
+    fn generate_block(
+        coinbase: CoinbaseTransaction,
+        transactions: Vec<NormalTransaction>,
+    ) -> Block {
+        BlockWithoutProof::new(
+            Transactions::new(coinbase, transactions),
+            "prev".to_string(),
+            POW_LIMIT_BITS,
+        )
+        .mine()
+        .unwrap()
+    }
+
+    #[test]
+    fn test_apply_and_rollback_block() {
+        let mut utxo_set = UTXOSet::new();
+
+        let coinbase1 = CoinbaseTransaction::new("alice".to_string(), 10, Utc::now());
+        let block1 = generate_block(coinbase1.clone(), vec![]);
+        let undo1 = utxo_set.apply_block(&block1).unwrap();
+        assert_eq!(utxo_set.get_balance(&"alice".to_string()), 10);
+
+        let coinbase2 = CoinbaseTransaction::new("bob".to_string(), 10, Utc::now());
+        let tx = NormalTransaction::new(
+            vec![TransactionInput::new(coinbase1.get_id(), 0)],
+            vec![
+                TransactionOutput::new("carol".to_string(), 3),
+                TransactionOutput::new("alice".to_string(), 7),
+            ],
+            Utc::now(),
+        );
+        let block2 = generate_block(coinbase2, vec![tx.clone()]);
+        let undo2 = utxo_set.apply_block(&block2).unwrap();
+
+        assert_eq!(utxo_set.len(), 3);
+        assert!(!utxo_set.contains(&OutPoint::new(coinbase1.get_id(), 0)));
+        assert!(utxo_set.contains(&OutPoint::new(tx.get_id(), 1)));
+        assert_eq!(utxo_set.get_balance(&"alice".to_string()), 7);
+        assert_eq!(utxo_set.get_balance(&"bob".to_string()), 10);
+        assert_eq!(utxo_set.get_balance(&"carol".to_string()), 3);
+        assert_eq!(
+            utxo_set.get_utxos_for(&"carol".to_string()),
+            vec![(
+                OutPoint::new(tx.get_id(), 0),
+                TransactionOutput::new("carol".to_string(), 3)
+            )]
+        );
+
+        // the same block can't be applied twice
+        assert!(utxo_set.apply_block(&block2).is_err());
+        assert_eq!(utxo_set.len(), 3);
+        assert_eq!(utxo_set.get_balance(&"bob".to_string()), 10);
+
+        utxo_set.rollback_block(undo2);
+        assert_eq!(utxo_set.len(), 1);
+        assert_eq!(utxo_set.get_balance(&"alice".to_string()), 10);
+        assert_eq!(utxo_set.get_balance(&"carol".to_string()), 0);
+
+        utxo_set.rollback_block(undo1);
+        assert!(utxo_set.is_empty());
+    }
+}
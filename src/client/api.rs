@@ -1,30 +1,40 @@
+use crate::client_core::VerifiedTransactions;
 use crate::ClientCore;
 use actix_web::{get, post, web, HttpResponse, Responder};
 use log::warn;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use simple_bitcoin::blockchain::transaction::Address;
+use simple_bitcoin::blockchain::block::BlockHash;
+use simple_bitcoin::blockchain::header_chain::HeaderChain;
+use simple_bitcoin::blockchain::transaction::{Address, NormalTransaction, TransactionId};
 use simple_bitcoin::blockchain::utxo::UTXOManager;
 use simple_bitcoin::key_manager::KeyManager;
 use simple_bitcoin::message::ApplicationPayload;
 use std::sync::{Arc, Mutex};
+use tokio::sync::Mutex as AsyncMutex;
 
 pub struct AppState {
-    core: Arc<Mutex<ClientCore>>,
-    key_manager: Mutex<KeyManager>,
+    core: Arc<AsyncMutex<ClientCore>>,
+    key_manager: Arc<Mutex<KeyManager>>,
     utxo_manager: Arc<Mutex<UTXOManager>>,
+    header_chain: Arc<Mutex<HeaderChain>>,
+    verified_transactions: Arc<Mutex<VerifiedTransactions>>,
 }
 
 impl AppState {
     pub fn new(
-        core: Arc<Mutex<ClientCore>>,
-        key_manager: Mutex<KeyManager>,
+        core: Arc<AsyncMutex<ClientCore>>,
+        key_manager: Arc<Mutex<KeyManager>>,
         utxo_manager: Arc<Mutex<UTXOManager>>,
+        header_chain: Arc<Mutex<HeaderChain>>,
+        verified_transactions: Arc<Mutex<VerifiedTransactions>>,
     ) -> AppState {
         AppState {
             core,
             key_manager,
             utxo_manager,
+            header_chain,
+            verified_transactions,
         }
     }
 }
@@ -65,9 +75,7 @@ async fn get_my_address(state: web::Data<AppState>) -> impl Responder {
 
 #[post("/update-balance")]
 async fn request_update_balance(state: web::Data<AppState>) -> impl Responder {
-    let payload = ApplicationPayload::RequestFullChain;
-    let core = state.core.lock().unwrap();
-    core.send_msg_to_core(payload).await;
+    state.core.lock().await.sync_chain().await;
     HttpResponse::Ok()
 }
 
@@ -78,7 +86,7 @@ async fn generate_block(state: web::Data<AppState>) -> impl Responder {
     let payload = ApplicationPayload::Enhanced {
         data: addr.as_bytes().to_owned(),
     };
-    state.core.lock().unwrap().send_msg_to_core(payload).await;
+    state.core.lock().await.send_msg_to_core(payload).await;
     HttpResponse::Ok()
 }
 
@@ -87,6 +95,14 @@ struct PostTransactionRequest {
     recipient: String,
     value: u64,
     fee: u64,
+    // true の場合、承認されるまで POST /transaction/{txid}/bump で fee を上げられる
+    #[serde(default)]
+    replaceable: bool,
+}
+
+#[derive(Deserialize, Serialize)]
+struct PostTransactionResponse {
+    txid: TransactionId,
 }
 
 #[post("/transaction")]
@@ -94,20 +110,108 @@ async fn post_transaction(
     req: web::Json<PostTransactionRequest>,
     state: web::Data<AppState>,
 ) -> impl Responder {
-    let mut utxo_manager = state.utxo_manager.lock().unwrap();
-
-    let tx = match utxo_manager.create_transaction_for(req.recipient.clone(), req.value, req.fee) {
+    let result = {
+        let mut utxo_manager = state.utxo_manager.lock().unwrap();
+        if req.replaceable {
+            utxo_manager.create_replaceable_transaction_for(
+                req.recipient.clone(),
+                req.value,
+                req.fee,
+            )
+        } else {
+            utxo_manager.create_transaction_for(req.recipient.clone(), req.value, req.fee)
+        }
+    };
+    let tx = match result {
         Ok(tx) => tx,
         Err(err) => {
             warn!("post_transaction failed: {:?}", err);
             return HttpResponse::BadRequest().json(json!({"error": "Failed to process request."}));
         }
     };
+    send_transaction(tx, &state).await
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct BumpFeeRequest {
+    fee: u64,
+}
 
+// 未承認の replaceable な transaction を、fee を上げた transaction で置き換える
+#[post("/transaction/{txid}/bump")]
+async fn bump_fee(
+    txid: web::Path<TransactionId>,
+    req: web::Json<BumpFeeRequest>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let result = state
+        .utxo_manager
+        .lock()
+        .unwrap()
+        .bump_fee(&txid.into_inner(), req.fee);
+    let tx = match result {
+        Ok(tx) => tx,
+        Err(err) => {
+            warn!("bump_fee failed: {:?}", err);
+            return HttpResponse::BadRequest().json(json!({"error": "Failed to process request."}));
+        }
+    };
+    send_transaction(tx, &state).await
+}
+
+// 署名して core ノードに送り、transaction id を返す
+async fn send_transaction(tx: NormalTransaction, state: &web::Data<AppState>) -> HttpResponse {
+    let txid = tx.get_id();
     let payload =
         ApplicationPayload::for_transaction(tx, &mut state.key_manager.lock().unwrap()).unwrap();
-    state.core.lock().unwrap().send_msg_to_core(payload).await;
-    HttpResponse::Created().finish()
+    state.core.lock().await.send_msg_to_core(payload).await;
+    HttpResponse::Created().json(PostTransactionResponse { txid })
+}
+
+// Core ノードに MerkleProof を要求する。結果は GET /transaction/{txid}/confirmations で確認する
+#[post("/transaction/{txid}/verify")]
+async fn request_verify_transaction(
+    txid: web::Path<TransactionId>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    state
+        .core
+        .lock()
+        .await
+        .request_merkle_proof(txid.into_inner())
+        .await;
+    HttpResponse::Accepted().finish()
+}
+
+#[derive(Deserialize, Serialize)]
+struct GetConfirmationsResponse {
+    txid: TransactionId,
+    block_hash: Option<BlockHash>,
+    confirmations: usize,
+}
+
+#[get("/transaction/{txid}/confirmations")]
+async fn get_transaction_confirmations(
+    txid: web::Path<TransactionId>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let txid = txid.into_inner();
+    let block_hash = state
+        .verified_transactions
+        .lock()
+        .unwrap()
+        .get(&txid)
+        .cloned();
+    // main chain の切り替えで外れた block は承認されていないものとする
+    let confirmations = block_hash
+        .as_ref()
+        .and_then(|hash| state.header_chain.lock().unwrap().get_confirmations(hash))
+        .unwrap_or(0);
+    web::Json(GetConfirmationsResponse {
+        txid,
+        block_hash,
+        confirmations,
+    })
 }
 
 pub fn config(cfg: &mut web::ServiceConfig) {
@@ -115,5 +219,8 @@ pub fn config(cfg: &mut web::ServiceConfig) {
         .service(get_my_address)
         .service(request_update_balance)
         .service(generate_block)
-        .service(post_transaction);
+        .service(post_transaction)
+        .service(bump_fee)
+        .service(request_verify_transaction)
+        .service(get_transaction_confirmations);
 }
@@ -1,8 +1,12 @@
-use log::{debug, info};
-use simple_bitcoin::blockchain::transaction::Transaction;
+use log::{debug, info, warn};
+use simple_bitcoin::blockchain::block::BlockHash;
+use simple_bitcoin::blockchain::header_chain::HeaderChain;
+use simple_bitcoin::blockchain::transaction::{Address, TransactionId};
 use simple_bitcoin::blockchain::utxo::UTXOManager;
 use simple_bitcoin::connection_manager_edge::{ApplicationPayloadHandler, ConnectionManagerEdge};
-use simple_bitcoin::message::ApplicationPayload;
+use simple_bitcoin::key_manager::KeyManager;
+use simple_bitcoin::message::{ApplicationPayload, MAX_HEADERS_PER_MESSAGE};
+use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
 
@@ -16,23 +20,82 @@ pub enum ClientCoreState {
 pub struct ClientCore {
     state: ClientCoreState,
     cm: ConnectionManagerEdge,
+    header_chain: Arc<Mutex<HeaderChain>>,
 }
 
+/// MerkleProof で確認できた transaction と、それを含む block の hash
+pub type VerifiedTransactions = HashMap<TransactionId, BlockHash>;
+
 fn generate_application_payload_handler(
+    my_address: Address,
     utxo_manager: Arc<Mutex<UTXOManager>>,
+    header_chain: Arc<Mutex<HeaderChain>>,
+    verified_transactions: Arc<Mutex<VerifiedTransactions>>,
 ) -> impl ApplicationPayloadHandler {
     move |payload: ApplicationPayload| {
         debug!("handle_application_payload: {:?}", payload);
 
         match payload {
-            ApplicationPayload::FullChain { chain } => {
-                let txs: Vec<Transaction> = chain
-                    .into_iter()
-                    .flat_map(|block| block.get_transactions())
-                    .collect();
+            ApplicationPayload::Headers { headers } => {
+                let mut header_chain = header_chain.lock().unwrap();
+                if let Err(err) = header_chain.add_headers(&headers) {
+                    warn!("Failed to add headers to header chain: {:?}", err);
+                    return None;
+                }
+                // 上限まで届いた場合は続きの header があるかもしれない
+                if headers.len() >= MAX_HEADERS_PER_MESSAGE {
+                    return Some(ApplicationPayload::GetHeaders {
+                        locator: header_chain.get_block_locator(),
+                    });
+                }
+                // header chain が追いついたので、自分に関係する transaction を proof 付きで要求する
+                Some(ApplicationPayload::GetAddressTransactions {
+                    address: my_address.clone(),
+                })
+            }
+            ApplicationPayload::AddressTransactions { transactions } => {
+                let header_chain = header_chain.lock().unwrap();
+                let mut verified_transactions = verified_transactions.lock().unwrap();
+                let mut txs = vec![];
+                // header chain に対して proof を確認できた transaction だけから UTXO を求める
+                for (tx, block_hash, proof) in transactions {
+                    let txid = tx.get_id();
+                    match header_chain.verify_merkle_proof(&block_hash, &txid, &proof) {
+                        Ok(_) => {
+                            verified_transactions.insert(txid, block_hash);
+                            txs.push(tx);
+                        }
+                        Err(err) => warn!("Invalid merkle proof of {}: {:?}", txid, err),
+                    }
+                }
                 utxo_manager.lock().unwrap().refresh_utxos(&txs);
+                None
+            }
+            ApplicationPayload::MerkleProof {
+                txid,
+                block_hash,
+                proof,
+            } => {
+                match header_chain
+                    .lock()
+                    .unwrap()
+                    .verify_merkle_proof(&block_hash, &txid, &proof)
+                {
+                    Ok(confirmations) => {
+                        info!(
+                            "Transaction {} is included in block {} ({} confirmations)",
+                            txid, block_hash, confirmations
+                        );
+                        verified_transactions
+                            .lock()
+                            .unwrap()
+                            .insert(txid, block_hash);
+                    }
+                    Err(err) => warn!("Invalid merkle proof: {:?}", err),
+                }
+                None
             }
-            _ => {}
+            _ => None,
         }
     }
 }
@@ -41,7 +104,10 @@ impl ClientCore {
     pub fn new(
         my_addr: SocketAddr,
         core_node_addr: SocketAddr,
+        my_address: Address,
         utxo_manager: Arc<Mutex<UTXOManager>>,
+        header_chain: Arc<Mutex<HeaderChain>>,
+        verified_transactions: Arc<Mutex<VerifiedTransactions>>,
     ) -> ClientCore {
         info!("Initializing ClientCore");
         ClientCore {
@@ -49,15 +115,46 @@ impl ClientCore {
             cm: ConnectionManagerEdge::new(
                 my_addr,
                 core_node_addr,
-                generate_application_payload_handler(utxo_manager),
+                generate_application_payload_handler(
+                    my_address,
+                    utxo_manager,
+                    Arc::clone(&header_chain),
+                    verified_transactions,
+                ),
+                {
+                    let header_chain = Arc::clone(&header_chain);
+                    move || header_chain.lock().unwrap().len()
+                },
             ),
+            header_chain,
         }
     }
 
+    /// Core ノードとの接続を暗号化し、鍵で相手を認証する。start の前に呼び出す。
+    pub fn enable_secure_transport(&mut self, key_manager: Arc<Mutex<KeyManager>>) {
+        self.cm.enable_secure_transport(key_manager);
+    }
+
     pub async fn start(&mut self) {
         self.state = ClientCoreState::Active;
         self.cm.start().await;
         self.cm.join_network().await;
+        self.sync_chain().await;
+    }
+
+    /// Core ノードに手元の header chain より後の header を要求し、差分だけを同期する。
+    /// block の body は受け取らず、追いついた後に自分に関係する transaction を MerkleProof 付きで受け取る。
+    pub async fn sync_chain(&self) {
+        let locator = self.header_chain.lock().unwrap().get_block_locator();
+        self.send_msg_to_core(ApplicationPayload::GetHeaders { locator })
+            .await;
+    }
+
+    /// Core ノードに transaction の MerkleProof を要求する。
+    /// 受け取った proof は header chain に対して検証し、正しければ VerifiedTransactions に記録する。
+    pub async fn request_merkle_proof(&self, txid: TransactionId) {
+        self.send_msg_to_core(ApplicationPayload::GetMerkleProof { txid })
+            .await;
     }
 
     pub async fn shutdown(&mut self) {
@@ -8,10 +8,15 @@ use log::{debug, info};
 use rand::rngs::OsRng;
 use signal_hook::consts::signal::*;
 use signal_hook_tokio::Signals;
+use simple_bitcoin::blockchain::difficulty::INITIAL_BITS;
+use simple_bitcoin::blockchain::header_chain::HeaderChain;
 use simple_bitcoin::blockchain::utxo::UTXOManager;
-use simple_bitcoin::key_manager::KeyManager;
+use simple_bitcoin::key_manager::{KeyManager, KEY_PASSPHRASE_ENV};
+use std::collections::HashMap;
 use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
+use tokio::sync::Mutex as AsyncMutex;
 
 mod api;
 pub mod client_core;
@@ -26,14 +31,20 @@ struct Args {
     core_addr: String,
     #[clap(short, long)]
     api_addr: String,
+    /// PEM file of the private key. It is created on first run.
+    /// The passphrase can be given by SIMPLE_BITCOIN_KEY_PASSPHRASE.
+    #[clap(short, long)]
+    key_file: Option<PathBuf>,
+    /// Encrypt connections and authenticate nodes with their keys.
+    /// The Core node must enable it too.
+    #[clap(long)]
+    secure_transport: bool,
 }
 
 async fn handle_signals(mut signals: Signals) {
-    while let Some(signal) = signals.next().await {
+    if let Some(signal) = signals.next().await {
         match signal {
-            SIGTERM | SIGINT | SIGQUIT => {
-                break;
-            }
+            SIGTERM | SIGINT | SIGQUIT => {}
             _ => unreachable!(),
         }
     }
@@ -49,7 +60,7 @@ fn convert_to_addr(s: String) -> Result<SocketAddr> {
 async fn main() -> Result<()> {
     env_logger::init();
 
-    let signals = Signals::new(&[SIGTERM, SIGINT, SIGQUIT])?;
+    let signals = Signals::new([SIGTERM, SIGINT, SIGQUIT])?;
     let handle = signals.handle();
     let signal_task = tokio::spawn(handle_signals(signals));
 
@@ -59,24 +70,43 @@ async fn main() -> Result<()> {
     let api_addr = convert_to_addr(args.api_addr)?;
 
     let rng = OsRng;
-    let key_manager = Mutex::new(KeyManager::new(rng).unwrap());
+    let key_manager = match args.key_file {
+        Some(key_file) => {
+            let passphrase = std::env::var(KEY_PASSPHRASE_ENV).ok();
+            KeyManager::load_or_create(key_file, passphrase.as_deref(), rng)?
+        }
+        None => KeyManager::new(rng)?,
+    };
+    let key_manager = Arc::new(Mutex::new(key_manager));
     debug!("my address: {}", key_manager.lock().unwrap().get_address());
-    let utxo_manager = Arc::new(Mutex::new(UTXOManager::new(
-        key_manager.lock().unwrap().get_address(),
-    )));
+    let my_address = key_manager.lock().unwrap().get_address();
+    let utxo_manager = Arc::new(Mutex::new(UTXOManager::new(my_address.clone())));
 
-    let core = Arc::new(Mutex::new(ClientCore::new(
+    let header_chain = Arc::new(Mutex::new(HeaderChain::new(INITIAL_BITS)));
+    let verified_transactions = Arc::new(Mutex::new(HashMap::new()));
+
+    let core = Arc::new(AsyncMutex::new(ClientCore::new(
         listen_addr,
         core_addr,
+        my_address,
         Arc::clone(&utxo_manager),
+        Arc::clone(&header_chain),
+        Arc::clone(&verified_transactions),
     )));
-    core.lock().unwrap().start().await;
+    if args.secure_transport {
+        core.lock()
+            .await
+            .enable_secure_transport(Arc::clone(&key_manager));
+    }
+    core.lock().await.start().await;
 
     info!("api binds at {}", api_addr);
     let app_data = web::Data::new(api::AppState::new(
         Arc::clone(&core),
         key_manager,
         Arc::clone(&utxo_manager),
+        header_chain,
+        verified_transactions,
     ));
     HttpServer::new(move || {
         // loose condition just for development
@@ -95,7 +125,7 @@ async fn main() -> Result<()> {
     info!("Stop client");
     handle.close();
 
-    core.lock().unwrap().shutdown().await;
+    core.lock().await.shutdown().await;
 
     Ok(())
 }
@@ -0,0 +1,734 @@
+use crate::blockchain::transaction::Address;
+use crate::key_manager::KeyManager;
+use crate::message::{
+    Message, Payload, Services, MIN_PROTOCOL_VERSION, PROTOCOL_NAME, PROTOCOL_VERSION,
+};
+use crate::secure_channel::{CipherState, HandshakeState, SessionKeys};
+use crate::util;
+use anyhow::{bail, Context, Result};
+use std::cmp;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+/// frame の先頭に置く magic number
+pub const MAGIC: [u8; 4] = *b"SBTC";
+/// frame の header の長さ (magic 4 byte, payload の長さ 4 byte, checksum 4 byte)
+pub const FRAME_HEADER_LEN: usize = 12;
+/// 1 つの frame で送れる payload の最大 byte 数
+pub const MAX_PAYLOAD_LEN: usize = 32 * 1024 * 1024;
+// payload を読み込む際に最初に確保する byte 数
+const READ_CHUNK_LEN: usize = 64 * 1024;
+/// 次の frame が届くまで待つ時間. これを超えて何も届かない接続は閉じる。
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+/// frame の header を受け取ってから payload を受け取り終えるまでの時間
+pub const FRAME_TIMEOUT: Duration = Duration::from_secs(30);
+/// 接続と送信にかける時間
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+pub const WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+/// 接続してから handshake を終えるまでの時間
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// 相手から受け取った frame が不正な場合のエラー. 通信の失敗と区別するために使う。
+#[derive(Debug, PartialEq, Eq)]
+pub enum FrameError {
+    /// magic number が異なる
+    InvalidMagic([u8; 4]),
+    /// payload が大きすぎる
+    TooLarge(usize),
+    /// checksum が一致しない
+    ChecksumMismatch,
+    /// 暗号化された frame を復号できない
+    Undecryptable,
+    /// payload を Message として解釈できない
+    InvalidMessage(String),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::InvalidMagic(magic) => write!(f, "Invalid magic number: {:02x?}", magic),
+            FrameError::TooLarge(len) => write!(
+                f,
+                "Payload is too large: {} bytes (max {} bytes)",
+                len, MAX_PAYLOAD_LEN
+            ),
+            FrameError::ChecksumMismatch => write!(f, "Checksum mismatch"),
+            FrameError::Undecryptable => {
+                write!(f, "Failed to decrypt frame (tampered or out of order)")
+            }
+            FrameError::InvalidMessage(reason) => write!(f, "Invalid message: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+/// payload の sha256 の先頭 4 byte
+fn checksum(payload: &[u8]) -> [u8; 4] {
+    let hash = util::calc_hash(payload);
+    [hash[0], hash[1], hash[2], hash[3]]
+}
+
+/// payload に header を付けて frame にする。
+pub fn encode_frame(payload: &[u8]) -> Result<Vec<u8>> {
+    if payload.len() > MAX_PAYLOAD_LEN {
+        bail!(
+            "Payload is too large: {} bytes (max {} bytes)",
+            payload.len(),
+            MAX_PAYLOAD_LEN
+        );
+    }
+    let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+    frame.extend(MAGIC);
+    frame.extend((payload.len() as u32).to_be_bytes());
+    frame.extend(checksum(payload));
+    frame.extend(payload);
+    Ok(frame)
+}
+
+/// frame を 1 つ読んで payload を返す。frame の境界で接続が閉じられた場合は None.
+pub async fn read_frame<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    idle_timeout: Duration,
+) -> Result<Option<Vec<u8>>> {
+    let mut header = [0u8; FRAME_HEADER_LEN];
+    let first = timeout(idle_timeout, reader.read(&mut header[..1]))
+        .await
+        .context("Connection is idle for too long")??;
+    if first == 0 {
+        return Ok(None);
+    }
+
+    timeout(FRAME_TIMEOUT, async {
+        reader.read_exact(&mut header[1..]).await?;
+        if header[..4] != MAGIC {
+            let mut magic = [0u8; 4];
+            magic.copy_from_slice(&header[..4]);
+            bail!(FrameError::InvalidMagic(magic));
+        }
+        let mut len = [0u8; 4];
+        len.copy_from_slice(&header[4..8]);
+        let len = u32::from_be_bytes(len) as usize;
+        if len > MAX_PAYLOAD_LEN {
+            bail!(FrameError::TooLarge(len));
+        }
+        // 長さは相手の申告なので先に確保せず、届いた分だけ読み込みながら広げる
+        let mut payload = Vec::with_capacity(cmp::min(len, READ_CHUNK_LEN));
+        (&mut *reader)
+            .take(len as u64)
+            .read_to_end(&mut payload)
+            .await?;
+        if payload.len() < len {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        if header[8..] != checksum(&payload) {
+            bail!(FrameError::ChecksumMismatch);
+        }
+        Ok(Some(payload))
+    })
+    .await
+    .context("Timed out while reading a frame")?
+}
+
+pub async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, payload: &[u8]) -> Result<()> {
+    let frame = encode_frame(payload)?;
+    timeout(WRITE_TIMEOUT, async {
+        writer.write_all(&frame).await?;
+        writer.flush().await
+    })
+    .await
+    .context("Timed out while writing a frame")??;
+    Ok(())
+}
+
+/// 接続から Message を読み出す側
+pub struct MessageReader {
+    inner: OwnedReadHalf,
+    // secure_handshake の後は frame を復号する
+    cipher: Option<CipherState>,
+}
+
+impl MessageReader {
+    async fn read_payload(&mut self, idle_timeout: Duration) -> Result<Option<Vec<u8>>> {
+        let payload = match read_frame(&mut self.inner, idle_timeout).await? {
+            Some(payload) => payload,
+            None => return Ok(None),
+        };
+        match self.cipher.as_mut() {
+            Some(cipher) => match cipher.decrypt(&payload) {
+                Ok(payload) => Ok(Some(payload)),
+                Err(_) => bail!(FrameError::Undecryptable),
+            },
+            None => Ok(Some(payload)),
+        }
+    }
+
+    /// 次の Message を読む。相手が接続を閉じた場合は None.
+    pub async fn read_message(&mut self) -> Result<Option<Message>> {
+        match self.read_payload(IDLE_TIMEOUT).await? {
+            Some(payload) => match serde_json::from_slice(&payload) {
+                Ok(message) => Ok(Some(message)),
+                Err(err) => bail!(FrameError::InvalidMessage(err.to_string())),
+            },
+            None => Ok(None),
+        }
+    }
+}
+
+/// 接続に Message を書き込む側
+pub struct MessageWriter {
+    inner: OwnedWriteHalf,
+    // secure_handshake の後は frame を暗号化する
+    cipher: Option<CipherState>,
+}
+
+impl MessageWriter {
+    async fn write_payload(&mut self, payload: &[u8]) -> Result<()> {
+        match self.cipher.as_mut() {
+            Some(cipher) => {
+                let encrypted = cipher.encrypt(payload)?;
+                write_frame(&mut self.inner, &encrypted).await
+            }
+            None => write_frame(&mut self.inner, payload).await,
+        }
+    }
+
+    pub async fn write_message(&mut self, msg: &Message) -> Result<()> {
+        let payload = serde_json::to_vec(msg)?;
+        self.write_payload(&payload).await
+    }
+
+    /// 複数の task から書き込めるようにする。
+    pub fn into_shared(self) -> SharedWriter {
+        Arc::new(tokio::sync::Mutex::new(self))
+    }
+}
+
+/// 接続を読み出し側と書き込み側に分ける。
+pub fn split(stream: TcpStream) -> (MessageReader, MessageWriter) {
+    let (reader, writer) = stream.into_split();
+    (
+        MessageReader {
+            inner: reader,
+            cipher: None,
+        },
+        MessageWriter {
+            inner: writer,
+            cipher: None,
+        },
+    )
+}
+
+pub async fn connect(addr: &SocketAddr) -> Result<(MessageReader, MessageWriter)> {
+    let stream = timeout(CONNECT_TIMEOUT, TcpStream::connect(addr))
+        .await
+        .with_context(|| format!("Timed out while connecting to {}", addr))??;
+    Ok(split(stream))
+}
+
+pub type SharedWriter = Arc<tokio::sync::Mutex<MessageWriter>>;
+
+/// 一時的な DH 鍵を交換して以降の frame を暗号化し、node の鍵による署名で相手を認証する。
+/// Version の handshake より前に行う。認証できた相手の address (公開鍵) を返す。
+pub async fn secure_handshake(
+    reader: &mut MessageReader,
+    writer: &mut MessageWriter,
+    key_manager: &Mutex<KeyManager>,
+    initiator: bool,
+) -> Result<Address> {
+    timeout(HANDSHAKE_TIMEOUT, async {
+        let state = HandshakeState::new(initiator);
+        writer.write_payload(&state.get_public_key()).await?;
+        let remote_public = match reader.read_payload(HANDSHAKE_TIMEOUT).await? {
+            Some(payload) => payload,
+            None => bail!("Connection was closed during secure handshake"),
+        };
+        let SessionKeys {
+            transcript,
+            sender,
+            receiver,
+        } = state.derive_keys(&remote_public)?;
+        writer.cipher = Some(sender);
+        reader.cipher = Some(receiver);
+
+        let auth = transcript.sign(&mut key_manager.lock().unwrap(), initiator)?;
+        writer.write_payload(&auth).await?;
+        let remote_auth = match reader.read_payload(HANDSHAKE_TIMEOUT).await? {
+            Some(payload) => payload,
+            None => bail!("Connection was closed during secure handshake"),
+        };
+        transcript.verify(&remote_auth, !initiator)
+    })
+    .await
+    .context("Timed out during secure handshake")?
+}
+
+/// handshake で接続を拒否した理由
+#[derive(Debug, PartialEq, Eq)]
+pub enum HandshakeError {
+    /// protocol の名前が異なる
+    UnknownProtocol(String),
+    /// protocol の version が古い
+    UnsupportedVersion(u32),
+    /// 自分自身に接続している
+    SelfConnection,
+    /// Edge ノード同士は接続しない
+    IncompatibleServices(Services),
+    /// handshake の途中で Version や VerAck 以外のメッセージを受け取った
+    UnexpectedMessage,
+    /// 相手に接続を拒否された
+    Rejected(String),
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandshakeError::UnknownProtocol(protocol) => {
+                write!(f, "Unknown protocol: {:?}", protocol)
+            }
+            HandshakeError::UnsupportedVersion(version) => write!(
+                f,
+                "Protocol version {} is not supported (min {})",
+                version, MIN_PROTOCOL_VERSION
+            ),
+            HandshakeError::SelfConnection => write!(f, "Connected to myself"),
+            HandshakeError::IncompatibleServices(services) => {
+                write!(f, "Can't connect to a node with {:?} services", services)
+            }
+            HandshakeError::UnexpectedMessage => {
+                write!(f, "Unexpected message during handshake")
+            }
+            HandshakeError::Rejected(reason) => write!(f, "Rejected by peer: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for HandshakeError {}
+
+/// handshake で交換する node の情報
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VersionInfo {
+    // 待ち受けている port
+    port: u16,
+    version: u32,
+    services: Services,
+    best_height: usize,
+    // 自分自身への接続を見分けるために node ごとに決める乱数
+    nonce: u64,
+}
+
+impl VersionInfo {
+    /// 自分の情報を作る。
+    pub fn new(port: u16, services: Services, best_height: usize, nonce: u64) -> VersionInfo {
+        VersionInfo {
+            port,
+            version: PROTOCOL_VERSION,
+            services,
+            best_height,
+            nonce,
+        }
+    }
+
+    pub fn get_port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn get_version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn get_services(&self) -> Services {
+        self.services
+    }
+
+    pub fn get_best_height(&self) -> usize {
+        self.best_height
+    }
+
+    fn to_message(&self) -> Message {
+        Message::new(
+            self.port,
+            Payload::Version {
+                protocol_version: self.version,
+                services: self.services,
+                best_height: self.best_height,
+                nonce: self.nonce,
+            },
+        )
+    }
+
+    fn from_message(msg: Message) -> Result<VersionInfo, HandshakeError> {
+        if msg.get_protocol() != PROTOCOL_NAME {
+            return Err(HandshakeError::UnknownProtocol(
+                msg.get_protocol().to_string(),
+            ));
+        }
+        match msg.payload {
+            Payload::Version {
+                protocol_version,
+                services,
+                best_height,
+                nonce,
+            } => Ok(VersionInfo {
+                port: msg.port,
+                version: protocol_version,
+                services,
+                best_height,
+                nonce,
+            }),
+            Payload::Reject { reason } => Err(HandshakeError::Rejected(reason)),
+            _ => Err(HandshakeError::UnexpectedMessage),
+        }
+    }
+
+    /// 相手の情報を見て接続を受け入れられるか確認する。
+    fn check_peer(&self, peer: &VersionInfo) -> Result<(), HandshakeError> {
+        if peer.version < MIN_PROTOCOL_VERSION {
+            return Err(HandshakeError::UnsupportedVersion(peer.version));
+        }
+        if peer.nonce == self.nonce {
+            return Err(HandshakeError::SelfConnection);
+        }
+        if self.services == Services::Edge && peer.services == Services::Edge {
+            return Err(HandshakeError::IncompatibleServices(peer.services));
+        }
+        Ok(())
+    }
+}
+
+async fn read_handshake_message(reader: &mut MessageReader) -> Result<Message> {
+    match reader.read_message().await? {
+        Some(msg) => Ok(msg),
+        None => bail!("Connection was closed during handshake"),
+    }
+}
+
+/// 接続した直後に Version と VerAck を送り合い、相手の情報を返す。
+/// 相手を受け入れられない場合は Reject で理由を伝えてエラーを返す。
+pub async fn handshake(
+    reader: &mut MessageReader,
+    writer: &mut MessageWriter,
+    local: &VersionInfo,
+) -> Result<VersionInfo> {
+    timeout(HANDSHAKE_TIMEOUT, async {
+        writer.write_message(&local.to_message()).await?;
+
+        let msg = read_handshake_message(reader).await?;
+        let peer =
+            VersionInfo::from_message(msg).and_then(|peer| local.check_peer(&peer).map(|_| peer));
+        let peer = match peer {
+            Ok(peer) => peer,
+            Err(err) => {
+                if !matches!(err, HandshakeError::Rejected(_)) {
+                    let reason = err.to_string();
+                    let reject = Message::new(local.port, Payload::Reject { reason });
+                    // 拒否したことが伝わらなくても接続は閉じる
+                    let _ = writer.write_message(&reject).await;
+                }
+                return Err(err.into());
+            }
+        };
+        writer
+            .write_message(&Message::new(local.port, Payload::VerAck))
+            .await?;
+
+        let msg = read_handshake_message(reader).await?;
+        match msg.payload {
+            Payload::VerAck => Ok(peer),
+            Payload::Reject { reason } => Err(HandshakeError::Rejected(reason).into()),
+            _ => Err(HandshakeError::UnexpectedMessage.into()),
+        }
+    })
+    .await
+    .context("Timed out during handshake")?
+}
+
+/// peer が待ち受けている address ごとに、使い回す接続の書き込み側を持つ。
+#[derive(Default)]
+pub struct ConnectionPool {
+    writers: HashMap<SocketAddr, SharedWriter>,
+}
+
+impl ConnectionPool {
+    pub fn new() -> ConnectionPool {
+        ConnectionPool {
+            writers: HashMap::new(),
+        }
+    }
+
+    pub fn get(&self, addr: &SocketAddr) -> Option<SharedWriter> {
+        self.writers.get(addr).cloned()
+    }
+
+    /// addr への接続が無ければ writer を登録する。登録した場合 true を返す。
+    pub fn insert_if_absent(&mut self, addr: SocketAddr, writer: &SharedWriter) -> bool {
+        if self.writers.contains_key(&addr) {
+            return false;
+        }
+        self.writers.insert(addr, Arc::clone(writer));
+        true
+    }
+
+    pub fn remove(&mut self, addr: &SocketAddr) -> Option<SharedWriter> {
+        self.writers.remove(addr)
+    }
+
+    /// writer が登録されている address を全て除く。接続が閉じた時に使う。
+    pub fn remove_writer(&mut self, writer: &SharedWriter) {
+        self.writers.retain(|_, w| !Arc::ptr_eq(w, writer));
+    }
+
+    pub fn len(&self) -> usize {
+        self.writers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.writers.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::Payload;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_read_and_write_frame() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        write_frame(&mut client, b"hello").await.unwrap();
+        write_frame(&mut client, b"").await.unwrap();
+        drop(client);
+
+        let idle = Duration::from_secs(1);
+        assert_eq!(
+            read_frame(&mut server, idle).await.unwrap(),
+            Some(b"hello".to_vec())
+        );
+        assert_eq!(read_frame(&mut server, idle).await.unwrap(), Some(vec![]));
+        assert_eq!(read_frame(&mut server, idle).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_read_frame_returns_err() {
+        // 不正な frame の場合だけ FrameError になる
+        fn frame_error(res: Result<Option<Vec<u8>>>) -> Option<FrameError> {
+            res.unwrap_err().downcast::<FrameError>().ok()
+        }
+        let idle = Duration::from_secs(1);
+        let frame = encode_frame(b"hello").unwrap();
+
+        // wrong magic number
+        let mut invalid = frame.clone();
+        invalid[0] = b'X';
+        assert_eq!(
+            frame_error(read_frame(&mut &invalid[..], idle).await),
+            Some(FrameError::InvalidMagic(*b"XBTC"))
+        );
+
+        // broken payload
+        let mut invalid = frame.clone();
+        *invalid.last_mut().unwrap() = b'!';
+        assert_eq!(
+            frame_error(read_frame(&mut &invalid[..], idle).await),
+            Some(FrameError::ChecksumMismatch)
+        );
+
+        // truncated frame
+        assert_eq!(
+            frame_error(read_frame(&mut &frame[..frame.len() - 1], idle).await),
+            None
+        );
+
+        // too large payload is rejected before reading it
+        let mut invalid = frame.clone();
+        invalid[4..8].copy_from_slice(&(MAX_PAYLOAD_LEN as u32 + 1).to_be_bytes());
+        assert_eq!(
+            frame_error(read_frame(&mut &invalid[..], idle).await),
+            Some(FrameError::TooLarge(MAX_PAYLOAD_LEN + 1))
+        );
+        assert!(encode_frame(&vec![0; MAX_PAYLOAD_LEN + 1]).is_err());
+
+        // the declared length is not trusted until the payload arrives
+        let mut truncated = frame[..FRAME_HEADER_LEN].to_vec();
+        truncated[4..8].copy_from_slice(&(MAX_PAYLOAD_LEN as u32).to_be_bytes());
+        truncated.extend_from_slice(&[0; 16]);
+        assert_eq!(
+            frame_error(read_frame(&mut &truncated[..], idle).await),
+            None
+        );
+
+        // nothing arrives
+        let (_client, mut server) = tokio::io::duplex(1024);
+        let idle = Duration::from_millis(10);
+        assert_eq!(frame_error(read_frame(&mut server, idle).await), None);
+    }
+
+    #[tokio::test]
+    async fn test_messages_over_persistent_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (_, mut writer) = connect(&addr).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let (mut reader, _) = split(stream);
+
+        for port in 1..=3 {
+            writer
+                .write_message(&Message::new(port, Payload::Ping))
+                .await
+                .unwrap();
+        }
+        for port in 1..=3 {
+            let msg = reader.read_message().await.unwrap().unwrap();
+            assert_eq!(msg, Message::new(port, Payload::Ping));
+        }
+        drop(writer);
+        assert_eq!(reader.read_message().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_secure_handshake() {
+        let km1 = Mutex::new(KeyManager::new(rand::rngs::OsRng).unwrap());
+        let km2 = Mutex::new(KeyManager::new(rand::rngs::OsRng).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (mut reader, mut writer) = connect(&addr).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let (mut remote_reader, mut remote_writer) = split(stream);
+
+        let (peer, remote_peer) = tokio::join!(
+            secure_handshake(&mut reader, &mut writer, &km1, true),
+            secure_handshake(&mut remote_reader, &mut remote_writer, &km2, false)
+        );
+        assert_eq!(peer.unwrap(), km2.lock().unwrap().get_address());
+        assert_eq!(remote_peer.unwrap(), km1.lock().unwrap().get_address());
+
+        // messages are encrypted in both directions
+        let msg = Message::new(1, Payload::Ping);
+        writer.write_message(&msg).await.unwrap();
+        assert_eq!(remote_reader.read_message().await.unwrap(), Some(msg));
+        let msg = Message::new(2, Payload::Ping);
+        remote_writer.write_message(&msg).await.unwrap();
+        assert_eq!(reader.read_message().await.unwrap(), Some(msg));
+
+        // a plaintext message can't be read on a secure connection
+        writer.cipher = None;
+        writer
+            .write_message(&Message::new(1, Payload::Ping))
+            .await
+            .unwrap();
+        assert!(remote_reader.read_message().await.is_err());
+
+        // a plaintext peer can't complete the secure handshake
+        let (mut reader, mut writer) = connect(&addr).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let (_, mut remote_writer) = split(stream);
+        remote_writer
+            .write_message(&Message::new(2, Payload::Ping))
+            .await
+            .unwrap();
+        assert!(secure_handshake(&mut reader, &mut writer, &km1, true)
+            .await
+            .is_err());
+    }
+
+    async fn do_handshake(
+        local: VersionInfo,
+        remote: VersionInfo,
+    ) -> (Result<VersionInfo>, Result<VersionInfo>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (mut reader, mut writer) = connect(&addr).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let (mut remote_reader, mut remote_writer) = split(stream);
+        tokio::join!(
+            handshake(&mut reader, &mut writer, &local),
+            handshake(&mut remote_reader, &mut remote_writer, &remote)
+        )
+    }
+
+    fn handshake_error(res: Result<VersionInfo>) -> HandshakeError {
+        res.unwrap_err().downcast::<HandshakeError>().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_handshake() {
+        let core = VersionInfo::new(1, Services::Core, 10, 1);
+        let edge = VersionInfo::new(2, Services::Edge, 3, 2);
+        let (to_core, to_edge) = do_handshake(edge.clone(), core.clone()).await;
+        assert_eq!(to_core.unwrap(), core);
+        assert_eq!(to_edge.unwrap(), edge);
+
+        // connection to myself
+        let (res1, res2) = do_handshake(core.clone(), core.clone()).await;
+        assert_eq!(handshake_error(res1), HandshakeError::SelfConnection);
+        assert_eq!(handshake_error(res2), HandshakeError::SelfConnection);
+
+        // edge nodes don't connect to each other
+        let other_edge = VersionInfo::new(3, Services::Edge, 3, 3);
+        let (res1, _) = do_handshake(edge.clone(), other_edge).await;
+        assert_eq!(
+            handshake_error(res1),
+            HandshakeError::IncompatibleServices(Services::Edge)
+        );
+
+        // old protocol version is refused and the peer is told why
+        let mut old = VersionInfo::new(4, Services::Core, 0, 4);
+        old.version = MIN_PROTOCOL_VERSION - 1;
+        let (res1, res2) = do_handshake(old, core).await;
+        assert_eq!(
+            handshake_error(res1),
+            HandshakeError::Rejected(
+                HandshakeError::UnsupportedVersion(MIN_PROTOCOL_VERSION - 1).to_string()
+            )
+        );
+        assert_eq!(
+            handshake_error(res2),
+            HandshakeError::UnsupportedVersion(MIN_PROTOCOL_VERSION - 1)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_handshake_refuses_unknown_protocol() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (_, mut writer) = connect(&addr).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let (mut reader, mut remote_writer) = split(stream);
+
+        let local = VersionInfo::new(1, Services::Core, 0, 1);
+        let msg = Message::new_with_proto_version(
+            "unknown_protocol".to_string(),
+            "0.1.0".to_string(),
+            2,
+            VersionInfo::new(2, Services::Core, 0, 2)
+                .to_message()
+                .payload,
+        );
+        writer.write_message(&msg).await.unwrap();
+        let res = handshake(&mut reader, &mut remote_writer, &local).await;
+        assert_eq!(
+            handshake_error(res),
+            HandshakeError::UnknownProtocol("unknown_protocol".to_string())
+        );
+
+        // application messages before handshake are refused
+        let (_, mut writer) = connect(&addr).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let (mut reader, mut remote_writer) = split(stream);
+        writer
+            .write_message(&Message::new(2, Payload::Ping))
+            .await
+            .unwrap();
+        let res = handshake(&mut reader, &mut remote_writer, &local).await;
+        assert_eq!(handshake_error(res), HandshakeError::UnexpectedMessage);
+    }
+}
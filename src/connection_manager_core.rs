@@ -1,33 +1,46 @@
-use crate::message::{ApplicationPayload, Message, Payload};
-use anyhow::Result;
-use log::{debug, error, info};
-use std::collections::HashSet;
-use std::net::SocketAddr;
+use crate::address_book::{AddressBook, AddressSource, MAX_ADDR_PER_MESSAGE};
+use crate::blockchain::transaction::Address;
+use crate::connection::{
+    self, ConnectionPool, FrameError, MessageReader, MessageWriter, SharedWriter, VersionInfo,
+};
+use crate::key_manager::KeyManager;
+use crate::message::{ApplicationPayload, Message, Payload, Services};
+use crate::misbehavior::{BanEntry, BanList, Misbehavior, DEFAULT_BAN_DURATION};
+use anyhow::{bail, Result};
+use log::{debug, error, info, warn};
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::task::JoinHandle;
 
+/// 接続を維持したい Core ノードの数. 足りない場合は AddressBook から補う。
+const TARGET_CORE_PEERS: usize = 8;
+
+/// ApplicationPayloadHandler の返り値. 送信する payload と送信先の組を並べる。
+/// 送信元が不正な payload を送ってきた場合は Err を返す。
+pub type HandlerResult =
+    std::result::Result<Vec<(ApplicationPayload, Vec<SocketAddr>)>, Misbehavior>;
+
+/// Application を送ってきた node の種類
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PeerKind {
+    /// Core ノードの一覧に含まれる node
+    Core,
+    /// AddAsEdge で参加した Edge ノード
+    Edge,
+    /// どちらにも含まれない node
+    Unknown,
+}
+
 // it works as trait alias which is not public API yet
 pub trait ApplicationPayloadHandler:
-    Fn(
-        ApplicationPayload,
-        SocketAddr,
-        Vec<SocketAddr>,
-        bool,
-    ) -> Option<(ApplicationPayload, Vec<SocketAddr>)>
-    + Send
-    + 'static
+    Fn(ApplicationPayload, SocketAddr, Vec<SocketAddr>, PeerKind) -> HandlerResult + Send + 'static
 {
 }
 impl<
-        T: Fn(
-                ApplicationPayload,
-                SocketAddr,
-                Vec<SocketAddr>,
-                bool,
-            ) -> Option<(ApplicationPayload, Vec<SocketAddr>)>
+        T: Fn(ApplicationPayload, SocketAddr, Vec<SocketAddr>, PeerKind) -> HandlerResult
             + Send
             + 'static,
     > ApplicationPayloadHandler for T
@@ -39,12 +52,27 @@ pub struct ConnectionManagerInner {
     app_msg_handler: Box<dyn ApplicationPayloadHandler>,
     core_node_set: HashSet<SocketAddr>,
     edge_node_set: HashSet<SocketAddr>,
+    // peer が待ち受けている address ごとの接続
+    connections: ConnectionPool,
+    // handshake で伝える best chain の高さを返す
+    best_height: Box<dyn Fn() -> usize + Send>,
+    // 自分自身への接続を見分けるための乱数
+    nonce: u64,
+    // secure transport で相手を認証するための node の鍵. None の場合は平文で通信する。
+    key_manager: Option<Arc<Mutex<KeyManager>>>,
+    // 認証できた node の鍵. 同じ address を別の鍵の node が名乗ることは許さない。
+    node_identities: HashMap<SocketAddr, Address>,
+    // 知っている Core ノードの address. 参加先や接続先を選ぶのに使う。
+    address_book: AddressBook,
+    // node ごとの misbehavior の点数と ban されている node
+    ban_list: BanList,
 }
 
 impl ConnectionManagerInner {
     pub fn new(
         addr: SocketAddr,
         app_msg_handler: impl ApplicationPayloadHandler,
+        best_height: impl Fn() -> usize + Send + 'static,
     ) -> ConnectionManagerInner {
         let node_set = HashSet::<SocketAddr>::new();
         let edge_set = HashSet::<SocketAddr>::new();
@@ -53,21 +81,39 @@ impl ConnectionManagerInner {
             app_msg_handler: Box::new(app_msg_handler),
             core_node_set: node_set,
             edge_node_set: edge_set,
+            connections: ConnectionPool::new(),
+            best_height: Box::new(best_height),
+            nonce: rand::random(),
+            key_manager: None,
+            node_identities: HashMap::new(),
+            address_book: AddressBook::new(),
+            ban_list: BanList::new(DEFAULT_BAN_DURATION),
         };
         manager.add_peer(addr);
         manager
     }
 
+    // 待ち受けている address を自分の address とする
+    fn set_my_addr(&mut self, addr: SocketAddr) {
+        self.core_node_set.remove(&self.addr);
+        self.addr = addr;
+        self.add_peer(addr);
+    }
+
     // 新たに接続された Core ノードをリストに追加する
     fn add_peer(&mut self, peer: SocketAddr) -> bool {
         debug!("Adding peer: {}", peer);
         self.core_node_set.insert(peer)
     }
 
-    // 離脱した Core ノードをリストから削除する
+    // 離脱した Core ノードをリストから削除する。自分自身は削除しない。
     fn remove_peer(&mut self, peer: &SocketAddr) -> bool {
+        if *peer == self.addr {
+            return false;
+        }
         debug!("Removing peer: {}", peer);
         let res = self.core_node_set.remove(peer);
+        self.node_identities.remove(peer);
         debug!("Current Core list: {:?}", self.core_node_set);
         res
     }
@@ -82,10 +128,73 @@ impl ConnectionManagerInner {
     fn remove_edge(&mut self, edge: &SocketAddr) -> bool {
         debug!("Removing edge: {}", edge);
         let res = self.edge_node_set.remove(edge);
+        self.node_identities.remove(edge);
         debug!("Current Edge list: {:?}", self.edge_node_set);
         res
     }
 
+    // 認証できた node の鍵を address に結び付ける。
+    // 別の鍵の node がすでにその address を使っている場合は false を返す。
+    fn bind_identity(&mut self, peer: SocketAddr, identity: Address) -> bool {
+        match self.node_identities.get(&peer) {
+            Some(known) => known == &identity,
+            None => {
+                self.node_identities.insert(peer, identity);
+                true
+            }
+        }
+    }
+
+    // AddressBook に address を追加する。自分の address と ban されている IP address は追加しない。
+    fn add_address(&mut self, addr: SocketAddr, source: AddressSource) {
+        if addr != self.addr && !self.is_banned(&addr.ip()) {
+            self.address_book.add(addr, source);
+        }
+    }
+
+    fn save_address_book(&self) {
+        if let Err(err) = self.address_book.save() {
+            error!("Failed to save address book: {:?}", err);
+        }
+    }
+
+    // peer の misbehavior を IP address ごとに記録する。ban した場合は接続を切り、一覧からも取り除く。
+    fn misbehaving(&mut self, peer: SocketAddr, misbehavior: Misbehavior) {
+        if self.ban_list.misbehaving(peer.ip(), misbehavior) {
+            self.remove_peer(&peer);
+            self.remove_edge(&peer);
+            self.address_book.remove_ip(&peer.ip());
+            self.connections.remove(&peer);
+        }
+    }
+
+    pub fn is_banned(&self, ip: &IpAddr) -> bool {
+        self.ban_list.is_banned(ip)
+    }
+
+    pub fn get_bans(&self) -> Vec<BanEntry> {
+        self.ban_list.get_bans()
+    }
+
+    // ban を解除する。ban されていた場合は true を返す。
+    pub fn unban(&mut self, ip: &IpAddr) -> bool {
+        self.ban_list.unban(ip)
+    }
+
+    pub fn is_secure(&self) -> bool {
+        self.key_manager.is_some()
+    }
+
+    // handshake で送る自分の情報
+    fn get_local_version(&self) -> VersionInfo {
+        VersionInfo::new(
+            self.get_my_addr().port(),
+            Services::Core,
+            (self.best_height)(),
+            self.nonce,
+        )
+    }
+
     pub fn get_my_addr(&self) -> SocketAddr {
         self.addr
     }
@@ -101,6 +210,16 @@ impl ConnectionManagerInner {
             .filter(|x| x != &self.addr)
             .collect()
     }
+
+    fn get_peer_kind(&self, peer: &SocketAddr) -> PeerKind {
+        if self.core_node_set.contains(peer) && peer != &self.addr {
+            PeerKind::Core
+        } else if self.edge_node_set.contains(peer) {
+            PeerKind::Edge
+        } else {
+            PeerKind::Unknown
+        }
+    }
 }
 
 pub struct ConnectionManagerCore {
@@ -115,6 +234,7 @@ impl ConnectionManagerCore {
     pub fn new(
         addr: SocketAddr,
         app_msg_handler: impl ApplicationPayloadHandler,
+        best_height: impl Fn() -> usize + Send + 'static,
     ) -> ConnectionManagerCore {
         info!("Initializing ConnectionManagerCore...");
         ConnectionManagerCore {
@@ -122,6 +242,7 @@ impl ConnectionManagerCore {
             inner: Arc::new(Mutex::new(ConnectionManagerInner::new(
                 addr,
                 app_msg_handler,
+                best_height,
             ))),
             check_peers_interval: Duration::from_secs(30),
             join_handle_for_listen: None,
@@ -129,8 +250,33 @@ impl ConnectionManagerCore {
         }
     }
 
-    // 待受を開始する前に呼び出される (Server Core 向け)
-    pub async fn start(&mut self) {
+    // 接続を暗号化し、node の鍵で相手を認証するようにする。start の前に呼び出す。
+    // 有効にした場合、平文で通信する node とは接続できない。
+    pub fn enable_secure_transport(&mut self, key_manager: Arc<Mutex<KeyManager>>) {
+        info!("Secure transport is enabled");
+        self.inner.lock().unwrap().key_manager = Some(key_manager);
+    }
+
+    // misbehavior の点数が閾値に達した node を ban する期間を設定する。start の前に呼び出す。
+    pub fn set_ban_duration(&mut self, ban_duration: Duration) {
+        self.inner.lock().unwrap().ban_list = BanList::new(ban_duration);
+    }
+
+    // 保存しておいた AddressBook を使うようにする。start の前に呼び出す。
+    pub fn set_address_book(&mut self, address_book: AddressBook) {
+        self.inner.lock().unwrap().address_book = address_book;
+    }
+
+    // 待受を開始する (Server Core 向け)
+    // port に 0 を指定した場合は、割り当てられた port を自分の address とする。
+    pub async fn start(&mut self) -> Result<()> {
+        let listener = TcpListener::bind(self.addr).await?;
+        let addr = listener.local_addr()?;
+        if addr != self.addr {
+            self.addr = addr;
+            self.inner.lock().unwrap().set_my_addr(addr);
+        }
+
         // do check_peers_connection repeatedly
         {
             let manager = Arc::clone(&self.inner);
@@ -141,8 +287,13 @@ impl ConnectionManagerCore {
             self.join_handle_for_check_peers = Some(handle);
         }
 
-        let handle = tokio::spawn(Self::wait_for_access(Arc::clone(&self.inner), self.addr));
+        let handle = tokio::spawn(Self::wait_for_access(Arc::clone(&self.inner), listener));
         self.join_handle_for_listen = Some(handle);
+        Ok(())
+    }
+
+    pub fn get_my_addr(&self) -> SocketAddr {
+        self.addr
     }
 
     // 終了前の処理としてソケットを閉じる (ServerCore 向け)
@@ -175,60 +326,216 @@ impl ConnectionManagerCore {
             Self::send_msg(
                 Arc::clone(&self.inner),
                 core_addr,
-                Message::new(manager_port, Payload::Remove {}),
+                Message::new(manager_port, Payload::Remove),
             )
             .await;
         }
+        let mut manager = self.inner.lock().unwrap();
+        manager.connections = ConnectionPool::new();
+        manager.save_address_book();
+    }
+
+    // ネットワークへの参加 (Server Core 向け)
+    // ユーザが指定した Core ノード、AddressBook にある Core ノードの順に参加を要求する。
+    // 参加できた Core ノードを返し、参加先の候補が無い場合は None を返す。
+    pub async fn join_network(&self, bootstrap: Option<SocketAddr>) -> Result<Option<SocketAddr>> {
+        let candidates = {
+            let mut manager = self.inner.lock().unwrap();
+            let mut candidates: Vec<SocketAddr> = bootstrap.into_iter().collect();
+            if let Some(addr) = bootstrap {
+                manager.add_address(addr, AddressSource::Bootstrap);
+            }
+            let mut exclude = candidates.clone();
+            exclude.push(self.addr);
+            candidates.extend(
+                manager
+                    .address_book
+                    .select_peers(TARGET_CORE_PEERS, &exclude),
+            );
+            candidates
+        };
+        if candidates.is_empty() {
+            return Ok(None);
+        }
+
+        for target_addr in candidates {
+            match Self::request_to_join(Arc::clone(&self.inner), target_addr).await {
+                Ok(()) => return Ok(Some(target_addr)),
+                Err(err) => warn!("Failed to join network via {}: {:#}", target_addr, err),
+            }
+        }
+        bail!("No known core node accepted the request to join")
     }
 
-    // ユーザが指定した既知の Core ノードへの接続 (Server Core 向け)
-    pub async fn join_network(&self, target_addr: SocketAddr) -> Result<()> {
+    // 指定された Core ノードに参加を要求し、その Core ノードが知っている address も要求する
+    async fn request_to_join(
+        manager: Arc<Mutex<ConnectionManagerInner>>,
+        target_addr: SocketAddr,
+    ) -> Result<()> {
         info!("Send request to join network to: {}", target_addr);
-        let mut stream = TcpStream::connect(target_addr).await?;
-        let payload = Payload::Add {};
-        let msg = Message::new(self.addr.port(), payload);
-        stream
-            .write_all(serde_json::to_string(&msg)?.as_bytes())
-            .await?;
+        let manager_port = {
+            let mut manager = manager.lock().unwrap();
+            // 参加先から届く CoreList を受け入れられるように、参加先を Core ノードとして扱う
+            manager.add_peer(target_addr);
+            manager.get_my_addr().port()
+        };
+        let msg = Message::new(manager_port, Payload::Add);
+        if let Err(err) = Self::do_send_msg(Arc::clone(&manager), &target_addr, msg).await {
+            let mut manager = manager.lock().unwrap();
+            manager.remove_peer(&target_addr);
+            manager.address_book.mark_failed(&target_addr);
+            return Err(err);
+        }
+        let msg = Message::new(manager_port, Payload::GetAddr);
+        Self::send_msg(manager, &target_addr, msg).await;
         Ok(())
     }
 
     async fn wait_for_access(
         manager: Arc<Mutex<ConnectionManagerInner>>,
-        manager_addr: SocketAddr,
+        sock: TcpListener,
     ) -> Result<()> {
-        let sock = TcpListener::bind(manager_addr).await?;
-
         loop {
             let (stream, src_addr) = sock.accept().await?;
-            let manager = Arc::clone(&manager);
-            tokio::spawn(Self::handle_message(
-                manager,
-                manager_addr,
+            tokio::spawn(Self::accept_connection(
+                Arc::clone(&manager),
                 stream,
                 src_addr,
             ));
         }
     }
 
+    // 接続してきた相手と handshake を行い、その後に届くメッセージを処理する
+    async fn accept_connection(
+        manager: Arc<Mutex<ConnectionManagerInner>>,
+        stream: TcpStream,
+        src_addr: SocketAddr, // address the connection comes from
+    ) {
+        let (mut reader, mut writer) = connection::split(stream);
+        let peer_addr =
+            match Self::handshake(&manager, &mut reader, &mut writer, src_addr, false).await {
+                Ok(peer_addr) => peer_addr,
+                Err(err) => {
+                    warn!("Refused connection from {}: {:#}", src_addr, err);
+                    return;
+                }
+            };
+        let writer = writer.into_shared();
+        manager
+            .lock()
+            .unwrap()
+            .connections
+            .insert_if_absent(peer_addr, &writer);
+        Self::handle_connection(manager, reader, writer, peer_addr).await;
+    }
+
+    // 自分の情報を送り、相手の情報を受け取る。
+    // secure transport が有効な場合は、先に接続を暗号化して相手を認証する。
+    // 相手が待ち受けている address を返す。
+    async fn handshake(
+        manager: &Arc<Mutex<ConnectionManagerInner>>,
+        reader: &mut MessageReader,
+        writer: &mut MessageWriter,
+        src_addr: SocketAddr, // address the connection comes from
+        initiator: bool,
+    ) -> Result<SocketAddr> {
+        // ban されている IP address とは鍵の交換も行わない
+        let key_manager = {
+            let manager = manager.lock().unwrap();
+            if manager.is_banned(&src_addr.ip()) {
+                bail!("{} is banned", src_addr.ip());
+            }
+            manager.key_manager.clone()
+        };
+        let identity = match key_manager {
+            Some(key_manager) => {
+                Some(connection::secure_handshake(reader, writer, &key_manager, initiator).await?)
+            }
+            None => None,
+        };
+
+        let local = manager.lock().unwrap().get_local_version();
+        let peer = connection::handshake(reader, writer, &local).await?;
+        let peer_addr = SocketAddr::new(src_addr.ip(), peer.get_port());
+        let mut manager = manager.lock().unwrap();
+        if let Some(identity) = identity {
+            if !manager.bind_identity(peer_addr, identity) {
+                bail!("{} is already used by a node with another key", peer_addr);
+            }
+        }
+        if peer.get_services() == Services::Core {
+            if !initiator {
+                manager.add_address(peer_addr, AddressSource::Inbound);
+            }
+            manager.address_book.mark_seen(&peer_addr);
+        }
+        info!(
+            "Handshake completed: addr={}, version={}, services={:?}, best_height={}",
+            peer_addr,
+            peer.get_version(),
+            peer.get_services(),
+            peer.get_best_height()
+        );
+        Ok(peer_addr)
+    }
+
+    // 接続から届くメッセージを順に処理する task を起動する
+    fn spawn_connection(
+        manager: Arc<Mutex<ConnectionManagerInner>>,
+        reader: MessageReader,
+        writer: SharedWriter,
+        peer_addr: SocketAddr,
+    ) {
+        tokio::spawn(Self::handle_connection(manager, reader, writer, peer_addr));
+    }
+
+    // 接続が閉じられるまでメッセージを読み続ける
+    async fn handle_connection(
+        manager: Arc<Mutex<ConnectionManagerInner>>,
+        mut reader: MessageReader,
+        writer: SharedWriter,
+        peer_addr: SocketAddr, // address the peer listens to
+    ) {
+        loop {
+            let message = match reader.read_message().await {
+                Ok(Some(message)) => message,
+                Ok(None) => {
+                    debug!("Connection with {} was closed", peer_addr);
+                    break;
+                }
+                Err(err) => {
+                    warn!("Close connection with {}: {:?}", peer_addr, err);
+                    if let Some(err) = err.downcast_ref::<FrameError>() {
+                        let misbehavior = match err {
+                            FrameError::TooLarge(_) => Misbehavior::OversizedMessage,
+                            _ => Misbehavior::MalformedMessage,
+                        };
+                        manager.lock().unwrap().misbehaving(peer_addr, misbehavior);
+                    }
+                    break;
+                }
+            };
+
+            Self::handle_message(Arc::clone(&manager), message, peer_addr).await;
+            if manager.lock().unwrap().is_banned(&peer_addr.ip()) {
+                debug!("Close connection with banned node {}", peer_addr);
+                break;
+            }
+        }
+        manager.lock().unwrap().connections.remove_writer(&writer);
+    }
+
     // 受信したメッセージを確認して、内容に応じた処理を行う
     async fn handle_message(
         manager: Arc<Mutex<ConnectionManagerInner>>,
-        manager_addr: SocketAddr,
-        mut stream: TcpStream,
-        src_addr: SocketAddr, // address the packet comes from
-    ) -> Result<()> {
-        let mut buf = vec![];
-        stream.read_to_end(&mut buf).await?;
-        let message: Message = serde_json::from_slice(&buf[..])?;
-        debug!("Received Message from {}: {:?}", src_addr, message);
-
-        // address the peer core node listens to.
-        let peer_addr = SocketAddr::new(src_addr.ip(), message.port);
-        let manager_port = manager_addr.port();
+        message: Message,
+        peer_addr: SocketAddr, // address the peer listens to
+    ) {
+        debug!("Received Message from {}: {:?}", peer_addr, message);
+        let manager_port = manager.lock().unwrap().get_my_addr().port();
 
         match message.payload {
-            Payload::Add { .. } => {
+            Payload::Add => {
                 let added = manager.lock().unwrap().add_peer(peer_addr);
                 let nodes = manager.lock().unwrap().get_core_nodes();
                 if added {
@@ -240,7 +547,21 @@ impl ConnectionManagerCore {
                 }
             }
             Payload::Remove => {
+                {
+                    // 離脱できるのは Core ノードとして参加している送信元自身だけ
+                    let manager = manager.lock().unwrap();
+                    if peer_addr == manager.get_my_addr()
+                        || !manager.core_node_set.contains(&peer_addr)
+                    {
+                        warn!(
+                            "Remove from {} which is not a core node. Ignore it",
+                            peer_addr
+                        );
+                        return;
+                    }
+                }
                 let removed = manager.lock().unwrap().remove_peer(&peer_addr);
+                manager.lock().unwrap().connections.remove(&peer_addr);
                 let nodes = manager.lock().unwrap().get_core_nodes();
                 if removed {
                     let payload = Payload::CoreList {
@@ -252,12 +573,45 @@ impl ConnectionManagerCore {
             }
             Payload::CoreList { nodes } => {
                 let mut manager = manager.lock().unwrap();
+                // secure transport では認証できた Core ノードからの一覧だけを受け入れる
+                if manager.is_secure() && !manager.core_node_set.contains(&peer_addr) {
+                    warn!("CoreList from {} which is not a core node", peer_addr);
+                    manager.misbehaving(peer_addr, Misbehavior::ProtocolViolation);
+                    return;
+                }
                 for node in nodes.into_iter() {
+                    manager.add_address(node, AddressSource::Gossip { from: peer_addr });
                     if !manager.core_node_set.contains(&node) {
                         manager.core_node_set.insert(node);
                     }
                 }
             }
+            Payload::GetAddr => {
+                let addrs = manager
+                    .lock()
+                    .unwrap()
+                    .address_book
+                    .get_addresses_to_share();
+                let payload = Payload::Addr { addrs };
+                Self::send_msg(manager, &peer_addr, Message::new(manager_port, payload)).await;
+            }
+            Payload::Addr { addrs } => {
+                let mut manager = manager.lock().unwrap();
+                // Edge ノードや知らない node から教えられた address は信用しない
+                if !manager.core_node_set.contains(&peer_addr) {
+                    warn!("Addr from {} which is not a core node", peer_addr);
+                    manager.misbehaving(peer_addr, Misbehavior::ProtocolViolation);
+                    return;
+                }
+                if addrs.len() > MAX_ADDR_PER_MESSAGE {
+                    warn!("Too many addresses from {}: {}", peer_addr, addrs.len());
+                    manager.misbehaving(peer_addr, Misbehavior::OversizedMessage);
+                    return;
+                }
+                for addr in addrs.into_iter() {
+                    manager.add_address(addr, AddressSource::Gossip { from: peer_addr });
+                }
+            }
             Payload::RequestCoreList => {
                 let nodes = manager.lock().unwrap().get_core_nodes();
                 let payload = Payload::CoreList { nodes };
@@ -271,16 +625,37 @@ impl ConnectionManagerCore {
                 Self::send_msg(manager, &peer_addr, Message::new(manager_port, payload)).await;
             }
             Payload::RemoveEdge => {
-                manager.lock().unwrap().remove_edge(&peer_addr);
+                let mut manager = manager.lock().unwrap();
+                manager.remove_edge(&peer_addr);
+                manager.connections.remove(&peer_addr);
+            }
+            Payload::Version { .. } | Payload::VerAck | Payload::Reject { .. } => {
+                warn!(
+                    "Handshake message after handshake from {}. Ignore it: {:?}",
+                    peer_addr, message.payload
+                );
+                manager
+                    .lock()
+                    .unwrap()
+                    .misbehaving(peer_addr, Misbehavior::ProtocolViolation);
             }
             Payload::Application { payload } => {
-                let nodes: Vec<SocketAddr> = manager.lock().unwrap().get_core_nodes_without_me();
-                let is_core = nodes.contains(&peer_addr);
-                let res =
-                    (manager.lock().unwrap().app_msg_handler)(payload, peer_addr, nodes, is_core);
-                if let Some((new_payload, addrs)) = res {
+                let res = {
+                    let manager = manager.lock().unwrap();
+                    let nodes = manager.get_core_nodes_without_me();
+                    let peer_kind = manager.get_peer_kind(&peer_addr);
+                    (manager.app_msg_handler)(payload, peer_addr, nodes, peer_kind)
+                };
+                let replies = match res {
+                    Ok(replies) => replies,
+                    Err(misbehavior) => {
+                        manager.lock().unwrap().misbehaving(peer_addr, misbehavior);
+                        return;
+                    }
+                };
+                for (new_payload, addrs) in replies {
                     Self::send_msg_to_nodes(
-                        manager,
+                        Arc::clone(&manager),
                         addrs,
                         Message::new(
                             manager_port,
@@ -293,8 +668,6 @@ impl ConnectionManagerCore {
                 }
             }
         }
-
-        Ok(())
     }
 
     // addr に msg を送信する。
@@ -306,30 +679,69 @@ impl ConnectionManagerCore {
         msg: Message,
     ) -> bool {
         debug!("Send message to {}: {:?}", addr, msg);
-        if let Err(e) = Self::do_send_msg(addr, msg).await {
+        if let Err(e) = Self::do_send_msg(Arc::clone(&manager), addr, msg).await {
             error!("Error occurred in send_msg: {:?}", e);
             let mut manager = manager.lock().unwrap();
             manager.remove_peer(addr);
+            manager.address_book.mark_failed(addr);
+            manager.connections.remove(addr);
             return false;
         }
         true
     }
 
-    // 指定されたノードに対してメッセージを送信する
-    async fn do_send_msg(addr: &SocketAddr, msg: Message) -> Result<()> {
-        let mut sock = TcpStream::connect(addr).await?;
-        let content = serde_json::to_string(&msg)?;
-        sock.write_all(content.as_bytes()).await?;
-        Ok(())
+    // 指定されたノードに対してメッセージを送信する。
+    // 既存の接続があれば使い回し、書き込めなかった場合は接続し直して送る。
+    async fn do_send_msg(
+        manager: Arc<Mutex<ConnectionManagerInner>>,
+        addr: &SocketAddr,
+        msg: Message,
+    ) -> Result<()> {
+        let pooled = manager.lock().unwrap().connections.get(addr);
+        if let Some(writer) = pooled {
+            match writer.lock().await.write_message(&msg).await {
+                Ok(()) => return Ok(()),
+                Err(err) => {
+                    debug!("Reconnect to {} because of {:?}", addr, err);
+                    manager.lock().unwrap().connections.remove_writer(&writer);
+                }
+            }
+        }
+
+        let writer = Self::connect(manager, addr).await?;
+        let mut writer = writer.lock().await;
+        writer.write_message(&msg).await
+    }
+
+    // 指定されたノードに接続して handshake を行い、その接続から届くメッセージも処理する
+    async fn connect(
+        manager: Arc<Mutex<ConnectionManagerInner>>,
+        addr: &SocketAddr,
+    ) -> Result<SharedWriter> {
+        if manager.lock().unwrap().is_banned(&addr.ip()) {
+            bail!("{} is banned", addr);
+        }
+        let (mut reader, mut writer) = connection::connect(addr).await?;
+        Self::handshake(&manager, &mut reader, &mut writer, *addr, true).await?;
+        let writer = writer.into_shared();
+        manager
+            .lock()
+            .unwrap()
+            .connections
+            .insert_if_absent(*addr, &writer);
+        Self::spawn_connection(manager, reader, Arc::clone(&writer), *addr);
+        Ok(writer)
     }
 
     // 指定されたノードに対して同じメッセージを broadcast する
+    // 自分自身への接続は handshake で拒否されるので、自分には送らない
     async fn send_msg_to_nodes(
         manager: Arc<Mutex<ConnectionManagerInner>>,
         addrs: Vec<SocketAddr>,
         msg: Message,
     ) {
-        for addr in addrs.iter() {
+        let my_addr = manager.lock().unwrap().get_my_addr();
+        for addr in addrs.iter().filter(|addr| **addr != my_addr) {
             Self::send_msg(Arc::clone(&manager), addr, msg.clone()).await;
         }
     }
@@ -352,7 +764,7 @@ impl ConnectionManagerCore {
 
             // check peers
             let manager_addr = manager.lock().unwrap().get_my_addr();
-            let target_nodes = manager.lock().unwrap().get_core_nodes();
+            let target_nodes = manager.lock().unwrap().get_core_nodes_without_me();
             let mut failed_nodes = vec![];
             for node in target_nodes.iter() {
                 let payload = Payload::Ping;
@@ -379,6 +791,239 @@ impl ConnectionManagerCore {
                 let msg = Message::new(manager_addr.port(), payload);
                 Self::send_msg_to_nodes(Arc::clone(&manager), nodes, msg).await;
             }
+
+            Self::find_more_peers(Arc::clone(&manager)).await;
+            let mut manager = manager.lock().unwrap();
+            manager.save_address_book();
+            manager.ban_list.remove_expired();
+        }
+    }
+
+    // 接続している Core ノードが少ない場合は、AddressBook から選んだ Core ノードに参加を要求する
+    async fn find_more_peers(manager: Arc<Mutex<ConnectionManagerInner>>) {
+        let candidates = {
+            let manager = manager.lock().unwrap();
+            let mut exclude = manager.get_core_nodes_without_me();
+            let missing = TARGET_CORE_PEERS.saturating_sub(exclude.len());
+            exclude.push(manager.get_my_addr());
+            manager.address_book.select_peers(missing, &exclude)
+        };
+        for target_addr in candidates {
+            if let Err(err) = Self::request_to_join(Arc::clone(&manager), target_addr).await {
+                debug!("Failed to connect to {}: {:#}", target_addr, err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{ApplicationPayload, PROTOCOL_VERSION};
+    use std::str::FromStr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // 空いている port で待ち受ける ConnectionManagerCore を起動する
+    async fn start_manager(
+        app_msg_handler: impl ApplicationPayloadHandler,
+    ) -> (ConnectionManagerCore, SocketAddr) {
+        let addr = SocketAddr::from_str("127.0.0.1:0").unwrap();
+        let mut cm = ConnectionManagerCore::new(addr, app_msg_handler, || 0);
+        cm.start().await.unwrap();
+        let addr = cm.get_my_addr();
+        (cm, addr)
+    }
+
+    // peer が名乗る address として、他と重ならない port を確保する
+    async fn reserve_addr() -> (TcpListener, SocketAddr) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        (listener, addr)
+    }
+
+    // 条件が満たされるまで待つ。一定時間内に満たされなければ失敗とする
+    async fn wait_until(mut cond: impl FnMut() -> bool) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !cond() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("condition is not met in time");
+    }
+
+    // Core ノードとして handshake を済ませた接続を作る
+    async fn connect_as_core(
+        manager_addr: &SocketAddr,
+        port: u16,
+    ) -> Result<(MessageReader, MessageWriter)> {
+        let (mut reader, mut writer) = connection::connect(manager_addr).await?;
+        let local = VersionInfo::new(port, Services::Core, 0, rand::random());
+        connection::handshake(&mut reader, &mut writer, &local).await?;
+        Ok((reader, writer))
+    }
+
+    #[tokio::test]
+    async fn test_ban_misbehaving_peer() {
+        let (mut cm, manager_addr) = start_manager(|_, _, _, _| Ok(vec![])).await;
+        let (_listener, peer_addr) = reserve_addr().await;
+
+        // handshake 後の Version は protocol 違反
+        let (mut reader, mut writer) = connect_as_core(&manager_addr, peer_addr.port())
+            .await
+            .unwrap();
+        let version = Message::new(
+            peer_addr.port(),
+            Payload::Version {
+                protocol_version: PROTOCOL_VERSION,
+                services: Services::Core,
+                best_height: 0,
+                nonce: 0,
+            },
+        );
+        let count = crate::misbehavior::BAN_THRESHOLD / Misbehavior::ProtocolViolation.get_score();
+        for _ in 0..count {
+            writer.write_message(&version).await.unwrap();
         }
+        // ban されると接続が閉じられる
+        assert!(!matches!(reader.read_message().await, Ok(Some(_))));
+        let bans = cm.inner.lock().unwrap().get_bans();
+        assert_eq!(bans.len(), 1);
+        assert_eq!(bans[0].get_ip(), peer_addr.ip());
+        assert_eq!(bans[0].get_reason(), "Protocol violation");
+
+        // ban されている間は、別の port を名乗っても handshake の前に切られる
+        let (_other_listener, other_addr) = reserve_addr().await;
+        for port in [peer_addr.port(), other_addr.port()] {
+            assert!(connect_as_core(&manager_addr, port).await.is_err());
+        }
+
+        assert!(cm.inner.lock().unwrap().unban(&peer_addr.ip()));
+        let (_reader, mut writer) = connect_as_core(&manager_addr, peer_addr.port())
+            .await
+            .unwrap();
+        writer
+            .write_message(&Message::new(peer_addr.port(), Payload::Ping))
+            .await
+            .unwrap();
+        assert!(cm.inner.lock().unwrap().get_bans().is_empty());
+
+        cm.connection_close(None).await;
+    }
+
+    #[tokio::test]
+    async fn test_remove_only_from_core_member_itself() {
+        // 各接続で Remove の後に送る Application が処理されたことを数える
+        let handled = Arc::new(AtomicUsize::new(0));
+        let (mut cm, manager_addr) = start_manager({
+            let handled = Arc::clone(&handled);
+            move |_, _, _, _| {
+                handled.fetch_add(1, Ordering::SeqCst);
+                Ok(vec![])
+            }
+        })
+        .await;
+        let (_listener, peer_addr) = reserve_addr().await;
+        let marker = |port| {
+            Message::new(
+                port,
+                Payload::Application {
+                    payload: ApplicationPayload::Enhanced { data: vec![] },
+                },
+            )
+        };
+
+        // 自分の address を名乗る node からの Remove で自分を一覧から消さない
+        let (_reader, mut writer) = connect_as_core(&manager_addr, manager_addr.port())
+            .await
+            .unwrap();
+        for msg in [
+            Message::new(manager_addr.port(), Payload::Remove),
+            marker(manager_addr.port()),
+        ] {
+            writer.write_message(&msg).await.unwrap();
+        }
+        // 参加していない node の Remove は無視する
+        let (_reader, mut writer) = connect_as_core(&manager_addr, peer_addr.port())
+            .await
+            .unwrap();
+        for msg in [
+            Message::new(peer_addr.port(), Payload::Remove),
+            marker(peer_addr.port()),
+        ] {
+            writer.write_message(&msg).await.unwrap();
+        }
+        wait_until(|| handled.load(Ordering::SeqCst) == 2).await;
+        assert_eq!(
+            cm.inner.lock().unwrap().get_core_nodes(),
+            vec![manager_addr]
+        );
+        ConnectionManagerCore::find_more_peers(Arc::clone(&cm.inner)).await;
+
+        // 参加した node は自分で離脱できる
+        writer
+            .write_message(&Message::new(peer_addr.port(), Payload::Add))
+            .await
+            .unwrap();
+        wait_until(|| {
+            cm.inner
+                .lock()
+                .unwrap()
+                .get_core_nodes()
+                .contains(&peer_addr)
+        })
+        .await;
+        writer
+            .write_message(&Message::new(peer_addr.port(), Payload::Remove))
+            .await
+            .unwrap();
+        wait_until(|| cm.inner.lock().unwrap().get_core_nodes() == vec![manager_addr]).await;
+
+        cm.connection_close(None).await;
+    }
+
+    #[tokio::test]
+    async fn test_handler_receives_peer_kind() {
+        let kinds = Arc::new(Mutex::new(vec![]));
+        let (mut cm, manager_addr) = start_manager({
+            let kinds = Arc::clone(&kinds);
+            move |_, _, _, peer_kind| {
+                kinds.lock().unwrap().push(peer_kind);
+                Ok(vec![])
+            }
+        })
+        .await;
+        let (_listener, peer_addr) = reserve_addr().await;
+        let marker = Message::new(
+            peer_addr.port(),
+            Payload::Application {
+                payload: ApplicationPayload::Enhanced { data: vec![] },
+            },
+        );
+
+        // AddAsEdge の前は Edge として扱わない
+        let (_reader, mut writer) = connect_as_core(&manager_addr, peer_addr.port())
+            .await
+            .unwrap();
+        writer.write_message(&marker).await.unwrap();
+        wait_until(|| kinds.lock().unwrap().len() == 1).await;
+        for msg in [
+            Message::new(peer_addr.port(), Payload::AddAsEdge),
+            marker.clone(),
+        ] {
+            writer.write_message(&msg).await.unwrap();
+        }
+        wait_until(|| kinds.lock().unwrap().len() == 2).await;
+        // Core ノードとして参加すると Core として扱う
+        for msg in [Message::new(peer_addr.port(), Payload::Add), marker] {
+            writer.write_message(&msg).await.unwrap();
+        }
+        wait_until(|| kinds.lock().unwrap().len() == 3).await;
+        assert_eq!(
+            *kinds.lock().unwrap(),
+            vec![PeerKind::Unknown, PeerKind::Edge, PeerKind::Core]
+        );
+
+        cm.connection_close(None).await;
     }
 }
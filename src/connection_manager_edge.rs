@@ -1,24 +1,42 @@
-use crate::message::{ApplicationPayload, Message, Payload};
+use crate::connection::{
+    self, ConnectionPool, MessageReader, MessageWriter, SharedWriter, VersionInfo,
+};
+use crate::key_manager::KeyManager;
+use crate::message::{ApplicationPayload, Message, Payload, Services};
 use anyhow::Result;
 use log::{debug, error, info, warn};
 use std::collections::HashSet;
 use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::task::JoinHandle;
 
 // it works as trait alias which is not public API yet
-pub trait ApplicationPayloadHandler: Fn(ApplicationPayload) + Send + 'static {}
+// 返り値の payload があれば送信元の Core ノードに返信する
+pub trait ApplicationPayloadHandler:
+    Fn(ApplicationPayload) -> Option<ApplicationPayload> + Send + 'static
+{
+}
 
-impl<T: Fn(ApplicationPayload) + Send + 'static> ApplicationPayloadHandler for T {}
+impl<T: Fn(ApplicationPayload) -> Option<ApplicationPayload> + Send + 'static>
+    ApplicationPayloadHandler for T
+{
+}
 
 pub struct ConnectionManagerInner {
     my_addr: SocketAddr,
     app_msg_handler: Box<dyn ApplicationPayloadHandler>,
     current_core_node: Option<SocketAddr>,
     core_node_set: HashSet<SocketAddr>,
+    // Core ノードが待ち受けている address ごとの接続
+    connections: ConnectionPool,
+    // handshake で伝える best chain の高さを返す
+    best_height: Box<dyn Fn() -> usize + Send>,
+    // 自分自身への接続を見分けるための乱数
+    nonce: u64,
+    // secure transport で相手を認証するための鍵. None の場合は平文で通信する。
+    key_manager: Option<Arc<Mutex<KeyManager>>>,
 }
 
 impl ConnectionManagerInner {
@@ -26,6 +44,7 @@ impl ConnectionManagerInner {
         my_addr: SocketAddr,
         core_node_addr: SocketAddr,
         app_msg_handler: impl ApplicationPayloadHandler,
+        best_height: impl Fn() -> usize + Send + 'static,
     ) -> ConnectionManagerInner {
         let node_set = HashSet::<SocketAddr>::new();
         let mut manager = ConnectionManagerInner {
@@ -33,6 +52,10 @@ impl ConnectionManagerInner {
             app_msg_handler: Box::new(app_msg_handler),
             current_core_node: Some(core_node_addr),
             core_node_set: node_set,
+            connections: ConnectionPool::new(),
+            best_height: Box::new(best_height),
+            nonce: rand::random(),
+            key_manager: None,
         };
         manager.add_peer(core_node_addr);
         manager
@@ -64,6 +87,16 @@ impl ConnectionManagerInner {
         res
     }
 
+    // handshake で送る自分の情報
+    fn get_local_version(&self) -> VersionInfo {
+        VersionInfo::new(
+            self.get_my_addr().port(),
+            Services::Edge,
+            (self.best_height)(),
+            self.nonce,
+        )
+    }
+
     pub fn get_my_addr(&self) -> SocketAddr {
         self.my_addr
     }
@@ -86,6 +119,7 @@ impl ConnectionManagerEdge {
         my_addr: SocketAddr,
         core_node_addr: SocketAddr,
         app_msg_handler: impl ApplicationPayloadHandler,
+        best_height: impl Fn() -> usize + Send + 'static,
     ) -> ConnectionManagerEdge {
         info!("Initializing ConnectionManagerEdge...");
         ConnectionManagerEdge {
@@ -94,6 +128,7 @@ impl ConnectionManagerEdge {
                 my_addr,
                 core_node_addr,
                 app_msg_handler,
+                best_height,
             ))),
             check_peers_interval: Duration::from_secs(30),
             join_handle_for_listen: None,
@@ -101,6 +136,13 @@ impl ConnectionManagerEdge {
         }
     }
 
+    // 接続を暗号化し、鍵で相手を認証するようにする。start の前に呼び出す。
+    // 有効にした場合、平文で通信する Core ノードとは接続できない。
+    pub fn enable_secure_transport(&mut self, key_manager: Arc<Mutex<KeyManager>>) {
+        info!("Secure transport is enabled");
+        self.inner.lock().unwrap().key_manager = Some(key_manager);
+    }
+
     // 待受を開始する前に呼び出される
     pub async fn start(&mut self) {
         // do check_peers_connection repeatedly
@@ -148,10 +190,11 @@ impl ConnectionManagerEdge {
             Self::send_msg(
                 Arc::clone(&self.inner),
                 &core_node_addr,
-                Message::new(manager_port, Payload::RemoveEdge {}),
+                Message::new(manager_port, Payload::RemoveEdge),
             )
             .await;
         }
+        self.inner.lock().unwrap().connections = ConnectionPool::new();
     }
 
     // ユーザが指定した既知の Core ノードへの接続
@@ -173,7 +216,7 @@ impl ConnectionManagerEdge {
         if let Some(core_node_addr) = core_node_addr {
             info!("Connecting to Core node: {}", core_node_addr);
             let my_addr = manager.lock().unwrap().my_addr;
-            let payload = Payload::AddAsEdge {};
+            let payload = Payload::AddAsEdge;
             let msg = Message::new(my_addr.port(), payload);
             Self::send_msg(manager, &core_node_addr, msg).await;
         }
@@ -187,25 +230,116 @@ impl ConnectionManagerEdge {
 
         loop {
             let (stream, src_addr) = sock.accept().await?;
-            let manager = Arc::clone(&manager);
-            tokio::spawn(Self::handle_message(manager, stream, src_addr));
+            tokio::spawn(Self::accept_connection(
+                Arc::clone(&manager),
+                stream,
+                src_addr,
+            ));
+        }
+    }
+
+    // 接続してきた相手と handshake を行い、その後に届くメッセージを処理する
+    async fn accept_connection(
+        manager: Arc<Mutex<ConnectionManagerInner>>,
+        stream: TcpStream,
+        src_addr: SocketAddr, // address the connection comes from
+    ) {
+        let (mut reader, mut writer) = connection::split(stream);
+        let peer = match Self::handshake(&manager, &mut reader, &mut writer, false).await {
+            Ok(peer) => peer,
+            Err(err) => {
+                warn!("Refused connection from {}: {:#}", src_addr, err);
+                return;
+            }
+        };
+        let peer_addr = SocketAddr::new(src_addr.ip(), peer.get_port());
+        let writer = writer.into_shared();
+        manager
+            .lock()
+            .unwrap()
+            .connections
+            .insert_if_absent(peer_addr, &writer);
+        Self::handle_connection(manager, reader, writer, peer_addr).await;
+    }
+
+    // 自分の情報を送り、相手の情報を受け取る。
+    // secure transport が有効な場合は、先に接続を暗号化して相手を認証する。
+    async fn handshake(
+        manager: &Arc<Mutex<ConnectionManagerInner>>,
+        reader: &mut MessageReader,
+        writer: &mut MessageWriter,
+        initiator: bool,
+    ) -> Result<VersionInfo> {
+        let key_manager = manager.lock().unwrap().key_manager.clone();
+        if let Some(key_manager) = key_manager {
+            let identity =
+                connection::secure_handshake(reader, writer, &key_manager, initiator).await?;
+            debug!("Authenticated peer: {}", identity);
+        }
+
+        let local = manager.lock().unwrap().get_local_version();
+        let peer = connection::handshake(reader, writer, &local).await?;
+        info!(
+            "Handshake completed: port={}, version={}, services={:?}, best_height={}",
+            peer.get_port(),
+            peer.get_version(),
+            peer.get_services(),
+            peer.get_best_height()
+        );
+        Ok(peer)
+    }
+
+    // 接続から届くメッセージを順に処理する task を起動する
+    fn spawn_connection(
+        manager: Arc<Mutex<ConnectionManagerInner>>,
+        reader: MessageReader,
+        writer: SharedWriter,
+        peer_addr: SocketAddr,
+    ) {
+        tokio::spawn(Self::handle_connection(manager, reader, writer, peer_addr));
+    }
+
+    // 接続が閉じられるまでメッセージを読み続ける
+    async fn handle_connection(
+        manager: Arc<Mutex<ConnectionManagerInner>>,
+        mut reader: MessageReader,
+        writer: SharedWriter,
+        peer_addr: SocketAddr, // address the peer listens to
+    ) {
+        loop {
+            let message = match reader.read_message().await {
+                Ok(Some(message)) => message,
+                Ok(None) => {
+                    debug!("Connection with {} was closed", peer_addr);
+                    break;
+                }
+                Err(err) => {
+                    warn!("Close connection with {}: {:?}", peer_addr, err);
+                    break;
+                }
+            };
+
+            Self::handle_message(Arc::clone(&manager), message, peer_addr).await;
         }
+        manager.lock().unwrap().connections.remove_writer(&writer);
     }
 
     // 受信したメッセージを確認して、内容に応じた処理を行う
     async fn handle_message(
         manager: Arc<Mutex<ConnectionManagerInner>>,
-        mut stream: TcpStream,
-        src_addr: SocketAddr, // address the packet comes from
-    ) -> Result<()> {
-        let mut buf = vec![];
-        stream.read_to_end(&mut buf).await?;
-        let message: Message = serde_json::from_slice(&buf[..])?;
-        debug!("Received Message from {}: {:?}", src_addr, message);
+        message: Message,
+        peer_addr: SocketAddr, // address the core node listens to
+    ) {
+        debug!("Received Message from {}: {:?}", peer_addr, message);
 
         match message.payload {
             Payload::CoreList { nodes } => {
                 let mut manager = manager.lock().unwrap();
+                // secure transport では認証できた Core ノードからの一覧だけを受け入れる
+                if manager.key_manager.is_some() && !manager.core_node_set.contains(&peer_addr) {
+                    warn!("CoreList from {} which is not a core node", peer_addr);
+                    return;
+                }
                 for node in nodes.into_iter() {
                     if !manager.core_node_set.contains(&node) {
                         manager.core_node_set.insert(node);
@@ -213,17 +347,25 @@ impl ConnectionManagerEdge {
                 }
             }
             Payload::Application { payload } => {
-                (manager.lock().unwrap().app_msg_handler)(payload);
+                let res = (manager.lock().unwrap().app_msg_handler)(payload);
+                if let Some(new_payload) = res {
+                    let my_port = manager.lock().unwrap().get_my_addr().port();
+                    let msg = Message::new(
+                        my_port,
+                        Payload::Application {
+                            payload: new_payload,
+                        },
+                    );
+                    Self::send_msg(manager, &peer_addr, msg).await;
+                }
             }
             _ => {
                 warn!(
                     "Unexpected message from {}. Ignore it: {:?}",
-                    src_addr, message
+                    peer_addr, message
                 );
             }
         }
-
-        Ok(())
     }
 
     // addr に msg を送信する。
@@ -235,21 +377,54 @@ impl ConnectionManagerEdge {
         msg: Message,
     ) -> bool {
         debug!("Send message to {}: {:?}", addr, msg);
-        if let Err(e) = Self::do_send_msg(addr, msg).await {
+        if let Err(e) = Self::do_send_msg(Arc::clone(&manager), addr, msg).await {
             error!("Error occurred in send_msg: {:?}", e);
             let mut manager = manager.lock().unwrap();
             manager.remove_peer(addr);
+            manager.connections.remove(addr);
             return false;
         }
         true
     }
 
-    // 指定されたノードに対してメッセージを送信する
-    async fn do_send_msg(addr: &SocketAddr, msg: Message) -> Result<()> {
-        let mut sock = TcpStream::connect(addr).await?;
-        let content = serde_json::to_string(&msg)?;
-        sock.write_all(content.as_bytes()).await?;
-        Ok(())
+    // 指定されたノードに対してメッセージを送信する。
+    // 既存の接続があれば使い回し、書き込めなかった場合は接続し直して送る。
+    async fn do_send_msg(
+        manager: Arc<Mutex<ConnectionManagerInner>>,
+        addr: &SocketAddr,
+        msg: Message,
+    ) -> Result<()> {
+        let pooled = manager.lock().unwrap().connections.get(addr);
+        if let Some(writer) = pooled {
+            match writer.lock().await.write_message(&msg).await {
+                Ok(()) => return Ok(()),
+                Err(err) => {
+                    debug!("Reconnect to {} because of {:?}", addr, err);
+                    manager.lock().unwrap().connections.remove_writer(&writer);
+                }
+            }
+        }
+
+        let writer = Self::connect(manager, addr).await?;
+        let mut writer = writer.lock().await;
+        writer.write_message(&msg).await
+    }
+
+    // 指定されたノードに接続して handshake を行い、その接続から届くメッセージも処理する
+    async fn connect(
+        manager: Arc<Mutex<ConnectionManagerInner>>,
+        addr: &SocketAddr,
+    ) -> Result<SharedWriter> {
+        let (mut reader, mut writer) = connection::connect(addr).await?;
+        Self::handshake(&manager, &mut reader, &mut writer, true).await?;
+        let writer = writer.into_shared();
+        manager
+            .lock()
+            .unwrap()
+            .connections
+            .insert_if_absent(*addr, &writer);
+        Self::spawn_connection(manager, reader, Arc::clone(&writer), *addr);
+        Ok(writer)
     }
 
     // 接続されている Core ノードすべての接続状況確認を行う
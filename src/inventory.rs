@@ -0,0 +1,262 @@
+use crate::blockchain::block::BlockHash;
+use crate::blockchain::manager::BlockchainManager;
+use crate::blockchain::transaction::TransactionId;
+use crate::blockchain::transaction_pool::TransactionPool;
+use crate::message::ApplicationPayload;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashSet, VecDeque};
+use std::net::SocketAddr;
+
+/// 覚えておく処理済みの inventory の数
+pub const MAX_SEEN_INVENTORY: usize = 50_000;
+
+/// Inv や GetData でやり取りする、transaction や block の識別子
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum InventoryItem {
+    Transaction(TransactionId),
+    Block(BlockHash),
+}
+
+/// 一度処理した inventory の集合. 同じ transaction や block を何度も取得、中継しないために使う。
+/// 上限を超えた場合は古いものから忘れる。
+pub struct SeenInventory {
+    items: HashSet<InventoryItem>,
+    order: VecDeque<InventoryItem>,
+    capacity: usize,
+}
+
+impl SeenInventory {
+    pub fn new(capacity: usize) -> SeenInventory {
+        SeenInventory {
+            items: HashSet::new(),
+            order: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    /// item を追加する。初めて見た item の場合は true を返す。
+    pub fn insert(&mut self, item: InventoryItem) -> bool {
+        if !self.items.insert(item.clone()) {
+            return false;
+        }
+        self.order.push_back(item);
+        if self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.items.remove(&oldest);
+            }
+        }
+        true
+    }
+
+    pub fn contains(&self, item: &InventoryItem) -> bool {
+        self.items.contains(item)
+    }
+
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+}
+
+/// 受け入れた transaction や block を Core ノードに中継し、同じ item を何度も取得しないようにする。
+pub struct InventoryRelay {
+    seen: SeenInventory,
+}
+
+impl InventoryRelay {
+    pub fn new(capacity: usize) -> InventoryRelay {
+        InventoryRelay {
+            seen: SeenInventory::new(capacity),
+        }
+    }
+
+    /// すでに処理した item か
+    pub fn is_seen(&self, item: &InventoryItem) -> bool {
+        self.seen.contains(item)
+    }
+
+    /// 中継はしないが、再び取得しない item を記録する
+    pub fn mark_seen(&mut self, item: InventoryItem) {
+        self.seen.insert(item);
+    }
+
+    /// 受け入れた item を記録し、送信元以外の Core ノードに知らせる Inv を返す。
+    pub fn accept(
+        &mut self,
+        item: InventoryItem,
+        from: SocketAddr,
+        core_nodes: Vec<SocketAddr>,
+    ) -> (ApplicationPayload, Vec<SocketAddr>) {
+        self.seen.insert(item.clone());
+        let addrs = core_nodes
+            .into_iter()
+            .filter(|addr| *addr != from)
+            .collect();
+        (ApplicationPayload::Inv { items: vec![item] }, addrs)
+    }
+
+    /// Inv で知らされた item のうち、未処理で手元にも無いものを返す。
+    pub fn get_wanted(
+        &self,
+        items: Vec<InventoryItem>,
+        pool: &TransactionPool,
+        manager: &BlockchainManager,
+    ) -> Vec<InventoryItem> {
+        items
+            .into_iter()
+            .filter(|item| {
+                !self.seen.contains(item)
+                    && match item {
+                        InventoryItem::Transaction(txid) => pool.get_transaction(txid).is_none(),
+                        InventoryItem::Block(hash) => !manager.has_block(hash),
+                    }
+            })
+            .collect()
+    }
+}
+
+/// GetData で要求された item のうち、手元にあるものを返す。持っていない item は無視する。
+pub fn get_data(
+    items: Vec<InventoryItem>,
+    pool: &TransactionPool,
+    manager: &BlockchainManager,
+) -> Vec<ApplicationPayload> {
+    items
+        .into_iter()
+        .filter_map(|item| match item {
+            InventoryItem::Transaction(txid) => pool
+                .get_transaction(&txid)
+                .map(|transaction| ApplicationPayload::NewTransaction { transaction }),
+            InventoryItem::Block(hash) => manager
+                .get_blocks(&[hash])
+                .pop()
+                .map(|block| ApplicationPayload::NewBlock { block }),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::block::{Block, BlockWithoutProof};
+    use crate::blockchain::difficulty::POW_LIMIT_BITS;
+    use crate::blockchain::transaction::{
+        CoinbaseTransaction, NormalTransaction, TransactionInput, TransactionOutput, Transactions,
+    };
+    use crate::key_manager::KeyManager;
+    use chrono::Utc;
+    use rand::rngs::OsRng;
+
+    /// 1 つの block を持つ manager と、その coinbase を使う transaction を持つ pool を作る。
+    fn setup() -> (TransactionPool, BlockchainManager, Block, NormalTransaction) {
+        let mut km = KeyManager::new(OsRng).unwrap();
+        let mut manager = BlockchainManager::new(POW_LIMIT_BITS);
+        let coinbase = CoinbaseTransaction::new(km.get_address(), 10, Utc::now());
+        let block = BlockWithoutProof::new(
+            Transactions::new(coinbase.clone(), vec![]),
+            manager.get_last_block_hash(),
+            manager.get_next_bits(),
+        )
+        .mine()
+        .unwrap();
+        manager.add_new_block(block.clone()).unwrap();
+
+        let mut transaction = NormalTransaction::new(
+            vec![TransactionInput::new(coinbase.get_id(), 0)],
+            vec![TransactionOutput::new("recipient".to_string(), 10)],
+            Utc::now(),
+        );
+        transaction.sign(&mut km).unwrap();
+        let mut pool = TransactionPool::new();
+        pool.add_new_transaction(transaction.clone(), &manager)
+            .unwrap();
+        (pool, manager, block, transaction)
+    }
+
+    #[test]
+    fn test_accept_announces_to_core_nodes_except_sender() {
+        let mut relay = InventoryRelay::new(MAX_SEEN_INVENTORY);
+        let item = InventoryItem::Transaction("aa".to_string());
+        let from: SocketAddr = "127.0.0.1:50001".parse().unwrap();
+        let others: Vec<SocketAddr> = vec![
+            "127.0.0.1:50002".parse().unwrap(),
+            "127.0.0.1:50003".parse().unwrap(),
+        ];
+        let mut core_nodes = others.clone();
+        core_nodes.insert(1, from);
+
+        assert!(!relay.is_seen(&item));
+        let (payload, addrs) = relay.accept(item.clone(), from, core_nodes);
+        assert_eq!(
+            payload,
+            ApplicationPayload::Inv {
+                items: vec![item.clone()]
+            }
+        );
+        assert_eq!(addrs, others);
+        assert!(relay.is_seen(&item));
+    }
+
+    #[test]
+    fn test_get_wanted_skips_seen_and_known_items() {
+        let (pool, manager, block, transaction) = setup();
+        let mut relay = InventoryRelay::new(MAX_SEEN_INVENTORY);
+        let known_tx = InventoryItem::Transaction(transaction.get_id());
+        let known_block = InventoryItem::Block(block.get_header().get_hash());
+        let new_tx = InventoryItem::Transaction("aa".to_string());
+        let new_block = InventoryItem::Block("bb".to_string());
+        let items = vec![known_tx, known_block, new_tx.clone(), new_block.clone()];
+
+        assert_eq!(
+            relay.get_wanted(items.clone(), &pool, &manager),
+            vec![new_tx.clone(), new_block.clone()]
+        );
+
+        // 受け入れた item や不正だった item は再び取得しない
+        let from: SocketAddr = "127.0.0.1:50001".parse().unwrap();
+        relay.accept(new_tx, from, vec![]);
+        relay.mark_seen(new_block);
+        assert!(relay.get_wanted(items, &pool, &manager).is_empty());
+    }
+
+    #[test]
+    fn test_get_data_returns_only_known_items() {
+        let (pool, manager, block, transaction) = setup();
+        let items = vec![
+            InventoryItem::Transaction("aa".to_string()),
+            InventoryItem::Transaction(transaction.get_id()),
+            InventoryItem::Block("bb".to_string()),
+            InventoryItem::Block(block.get_header().get_hash()),
+        ];
+
+        assert_eq!(
+            get_data(items, &pool, &manager),
+            vec![
+                ApplicationPayload::NewTransaction { transaction },
+                ApplicationPayload::NewBlock { block },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_seen_inventory() {
+        let mut seen = SeenInventory::new(2);
+        let tx = InventoryItem::Transaction("aa".to_string());
+        let block = InventoryItem::Block("aa".to_string());
+
+        assert!(seen.insert(tx.clone()));
+        assert!(!seen.insert(tx.clone()));
+        assert!(seen.contains(&tx));
+        assert!(!seen.contains(&block));
+
+        // 上限を超えると古いものから忘れる
+        assert!(seen.insert(block.clone()));
+        assert!(seen.insert(InventoryItem::Block("bb".to_string())));
+        assert_eq!(seen.len(), 2);
+        assert!(!seen.contains(&tx));
+        assert!(seen.contains(&block));
+    }
+}
@@ -1,6 +1,11 @@
+pub mod address_book;
 pub mod blockchain;
+pub mod connection;
 pub mod connection_manager_core;
 pub mod connection_manager_edge;
+pub mod inventory;
 pub mod key_manager;
 pub mod message;
+pub mod misbehavior;
+pub mod secure_channel;
 pub mod util;
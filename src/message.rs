@@ -1,13 +1,24 @@
-use crate::blockchain::block::Block;
-use crate::blockchain::transaction::{NormalTransaction, TransactionSignature};
+use crate::blockchain::block::{Block, BlockHash, BlockHeader};
+use crate::blockchain::merkle::MerkleProof;
+use crate::blockchain::transaction::{Address, NormalTransaction, Transaction, TransactionId};
+use crate::inventory::InventoryItem;
 use crate::key_manager::KeyManager;
-use crate::util;
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
 use std::net::SocketAddr;
 
-const PROTOCOL_NAME: &str = "simple_bitcoin_protocol";
+pub const PROTOCOL_NAME: &str = "simple_bitcoin_protocol";
 const MY_VERSION: &str = "0.1.0";
+/// handshake で交換する protocol の version
+pub const PROTOCOL_VERSION: u32 = 1;
+/// 接続を受け入れる protocol の最小の version
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+/// Headers で一度に送る header の最大数
+pub const MAX_HEADERS_PER_MESSAGE: usize = 2000;
+/// GetBlocks で一度に要求する block の最大数
+pub const MAX_BLOCKS_PER_MESSAGE: usize = 100;
+/// Inv や GetData で一度に送る item の最大数
+pub const MAX_INV_PER_MESSAGE: usize = 1000;
 
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Message {
@@ -41,6 +52,23 @@ impl Message {
             payload,
         }
     }
+
+    pub fn get_protocol(&self) -> &str {
+        &self.protocol
+    }
+
+    pub fn get_version(&self) -> &str {
+        &self.version
+    }
+}
+
+/// node が提供する機能
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Services {
+    /// block と transaction を中継する Core ノード
+    Core,
+    /// Core ノードにだけ接続する Edge ノード (wallet)
+    Edge,
 }
 
 /// ネットワークで実装されるアプリケーション用のペイロード
@@ -49,31 +77,53 @@ impl Message {
 #[serde(tag = "msg_type")]
 pub enum ApplicationPayload {
     #[serde(rename = "0")]
-    NewTransaction {
-        transaction: NormalTransaction,
-        signature: TransactionSignature,
-    },
+    NewTransaction { transaction: NormalTransaction },
     #[serde(rename = "1")]
     NewBlock { block: Block },
-    #[serde(rename = "2")]
-    RequestFullChain,
-    #[serde(rename = "3")]
-    FullChain { chain: Vec<Block> },
     #[serde(rename = "4")]
     Enhanced { data: Vec<u8> },
+    /// locator の中で最初に main chain 上にある block より後の header を要求する
+    #[serde(rename = "5")]
+    GetHeaders { locator: Vec<BlockHash> },
+    #[serde(rename = "6")]
+    Headers { headers: Vec<BlockHeader> },
+    #[serde(rename = "7")]
+    GetBlocks { hashes: Vec<BlockHash> },
+    #[serde(rename = "8")]
+    Blocks { blocks: Vec<Block> },
+    /// main chain 上で txid の transaction を含む block と MerkleProof を要求する
+    #[serde(rename = "9")]
+    GetMerkleProof { txid: TransactionId },
+    #[serde(rename = "10")]
+    MerkleProof {
+        txid: TransactionId,
+        block_hash: BlockHash,
+        proof: MerkleProof,
+    },
+    /// 新たに受け入れた transaction や block を知らせる
+    #[serde(rename = "11")]
+    Inv { items: Vec<InventoryItem> },
+    /// Inv で知らされた transaction や block を要求する。NewTransaction や NewBlock で返す。
+    #[serde(rename = "12")]
+    GetData { items: Vec<InventoryItem> },
+    /// main chain 上で address に関係する transaction を MerkleProof とともに要求する
+    #[serde(rename = "13")]
+    GetAddressTransactions { address: Address },
+    /// transaction と、それを含む block の hash と MerkleProof
+    #[serde(rename = "14")]
+    AddressTransactions {
+        transactions: Vec<(Transaction, BlockHash, MerkleProof)>,
+    },
 }
 
 impl ApplicationPayload {
+    /// transaction の各 input に署名して NewTransaction を作る。
     pub fn for_transaction(
-        transaction: NormalTransaction,
+        mut transaction: NormalTransaction,
         km: &mut KeyManager,
     ) -> Result<ApplicationPayload> {
-        let data = serde_json::to_string(&transaction)?;
-        let signature = km.sign(data.as_bytes())?;
-        Ok(ApplicationPayload::NewTransaction {
-            transaction,
-            signature: util::bytes_to_hex(&signature),
-        })
+        transaction.sign(km)?;
+        Ok(ApplicationPayload::NewTransaction { transaction })
     }
 }
 
@@ -96,6 +146,25 @@ pub enum Payload {
     RemoveEdge,
     #[serde(rename = "7")]
     Application { payload: ApplicationPayload },
+    /// 接続した直後に互いに送り合い、protocol の version などを伝える
+    #[serde(rename = "8")]
+    Version {
+        protocol_version: u32,
+        services: Services,
+        best_height: usize,
+        nonce: u64,
+    },
+    /// 相手の Version を受け入れたことを伝える
+    #[serde(rename = "9")]
+    VerAck,
+    /// 相手の Version を受け入れられないことを伝える。この後接続は閉じられる。
+    #[serde(rename = "10")]
+    Reject { reason: String },
+    /// 相手が知っている Core ノードの address を要求する
+    #[serde(rename = "11")]
+    GetAddr,
+    #[serde(rename = "12")]
+    Addr { addrs: Vec<SocketAddr> },
 }
 
 #[cfg(test)]
@@ -175,4 +244,39 @@ mod tests {
         let actual: Message = serde_json::from_str(&actual[..]).unwrap();
         assert_eq!(actual, message);
     }
+
+    #[test]
+    fn test_round_trip_message_version() {
+        let message = Message::new(
+            12345,
+            Payload::Version {
+                protocol_version: PROTOCOL_VERSION,
+                services: Services::Edge,
+                best_height: 10,
+                nonce: 42,
+            },
+        );
+
+        let actual = serde_json::to_string(&message).unwrap();
+        let actual: Message = serde_json::from_str(&actual[..]).unwrap();
+        assert_eq!(actual, message);
+        assert_eq!(actual.get_protocol(), PROTOCOL_NAME);
+    }
+
+    #[test]
+    fn test_round_trip_message_addr() {
+        let message = Message::new(
+            12345,
+            Payload::Addr {
+                addrs: vec![
+                    SocketAddr::from_str("127.0.0.1:50001").unwrap(),
+                    SocketAddr::from_str("127.0.0.1:50002").unwrap(),
+                ],
+            },
+        );
+
+        let actual = serde_json::to_string(&message).unwrap();
+        let actual: Message = serde_json::from_str(&actual[..]).unwrap();
+        assert_eq!(actual, message);
+    }
 }
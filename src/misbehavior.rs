@@ -0,0 +1,289 @@
+use chrono::{DateTime, Utc};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::time::Duration;
+
+/// misbehavior の点数の合計がこの値に達した node は ban する
+pub const BAN_THRESHOLD: u32 = 100;
+/// ban する期間の既定値
+pub const DEFAULT_BAN_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+/// misbehavior の点数が 1 時間あたりに減る量
+pub const SCORE_DECAY_PER_HOUR: u32 = 10;
+/// 点数を記録する IP address の最大数. 超えた場合は点数の最も低いものを忘れる。
+pub const MAX_SCORE_ENTRIES: usize = 10_000;
+
+/// node の不正な振る舞い
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Misbehavior {
+    /// frame や Message として解釈できないデータを送ってきた
+    MalformedMessage,
+    /// 上限を超える大きさのデータを送ってきた
+    OversizedMessage,
+    /// 想定していない Message を送ってきた
+    ProtocolViolation,
+    /// 規則に違反した transaction を送ってきた
+    InvalidTransaction,
+    /// 署名が正しくない transaction を送ってきた
+    InvalidSignature,
+    /// proof of work や merkle root などが正しくない block や header を送ってきた
+    InvalidBlock,
+}
+
+impl Misbehavior {
+    /// 振る舞いごとの点数. 偶然起こり得ない振る舞いほど高い。
+    pub fn get_score(&self) -> u32 {
+        match self {
+            Misbehavior::MalformedMessage => 20,
+            Misbehavior::OversizedMessage => 50,
+            Misbehavior::ProtocolViolation => 10,
+            Misbehavior::InvalidTransaction => 10,
+            Misbehavior::InvalidSignature => 50,
+            Misbehavior::InvalidBlock => 100,
+        }
+    }
+}
+
+impl fmt::Display for Misbehavior {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Misbehavior::MalformedMessage => write!(f, "Malformed message"),
+            Misbehavior::OversizedMessage => write!(f, "Oversized message"),
+            Misbehavior::ProtocolViolation => write!(f, "Protocol violation"),
+            Misbehavior::InvalidTransaction => write!(f, "Invalid transaction"),
+            Misbehavior::InvalidSignature => write!(f, "Invalid signature"),
+            Misbehavior::InvalidBlock => write!(f, "Invalid block"),
+        }
+    }
+}
+
+/// ban されている node の情報
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BanEntry {
+    ip: IpAddr,
+    banned_until: DateTime<Utc>,
+    reason: String,
+}
+
+impl BanEntry {
+    pub fn get_ip(&self) -> IpAddr {
+        self.ip
+    }
+
+    pub fn get_banned_until(&self) -> DateTime<Utc> {
+        self.banned_until
+    }
+
+    pub fn get_reason(&self) -> &str {
+        &self.reason
+    }
+}
+
+// 最後に加算した時刻から時間と共に減っていく点数
+struct Score {
+    score: u32,
+    updated_at: DateTime<Utc>,
+}
+
+impl Score {
+    fn get_current(&self, now: DateTime<Utc>) -> u32 {
+        let elapsed = now
+            .signed_duration_since(self.updated_at)
+            .num_seconds()
+            .max(0) as u64;
+        let decay = elapsed.saturating_mul(SCORE_DECAY_PER_HOUR as u64) / (60 * 60);
+        self.score.saturating_sub(decay.min(u32::MAX as u64) as u32)
+    }
+}
+
+/// node ごとの misbehavior の点数と ban の一覧.
+/// node は IP address で区別する。待ち受ける port は相手が自由に名乗れるので使わない。
+pub struct BanList {
+    scores: HashMap<IpAddr, Score>,
+    bans: HashMap<IpAddr, BanEntry>,
+    ban_duration: Duration,
+}
+
+impl BanList {
+    pub fn new(ban_duration: Duration) -> BanList {
+        BanList {
+            scores: HashMap::new(),
+            bans: HashMap::new(),
+            ban_duration,
+        }
+    }
+
+    /// 点数を加算し、閾値に達した場合は ban する。新たに ban した場合は true を返す。
+    pub fn misbehaving(&mut self, ip: IpAddr, misbehavior: Misbehavior) -> bool {
+        if self.is_banned(&ip) {
+            return false;
+        }
+        let now = Utc::now();
+        if !self.scores.contains_key(&ip) && self.scores.len() >= MAX_SCORE_ENTRIES {
+            self.forget_lowest_score(now);
+        }
+        let score = self.get_score(&ip) + misbehavior.get_score();
+        self.scores.insert(
+            ip,
+            Score {
+                score,
+                updated_at: now,
+            },
+        );
+        warn!("Misbehavior of {}: {} (score: {})", ip, misbehavior, score);
+        if score < BAN_THRESHOLD {
+            return false;
+        }
+        self.ban(ip, misbehavior.to_string());
+        true
+    }
+
+    // 点数の最も低い IP address の記録を消す
+    fn forget_lowest_score(&mut self, now: DateTime<Utc>) {
+        let lowest = self
+            .scores
+            .iter()
+            .min_by_key(|(_, score)| score.get_current(now))
+            .map(|(ip, _)| *ip);
+        if let Some(ip) = lowest {
+            self.scores.remove(&ip);
+        }
+    }
+
+    /// ban_duration の間 ip を ban する。
+    pub fn ban(&mut self, ip: IpAddr, reason: String) {
+        let banned_until = chrono::Duration::from_std(self.ban_duration)
+            .ok()
+            .and_then(|duration| Utc::now().checked_add_signed(duration))
+            .unwrap_or(chrono::MAX_DATETIME);
+        warn!("Ban {} until {}: {}", ip, banned_until, reason);
+        self.scores.remove(&ip);
+        self.bans.insert(
+            ip,
+            BanEntry {
+                ip,
+                banned_until,
+                reason,
+            },
+        );
+    }
+
+    /// ban を解除する。ban されていた場合は true を返す。
+    pub fn unban(&mut self, ip: &IpAddr) -> bool {
+        self.bans.remove(ip).is_some()
+    }
+
+    pub fn is_banned(&self, ip: &IpAddr) -> bool {
+        match self.bans.get(ip) {
+            Some(entry) => entry.banned_until > Utc::now(),
+            None => false,
+        }
+    }
+
+    /// 時間による減少を反映した現在の点数
+    pub fn get_score(&self, ip: &IpAddr) -> u32 {
+        self.scores
+            .get(ip)
+            .map(|score| score.get_current(Utc::now()))
+            .unwrap_or(0)
+    }
+
+    /// 期限が切れていない ban の一覧
+    pub fn get_bans(&self) -> Vec<BanEntry> {
+        let now = Utc::now();
+        let mut bans: Vec<BanEntry> = self
+            .bans
+            .values()
+            .filter(|entry| entry.banned_until > now)
+            .cloned()
+            .collect();
+        bans.sort_by_key(|entry| entry.ip);
+        bans
+    }
+
+    /// 期限が切れた ban と、0 まで減った点数を取り除く。
+    pub fn remove_expired(&mut self) {
+        let now = Utc::now();
+        self.bans.retain(|_, entry| entry.banned_until > now);
+        self.scores.retain(|_, score| score.get_current(now) > 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn ip(s: &str) -> IpAddr {
+        IpAddr::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn test_ban_after_threshold() {
+        let mut ban_list = BanList::new(DEFAULT_BAN_DURATION);
+        let addr = ip("127.0.0.1");
+        let other = ip("127.0.0.2");
+
+        assert!(!ban_list.misbehaving(addr, Misbehavior::InvalidSignature));
+        assert!(!ban_list.misbehaving(other, Misbehavior::ProtocolViolation));
+        assert_eq!(ban_list.get_score(&addr), 50);
+        assert!(!ban_list.is_banned(&addr));
+
+        assert!(ban_list.misbehaving(addr, Misbehavior::OversizedMessage));
+        assert!(ban_list.is_banned(&addr));
+        assert!(!ban_list.is_banned(&other));
+        assert!(!ban_list.misbehaving(addr, Misbehavior::InvalidBlock));
+
+        let bans = ban_list.get_bans();
+        assert_eq!(bans.len(), 1);
+        assert_eq!(bans[0].get_ip(), addr);
+        assert_eq!(bans[0].get_reason(), "Oversized message");
+
+        assert!(ban_list.unban(&addr));
+        assert!(!ban_list.is_banned(&addr));
+        assert_eq!(ban_list.get_score(&addr), 0);
+        assert!(!ban_list.unban(&addr));
+    }
+
+    #[test]
+    fn test_ban_expires() {
+        let mut ban_list = BanList::new(Duration::from_secs(0));
+        let addr = ip("127.0.0.1");
+        assert!(ban_list.misbehaving(addr, Misbehavior::InvalidBlock));
+        assert!(!ban_list.is_banned(&addr));
+        assert!(ban_list.get_bans().is_empty());
+        ban_list.remove_expired();
+        assert!(!ban_list.unban(&addr));
+    }
+
+    #[test]
+    fn test_score_decays_and_is_capped() {
+        let mut ban_list = BanList::new(DEFAULT_BAN_DURATION);
+        let addr = ip("127.0.0.1");
+        ban_list.scores.insert(
+            addr,
+            Score {
+                score: 50,
+                updated_at: Utc::now() - chrono::Duration::hours(2),
+            },
+        );
+        assert_eq!(ban_list.get_score(&addr), 50 - SCORE_DECAY_PER_HOUR * 2);
+        assert!(!ban_list.misbehaving(addr, Misbehavior::InvalidSignature));
+        assert_eq!(ban_list.get_score(&addr), 80);
+
+        ban_list.scores.get_mut(&addr).unwrap().updated_at = Utc::now() - chrono::Duration::days(1);
+        assert_eq!(ban_list.get_score(&addr), 0);
+        ban_list.remove_expired();
+        assert!(ban_list.scores.is_empty());
+
+        for i in 0..MAX_SCORE_ENTRIES {
+            let other = IpAddr::from([10, (i >> 16) as u8, (i >> 8) as u8, i as u8]);
+            ban_list.misbehaving(other, Misbehavior::ProtocolViolation);
+        }
+        ban_list.misbehaving(addr, Misbehavior::InvalidSignature);
+        assert_eq!(ban_list.scores.len(), MAX_SCORE_ENTRIES);
+        assert_eq!(ban_list.get_score(&addr), 50);
+    }
+}
@@ -0,0 +1,255 @@
+use crate::blockchain::encoding::{self, Decode, Encode, Reader};
+use crate::blockchain::transaction::Address;
+use crate::key_manager::KeyManager;
+use crate::util;
+use anyhow::{anyhow, bail, Context, Result};
+use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use rsa::pkcs1::FromRsaPublicKey;
+use rsa::RsaPublicKey;
+use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey as DhPublicKey, StaticSecret};
+
+/// handshake の transcript の先頭に置く protocol の名前
+const PROTOCOL_LABEL: &[u8] = b"simple_bitcoin_secure_channel_v1";
+const INITIATOR_KEY_INFO: &[u8] = b"initiator to responder";
+const RESPONDER_KEY_INFO: &[u8] = b"responder to initiator";
+
+/// 一方向の暗号化の状態. 送った frame の数を nonce として使う。
+pub struct CipherState {
+    cipher: ChaCha20Poly1305,
+    nonce: u64,
+}
+
+impl CipherState {
+    fn new(key: &[u8; 32]) -> CipherState {
+        CipherState {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(key)),
+            nonce: 0,
+        }
+    }
+
+    fn next_nonce(&mut self) -> Result<Nonce> {
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&self.nonce.to_be_bytes());
+        self.nonce = self
+            .nonce
+            .checked_add(1)
+            .context("Nonce of secure channel is exhausted")?;
+        Ok(*Nonce::from_slice(&nonce))
+    }
+
+    pub fn encrypt(&mut self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let nonce = self.next_nonce()?;
+        self.cipher
+            .encrypt(&nonce, plaintext)
+            .map_err(|_| anyhow!("Failed to encrypt frame"))
+    }
+
+    /// 改ざんされた frame や、順番が入れ替わった frame はエラーになる。
+    pub fn decrypt(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>> {
+        let nonce = self.next_nonce()?;
+        self.cipher
+            .decrypt(&nonce, ciphertext)
+            .map_err(|_| anyhow!("Failed to decrypt frame (tampered or out of order)"))
+    }
+}
+
+/// 鍵交換の前の状態. 接続ごとに使い捨ての DH 鍵を持つ。
+pub struct HandshakeState {
+    secret: StaticSecret,
+    public: DhPublicKey,
+    initiator: bool,
+}
+
+/// 両者の一時公開鍵から作る hash. node の鍵で署名して相手を認証する。
+pub struct Transcript(Vec<u8>);
+
+/// 鍵交換の結果
+pub struct SessionKeys {
+    pub transcript: Transcript,
+    pub sender: CipherState,
+    pub receiver: CipherState,
+}
+
+impl HandshakeState {
+    /// initiator は接続した側
+    pub fn new(initiator: bool) -> HandshakeState {
+        let secret = StaticSecret::from(rand::random::<[u8; 32]>());
+        let public = DhPublicKey::from(&secret);
+        HandshakeState {
+            secret,
+            public,
+            initiator,
+        }
+    }
+
+    pub fn get_public_key(&self) -> [u8; 32] {
+        self.public.to_bytes()
+    }
+
+    /// 相手の一時公開鍵と DH を行い、送信用と受信用の鍵を導出する。
+    pub fn derive_keys(self, remote_public: &[u8]) -> Result<SessionKeys> {
+        if remote_public.len() != 32 {
+            bail!(
+                "Invalid length of ephemeral key: {} bytes",
+                remote_public.len()
+            );
+        }
+        let mut remote = [0u8; 32];
+        remote.copy_from_slice(remote_public);
+        let shared = self.secret.diffie_hellman(&DhPublicKey::from(remote));
+        if shared.as_bytes() == &[0u8; 32] {
+            bail!("Ephemeral key of peer is a low order point");
+        }
+
+        let (initiator_public, responder_public) = if self.initiator {
+            (self.public.to_bytes(), remote)
+        } else {
+            (remote, self.public.to_bytes())
+        };
+        let mut hasher = Sha256::new();
+        hasher.update(PROTOCOL_LABEL);
+        hasher.update(initiator_public);
+        hasher.update(responder_public);
+        let transcript = hasher.finalize().to_vec();
+
+        let hkdf = Hkdf::<Sha256>::new(Some(&transcript), shared.as_bytes());
+        let mut initiator_key = [0u8; 32];
+        let mut responder_key = [0u8; 32];
+        hkdf.expand(INITIATOR_KEY_INFO, &mut initiator_key)
+            .map_err(|_| anyhow!("Failed to derive key"))?;
+        hkdf.expand(RESPONDER_KEY_INFO, &mut responder_key)
+            .map_err(|_| anyhow!("Failed to derive key"))?;
+        let (sender, receiver) = if self.initiator {
+            (initiator_key, responder_key)
+        } else {
+            (responder_key, initiator_key)
+        };
+
+        Ok(SessionKeys {
+            transcript: Transcript(transcript),
+            sender: CipherState::new(&sender),
+            receiver: CipherState::new(&receiver),
+        })
+    }
+}
+
+/// node の鍵で transcript に署名したもの. 暗号化した上で送り合う。
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AuthMessage {
+    address: Address,
+    signature: Vec<u8>,
+}
+
+impl Encode for AuthMessage {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.address.encode(buf);
+        self.signature.encode(buf);
+    }
+}
+
+impl Decode for AuthMessage {
+    fn decode(reader: &mut Reader) -> Result<AuthMessage> {
+        Ok(AuthMessage {
+            address: String::decode(reader)?,
+            signature: Vec::decode(reader)?,
+        })
+    }
+}
+
+/// 署名する data. 相手の署名を送り返されても通らないように役割を含める。
+fn signing_data(transcript: &[u8], initiator: bool) -> Vec<u8> {
+    let mut data = transcript.to_vec();
+    data.push(initiator as u8);
+    data
+}
+
+impl Transcript {
+    /// 自分の node の鍵で transcript に署名する。
+    pub fn sign(&self, key_manager: &mut KeyManager, initiator: bool) -> Result<Vec<u8>> {
+        let auth = AuthMessage {
+            address: key_manager.get_address(),
+            signature: key_manager.sign(&signing_data(&self.0, initiator))?,
+        };
+        Ok(encoding::to_bytes(&auth))
+    }
+
+    /// 相手の署名を確認し、相手の address (公開鍵) を返す。
+    pub fn verify(&self, auth: &[u8], initiator: bool) -> Result<Address> {
+        let auth: AuthMessage = encoding::from_bytes(auth)?;
+        let der = util::hex_to_bytes(auth.address.clone())?;
+        let public_key =
+            RsaPublicKey::from_pkcs1_der(&der).context("Invalid public key of peer")?;
+        util::verify_signature(
+            &auth.signature,
+            &public_key,
+            &signing_data(&self.0, initiator),
+        )
+        .context("Invalid signature of peer")?;
+        Ok(auth.address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn derive_pair() -> (SessionKeys, SessionKeys) {
+        let initiator = HandshakeState::new(true);
+        let responder = HandshakeState::new(false);
+        let initiator_public = initiator.get_public_key();
+        let responder_public = responder.get_public_key();
+        (
+            initiator.derive_keys(&responder_public).unwrap(),
+            responder.derive_keys(&initiator_public).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_cipher_state() {
+        let (mut initiator, mut responder) = derive_pair();
+        assert_eq!(initiator.transcript.0, responder.transcript.0);
+
+        for data in [&b"hello"[..], b"", b"world"] {
+            let encrypted = initiator.sender.encrypt(data).unwrap();
+            assert_ne!(encrypted, data);
+            assert_eq!(responder.receiver.decrypt(&encrypted).unwrap(), data);
+        }
+        let encrypted = responder.sender.encrypt(b"reply").unwrap();
+        assert_eq!(initiator.receiver.decrypt(&encrypted).unwrap(), b"reply");
+
+        // tampered frame
+        let mut encrypted = initiator.sender.encrypt(b"hello").unwrap();
+        encrypted[0] ^= 1;
+        assert!(responder.receiver.decrypt(&encrypted).is_err());
+
+        // replayed or reordered frame
+        let (mut initiator, mut responder) = derive_pair();
+        let first = initiator.sender.encrypt(b"first").unwrap();
+        let second = initiator.sender.encrypt(b"second").unwrap();
+        assert!(responder.receiver.decrypt(&second).is_err());
+        assert!(responder.receiver.decrypt(&first).is_err());
+
+        // invalid ephemeral keys
+        assert!(HandshakeState::new(true).derive_keys(&[1; 31]).is_err());
+        assert!(HandshakeState::new(true).derive_keys(&[0; 32]).is_err());
+    }
+
+    #[test]
+    fn test_sign_and_verify_transcript() {
+        let mut km = KeyManager::new(rand::rngs::OsRng).unwrap();
+        let (initiator, responder) = derive_pair();
+
+        let auth = initiator.transcript.sign(&mut km, true).unwrap();
+        let transcript = responder.transcript;
+        assert_eq!(transcript.verify(&auth, true).unwrap(), km.get_address());
+
+        // signature for the other role
+        assert!(transcript.verify(&auth, false).is_err());
+        // signature for another session
+        let (_, other) = derive_pair();
+        assert!(other.transcript.verify(&auth, true).is_err());
+    }
+}
@@ -0,0 +1,77 @@
+use actix_web::{delete, get, web, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use simple_bitcoin::blockchain::manager::BlockchainManager;
+use simple_bitcoin::blockchain::subsidy::MAX_SUPPLY;
+use simple_bitcoin::connection_manager_core::ConnectionManagerInner;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+/// node の管理者向けの API. 外部に公開しない address で待ち受けること。
+pub struct AdminState {
+    connection_manager: Arc<Mutex<ConnectionManagerInner>>,
+    blockchain_manager: Arc<Mutex<BlockchainManager>>,
+}
+
+impl AdminState {
+    pub fn new(
+        connection_manager: Arc<Mutex<ConnectionManagerInner>>,
+        blockchain_manager: Arc<Mutex<BlockchainManager>>,
+    ) -> AdminState {
+        AdminState {
+            connection_manager,
+            blockchain_manager,
+        }
+    }
+}
+
+#[get("/bans")]
+async fn get_bans(state: web::Data<AdminState>) -> impl Responder {
+    let bans = state.connection_manager.lock().unwrap().get_bans();
+    web::Json(bans)
+}
+
+#[delete("/bans/{ip}")]
+async fn delete_ban(ip: web::Path<IpAddr>, state: web::Data<AdminState>) -> impl Responder {
+    if state.connection_manager.lock().unwrap().unban(&ip) {
+        HttpResponse::NoContent().finish()
+    } else {
+        HttpResponse::NotFound().json(json!({"error": "The node is not banned."}))
+    }
+}
+
+#[derive(Deserialize)]
+struct GetSupplyQuery {
+    height: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct GetSupplyResponse {
+    height: usize,
+    supply: u64,
+    max_supply: u64,
+}
+
+// height の block までに発行された金額. height を省略した場合は main chain の先頭まで
+#[get("/supply")]
+async fn get_supply(
+    query: web::Query<GetSupplyQuery>,
+    state: web::Data<AdminState>,
+) -> impl Responder {
+    let manager = state.blockchain_manager.lock().unwrap();
+    let height = query.height.unwrap_or_else(|| manager.get_height());
+    match manager.get_supply(height) {
+        Some(supply) => HttpResponse::Ok().json(GetSupplyResponse {
+            height,
+            supply,
+            max_supply: MAX_SUPPLY,
+        }),
+        None => HttpResponse::NotFound().json(json!({"error": "The height is beyond the chain."})),
+    }
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_bans)
+        .service(delete_ban)
+        .service(get_supply);
+}
@@ -1,16 +1,24 @@
-use anyhow::{anyhow, Result};
+use actix_web::{web, App, HttpServer};
+use anyhow::{anyhow, bail, Result};
 use clap::Parser;
 use futures::StreamExt;
+use log::info;
 use rand::rngs::OsRng;
 use server_core::ServerCore;
 use signal_hook::consts::signal::*;
 use signal_hook_tokio::Signals;
+use simple_bitcoin::address_book::ADDRESS_BOOK_FILE_NAME;
+use simple_bitcoin::blockchain::difficulty::INITIAL_BITS;
 use simple_bitcoin::blockchain::manager::BlockchainManager;
-use simple_bitcoin::blockchain::transaction_pool::TransactionPool;
-use simple_bitcoin::key_manager::KeyManager;
+use simple_bitcoin::blockchain::store::FileBlockStore;
+use simple_bitcoin::blockchain::transaction_pool::{TransactionPool, MEMPOOL_FILE_NAME};
+use simple_bitcoin::key_manager::{KeyManager, KEY_PASSPHRASE_ENV};
 use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
+mod admin;
 pub mod server_core;
 
 /// Simple Bitcoin server
@@ -19,16 +27,39 @@ pub mod server_core;
 struct Args {
     #[clap(short, long)]
     listen_addr: String,
+    /// Core node to join. Peers saved in the data directory are also tried.
     #[clap(short, long)]
     core_addr: Option<String>,
+    /// Directory to store blocks, known peers and unconfirmed transactions.
+    /// They are kept only in memory if not specified.
+    #[clap(short, long)]
+    data_dir: Option<PathBuf>,
+    /// PEM file of the private key. It is created on first run.
+    /// The passphrase can be given by SIMPLE_BITCOIN_KEY_PASSPHRASE.
+    #[clap(short, long)]
+    key_file: Option<PathBuf>,
+    /// Encrypt connections and authenticate nodes with their keys.
+    /// All nodes in the network must enable it.
+    #[clap(long)]
+    secure_transport: bool,
+    /// Seconds to ban a node which misbehaves.
+    #[clap(long, default_value_t = 24 * 60 * 60)]
+    ban_duration: u64,
+    /// Address for the admin API. It must not be exposed to others.
+    #[clap(long)]
+    admin_addr: Option<String>,
+    /// Number of threads to mine blocks.
+    #[clap(long, default_value_t = 1)]
+    mining_threads: usize,
+    /// Seconds to keep a transaction in the pool until it is included in a block.
+    #[clap(long, default_value_t = 14 * 24 * 60 * 60)]
+    mempool_expiry: u64,
 }
 
 async fn handle_signals(mut signals: Signals) {
-    while let Some(signal) = signals.next().await {
+    if let Some(signal) = signals.next().await {
         match signal {
-            SIGTERM | SIGINT | SIGQUIT => {
-                break;
-            }
+            SIGTERM | SIGINT | SIGQUIT => {}
             _ => unreachable!(),
         }
     }
@@ -44,27 +75,84 @@ fn convert_to_addr(s: String) -> Result<SocketAddr> {
 async fn main() -> Result<()> {
     env_logger::init();
 
-    let signals = Signals::new(&[SIGTERM, SIGINT, SIGQUIT])?;
+    let signals = Signals::new([SIGTERM, SIGINT, SIGQUIT])?;
     let handle = signals.handle();
     let signal_task = tokio::spawn(handle_signals(signals));
 
     let rng = OsRng;
 
-    let bm = Arc::new(Mutex::new(BlockchainManager::new(3)));
-    let tp = Arc::new(Mutex::new(TransactionPool::new()));
-    let km = Arc::new(Mutex::new(KeyManager::new(rng).unwrap()));
-
     let args = Args::parse();
+    if args.mining_threads == 0 {
+        bail!("--mining-threads must be at least 1");
+    }
+
+    let bm = match &args.data_dir {
+        Some(data_dir) => {
+            let store = FileBlockStore::open(data_dir)?;
+            BlockchainManager::with_store(INITIAL_BITS, Box::new(store))?
+        }
+        None => BlockchainManager::new(INITIAL_BITS),
+    };
+    let bm = Arc::new(Mutex::new(bm));
+    let mut tp = TransactionPool::new();
+    tp.set_expiry(Duration::from_secs(args.mempool_expiry));
+    let tp = Arc::new(Mutex::new(tp));
+    let km = match args.key_file {
+        Some(key_file) => {
+            let passphrase = std::env::var(KEY_PASSPHRASE_ENV).ok();
+            KeyManager::load_or_create(key_file, passphrase.as_deref(), rng)?
+        }
+        None => KeyManager::new(rng)?,
+    };
+    let km = Arc::new(Mutex::new(km));
+
     let listen_addr = convert_to_addr(args.listen_addr)?;
     let core_addr = args.core_addr.map(|x| convert_to_addr(x).unwrap());
 
     let mut core = ServerCore::new(listen_addr, core_addr, tp, bm, km);
-    core.start().await;
+    if let Some(data_dir) = &args.data_dir {
+        core.load_address_book(data_dir.join(ADDRESS_BOOK_FILE_NAME))?;
+        core.load_transaction_pool(data_dir.join(MEMPOOL_FILE_NAME))?;
+    }
+    if args.secure_transport {
+        core.enable_secure_transport();
+    }
+    core.set_ban_duration(Duration::from_secs(args.ban_duration));
+    core.set_mining_threads(args.mining_threads);
+
+    let admin_server = match args.admin_addr {
+        Some(admin_addr) => {
+            let admin_addr = convert_to_addr(admin_addr)?;
+            info!("admin api binds at {}", admin_addr);
+            let app_data = web::Data::new(admin::AdminState::new(
+                core.get_connection_manager(),
+                core.get_blockchain_manager(),
+            ));
+            let server = HttpServer::new(move || {
+                App::new()
+                    .configure(admin::config)
+                    .app_data(app_data.clone())
+            })
+            .bind((admin_addr.ip().to_string(), admin_addr.port()))?
+            .disable_signals()
+            .run();
+            let server_handle = server.handle();
+            tokio::spawn(server);
+            Some(server_handle)
+        }
+        None => None,
+    };
+
+    core.start().await?;
     core.join_network().await;
 
     signal_task.await?;
     handle.close();
 
+    if let Some(server_handle) = admin_server {
+        server_handle.stop(true).await;
+    }
+
     core.shutdown().await;
 
     Ok(())
@@ -1,16 +1,26 @@
-use anyhow::Context;
+use anyhow::Result;
 use chrono::Utc;
-use log::{debug, info, warn};
-use simple_bitcoin::blockchain::manager::BlockchainManager;
+use log::{debug, error, info, warn};
+use simple_bitcoin::address_book::AddressBook;
+use simple_bitcoin::blockchain::block::BlockHash;
+use simple_bitcoin::blockchain::manager::{BlockchainManager, ChainUpdate};
+use simple_bitcoin::blockchain::miner::Miner;
 use simple_bitcoin::blockchain::transaction::{
-    NormalTransaction, TransactionInput, TransactionOutput,
+    self, NormalTransaction, TransactionError, TransactionInput, TransactionOutput,
 };
-use simple_bitcoin::blockchain::transaction_pool::TransactionPool;
-use simple_bitcoin::connection_manager_core::{ApplicationPayloadHandler, ConnectionManagerCore};
+use simple_bitcoin::blockchain::transaction_pool::{MiningCanceller, TransactionPool};
+use simple_bitcoin::connection_manager_core::{
+    ApplicationPayloadHandler, ConnectionManagerCore, ConnectionManagerInner, PeerKind,
+};
+use simple_bitcoin::inventory::{self, InventoryItem, InventoryRelay, MAX_SEEN_INVENTORY};
 use simple_bitcoin::key_manager::KeyManager;
-use simple_bitcoin::message::ApplicationPayload;
-use simple_bitcoin::util;
+use simple_bitcoin::message::{
+    ApplicationPayload, Message, Payload, MAX_BLOCKS_PER_MESSAGE, MAX_HEADERS_PER_MESSAGE,
+    MAX_INV_PER_MESSAGE,
+};
+use simple_bitcoin::misbehavior::Misbehavior;
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
@@ -28,142 +38,359 @@ pub struct ServerCore {
     bm: Arc<Mutex<BlockchainManager>>,
     tp: Arc<Mutex<TransactionPool>>,
     km: Arc<Mutex<KeyManager>>,
+    miner: Miner,
+    mining_canceller: MiningCanceller,
+    mempool_path: Option<PathBuf>,
 }
 
+// pool の有効期限の確認と保存の間隔
+const POOL_MAINTENANCE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
 fn generate_application_payload_handler(
     transaction_pool: Arc<Mutex<TransactionPool>>,
     blockchain_manager: Arc<Mutex<BlockchainManager>>,
     key_manager: Arc<Mutex<KeyManager>>,
+    mining_canceller: MiningCanceller,
 ) -> impl ApplicationPayloadHandler {
+    // 一度処理した transaction や block は再び取得も中継もしない
+    let relay = Mutex::new(InventoryRelay::new(MAX_SEEN_INVENTORY));
+
     // An implementation of ApplicationPayloadHandler
     move |payload: ApplicationPayload,
           peer: SocketAddr,
           core_nodes: Vec<SocketAddr>,
-          is_core: bool| {
+          peer_kind: PeerKind| {
         debug!("handle_application_payload: {:?}", payload);
         match payload {
-            ApplicationPayload::NewTransaction {
-                transaction,
-                signature,
-            } => {
+            ApplicationPayload::NewTransaction { transaction } => {
+                let item = InventoryItem::Transaction(transaction.get_id());
                 let blockchain_manager = blockchain_manager.lock().unwrap();
                 let mut transaction_pool = transaction_pool.lock().unwrap();
-
-                let public_key = transaction.get_input_pubkey();
-                let data = serde_json::to_string(&transaction).unwrap();
-                let sig_bytes = util::hex_to_bytes(signature.clone());
-                if let Err(err) = util::verify_signature(&sig_bytes, &public_key, data.as_bytes()) {
-                    warn!("Invalid transaction signature: {:?}", err);
-                    return None;
+                let mut relay = relay.lock().unwrap();
+                if relay.is_seen(&item) {
+                    debug!("Transaction {:?} is already processed", item);
+                    return Ok(vec![]);
                 }
 
-                if let Err(err) = blockchain_manager.is_valid_transaction(&transaction) {
+                if let Err(err) =
+                    blockchain_manager.is_valid_transaction_in_pool(&transaction, &transaction_pool)
+                {
                     warn!("Invalid transaction: {:?}", err);
-                    return None;
+                    return match err {
+                        // 参照先が無いのは chain の同期が遅れているだけかもしれないので、
+                        // 後で受け取り直せるように処理済みにしない
+                        TransactionError::UnknownInput(_) => Ok(vec![]),
+                        TransactionError::InvalidSignature(_) => {
+                            relay.mark_seen(item);
+                            Err(Misbehavior::InvalidSignature)
+                        }
+                        _ => {
+                            relay.mark_seen(item);
+                            Err(Misbehavior::InvalidTransaction)
+                        }
+                    };
                 }
 
-                for input in transaction.get_inputs() {
-                    if transaction_pool.has_transaction_input(&input) {
-                        warn!(
-                            "Invalid transaction because {:?} is already in transaction pool.",
-                            input
-                        );
-                        return None;
-                    }
-                }
-
-                transaction_pool.add_new_transaction(transaction.clone());
-
-                if !is_core {
-                    let new_payload = ApplicationPayload::NewTransaction {
-                        transaction,
-                        signature,
-                    };
-                    Some((new_payload, core_nodes))
-                } else {
-                    None
+                if let Err(err) =
+                    transaction_pool.add_new_transaction(transaction, &*blockchain_manager)
+                {
+                    warn!("Transaction is not added to transaction pool: {}", err);
+                    return Ok(vec![]);
                 }
+                Ok(vec![relay.accept(item, peer, core_nodes)])
             }
             ApplicationPayload::NewBlock { block } => {
+                // 不正な block や親の無い block は、後で受け取り直せるように処理済みにしない
+                let item = InventoryItem::Block(block.get_header().get_hash());
                 let mut blockchain_manager = blockchain_manager.lock().unwrap();
                 let mut transaction_pool = transaction_pool.lock().unwrap();
 
-                if let Err(err) = blockchain_manager.is_valid_block(&block) {
-                    warn!("Invalid block: {}", err);
+                match blockchain_manager.add_new_block(block) {
+                    Ok(ChainUpdate::MissingParent) => {
+                        info!("Parent of received block is unknown, request headers");
+                        let payload = ApplicationPayload::GetHeaders {
+                            locator: blockchain_manager.get_block_locator(),
+                        };
+                        return Ok(vec![(payload, vec![peer])]);
+                    }
+                    Ok(ChainUpdate::NewTip {
+                        orphan_transactions,
+                    }) => {
+                        // 古い先頭の上での mining は無駄になる
+                        mining_canceller.cancel();
+                        debug!(
+                            "Current blockchain is: {:?}",
+                            blockchain_manager.get_chain()
+                        );
+                        // 外れた block の transaction を先に戻し、それを使う pool 内の子を残す
+                        blockchain_manager.restore_orphan_transactions(
+                            &mut transaction_pool,
+                            orphan_transactions,
+                        );
+                        blockchain_manager.remove_useless_transactions(&mut transaction_pool);
+                    }
+                    Ok(ChainUpdate::SideChain) => debug!("Received block is not in main chain"),
+                    Ok(ChainUpdate::AlreadyKnown) => return Ok(vec![]),
+                    Err(err) => {
+                        warn!("Invalid block: {:?}", err);
+                        return Err(Misbehavior::InvalidBlock);
+                    }
+                }
 
-                    let payload = ApplicationPayload::RequestFullChain;
-                    return Some((payload, vec![peer]));
+                Ok(vec![relay.lock().unwrap().accept(item, peer, core_nodes)])
+            }
+            ApplicationPayload::GetHeaders { locator } => {
+                let headers = blockchain_manager
+                    .lock()
+                    .unwrap()
+                    .get_headers_after(&locator, MAX_HEADERS_PER_MESSAGE);
+                debug!("Send {} headers for reply to {}", headers.len(), peer);
+                let payload = ApplicationPayload::Headers { headers };
+                Ok(vec![(payload, vec![peer])])
+            }
+            ApplicationPayload::Headers { headers } => {
+                if peer_kind != PeerKind::Core {
+                    warn!("Headers received from unknown");
+                    return Err(Misbehavior::ProtocolViolation);
+                }
+                if headers.len() > MAX_HEADERS_PER_MESSAGE {
+                    warn!("Too many headers from {}: {}", peer, headers.len());
+                    return Err(Misbehavior::OversizedMessage);
                 }
 
-                blockchain_manager.add_new_block(block);
+                let hashes = match blockchain_manager.lock().unwrap().process_headers(&headers) {
+                    Ok(hashes) => hashes,
+                    Err(err) => {
+                        warn!("Invalid headers: {:?}", err);
+                        return Err(Misbehavior::InvalidBlock);
+                    }
+                };
+                if hashes.is_empty() {
+                    return Ok(vec![]);
+                }
+                let hashes = hashes.into_iter().take(MAX_BLOCKS_PER_MESSAGE).collect();
+                let payload = ApplicationPayload::GetBlocks { hashes };
+                Ok(vec![(payload, vec![peer])])
+            }
+            ApplicationPayload::GetBlocks { hashes } => {
+                let hashes = &hashes[..hashes.len().min(MAX_BLOCKS_PER_MESSAGE)];
+                let blocks = blockchain_manager.lock().unwrap().get_blocks(hashes);
+                let payload = ApplicationPayload::Blocks { blocks };
+                Ok(vec![(payload, vec![peer])])
+            }
+            ApplicationPayload::GetMerkleProof { txid } => {
+                if peer_kind == PeerKind::Unknown {
+                    warn!("GetMerkleProof received from unknown");
+                    return Err(Misbehavior::ProtocolViolation);
+                }
+                let (block_hash, proof) =
+                    match blockchain_manager.lock().unwrap().get_merkle_proof(&txid) {
+                        Some(res) => res,
+                        None => return Ok(vec![]),
+                    };
+                let payload = ApplicationPayload::MerkleProof {
+                    txid,
+                    block_hash,
+                    proof,
+                };
+                Ok(vec![(payload, vec![peer])])
+            }
+            ApplicationPayload::MerkleProof { .. } => Ok(vec![]),
+            ApplicationPayload::GetAddressTransactions { address } => {
+                if peer_kind == PeerKind::Unknown {
+                    warn!("GetAddressTransactions received from unknown");
+                    return Err(Misbehavior::ProtocolViolation);
+                }
+                let transactions = blockchain_manager
+                    .lock()
+                    .unwrap()
+                    .get_transactions_for(&address);
                 debug!(
-                    "Current blockchain is: {:?}",
-                    blockchain_manager.get_chain()
+                    "Send {} transactions of {} for reply to {}",
+                    transactions.len(),
+                    address,
+                    peer
                 );
-
-                blockchain_manager.remove_useless_transactions(&mut transaction_pool);
-
-                None
-            }
-            ApplicationPayload::RequestFullChain => {
-                debug!("Send our latest blockchain for reply to {}", peer);
-                let chain = blockchain_manager.lock().unwrap().get_chain();
-                let payload = ApplicationPayload::FullChain { chain };
-                Some((payload, vec![peer]))
+                let payload = ApplicationPayload::AddressTransactions { transactions };
+                Ok(vec![(payload, vec![peer])])
             }
-            ApplicationPayload::FullChain { chain } => {
-                if !is_core {
-                    warn!("Blockchain received from unknown");
-                    return None;
+            ApplicationPayload::AddressTransactions { .. } => Ok(vec![]),
+            ApplicationPayload::Blocks { blocks } => {
+                if peer_kind != PeerKind::Core {
+                    warn!("Blocks received from unknown");
+                    return Err(Misbehavior::ProtocolViolation);
+                }
+                if blocks.len() > MAX_BLOCKS_PER_MESSAGE {
+                    warn!("Too many blocks from {}: {}", peer, blocks.len());
+                    return Err(Misbehavior::OversizedMessage);
+                }
+                // 前後の block によらず確認できる誤りは送信元の不正とみなす
+                for block in blocks.iter() {
+                    if let Err(err) = block
+                        .get_header()
+                        .check_proof_of_work()
+                        .and_then(|_| block.check_merkle_root())
+                    {
+                        warn!("Invalid block: {:?}", err);
+                        return Err(Misbehavior::InvalidBlock);
+                    }
                 }
 
                 let mut blockchain_manager = blockchain_manager.lock().unwrap();
                 let mut transaction_pool = transaction_pool.lock().unwrap();
 
-                let orphan_transactions = blockchain_manager.resolve_conflicts(chain);
-                for transaction in orphan_transactions {
-                    transaction_pool.add_new_transaction(transaction);
+                let hashes: Vec<BlockHash> = blocks
+                    .iter()
+                    .map(|block| block.get_header().get_hash())
+                    .collect();
+                let last_block_hash = blockchain_manager.get_last_block_hash();
+                let orphan_transactions = match blockchain_manager.resolve_conflicts(blocks) {
+                    Ok(orphan_transactions) => orphan_transactions,
+                    Err(err) => {
+                        error!("Failed to resolve conflicts: {:?}", err);
+                        return Ok(vec![]);
+                    }
+                };
+                // 受け入れた block だけを処理済みにする
+                let mut relay = relay.lock().unwrap();
+                for hash in hashes {
+                    if blockchain_manager.has_block(&hash) {
+                        relay.mark_seen(InventoryItem::Block(hash));
+                    }
                 }
+                if blockchain_manager.get_last_block_hash() == last_block_hash {
+                    return Ok(vec![]);
+                }
+                mining_canceller.cancel();
+                blockchain_manager
+                    .restore_orphan_transactions(&mut transaction_pool, orphan_transactions);
+                blockchain_manager.remove_useless_transactions(&mut transaction_pool);
 
-                None
+                // 続きの block があるかもしれないので再度 header を要求する
+                let payload = ApplicationPayload::GetHeaders {
+                    locator: blockchain_manager.get_block_locator(),
+                };
+                // 新しい先頭の block を知らせる。途中の block は受け取った node が header から辿る
+                let tip = InventoryItem::Block(blockchain_manager.get_last_block_hash());
+                Ok(vec![
+                    (payload, vec![peer]),
+                    relay.accept(tip, peer, core_nodes),
+                ])
+            }
+            ApplicationPayload::Inv { items } => {
+                if peer_kind != PeerKind::Core {
+                    warn!("Inv received from unknown");
+                    return Err(Misbehavior::ProtocolViolation);
+                }
+                if items.len() > MAX_INV_PER_MESSAGE {
+                    warn!("Too many items in Inv from {}: {}", peer, items.len());
+                    return Err(Misbehavior::OversizedMessage);
+                }
+
+                let blockchain_manager = blockchain_manager.lock().unwrap();
+                let transaction_pool = transaction_pool.lock().unwrap();
+                let relay = relay.lock().unwrap();
+                let items = relay.get_wanted(items, &transaction_pool, &blockchain_manager);
+                if items.is_empty() {
+                    return Ok(vec![]);
+                }
+                Ok(vec![(ApplicationPayload::GetData { items }, vec![peer])])
+            }
+            ApplicationPayload::GetData { items } => {
+                if items.len() > MAX_INV_PER_MESSAGE {
+                    warn!("Too many items in GetData from {}: {}", peer, items.len());
+                    return Err(Misbehavior::OversizedMessage);
+                }
+
+                let blockchain_manager = blockchain_manager.lock().unwrap();
+                let transaction_pool = transaction_pool.lock().unwrap();
+                let replies = inventory::get_data(items, &transaction_pool, &blockchain_manager)
+                    .into_iter()
+                    .map(|payload| (payload, vec![peer]))
+                    .collect();
+                Ok(replies)
             }
             ApplicationPayload::Enhanced { data } => {
                 // Supply coin for development
                 let my_addr = key_manager.lock().unwrap().get_address();
-                let recipient_addr = String::from_utf8(data).unwrap();
+                let recipient_addr = match String::from_utf8(data) {
+                    Ok(addr) => addr,
+                    Err(err) => {
+                        warn!("Recipient of coin is not UTF-8: {:?}", err);
+                        return Err(Misbehavior::MalformedMessage);
+                    }
+                };
+                if let Err(err) = transaction::address_to_public_key(&recipient_addr) {
+                    warn!("Recipient of coin is not a valid address: {:?}", err);
+                    return Err(Misbehavior::MalformedMessage);
+                }
                 println!("my_addr: {}, recipient_addr: {}", my_addr, recipient_addr);
 
-                let chain = blockchain_manager.lock().unwrap().get_chain();
-                let block = chain.into_iter().find(|block| block.miner() == my_addr);
+                let utxos = blockchain_manager.lock().unwrap().get_utxos_for(&my_addr);
+                let utxo = {
+                    let transaction_pool = transaction_pool.lock().unwrap();
+                    utxos.into_iter().find(|(outpoint, output)| {
+                        let input = TransactionInput::new(
+                            outpoint.get_txid().clone(),
+                            outpoint.get_index(),
+                        );
+                        output.get_value() >= 10 && !transaction_pool.has_transaction_input(&input)
+                    })
+                };
 
-                if let Some(block) = block {
-                    let input_tx = block.get_transaction_at(0).unwrap();
-                    let transaction = NormalTransaction::new(
-                        vec![TransactionInput::new(input_tx, 0)],
+                if let Some((outpoint, _)) = utxo {
+                    let mut transaction = NormalTransaction::new(
+                        vec![TransactionInput::new(
+                            outpoint.get_txid().clone(),
+                            outpoint.get_index(),
+                        )],
                         vec![TransactionOutput::new(recipient_addr, 10)],
                         Utc::now(),
                     );
 
-                    transaction_pool
-                        .lock()
-                        .unwrap()
-                        .add_new_transaction(transaction.clone());
-
-                    let new_payload = ApplicationPayload::for_transaction(
-                        transaction,
-                        &mut key_manager.lock().unwrap(),
-                    )
-                    .unwrap();
-                    Some((new_payload, core_nodes))
+                    transaction.sign(&mut key_manager.lock().unwrap()).unwrap();
+                    let item = InventoryItem::Transaction(transaction.get_id());
+                    let blockchain_manager = blockchain_manager.lock().unwrap();
+                    let mut transaction_pool = transaction_pool.lock().unwrap();
+                    if let Err(err) =
+                        transaction_pool.add_new_transaction(transaction, &*blockchain_manager)
+                    {
+                        warn!("Failed to supply coin: {}", err);
+                        return Ok(vec![]);
+                    }
+                    Ok(vec![relay.lock().unwrap().accept(item, peer, core_nodes)])
                 } else {
-                    warn!("No block was found to supply coin");
-                    None
+                    warn!("No UTXO was found to supply coin");
+                    Ok(vec![])
                 }
             }
         }
     }
 }
 
+// block に取り込まれないまま有効期限が過ぎた transaction を定期的に除き、path があれば pool を保存する
+async fn maintain_transaction_pool(
+    pool: Arc<Mutex<TransactionPool>>,
+    path: Option<PathBuf>,
+    interval: Duration,
+) {
+    loop {
+        tokio::time::sleep(interval).await;
+        let mut pool = pool.lock().unwrap();
+        pool.remove_expired_transactions(Utc::now());
+        if let Some(path) = &path {
+            save_transaction_pool(&pool, path);
+        }
+    }
+}
+
+fn save_transaction_pool(pool: &TransactionPool, path: &Path) {
+    match pool.save(path) {
+        Ok(()) => debug!("Saved {} transactions to {:?}", pool.len(), path),
+        Err(err) => error!("Failed to save transaction pool: {:?}", err),
+    }
+}
+
 impl ServerCore {
     pub fn new(
         my_addr: SocketAddr,
@@ -173,6 +400,7 @@ impl ServerCore {
         key_manager: Arc<Mutex<KeyManager>>,
     ) -> ServerCore {
         info!("Initializing ServerCore...");
+        let mining_canceller = MiningCanceller::new();
         ServerCore {
             state: ServerCoreState::Init,
             core_node_addr,
@@ -182,35 +410,111 @@ impl ServerCore {
                     Arc::clone(&pool),
                     Arc::clone(&manager),
                     Arc::clone(&key_manager),
+                    mining_canceller.clone(),
                 ),
+                {
+                    let manager = Arc::clone(&manager);
+                    move || manager.lock().unwrap().get_height()
+                },
             ),
             bm: manager,
             tp: pool,
             km: key_manager,
+            miner: Miner::new(1),
+            mining_canceller,
+            mempool_path: None,
         }
     }
 
-    pub async fn start(&mut self) {
+    /// node の鍵で接続を暗号化し、相手を認証する。start の前に呼び出す。
+    pub fn enable_secure_transport(&mut self) {
+        self.cm.enable_secure_transport(Arc::clone(&self.km));
+    }
+
+    /// 不正な振る舞いをした node を ban する期間を設定する。start の前に呼び出す。
+    pub fn set_ban_duration(&mut self, ban_duration: Duration) {
+        self.cm.set_ban_duration(ban_duration);
+    }
+
+    /// 管理用 API から接続の状態を参照するために使う。
+    pub fn get_connection_manager(&self) -> Arc<Mutex<ConnectionManagerInner>> {
+        Arc::clone(&self.cm.inner)
+    }
+
+    /// 管理用 API から chain の状態を参照するために使う。
+    pub fn get_blockchain_manager(&self) -> Arc<Mutex<BlockchainManager>> {
+        Arc::clone(&self.bm)
+    }
+
+    /// path に保存された Core ノードの address を参加先の候補にし、終了時に保存する。start の前に呼び出す。
+    pub fn load_address_book<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        self.cm.set_address_book(AddressBook::open(path)?);
+        Ok(())
+    }
+
+    /// path に保存された transaction のうち、現在の chain に対して有効なものを pool に戻し、
+    /// 定期的にと終了時に pool を path に保存する。start の前に呼び出す。
+    pub fn load_transaction_pool<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let saved = TransactionPool::load_saved(&path)?;
+        let blockchain_manager = self.bm.lock().unwrap();
+        let mut transaction_pool = self.tp.lock().unwrap();
+        blockchain_manager.restore_saved_transactions(&mut transaction_pool, saved);
+        self.mempool_path = Some(path.as_ref().to_path_buf());
+        Ok(())
+    }
+
+    /// mining に使う thread の数を設定する。start の前に呼び出す。
+    pub fn set_mining_threads(&mut self, threads: usize) {
+        self.miner = Miner::new(threads);
+    }
+
+    pub async fn start(&mut self) -> Result<()> {
         self.state = ServerCoreState::Standby;
-        self.cm.start().await;
+        self.cm.start().await?;
 
         tokio::spawn(TransactionPool::generate_block_periodically(
             Arc::clone(&self.tp),
             Arc::clone(&self.bm),
             Arc::clone(&self.cm.inner),
             Arc::clone(&self.km),
+            self.miner,
+            self.mining_canceller.clone(),
             Duration::from_secs(60),
         ));
+        tokio::spawn(maintain_transaction_pool(
+            Arc::clone(&self.tp),
+            self.mempool_path.clone(),
+            POOL_MAINTENANCE_INTERVAL,
+        ));
+        Ok(())
     }
 
     pub async fn join_network(&mut self) {
-        if let Some(core_node_addr) = self.core_node_addr {
+        let joined = match self.cm.join_network(self.core_node_addr).await {
+            Ok(joined) => joined,
+            // 参加先を指定されていない場合は、保存された Core ノードが戻るのを待ちながら単独で動く
+            Err(err) if self.core_node_addr.is_none() => {
+                warn!("Failed to join network: {:#}", err);
+                return;
+            }
+            Err(err) => panic!("{:?}", err.context("Failed to join network")),
+        };
+        if let Some(core_node_addr) = joined {
+            self.core_node_addr = Some(core_node_addr);
             self.state = ServerCoreState::ConnectedToNetwork;
-            self.cm
-                .join_network(core_node_addr)
-                .await
-                .with_context(|| "Failed to join network")
-                .unwrap();
+
+            // 参加したネットワークの chain に追いつく
+            let payload = Payload::Application {
+                payload: ApplicationPayload::GetHeaders {
+                    locator: self.bm.lock().unwrap().get_block_locator(),
+                },
+            };
+            let port = self.cm.inner.lock().unwrap().get_my_addr().port();
+            ConnectionManagerCore::send_msg_to_core_nodes(
+                Arc::clone(&self.cm.inner),
+                Message::new(port, payload),
+            )
+            .await;
         } else {
             info!("This server is running as Genesis Core Node...");
         }
@@ -220,6 +524,9 @@ impl ServerCore {
         self.state = ServerCoreState::ShuttingDown;
         info!("Shutdown ServerCore ...");
         self.cm.connection_close(self.core_node_addr.as_ref()).await;
+        if let Some(path) = &self.mempool_path {
+            save_transaction_pool(&self.tp.lock().unwrap(), path);
+        }
     }
 
     pub fn get_my_current_state(&self) -> &ServerCoreState {
@@ -1,4 +1,4 @@
-use anyhow::Result;
+use anyhow::{bail, Result};
 use rsa::{Hash, PaddingScheme, PublicKey, RsaPrivateKey, RsaPublicKey};
 use sha2::{Digest, Sha256};
 
@@ -17,28 +17,30 @@ pub fn bytes_to_hex(xs: &[u8]) -> String {
         buf
     }
 
-    xs.iter().map(|x| hex(*x)).flatten().collect()
+    xs.iter().flat_map(|x| hex(*x)).collect()
 }
 
 pub fn sha256(data: &[u8], nonce: &[u8]) -> String {
     let mut hasher = Sha256::new();
     hasher.update(data);
     hasher.update(nonce);
-    bytes_to_hex(&hasher.finalize().to_vec())
+    bytes_to_hex(&hasher.finalize())
 }
 
-pub fn hex_to_bytes(xs: String) -> Vec<u8> {
-    fn h_to_n(x: u8) -> Option<u8> {
+pub fn hex_to_bytes(xs: String) -> Result<Vec<u8>> {
+    fn h_to_n(x: u8) -> Result<u8> {
         if (b'a'..=b'f').contains(&x) {
-            Some(x - b'a' + 10)
-        } else if (b'0'..=b'9').contains(&x) {
-            Some(x - b'0')
+            Ok(x - b'a' + 10)
+        } else if x.is_ascii_digit() {
+            Ok(x - b'0')
         } else {
-            None
+            bail!("Invalid hex character: {:?}", x as char)
         }
     }
 
-    assert_eq!(xs.len() % 2, 0);
+    if xs.len() % 2 == 1 {
+        bail!("Length of hex string must be even: {}", xs.len());
+    }
 
     let mut res = vec![];
     let upper = xs
@@ -49,10 +51,10 @@ pub fn hex_to_bytes(xs: String) -> Vec<u8> {
         .bytes()
         .enumerate()
         .filter_map(|(i, x)| if i % 2 == 1 { Some(x) } else { None });
-    upper.zip(lower).for_each(|(u, l)| {
-        res.push(h_to_n(u).unwrap() * 16 + h_to_n(l).unwrap());
-    });
-    res
+    for (u, l) in upper.zip(lower) {
+        res.push(h_to_n(u)? * 16 + h_to_n(l)?);
+    }
+    Ok(res)
 }
 
 pub fn calc_hash(data: &[u8]) -> Vec<u8> {
@@ -84,6 +86,8 @@ mod tests {
 
     #[test]
     fn test_to_bytes() {
-        assert_eq!(&hex_to_bytes("41624e".to_string()), &[65, 98, 78]);
+        assert_eq!(&hex_to_bytes("41624e".to_string()).unwrap(), &[65, 98, 78]);
+        assert!(hex_to_bytes("41624".to_string()).is_err());
+        assert!(hex_to_bytes("alice1".to_string()).is_err());
     }
 }